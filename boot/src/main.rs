@@ -72,6 +72,15 @@ fn _main(handle: uefi::Handle, mut st: SystemTable<Boot>) -> Status {
         "_main(): Kernel loaded at {:#x}",
         kernel.start_address as u64
     );
+    // Load the initramfs, which stays in `LOADER_DATA` pages and is not touched by the kernel frame allocator.
+    let initrd = utils::load_initrd(bs, &config);
+    if let Some(initrd) = initrd {
+        info!(
+            "_main(): Initramfs loaded at {:#x} with size {:#x}",
+            initrd.as_ptr() as u64,
+            initrd.len()
+        );
+    }
     // In the context of UEFI (Unified Extensible Firmware Interface),
     // the memory_map_size parameter specifies the size of the memory
     // map that is provided by the UEFI firmware. The memory map is a
@@ -145,6 +154,8 @@ fn _main(handle: uefi::Handle, mut st: SystemTable<Boot>) -> Status {
         first_proc_len: config.first_proc.len() as _,
         args: config.args.as_ptr(),
         args_len: config.args.len() as _,
+        initrd: initrd.map_or(0, |initrd| initrd.as_ptr() as u64),
+        initrd_size: initrd.map_or(0, |initrd| initrd.len() as u64),
     };
     page_table::map_gdt(&kernel, &mut allocator, &mut pt);
 
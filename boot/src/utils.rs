@@ -101,10 +101,8 @@ pub struct BootLoaderConfig<'a> {
     pub cmdline: &'a str,
     /// The starting virtual address of the allocatable memory.
     pub physical_mem: u64,
-    /// The starting address of the initramfs.
-    pub initramfs: u64,
-    /// The size of the initramfs.
-    pub initramfs_size: u64,
+    /// The path to the initramfs (a cpio archive in the "newc" format). Optional.
+    pub initrd_path: &'a str,
     /// The first process's name.
     pub first_proc: &'a str,
     /// The arguments for the first process.
@@ -120,8 +118,7 @@ impl<'a> BootLoaderConfig<'a> {
         let kernel_path = parse_str(config_str, "kernel_path");
         let cmdline = parse_str(config_str, "cmdline");
         let physical_mem = parse_u64(config_str, "physical_mem");
-        let initramfs = parse_u64(config_str, "initramfs");
-        let initramfs_size = parse_u64(config_str, "initramfs_size");
+        let initrd_path = parse_str(config_str, "initrd_path");
         let first_proc = parse_str(config_str, "first_proc");
         let args = parse_str(config_str, "args");
 
@@ -131,8 +128,7 @@ impl<'a> BootLoaderConfig<'a> {
             kernel_path,
             cmdline,
             physical_mem,
-            initramfs,
-            initramfs_size,
+            initrd_path,
             first_proc,
             args,
         }
@@ -147,8 +143,7 @@ impl<'a> Default for BootLoaderConfig<'a> {
             kernel_path: "\\efi\\boot\\NeoOS.img",
             cmdline: "",
             physical_mem: 0xFFFF800000000000,
-            initramfs: 0,
-            initramfs_size: 0,
+            initrd_path: "",
             first_proc: "",
             args: "",
         }
@@ -158,6 +153,11 @@ impl<'a> Default for BootLoaderConfig<'a> {
 /// Opens a file on the disk.
 /// At this timepoint, the filesystem is not created, so we need to create a temporary one.
 pub fn open_file(bs: &BootServices, path: &str) -> RegularFile {
+    try_open_file(bs, path).expect("Failed to open file")
+}
+
+/// Opens a file on the disk, or returns `None` if it does not exist or is not a regular file.
+pub fn try_open_file(bs: &BootServices, path: &str) -> Option<RegularFile> {
     // Create a temporary filesystem.
     let handle = bs.get_handle_for_protocol::<SimpleFileSystem>().unwrap();
     let mut file_system = bs
@@ -169,15 +169,37 @@ pub fn open_file(bs: &BootServices, path: &str) -> RegularFile {
     let filename = CStr16::from_str_with_buf(path, &mut buf).unwrap();
     let handle = root
         .open(filename, FileMode::Read, FileAttribute::empty())
-        .expect("Failed to open file");
+        .ok()?;
 
     let file = match handle.into_type().unwrap() {
         FileType::Regular(f) => f,
-        _ => panic!("This file does not exist!"),
+        _ => return None,
     };
-    info!("open_file(): File {} successfullly opened!", path);
+    info!("try_open_file(): File {} successfullly opened!", path);
 
-    file
+    Some(file)
+}
+
+/// Loads the initramfs specified by `initrd_path` into the memory. Returns `None` if it is not specified or cannot be
+/// found, in which case the kernel needs a disk to boot.
+pub fn load_initrd(bs: &BootServices, config: &BootLoaderConfig) -> Option<&'static [u8]> {
+    if config.initrd_path.is_empty() {
+        return None;
+    }
+
+    let mut file = match try_open_file(bs, config.initrd_path) {
+        Some(file) => file,
+        None => {
+            info!(
+                "load_initrd(): {} not found; booting without initramfs.",
+                config.initrd_path
+            );
+            return None;
+        }
+    };
+    let initrd = read_buf(bs, &mut file);
+
+    (!initrd.is_empty()).then_some(initrd)
 }
 
 pub fn read_buf(bs: &BootServices, file: &mut RegularFile) -> &'static mut [u8] {
@@ -185,6 +207,9 @@ pub fn read_buf(bs: &BootServices, file: &mut RegularFile) -> &'static mut [u8]
     let info: &mut FileInfo = file.get_info(&mut file_info).unwrap();
     let size = usize::try_from(info.file_size()).unwrap();
     info!("read_buf(): File size is {}", size);
+    if size == 0 {
+        return &mut [];
+    }
 
     // Allocate ramdisk pages in the memory.
     let file_mem_ptr = bs
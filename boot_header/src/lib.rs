@@ -51,6 +51,10 @@ pub struct Header {
     pub args: *const u8,
     /// The length of the arguments.
     pub args_len: u64,
+    /// The physical address of the initramfs (a cpio archive). Zero if the bootloader did not load one.
+    pub initrd: u64,
+    /// The size of the initramfs.
+    pub initrd_size: u64,
 }
 
 /// Graphic informations for printing to the console.
@@ -13,7 +13,7 @@ use crate::{
         PAGE_SIZE,
     },
     error::KResult,
-    memory::{allocate_frame_contiguous, atomic_memset, phys_to_virt, read_at},
+    memory::{atomic_memset, phys_to_virt, read_at, KernelStack},
 };
 
 pub fn init_aps<H>(madt: &PhysicalMapping<H, Madt>) -> KResult<()>
@@ -73,9 +73,9 @@ where
                     }
 
                     // Allocate stack frames for the AP.
-                    let ap_stack_frame = allocate_frame_contiguous(0x40, 0)?;
-                    let stack_bottom = phys_to_virt(ap_stack_frame.as_u64());
-                    let stack_top = stack_bottom + 0x40 * PAGE_SIZE as u64;
+                    let ap_stack = KernelStack::with_size(0x40 * PAGE_SIZE)?;
+                    let stack_bottom = ap_stack.bottom() as u64;
+                    let stack_top = ap_stack.leak() as u64;
                     let page_table = KernelPageTable::active()
                         .page_table_frame
                         .start_address()
@@ -14,6 +14,7 @@ use crate::{
 use super::{
     cpu::{cpu_id, cpuid},
     interrupt::IRQ_MIN,
+    timer::TimerMode,
 };
 
 pub mod ioapic;
@@ -64,6 +65,10 @@ pub trait ApicSupport: Send + Sync {
 
     /// Initializes the timer source.
     fn init_timer(&self) -> KResult<()>;
+
+    /// Reprograms the local timer in `mode`. For [`TimerMode::OneShot`] and [`TimerMode::Periodic`], `count` is the
+    /// initial count; for [`TimerMode::TscDeadline`], `count` is the absolute TSC value at which the timer fires.
+    fn set_timer(&self, mode: TimerMode, count: u64);
 }
 
 #[derive(Debug)]
@@ -9,13 +9,18 @@
 use core::sync::atomic::Ordering;
 
 use x86::msr::{
-    rdmsr, wrmsr, IA32_APIC_BASE, IA32_X2APIC_APICID, IA32_X2APIC_CUR_COUNT, IA32_X2APIC_DIV_CONF,
-    IA32_X2APIC_EOI, IA32_X2APIC_ICR, IA32_X2APIC_INIT_COUNT, IA32_X2APIC_LVT_TIMER,
-    IA32_X2APIC_SIVR, IA32_X2APIC_VERSION,
+    rdmsr, wrmsr, IA32_APIC_BASE, IA32_TSC_DEADLINE, IA32_X2APIC_APICID, IA32_X2APIC_CUR_COUNT,
+    IA32_X2APIC_DIV_CONF, IA32_X2APIC_EOI, IA32_X2APIC_ICR, IA32_X2APIC_INIT_COUNT,
+    IA32_X2APIC_LVT_TIMER, IA32_X2APIC_SIVR, IA32_X2APIC_VERSION,
 };
 
 use crate::{
-    arch::{apic::disable_irq, interrupt::timer::APIC_UP, pit::countdown},
+    arch::{
+        apic::disable_irq,
+        interrupt::timer::APIC_UP,
+        pit::countdown,
+        timer::{TimerMode, APIC_TIMER_TICKS},
+    },
     error::KResult,
 };
 
@@ -80,6 +85,7 @@ impl ApicSupport for X2Apic {
                 wrmsr(IA32_X2APIC_LVT_TIMER, 0x10000);
                 let apic_timer_current = 0xFFFFFFFF - rdmsr(IA32_X2APIC_CUR_COUNT);
                 // Now we know how often the APIC timer has ticked in 10ms.
+                APIC_TIMER_TICKS.call_once(|| apic_timer_current);
 
                 // Start timer as periodic on IRQ 0, divider 16, with the number of ticks we counted
                 wrmsr(IA32_X2APIC_LVT_TIMER, 0x20 | 0x20000);
@@ -95,4 +101,18 @@ impl ApicSupport for X2Apic {
         }
         Ok(())
     }
+
+    fn set_timer(&self, mode: TimerMode, count: u64) {
+        unsafe {
+            wrmsr(IA32_X2APIC_LVT_TIMER, (mode as u64) << 17 | 0x20);
+
+            match mode {
+                TimerMode::TscDeadline => wrmsr(IA32_TSC_DEADLINE, count),
+                _ => {
+                    wrmsr(IA32_X2APIC_DIV_CONF, 0x3);
+                    wrmsr(IA32_X2APIC_INIT_COUNT, count.min(u32::MAX as u64));
+                }
+            }
+        }
+    }
 }
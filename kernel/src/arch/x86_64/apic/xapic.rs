@@ -5,7 +5,12 @@
 
 use bit_field::BitField;
 
-use crate::error::KResult;
+use x86::msr::{wrmsr, IA32_TSC_DEADLINE};
+
+use crate::{
+    arch::timer::{TimerMode, APIC_TIMER_TICKS},
+    error::KResult,
+};
 
 use super::{ApicInfo, ApicSupport, ApicType};
 
@@ -42,6 +47,7 @@ const MASKED: u32 = 0x00010000; // Interrupt masked
 const TICR: u32 = 0x0380; // Timer Initial Count
 const TCCR: u32 = 0x0390; // Timer Current Count
 const TDCR: u32 = 0x03E0; // Timer Divide Configuration
+const TICR_DEFAULT: u32 = 10000000; // Initial count of the periodic timer
 
 const T_IRQ0: u32 = 32; // IRQ 0 corresponds to int T_IRQ
 const IRQ_TIMER: u32 = 0;
@@ -67,7 +73,8 @@ impl ApicSupport for XApic {
             // TICR would be calibrated using an external time source.
             self.write(TDCR, X1);
             self.write(TIMER, PERIODIC | (T_IRQ0 + IRQ_TIMER));
-            self.write(TICR, 10000000);
+            self.write(TICR, TICR_DEFAULT);
+            APIC_TIMER_TICKS.call_once(|| TICR_DEFAULT as u64);
 
             // Disable logical interrupt lines.
             self.write(LINT0, MASKED);
@@ -141,6 +148,20 @@ impl ApicSupport for XApic {
     fn init_timer(&self) -> KResult<()> {
         Ok(())
     }
+
+    fn set_timer(&self, mode: TimerMode, count: u64) {
+        unsafe {
+            self.write(TIMER, (mode as u32) << 17 | (T_IRQ0 + IRQ_TIMER));
+
+            match mode {
+                TimerMode::TscDeadline => wrmsr(IA32_TSC_DEADLINE, count),
+                _ => {
+                    self.write(TDCR, X1);
+                    self.write(TICR, count.min(u32::MAX as u64) as u32);
+                }
+            }
+        }
+    }
 }
 
 impl XApic {
@@ -1,5 +1,6 @@
 //! Starts the kernel.
 
+use alloc::string::String;
 use boot_header::Header;
 use core::{
     hint::spin_loop,
@@ -11,16 +12,19 @@ use crate::{
         acpi::init_acpi,
         cpu::{cpu_id, init_cpu, measure_frequency, print_cpu_topology, AP_UP_NUM, CPU_NUM},
         interrupt::init_interrupt_all,
-        mm::paging::{init_kernel_page_table, init_mm},
+        mm::paging::{init_direct_map, init_kernel_page_table, init_mm},
         timer::{init_apic_timer, TimerSource, TIMER_SOURCE},
+        PAGE_SIZE,
     },
     drivers::{
         keyboard::init_keyboard, pci_bus::init_pci, rtc::init_rtc, serial::init_all_serial_ports,
     },
+    fs::{initramfs::INITRD, ROOT_FLAGS},
     kmain,
     logging::init_env_logger,
-    memory::{init_heap, phys_to_virt},
-    process::scheduler::FIFO_SCHEDULER,
+    memory::{init_heap, phys_to_virt, KernelStack},
+    process::{scheduler::FIFO_SCHEDULER, workqueue::init_workqueues},
+    slab::init_slab,
     LOG_LEVEL,
 };
 
@@ -66,6 +70,14 @@ pub unsafe extern "C" fn _start(header: *const Header) -> ! {
         );
     }
     kinfo!("initialized memory management.");
+    if let Err(errno) = init_direct_map(header) {
+        panic!(
+            "init_direct_map(): failed to remap the physical memory! Errno: {:?}",
+            errno
+        );
+    }
+    // Frames are available now; small objects can be served by slabs.
+    init_slab();
 
     // Initialize the interrupt-related data structures and handlers.
     if let Err(errno) = init_interrupt_all() {
@@ -117,9 +129,34 @@ pub unsafe extern "C" fn _start(header: *const Header) -> ! {
             header.args_len as usize,
         )
     };
+    if header.initrd != 0 {
+        let initrd = unsafe {
+            core::slice::from_raw_parts(
+                phys_to_virt(header.initrd) as *const u8,
+                header.initrd_size as usize,
+            )
+        };
+        INITRD.call_once(|| initrd);
+        kinfo!("initramfs found with size {:#x}", initrd.len());
+    }
+    let cmdline = unsafe {
+        core::slice::from_raw_parts(
+            phys_to_virt(header.cmdline as u64) as *const u8,
+            header.cmdline_len as usize,
+        )
+    };
+    // Copy the root mount options since the loader memory is not kept; the root is mounted lazily.
+    if let Some(flags) = core::str::from_utf8(cmdline)
+        .unwrap_or_default()
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix("rootflags="))
+    {
+        ROOT_FLAGS.call_once(|| String::from(flags));
+    }
     let first_proc = core::str::from_utf8(first_proc).unwrap_or_default();
     let args = core::str::from_utf8(args).unwrap_or_default();
     FIFO_SCHEDULER.init();
+    init_workqueues();
     crate::process::thread::init_ash(first_proc, args);
 
     // Step into the kernel main function.
@@ -130,6 +167,24 @@ pub unsafe extern "C" fn _start(header: *const Header) -> ! {
         spin_loop();
     }
 
+    // The BSP runs the executor, and thus the syscalls of the threads it picks, from here on. The boot stack given by
+    // the bootloader has no guard page, so switch to a [`KernelStack`] like the APs do.
+    let stack_top = match KernelStack::with_size(0x40 * PAGE_SIZE) {
+        Ok(stack) => stack.leak(),
+        Err(errno) => panic!("failed to allocate the BSP stack! Errno: {:?}", errno),
+    };
+    core::arch::asm!(
+        "mov rsp, {stack_top}",
+        "xor rbp, rbp",
+        "call {kmain}",
+        stack_top = in(reg) stack_top,
+        kmain = sym kmain_bsp,
+        options(noreturn),
+    );
+}
+
+/// Enters [`kmain`] on the stack that [`_start`] has switched to.
+extern "C" fn kmain_bsp() -> ! {
     kmain();
 }
 
@@ -1,17 +1,29 @@
 //! Rust port of linux/arch/x86/cpu.c
 
 use core::{
-    arch::x86_64::{_fxrstor64, _fxsave64},
+    alloc::Layout,
+    arch::x86_64::{_fxrstor64, _fxsave64, _xrstor64, _xsave64, _xsaveopt64},
+    fmt::Debug,
+    ptr::NonNull,
     sync::atomic::AtomicUsize,
 };
 
-use alloc::{boxed::Box, format, string::String, vec::Vec};
+use alloc::{
+    alloc::{alloc_zeroed, dealloc, handle_alloc_error},
+    boxed::Box,
+    format,
+    string::String,
+    vec::Vec,
+};
 
 use raw_cpuid::{CpuId, CpuIdResult, FeatureInfo};
 use spin::Once;
 use x86::random::rdrand64;
 use x86_64::{
-    registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+    registers::{
+        control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+        xcontrol::{XCr0, XCr0Flags},
+    },
     structures::tss::TaskStateSegment,
 };
 
@@ -52,9 +64,11 @@ pub static mut CPUS: [spin::Once<AbstractCpu>; MAX_CPU_NUM] = {
 /// such as addition, subtraction, multiplication, and division, on real numbers. The FP unit is designed to handle
 /// operations involving floating-point numbers with higher precision than the regular integer arithmetic units in the
 /// processor.
+///
+/// This is the layout written by `fxsave`, which is also the legacy region at the beginning of an XSAVE area.
 #[derive(Debug, Copy, Clone, Default)]
 #[repr(C, align(16))]
-pub struct FpState {
+pub struct FxsaveArea {
     /// x87 FPU Control Word (16 bits). See Figure 8-6 in the Intel® 64 and IA-32 Architectures Software Developer’s Manual
     /// Volume 1, for the layout of the x87 FPU control word.
     pub fcw: u16,
@@ -91,32 +105,177 @@ pub struct FpState {
     pub _pad: [u64; 12],
 }
 
+/// The header that follows the legacy region in an XSAVE area.
+#[derive(Debug, Copy, Clone, Default)]
+#[repr(C)]
+pub struct XsaveHeader {
+    /// The state components that are not in their initial configuration.
+    pub xstate_bv: u64,
+    /// Bit 63 indicates the compacted format, which we never use.
+    pub xcomp_bv: u64,
+    /// reserved; must be zero or `xrstor` raises #GP.
+    pub _reserved: [u64; 6],
+}
+
+/// How the floating-point state is saved on this machine. Determined once by the BSP.
+#[derive(Debug, Clone, Copy)]
+pub struct FpuInfo {
+    /// The state components enabled in XCR0. Empty if XSAVE is unavailable and we fall back to `fxsave`.
+    pub features: XCr0Flags,
+    /// The size of the save area.
+    pub size: usize,
+    /// Whether `xsaveopt` can skip the components that were not modified since they were restored.
+    pub xsaveopt: bool,
+    /// The MXCSR bits that the CPU supports. Setting others raises #GP on restore.
+    pub mxcsr_mask: u32,
+}
+
+pub static FPU_INFO: Once<FpuInfo> = Once::new();
+
+/// XSAVE requires its area to be aligned on a 64-byte boundary.
+const FP_STATE_ALIGN: usize = 0x40;
+/// The value of MXCSR_MASK if the CPU leaves it zero.
+const MXCSR_DEFAULT_MASK: u32 = 0xffbf;
+
+/// The floating-point and vector register state of a user thread.
+///
+/// The save area starts with the [`FxsaveArea`]. If XSAVE is available, it is followed by the [`XsaveHeader`] and the
+/// extended components enabled in XCR0, such as the upper halves of the YMM registers. Its size thus depends on the
+/// CPU, so it lives on the heap rather than inline.
+pub struct FpState {
+    area: NonNull<u8>,
+    layout: Layout,
+}
+
+unsafe impl Send for FpState {}
+unsafe impl Sync for FpState {}
+
 impl FpState {
     pub fn new() -> Self {
-        assert!(core::mem::size_of::<Self>() == 0x200);
+        assert!(core::mem::size_of::<FxsaveArea>() == 0x200);
+
+        let size = FPU_INFO
+            .get()
+            .map_or(core::mem::size_of::<FxsaveArea>(), |info| info.size);
+        let layout = Layout::from_size_align(size, FP_STATE_ALIGN).unwrap();
+        let area = NonNull::new(unsafe { alloc_zeroed(layout) })
+            .unwrap_or_else(|| handle_alloc_error(layout));
+
+        let mut fp_state = Self { area, layout };
+        fp_state.reset();
+        fp_state
+    }
 
-        Self {
+    /// Puts every register into its initial configuration. An all-zero XSAVE header does so for the extended
+    /// components.
+    pub fn reset(&mut self) {
+        self.as_bytes_mut().fill(0);
+        *self.legacy_mut() = FxsaveArea {
+            // Mask all x87 exceptions.
+            fcw: 0x037f,
             // RESET_VALUE = 0x1f80
             mxcsr: 0x1f80,
-            // Initial value for fctrl register.
-            mxcsr_mask: 0x037f,
             ..Default::default()
+        };
+    }
+
+    pub fn size(&self) -> usize {
+        self.layout.size()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.area.as_ptr(), self.size()) }
+    }
+
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.area.as_ptr(), self.size()) }
+    }
+
+    pub fn legacy(&self) -> &FxsaveArea {
+        unsafe { &*(self.area.as_ptr() as *const FxsaveArea) }
+    }
+
+    pub fn legacy_mut(&mut self) -> &mut FxsaveArea {
+        unsafe { &mut *(self.area.as_ptr() as *mut FxsaveArea) }
+    }
+
+    /// The XSAVE header, if the area has one.
+    fn header_mut(&mut self) -> Option<&mut XsaveHeader> {
+        let offset = core::mem::size_of::<FxsaveArea>();
+        if self.size() < offset + core::mem::size_of::<XsaveHeader>() {
+            return None;
+        }
+
+        Some(unsafe { &mut *(self.area.as_ptr().add(offset) as *mut XsaveHeader) })
+    }
+
+    /// Clears the bits of a state provided by the user that would make [`FpState::restore`] fault.
+    pub fn sanitize(&mut self) {
+        let info = FPU_INFO.get().copied();
+        let mxcsr_mask = info.map_or(MXCSR_DEFAULT_MASK, |info| info.mxcsr_mask);
+        self.legacy_mut().mxcsr &= mxcsr_mask;
+
+        let features = info.map_or(XCr0Flags::empty(), |info| info.features);
+        if let Some(header) = self.header_mut() {
+            header.xstate_bv &= features.bits();
+            header.xcomp_bv = 0;
+            header._reserved = [0; 6];
         }
     }
 
-    pub fn fxsave(&mut self) {
+    /// Saves the registers of the current CPU into `self`.
+    pub fn save(&mut self) {
+        let ptr = self.area.as_ptr();
         unsafe {
-            _fxsave64(self as *mut Self as *mut u8);
+            match FPU_INFO.get() {
+                Some(info) if !info.features.is_empty() => {
+                    let mask = info.features.bits();
+                    if info.xsaveopt {
+                        _xsaveopt64(ptr, mask);
+                    } else {
+                        _xsave64(ptr, mask);
+                    }
+                }
+                _ => _fxsave64(ptr),
+            }
         }
     }
 
-    pub fn fxrstor(&self) {
+    /// Loads `self` into the registers of the current CPU.
+    pub fn restore(&self) {
+        let ptr = self.area.as_ptr() as *const u8;
         unsafe {
-            _fxrstor64(self as *const Self as *const u8);
+            match FPU_INFO.get() {
+                Some(info) if !info.features.is_empty() => _xrstor64(ptr, info.features.bits()),
+                _ => _fxrstor64(ptr),
+            }
         }
     }
 }
 
+impl Clone for FpState {
+    fn clone(&self) -> Self {
+        let mut fp_state = Self::new();
+        fp_state.as_bytes_mut().copy_from_slice(self.as_bytes());
+        fp_state
+    }
+}
+
+impl Drop for FpState {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.area.as_ptr(), self.layout) }
+    }
+}
+
+impl Debug for FpState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("FpState")
+            .field("size", &self.size())
+            .field("legacy", self.legacy())
+            .finish()
+    }
+}
+
 /// The Rust-like representation of the header stored in `ap_trampoline.S`.
 ///
 /// This struct serializes the memory bytes into a human-readable struct that is easy for us to work with, and
@@ -380,6 +539,77 @@ unsafe fn enable_float_processing_unit() {
         cr0.remove(Cr0Flags::EMULATE_COPROCESSOR);
         cr0.insert(Cr0Flags::MONITOR_COPROCESSOR);
     });
+
+    let features = enable_xsave();
+    let info = FPU_INFO.call_once(|| {
+        let size = match features.is_empty() {
+            true => core::mem::size_of::<FxsaveArea>(),
+            // Depends on the features that are currently enabled in XCR0.
+            false => cpuid()
+                .get_extended_state_info()
+                .unwrap()
+                .xsave_area_size_enabled_features() as usize,
+        };
+        let xsaveopt = !features.is_empty()
+            && cpuid()
+                .get_extended_state_info()
+                .map_or(false, |info| info.has_xsaveopt());
+
+        let mut area = FxsaveArea::default();
+        _fxsave64(&mut area as *mut FxsaveArea as *mut u8);
+        let mxcsr_mask = match area.mxcsr_mask {
+            0 => MXCSR_DEFAULT_MASK,
+            mask => mask,
+        };
+
+        FpuInfo {
+            features,
+            size,
+            xsaveopt,
+            mxcsr_mask,
+        }
+    });
+
+    if info.features != features {
+        kwarn!(
+            "enable_float_processing_unit(): CPU #{:#x} enables {:?}, but the BSP enables {:?}.",
+            cpu_id(),
+            features,
+            info.features
+        );
+    }
+}
+
+/// Enables XSAVE and the user state components it should manage. Returns the components enabled in XCR0, which is
+/// empty if the CPU does not support XSAVE.
+unsafe fn enable_xsave() -> XCr0Flags {
+    let cpuid = cpuid();
+    let state_info = match cpuid.get_feature_info() {
+        Some(fi) if fi.has_xsave() => cpuid.get_extended_state_info(),
+        _ => None,
+    };
+    let state_info = match state_info {
+        Some(state_info) => state_info,
+        None => return XCr0Flags::empty(),
+    };
+
+    let mut features = XCr0Flags::X87 | XCr0Flags::SSE;
+    if state_info.xcr0_supports_avx_256() {
+        features |= XCr0Flags::AVX;
+
+        // AVX-512 needs all three components.
+        if state_info.xcr0_supports_avx512_opmask()
+            && state_info.xcr0_supports_avx512_zmm_hi256()
+            && state_info.xcr0_supports_avx512_zmm_hi16()
+        {
+            features |= XCr0Flags::OPMASK | XCr0Flags::ZMM_HI256 | XCr0Flags::HI16_ZMM;
+        }
+    }
+
+    Cr4::update(|cr4| cr4.insert(Cr4Flags::OSXSAVE));
+    XCr0::write(features);
+
+    features
 }
 
 /// Wrapper function for getting random number in the CPU. Mounted as `/dev/random`.
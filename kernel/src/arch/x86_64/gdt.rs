@@ -25,7 +25,7 @@ use x86_64::{
 use crate::{
     arch::{cpu::init_current_cpu, PAGE_SIZE},
     error::{Errno, KResult},
-    memory::virt_to_phys,
+    memory::{virt_to_phys, KernelStack, STACK_SIZE},
 };
 
 // The layout of GDT entry is given as follows (x86_64).
@@ -56,6 +56,9 @@ pub const GDT_ENTRIES: &[u64; 5] = &[
     USER_CODE_64B,
 ];
 
+/// The index into the interrupt stack table (IST) of the stack used by the double fault handler.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
 pub const AP_TRAMPOLINE_GDT: &[u16; 12] = &[
     0x0000, 0x0000, 0x0000, 0x0000, // Null
     0xffff, 0x0000, 0x9a00, 0x00cf, // Code32
@@ -117,8 +120,12 @@ pub unsafe fn init_gdt() -> KResult<()> {
     // Step 3: Set up the TSS entries.
     let mut tss = Box::new(TaskStateSegment::new());
     // Allocate stack for trap from user
-    let trap_stack_top = Box::leak(Box::new([0u8; PAGE_SIZE])).as_ptr() as u64 + PAGE_SIZE as u64;
+    let trap_stack_top = KernelStack::with_size(PAGE_SIZE)?.leak() as u64;
     tss.privilege_stack_table[0] = virt!(trap_stack_top);
+    // The double fault handler must not run on the faulting stack: if the kernel stack overflows, it is the guard page
+    // that causes the double fault.
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        virt!(KernelStack::with_size(STACK_SIZE)?.leak() as u64);
     let tss: &'static _ = Box::leak(tss);
     let (tss0, tss1) = match Descriptor::tss_segment(tss) {
         Descriptor::SystemSegment(tss0, tss1) => (tss0, tss1),
@@ -3,7 +3,10 @@
 //! 64-bit wide comparators. HPET is programmed using memory mapped IO, and the base address of HPET can be found
 //! using ACPI.
 
+use core::time::Duration;
+
 use acpi::HpetInfo;
+use spin::Once;
 
 use crate::{
     error::{Errno, KResult},
@@ -26,6 +29,10 @@ const T0_COMPARATOR_OFFSET: u64 = 0x108;
 
 const PER_INT_CAP: u64 = 0x10;
 const LEG_RT_CAP: u64 = 0x8000;
+
+/// The HPET found in the ACPI table, if it has been successfully initialized.
+pub static HPET: Once<Hpet> = Once::new();
+
 #[derive(Debug, Clone)]
 pub struct Hpet {
     base: u64,
@@ -63,6 +70,35 @@ impl Hpet {
 
         self.write(GENERAL_CONFIG_OFFSET, configurations);
     }
+
+    /// Main counter tick period in femtoseconds (10^-15 seconds).
+    pub fn period_fs(&self) -> u64 {
+        self.read(CAPABILITY_OFFSET) >> 0x20
+    }
+
+    /// Arms timer 0 in periodic mode so that it fires once per kernel tick.
+    pub fn start_periodic(&self) {
+        // Must not be zero, must be less or equal to 0x05F5E100, or 100 nanoseconds.
+        let counter_clk_period_fs = self.period_fs();
+        let desired_fs_period: u64 = 2_250_286 * 1_000_000;
+        let clk_periods_per_kernel_tick: u64 = desired_fs_period / counter_clk_period_fs;
+
+        let counter = self.read(MAIN_COUNTER_OFFSET);
+        let t0_config_word: u64 = TN_VAL_SET_CNF | TN_TYPE_CNF | TN_INT_ENB_CNF;
+        self.write(T0_CONFIG_CAPABILITY_OFFSET, t0_config_word);
+        self.write(T0_COMPARATOR_OFFSET, counter + clk_periods_per_kernel_tick);
+        // set interval
+        self.write(T0_COMPARATOR_OFFSET, clk_periods_per_kernel_tick);
+    }
+
+    /// Arms timer 0 in non-periodic mode so that it fires exactly once after `delta`.
+    pub fn start_oneshot(&self, delta: Duration) {
+        let ticks = (delta.as_nanos() * 1_000_000 / self.period_fs() as u128).max(1) as u64;
+
+        let counter = self.read(MAIN_COUNTER_OFFSET);
+        self.write(T0_CONFIG_CAPABILITY_OFFSET, TN_INT_ENB_CNF);
+        self.write(T0_COMPARATOR_OFFSET, counter + ticks);
+    }
 }
 
 /// 1. Find HPET base address in 'HPET' ACPI table.
@@ -84,27 +120,17 @@ pub fn init_hpet(hpet_info: &HpetInfo) -> KResult<()> {
         return Err(Errno::EINVAL);
     }
 
-    // Main counter tick period in femtoseconds (10^-15 seconds). Must not be zero, must be
-    // less or equal to 0x05F5E100, or 100 nanoseconds.
-    let counter_clk_period_fs = cap >> 0x20;
-    let desired_fs_period: u64 = 2_250_286 * 1_000_000;
-    let clk_periods_per_kernel_tick: u64 = desired_fs_period / counter_clk_period_fs;
-
     let t0_cap = hpet.read(T0_CONFIG_CAPABILITY_OFFSET);
     if t0_cap & PER_INT_CAP == 0 {
         kwarn!("init_hpet(): T0 missing capability PER_INT_CAP {t0_cap}");
         return Err(Errno::EINVAL);
     }
 
-    let counter = hpet.read(MAIN_COUNTER_OFFSET);
-    let t0_config_word: u64 = TN_VAL_SET_CNF | TN_TYPE_CNF | TN_INT_ENB_CNF;
-    hpet.write(T0_CONFIG_CAPABILITY_OFFSET, t0_config_word);
-    hpet.write(T0_COMPARATOR_OFFSET, counter + clk_periods_per_kernel_tick);
-    // set interval
-    hpet.write(T0_COMPARATOR_OFFSET, clk_periods_per_kernel_tick);
+    hpet.start_periodic();
 
     // Enable.
     hpet.toggle(true);
+    HPET.call_once(|| hpet);
 
     kinfo!("init_hpet(): successfully initialized HPET.");
     Ok(())
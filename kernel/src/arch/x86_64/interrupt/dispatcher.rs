@@ -1,12 +1,11 @@
 //! This module implementes the interrupt handlers.
 
 use alloc::sync::Arc;
-use x86_64::instructions::tlb::flush_all;
 
 use crate::{
     arch::{
         self,
-        cpu::AbstractCpu,
+        cpu::{cpu_id, AbstractCpu},
         interrupt::{
             eoi, ipi::IpiType, timer::handle_timer, BREAKPOINT_INTERRUPT, DOUBLE_FAULT_INTERRUPT,
             GENERAL_PROTECTION_INTERRUPT, IRQ_MAX, IRQ_MIN, PAGE_FAULT_INTERRUPT, TIMER_INTERRUPT,
@@ -14,14 +13,19 @@ use crate::{
         mm::{
             paging::{get_pf_addr, handle_page_fault},
             pretty_interpret,
+            tlb::handle_tlb_shootdown,
+            uaccess::search_exception_table,
         },
     },
+    debug::{Frame, UNWIND_DEPTH},
     drivers::IRQ_MANAGER,
+    memory::check_within_kernel_stacks,
     process::{
         scheduler::FIFO_SCHEDULER,
         thread::{current, Thread, ThreadContext},
     },
     signal::{send_signal, SiFields, SigInfo, Signal},
+    sync::preempt::IrqContext,
     syscall::handle_syscall,
 };
 
@@ -41,13 +45,9 @@ pub extern "C" fn __trap_dispatcher(tf: &mut TrapFrame) {
     // Dispatch based on tf.trap_num.
     match tf.trap_num {
         BREAKPOINT_INTERRUPT => dump_all(tf),
-        GENERAL_PROTECTION_INTERRUPT => {
-            panic!("__trap_dispatcher(): segmentation fault! dumped {:#x?}", tf);
-        }
+        GENERAL_PROTECTION_INTERRUPT => general_protection(tf),
         PAGE_FAULT_INTERRUPT => page_fault(tf),
-        DOUBLE_FAULT_INTERRUPT => {
-            panic!("__trap_dispatcher(): interrupt cannot be handled! CPU is dead.");
-        }
+        DOUBLE_FAULT_INTERRUPT => double_fault(tf),
         IRQ_MIN..=IRQ_MAX => {
             handle_irq(tf.trap_num as _, false, None);
         }
@@ -167,6 +167,7 @@ pub async fn trap_dispatcher_user(
 /// This functions also takes as input the value `user` indicating whether the kernel is dealing with user interrupt
 /// and a callback `cb` to be invoked later.
 fn handle_irq(trapno: u8, user: bool, should_yield: Option<&mut bool>) -> bool {
+    let _irq = IrqContext::enter();
     eoi(trapno);
 
     // Must check before we handle the IRQ.
@@ -200,15 +201,15 @@ fn handle_irq(trapno: u8, user: bool, should_yield: Option<&mut bool>) -> bool {
 ///
 /// This function takes an inter-processor interrupt number as an argument, acknowledges the interrupt using
 /// EOI (end of interrupt), and dispatches the interrupt to the appropriate handler. It matches the interrupt number
-/// with an `IpiType` using `transmute` function. If the interrupt is `TlbFlush`, it calls `flush_all` function to flush
-/// the TLB entries. If the interrupt is `WakeUp`, it does nothing. If the interrupt is `Others`, it pops the event from the
+/// with an `IpiType` using `transmute` function. If the interrupt is `TlbFlush`, it calls `handle_tlb_shootdown` to
+/// invalidate the stale TLB entries. If the interrupt is `WakeUp`, it does nothing. If the interrupt is `Others`, it pops the event from the
 /// current CPU's event queue using `pop_event` method of the `AbstractCpu`. Finally, it returns `true`.
 fn handle_ipi(ipi: u8) -> bool {
     eoi(ipi - IRQ_MIN as u8);
 
     match IpiType::try_from(ipi) {
         Ok(ipi) => match ipi {
-            IpiType::TlbFlush => flush_all(),
+            IpiType::TlbFlush => handle_tlb_shootdown(),
             // Does nothing.
             IpiType::WakeUp => FIFO_SCHEDULER.load_balance(),
             // Do something with the runqueue.
@@ -230,6 +231,57 @@ fn dump_all(tf: &mut TrapFrame) {
     kdebug!("dump_all(): dumped tf (Not TensorFlow :)) as\n{:#x?}", tf);
 }
 
+/// Handles double fault. We are on the IST stack here, so this works even if the faulting stack is unusable.
+///
+/// The typical cause is a kernel stack overflow: the page fault raised by touching the guard page cannot be delivered
+/// because pushing its frame touches the guard page again.
+fn double_fault(tf: &mut TrapFrame) -> ! {
+    let pf_addr = get_pf_addr();
+    let tid = current().map(|thread| thread.id).ok();
+
+    if check_within_kernel_stacks(pf_addr) || check_within_kernel_stacks(tf.rsp as u64) {
+        kerror!(
+            "double_fault(): kernel stack overflow on CPU #{:#x}! Thread id: {:#x?}, fault address: {:#x}, rsp: {:#x}, rip: {:#x}",
+            cpu_id(),
+            tid,
+            pf_addr,
+            tf.rsp,
+            tf.rip
+        );
+    } else {
+        kerror!(
+            "double_fault(): unexpected double fault on CPU #{:#x}! Thread id: {:#x?}. Dumped context is {:#x?}",
+            cpu_id(),
+            tid,
+            tf
+        );
+    }
+
+    Frame {
+        rip: tf.rip as _,
+        rsp: tf.rsp as _,
+        rbp: tf.rbp as _,
+    }
+    .unwind(*UNWIND_DEPTH);
+
+    arch::cpu::die();
+}
+
+/// Handles general protection fault. The user-copy routines may still hit one, e.g., on a non-canonical address, and
+/// those fail with `EFAULT` like a bad page fault does.
+fn general_protection(tf: &mut TrapFrame) {
+    match search_exception_table(tf.rip as u64) {
+        Some(fixup) => {
+            kdebug!(
+                "general_protection(): bad user access; fixing up to {:#x}.",
+                fixup
+            );
+            tf.rip = fixup as _;
+        }
+        None => panic!("__trap_dispatcher(): segmentation fault! dumped {:#x?}", tf),
+    }
+}
+
 /// Handles page fault.
 fn page_fault(tf: &mut TrapFrame) {
     let pf_addr = arch::mm::paging::get_pf_addr();
@@ -251,6 +303,25 @@ fn page_fault(tf: &mut TrapFrame) {
         }
     };
 
+    // Faults raised by the user-copy routines must never kill the kernel. If the faulting code holds the vm lock
+    // itself, waiting for it would deadlock, so fail the copy with `EFAULT` instead.
+    if let Some(fixup) = search_exception_table(tf.rip as u64) {
+        let handled = match thread.vm.held_by_current_cpu() {
+            true => false,
+            false => thread.vm.lock().handle_page_fault(pf_addr),
+        };
+        if !handled {
+            kdebug!(
+                "page_fault(): bad user access @ {:#x}; fixing up to {:#x}.",
+                pf_addr,
+                fixup
+            );
+            tf.rip = fixup as _;
+        }
+
+        return;
+    }
+
     let mut vm = thread.vm.lock();
     if !vm.handle_page_fault(pf_addr) {
         kerror!("page_fault(): this thread cannot handle page fault!");
@@ -16,7 +16,9 @@ use x86_64::{
     PrivilegeLevel, VirtAddr,
 };
 
-use crate::error::KResult;
+use crate::{arch::gdt::DOUBLE_FAULT_IST_INDEX, error::KResult};
+
+use super::DOUBLE_FAULT_INTERRUPT;
 
 pub const IDT_ENTRY_SIZE: usize = 0x100;
 pub const INT3: usize = 0x3;
@@ -63,6 +65,12 @@ pub fn init_idt() -> KResult<()> {
         } else {
             PrivilegeLevel::Ring0
         });
+        // Double fault runs on its own stack because the current one may have overflowed.
+        if i == DOUBLE_FAULT_INTERRUPT {
+            unsafe {
+                entry.set_stack_index(DOUBLE_FAULT_IST_INDEX);
+            }
+        }
     }
 
     // Load LDT.
@@ -101,6 +101,7 @@ use crate::{
     arch::{
         gdt::init_gdt,
         interrupt::{idt::init_idt, syscall::init_syscall},
+        mm::{tlb::init_pcid, uaccess::init_user_access},
     },
     error::KResult,
     irq::{IrqType, IRQ_TYPE},
@@ -312,7 +313,12 @@ pub unsafe fn init_interrupt_all() -> KResult<()> {
     // Step 4: Set up the syscall handlers.
     init_syscall()?;
     kinfo!("init_interrupt_all(): initialized syscall handlers.");
-    // Step 5: Restore the interrupt.
+    // Step 5: Forbid the kernel from touching user pages outside of the user-copy routines.
+    init_user_access()?;
+    kinfo!("init_interrupt_all(): enabled SMEP/SMAP.");
+    // Step 6: Tag the TLB entries of each address space so that switching does not flush them.
+    init_pcid();
+    // Step 7: Restore the interrupt.
     restore(flags);
 
     Ok(())
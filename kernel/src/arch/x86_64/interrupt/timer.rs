@@ -7,12 +7,17 @@ use core::{
 
 use lazy_static::lazy_static;
 
+#[cfg(feature = "tickless")]
+use crate::arch::{
+    cpu::MAX_CPU_NUM,
+    timer::{program_oneshot, restore_periodic},
+};
 use crate::{
     arch::{
         cpu::{cpu_id, AP_UP_NUM, BSP_ID, CPU_NUM},
         timer::rdtsc_timer,
     },
-    process::scheduler::FIFO_SCHEDULER,
+    process::scheduler::{set_need_resched, FIFO_SCHEDULER},
     sync::mutex::SpinLockNoInterrupt as Mutex,
     trigger::Trigger,
 };
@@ -26,6 +31,17 @@ pub static TICK: AtomicUsize = AtomicUsize::new(0usize);
 pub static TICK_WALL: AtomicUsize = AtomicUsize::new(0usize);
 pub static APIC_UP: AtomicBool = AtomicBool::new(false);
 
+/// The interval of the periodic timer interrupt.
+pub const TICK_INTERVAL: Duration = Duration::from_micros(10000);
+/// The longest time an idle CPU may sleep without a timer interrupt. Tasks woken up by other cores are only noticed when
+/// the owning core polls its queue, so we still need an upper bound even if no [`Trigger`] is pending.
+#[cfg(feature = "tickless")]
+pub const MAX_IDLE_SLEEP: Duration = Duration::from_millis(100);
+
+/// When each CPU stopped its periodic tick, if it is in tickless idle.
+#[cfg(feature = "tickless")]
+static mut IDLE_SINCE: [Option<Duration>; MAX_CPU_NUM] = [None; MAX_CPU_NUM];
+
 lazy_static! {
     /// A clock that will trigger the callback if the given time ends.
     pub static ref TRIGGER: Mutex<Trigger> = Mutex::new(Trigger::default());
@@ -38,9 +54,74 @@ pub fn tick_microsecond() -> Duration {
     Duration::from_micros(10000 * tick as u64)
 }
 
+/// Stops the periodic tick of the current CPU and programs a one-shot timer for the next [`Trigger`] deadline, capped
+/// by [`MAX_IDLE_SLEEP`]. Must be called right before halting; interrupts are disabled when this function returns.
+#[cfg(feature = "tickless")]
+pub fn tickless_enter() {
+    if !APIC_UP.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let next = TRIGGER.lock().next();
+    // Dropping the guard above turns interrupts back on.
+    x86_64::instructions::interrupts::disable();
+
+    let now = rdtsc_timer();
+    let deadline = match next {
+        Some(next) => next.min(now + MAX_IDLE_SLEEP),
+        None => now + MAX_IDLE_SLEEP,
+    };
+
+    // The next event is due within a tick anyway.
+    if deadline <= now + TICK_INTERVAL {
+        return;
+    }
+
+    match program_oneshot(deadline) {
+        Ok(()) => unsafe {
+            IDLE_SINCE[cpu_id()] = Some(now);
+        },
+        Err(errno) => {
+            ktrace!("tickless_enter(): keep ticking due to {:?}", errno);
+        }
+    }
+}
+
+/// Restarts the periodic tick after the current CPU wakes up from tickless idle and accounts for the ticks elapsed
+/// during the sleep, including the one-shot interrupt that woke us up (see [`handle_timer`]). Does nothing if the CPU
+/// did not enter tickless idle.
+#[cfg(feature = "tickless")]
+pub fn tickless_exit() {
+    let since = match unsafe { IDLE_SINCE[cpu_id()].take() } {
+        Some(since) => since,
+        None => return,
+    };
+
+    if let Err(errno) = restore_periodic() {
+        kerror!("tickless_exit(): cannot restore the periodic tick: {:?}", errno);
+    }
+
+    let now = rdtsc_timer();
+    let skipped = (now.saturating_sub(since).as_micros() / TICK_INTERVAL.as_micros()) as usize;
+    if cpu_id() == *BSP_ID.get().unwrap_or(&0) as usize {
+        MONOTONIC_TICK.fetch_add(skipped, Ordering::Relaxed);
+    }
+    TICK_WALL.fetch_add(skipped, Ordering::Relaxed);
+
+    TRIGGER.lock().expire(now);
+}
+
 pub fn handle_timer() {
+    // The one-shot interrupt ending a tickless idle is accounted for by `tickless_exit`.
+    #[cfg(feature = "tickless")]
+    let idle = unsafe { IDLE_SINCE[cpu_id()].is_some() };
+    #[cfg(not(feature = "tickless"))]
+    let idle = false;
+
     if cpu_id() == *BSP_ID.get().unwrap_or(&0) as usize {
-        MONOTONIC_TICK.fetch_add(0x1, Ordering::Relaxed);
+        if !idle {
+            MONOTONIC_TICK.fetch_add(0x1, Ordering::Relaxed);
+        }
         // Only the primary core can do tick.
         let prev = TICK.fetch_add(0x1, Ordering::Release);
         let ap_num = AP_UP_NUM.load(Ordering::Relaxed);
@@ -56,7 +137,11 @@ pub fn handle_timer() {
         }
     }
     // Do tick.
-    TICK_WALL.fetch_add(0x1, Ordering::Relaxed);
+    if !idle {
+        TICK_WALL.fetch_add(0x1, Ordering::Relaxed);
+    }
+    // The time slice of the running thread is used up.
+    set_need_resched();
 
     if APIC_UP.load(Ordering::Relaxed) {
         TRIGGER.lock().expire(rdtsc_timer());
@@ -5,9 +5,9 @@ use core::{
     fmt::Arguments,
 };
 
-use alloc::{string::ToString, sync::Arc, vec::Vec};
+use alloc::{string::ToString, sync::Arc, vec, vec::Vec};
 
-use crate::{drivers::SERIAL_DRIVERS, error::KResult, process::thread::Thread};
+use crate::{drivers::SERIAL_DRIVERS, error::KResult, process::thread::Thread, utils::ptr::Ptr};
 
 pub fn writefmt(arg: Arguments) {
     // Default to serial port.
@@ -64,6 +64,14 @@ pub struct IoVec {
 }
 
 impl IoVec {
+    /// Copies the io vectors themselves into the kernel.
+    fn read_iovecs(iov_ptr: *const Self, iov_count: usize) -> KResult<Vec<Self>> {
+        let iov_ptr = Ptr::<Self>::new(iov_ptr as _);
+        (0..iov_count)
+            .map(|i| unsafe { iov_ptr.add(i).read() })
+            .collect()
+    }
+
     /// Read all the buffers from this IoVec pointer. Note the parameter in the syscall is `struct iovec* iov`.
     /// So IoVec describes a seris of buffers!
     pub fn get_all_iovecs(
@@ -71,19 +79,18 @@ impl IoVec {
         iov_ptr: *const Self,
         iov_count: usize,
     ) -> KResult<Vec<Vec<u8>>> {
-        let vm = thread.vm.lock();
-        let io_vectors = unsafe { core::slice::from_raw_parts(iov_ptr, iov_count) };
+        let io_vectors = Self::read_iovecs(iov_ptr, iov_count)?;
 
         let mut v = Vec::with_capacity(io_vectors.len());
         for iov in io_vectors.iter() {
             if iov.iov_len != 0 {
-                vm.get_slice::<u8>(iov.iov_base as _, iov.iov_len)?;
-                unsafe {
-                    v.push(
-                        core::slice::from_raw_parts(iov.iov_base as *const u8, iov.iov_len)
-                            .to_vec(),
-                    );
-                }
+                let base = thread
+                    .vm
+                    .lock()
+                    .get_slice::<u8>(iov.iov_base as _, iov.iov_len)?;
+                let mut buf = vec![0u8; iov.iov_len];
+                unsafe { base.read_slice(&mut buf) }?;
+                v.push(buf);
             }
         }
 
@@ -97,15 +104,13 @@ impl IoVec {
         iov_count: usize,
         buf: &[u8],
     ) -> KResult<usize> {
-        let io_vectors = unsafe { core::slice::from_raw_parts(iov_ptr, iov_count) };
+        let io_vectors = Self::read_iovecs(iov_ptr, iov_count)?;
 
         // Denote the position of buf.
         let mut cur = 0usize;
         for iov in io_vectors.iter() {
-            let byte_write = (buf.len() - cur + 1).min(iov.iov_len);
-            (0..byte_write).for_each(|i| unsafe {
-                core::ptr::write(iov.iov_base.add(i) as *mut u8, buf[cur + i]);
-            });
+            let byte_write = (buf.len() - cur).min(iov.iov_len);
+            unsafe { Ptr::new(iov.iov_base as _).write_slice(&buf[cur..cur + byte_write]) }?;
             cur += byte_write;
         }
 
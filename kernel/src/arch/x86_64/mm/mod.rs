@@ -13,6 +13,8 @@
 //! we do not use 5-level page table.
 
 pub mod paging;
+pub mod tlb;
+pub mod uaccess;
 
 use log::debug;
 
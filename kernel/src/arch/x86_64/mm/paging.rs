@@ -6,21 +6,28 @@ use alloc::{boxed::Box, format};
 use boot_header::{Header, MemoryDescriptor, MemoryType};
 use x86_64::{
     instructions::tlb::flush,
-    registers::control::{Cr0, Cr0Flags, Cr2, Cr3, Cr3Flags},
+    registers::control::{Cr0, Cr0Flags, Cr2, Cr3, Cr4, Cr4Flags},
     structures::paging::{
-        mapper::PageTableFrameMapping,
+        mapper::{MapToError, PageTableFrameMapping, TranslateResult},
         page_table::{PageTableEntry, PageTableLevel},
         FrameAllocator, FrameDeallocator, MappedPageTable, Mapper, Page, PageTable, PageTableFlags,
-        PhysFrame, Size4KiB,
+        PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
     },
     PhysAddr, VirtAddr,
 };
 
 use crate::{
-    arch::{KERNEL_PM4, PAGE_SIZE, PHYSICAL_MEMORY_PM4},
+    arch::{
+        cpu::cpuid,
+        mm::tlb::{forget_page_table, load_page_table},
+        KERNEL_PM4, PAGE_SIZE, PHYSICAL_MEMORY_PM4, PHYSICAL_MEMORY_START,
+    },
     error::{Errno, KResult},
-    memory::{allocate_frame, deallocate_frame, phys_to_virt, BitMapAlloc, LOCKED_FRAME_ALLOCATOR},
-    mm::AccessType,
+    memory::{
+        allocate_frame, deallocate_frame, init_frame_owners, phys_to_virt, BitMapAlloc,
+        LOCKED_FRAME_ALLOCATOR,
+    },
+    mm::{AccessType, ArenaFlags},
     process::thread::current,
 };
 
@@ -94,6 +101,39 @@ pub fn handle_page_fault(addr: u64, errno: u64) -> bool {
     vm.do_handle_page_fault(addr, access_type)
 }
 
+/// The size of a huge page. A huge page is mapped by a page directory entry (2 MiB) or a page directory pointer table
+/// entry (1 GiB) with the `HUGE_PAGE` bit set, so it saves one or two levels of page tables and, more importantly, TLB
+/// entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HugePageSize {
+    Size2MiB,
+    /// Only available if the CPU supports `pdpe1gb`.
+    Size1GiB,
+}
+
+impl HugePageSize {
+    /// The size in bytes.
+    pub const fn size(&self) -> u64 {
+        match self {
+            Self::Size2MiB => 0x20_0000,
+            Self::Size1GiB => 0x4000_0000,
+        }
+    }
+
+    /// The number of 4 KiB frames it covers.
+    pub const fn frames(&self) -> usize {
+        (self.size() / PAGE_SIZE as u64) as usize
+    }
+
+    /// The level (as in [`index_at_level`]) of the entry that maps this page.
+    const fn level(&self) -> usize {
+        match self {
+            Self::Size2MiB => 2,
+            Self::Size1GiB => 1,
+        }
+    }
+}
+
 pub trait EntryBehaviors: Debug {
     /// Make all changes take effect.
     ///
@@ -152,7 +192,29 @@ pub trait PageTableBehaviors {
     /// Unmap a page of virual address `addr`
     fn unmap(&mut self, addr: VirtAddr);
 
+    /// Maps a huge page of virtual address `addr` to the physical address `target` with the permissions in `flags`.
+    /// Both must be aligned to `size`. Return the page table entry of the huge page.
+    fn map_huge(
+        &mut self,
+        addr: VirtAddr,
+        target: PhysAddr,
+        size: HugePageSize,
+        flags: &ArenaFlags,
+    ) -> KResult<&mut dyn EntryBehaviors>;
+
+    /// Unmaps the huge page of virtual address `addr` and returns the physical address it was mapped to.
+    fn unmap_huge(&mut self, addr: VirtAddr, size: HugePageSize) -> KResult<PhysAddr>;
+
+    /// Returns the size of the huge page that maps `addr`, or [`None`] if `addr` is not mapped by a huge page.
+    fn huge_page_size(&mut self, addr: VirtAddr) -> Option<HugePageSize>;
+
+    /// Splits the huge page containing `addr` into pages of the next smaller size that map the same physical memory
+    /// with the same flags. This is needed before a part of the huge page can be unmapped or protected. Does nothing
+    /// if `addr` is not mapped by a huge page.
+    fn split_huge(&mut self, addr: VirtAddr) -> KResult<()>;
+
     /// Gets the page table entry of a page of virual address `addr` and performs a closure `f` on it.
+    /// If `addr` is mapped by a huge page, the entry of the huge page is returned.
     /// If its page do not exist, return `None`
     fn get_entry_with(
         &mut self,
@@ -404,6 +466,7 @@ impl Drop for KernelPageTable {
             "drop(): dropping page table at {:#x?}",
             self.page_table_frame
         );
+        forget_page_table(self.cr3());
         deallocate_frame(self.page_table_frame.start_address().as_u64()).unwrap();
     }
 }
@@ -461,6 +524,131 @@ impl PageTableBehaviors for KernelPageTable {
             .flush();
     }
 
+    fn map_huge(
+        &mut self,
+        addr: VirtAddr,
+        target: PhysAddr,
+        size: HugePageSize,
+        flags: &ArenaFlags,
+    ) -> KResult<&mut dyn EntryBehaviors> {
+        // The intermediate tables stay as permissive as those created by `map`; the huge page itself carries the
+        // permissions of the arena.
+        let table_flags =
+            PageTableFlags::WRITABLE | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let mut leaf_flags = PageTableFlags::PRESENT;
+        leaf_flags.set(PageTableFlags::WRITABLE, flags.writable);
+        leaf_flags.set(PageTableFlags::USER_ACCESSIBLE, flags.user_accessible);
+        leaf_flags.set(PageTableFlags::NO_EXECUTE, flags.non_executable);
+        let res = unsafe {
+            match size {
+                HugePageSize::Size2MiB => self
+                    .page_table
+                    .map_to_with_table_flags(
+                        Page::<Size2MiB>::from_start_address(addr).map_err(|_| Errno::EINVAL)?,
+                        PhysFrame::<Size2MiB>::from_start_address(target)
+                            .map_err(|_| Errno::EINVAL)?,
+                        leaf_flags,
+                        table_flags,
+                        &mut PTFrameAllocator,
+                    )
+                    .map(|flush| flush.flush()),
+                HugePageSize::Size1GiB => self
+                    .page_table
+                    .map_to_with_table_flags(
+                        Page::<Size1GiB>::from_start_address(addr).map_err(|_| Errno::EINVAL)?,
+                        PhysFrame::<Size1GiB>::from_start_address(target)
+                            .map_err(|_| Errno::EINVAL)?,
+                        leaf_flags,
+                        table_flags,
+                        &mut PTFrameAllocator,
+                    )
+                    .map(|flush| flush.flush()),
+            }
+        };
+
+        if let Err(err) = res {
+            kerror!(
+                "map_huge(): cannot map from {:#x} to {:#x}. Error: {:?}",
+                addr,
+                target,
+                err
+            );
+            return Err(match err {
+                MapToError::FrameAllocationFailed => Errno::ENOMEM,
+                _ => Errno::EEXIST,
+            });
+        }
+
+        self.get_entry(addr)
+    }
+
+    fn unmap_huge(&mut self, addr: VirtAddr, size: HugePageSize) -> KResult<PhysAddr> {
+        let target = match size {
+            HugePageSize::Size2MiB => self
+                .page_table
+                .unmap(Page::<Size2MiB>::from_start_address(addr).map_err(|_| Errno::EINVAL)?)
+                .map(|(frame, flush)| {
+                    flush.flush();
+                    frame.start_address()
+                }),
+            HugePageSize::Size1GiB => self
+                .page_table
+                .unmap(Page::<Size1GiB>::from_start_address(addr).map_err(|_| Errno::EINVAL)?)
+                .map(|(frame, flush)| {
+                    flush.flush();
+                    frame.start_address()
+                }),
+        };
+
+        target.map_err(|err| {
+            kerror!("unmap_huge(): cannot unmap {:#x}. Error: {:?}", addr, err);
+            Errno::EINVAL
+        })
+    }
+
+    fn huge_page_size(&mut self, addr: VirtAddr) -> Option<HugePageSize> {
+        self.huge_entry(addr).map(|(_, size)| size)
+    }
+
+    fn split_huge(&mut self, addr: VirtAddr) -> KResult<()> {
+        let (entry, size) = match self.huge_entry(addr) {
+            Some(res) => res,
+            None => return Ok(()),
+        };
+
+        // A 1 GiB page becomes 512 2 MiB pages and a 2 MiB page becomes 512 4 KiB pages.
+        let (step, leaf_flags) = match size {
+            HugePageSize::Size1GiB => (HugePageSize::Size2MiB.size(), entry.flags()),
+            HugePageSize::Size2MiB => (PAGE_SIZE as u64, entry.flags() - PageTableFlags::HUGE_PAGE),
+        };
+
+        let table_addr = allocate_frame()?;
+        let table = unsafe { &mut *frame_to_page_table(frame!(table_addr.as_u64())) };
+        let target = entry.addr();
+        for (index, leaf) in table.iter_mut().enumerate() {
+            leaf.set_addr(target + index as u64 * step, leaf_flags);
+        }
+
+        // Permissions are enforced by the leaves, so the new table itself must be permissive like the ones created by
+        // `map_to`; otherwise we could not relax the permission of a single page later.
+        let mut table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        if entry.flags().contains(PageTableFlags::USER_ACCESSIBLE) {
+            table_flags |= PageTableFlags::USER_ACCESSIBLE;
+        }
+        entry.set_addr(table_addr, table_flags);
+        // Invalidating any address within the huge page drops its TLB entry.
+        flush(addr);
+
+        ktrace!(
+            "split_huge(): split {:?} page @ {:#x} into table {:#x}",
+            size,
+            addr,
+            table_addr
+        );
+
+        Ok(())
+    }
+
     fn get_entry_with(
         &mut self,
         addr: VirtAddr,
@@ -492,6 +680,13 @@ impl PageTableBehaviors for KernelPageTable {
                 return Err(Errno::EEXIST);
             }
 
+            // A huge page has no next level; it is the entry itself.
+            if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                let page = page!(addr.as_u64());
+                self.page_table_entry = Some(PageEntryWrapper(entry, page, self.page_table_frame));
+                return Ok(self.page_table_entry.as_mut().unwrap());
+            }
+
             ktrace!("get_entry(): visiting {:#x?}", entry);
 
             // Retrive page table at the current level.
@@ -502,8 +697,10 @@ impl PageTableBehaviors for KernelPageTable {
     }
 
     fn get_page_slice_mut<'a>(&mut self, addr: VirtAddr) -> KResult<&'a mut [u8]> {
-        if let Ok(frame) = self.page_table.translate_page(page!(addr.as_u64())) {
-            let virt_addr = phys_to_virt(frame.start_address().as_u64());
+        // Works for huge pages as well: we take the 4 KiB piece containing `addr`.
+        if let TranslateResult::Mapped { frame, offset, .. } = self.page_table.translate(addr) {
+            let phys_addr = (frame.start_address().as_u64() + offset) & !(PAGE_SIZE as u64 - 1);
+            let virt_addr = phys_to_virt(phys_addr);
             let slice = unsafe { core::slice::from_raw_parts_mut(virt_addr as *mut u8, PAGE_SIZE) };
 
             Ok(slice)
@@ -535,6 +732,35 @@ impl PageTableBehaviors for KernelPageTable {
     }
 }
 
+impl KernelPageTable {
+    /// Walks the page table and returns the entry of the huge page that maps `addr`, if any.
+    fn huge_entry(
+        &mut self,
+        addr: VirtAddr,
+    ) -> Option<(&'static mut PageTableEntry, HugePageSize)> {
+        let mut page_table = frame_to_page_table(self.page_table_frame);
+        for page_table_level in 0..3 {
+            let index = index_at_level(page_table_level, addr.as_u64());
+            let entry = unsafe { &mut (&mut *page_table)[index as usize] };
+
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+
+            if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return [HugePageSize::Size1GiB, HugePageSize::Size2MiB]
+                    .into_iter()
+                    .find(|size| size.level() == page_table_level)
+                    .map(|size| (entry, size));
+            }
+
+            page_table = frame_to_page_table(entry.frame().ok()?);
+        }
+
+        None
+    }
+}
+
 impl PageTableMoreBehaviors for KernelPageTable {
     fn empty() -> Self {
         let phys_addr = allocate_frame().expect("empty(): failed to allocate frame");
@@ -591,6 +817,7 @@ pub fn init_mm(header: &'static Header) -> KResult<()> {
         )
     };
 
+    let mut frames = 0;
     for descriptor in mmap.iter() {
         kdebug!("init_mm(): {:x?}", descriptor);
 
@@ -598,9 +825,93 @@ pub fn init_mm(header: &'static Header) -> KResult<()> {
             let start_frame = descriptor.phys_start as usize / PAGE_SIZE;
             let end_frame = start_frame + descriptor.page_count as usize;
             allocator.insert(start_frame..end_frame)?;
+            frames = frames.max(end_frame);
+        }
+    }
+
+    init_frame_owners(&mut allocator, frames)
+}
+
+/// Rebuilds the linear mapping of the physical memory at [`PHYSICAL_MEMORY_START`] with huge pages.
+///
+/// The bootloader maps the physical memory with 4 KiB pages, which costs 8 MiB of page tables per 4 GiB and wastes
+/// plenty of TLB entries since almost every kernel access goes through [`phys_to_virt`]. We use 1 GiB pages if the CPU
+/// supports them and 2 MiB pages otherwise. The old tables belong to the bootloader and are simply abandoned.
+///
+/// Must be called after [`init_mm`] and before any user page table is created, since those copy the entry.
+pub fn init_direct_map(header: &'static Header) -> KResult<()> {
+    let mmap = unsafe {
+        core::slice::from_raw_parts(
+            phys_to_virt(header.mmap) as *const MemoryDescriptor,
+            header.mmap_len as usize,
+        )
+    };
+
+    // Same as the bootloader: the first 4 GiB is always mapped because of the MMIO regions. One PML4 entry can map at
+    // most 512 GiB.
+    let max_addr = mmap
+        .iter()
+        .map(|descriptor| descriptor.phys_start + descriptor.page_count * PAGE_SIZE as u64)
+        .max()
+        .unwrap_or_default()
+        .max(0x1_0000_0000)
+        .min(0x80_0000_0000);
+    let use_1gib = cpuid()
+        .get_extended_processor_and_feature_identifiers()
+        .map_or(false, |info| info.has_1gib_pages());
+
+    let huge_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::GLOBAL
+        | PageTableFlags::HUGE_PAGE;
+    let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let pdpt_addr = allocate_frame()?;
+    let pdpt = unsafe { &mut *frame_to_page_table(frame!(pdpt_addr.as_u64())) };
+    pdpt.zero();
+
+    let gib_size = HugePageSize::Size1GiB.size();
+    for (gib, pdpt_entry) in pdpt
+        .iter_mut()
+        .enumerate()
+        .take(((max_addr + gib_size - 1) / gib_size) as usize)
+    {
+        let phys_start = gib as u64 * gib_size;
+
+        if use_1gib {
+            pdpt_entry.set_addr(PhysAddr::new(phys_start), huge_flags);
+            continue;
         }
+
+        let pd_addr = allocate_frame()?;
+        let pd = unsafe { &mut *frame_to_page_table(frame!(pd_addr.as_u64())) };
+        for (index, pd_entry) in pd.iter_mut().enumerate() {
+            pd_entry.set_addr(
+                PhysAddr::new(phys_start + index as u64 * HugePageSize::Size2MiB.size()),
+                huge_flags,
+            );
+        }
+        pdpt_entry.set_addr(pd_addr, table_flags);
     }
 
+    // The translation stays the same, so it does not matter which mapping is used until the TLB is flushed.
+    let pml4 = unsafe { &mut *frame_to_page_table(Cr3::read().0) };
+    let pml4_flags = pml4[PHYSICAL_MEMORY_PM4 as usize].flags();
+    pml4[PHYSICAL_MEMORY_PM4 as usize].set_addr(pdpt_addr, pml4_flags);
+
+    // Global entries survive a CR3 reload; toggling CR4.PGE drops them.
+    unsafe {
+        Cr4::update(|flags| flags.remove(Cr4Flags::PAGE_GLOBAL));
+        Cr4::update(|flags| flags.insert(Cr4Flags::PAGE_GLOBAL));
+    }
+
+    kinfo!(
+        "init_direct_map(): mapped {:#x} bytes at {:#x} with {} pages.",
+        max_addr,
+        PHYSICAL_MEMORY_START,
+        if use_1gib { "1 GiB" } else { "2 MiB" }
+    );
+
     Ok(())
 }
 
@@ -644,9 +955,12 @@ where
 /// It is kernel's responsibility to ensure that `page_table_addr` is always valid. Otherwise,
 /// the kernel will crash.
 pub fn set_page_table(page_table_addr: u64) {
-    unsafe {
-        Cr3::write(frame!(page_table_addr), Cr3Flags::empty());
-    }
+    load_page_table(page_table_addr);
+}
+
+/// Returns the physical address of the page table currently loaded on this CPU.
+pub fn get_page_table_addr() -> u64 {
+    Cr3::read().0.start_address().as_u64()
 }
 
 /// Extract the page entry index for `level`.
@@ -655,29 +969,6 @@ pub fn index_at_level(level: usize, addr: u64) -> u64 {
     (addr >> (12 + (3 - level) * 9)) & 0o777
 }
 
-/// This function broadcasts the change of the page table to all the cores to allow for a synchronized
-/// page table among them. This process *may be* slow; so it is recommended that one use single flush
-/// rather than reloading the whole page table via [`Cr3`].
-///
-/// If the argument `addr` is [`None`], then we flush all.
-#[cfg(feature = "multiprocessor")]
-#[inline(always)]
-pub fn tlb_broadcast(target: Option<u8>, addr: Option<VirtAddr>) {
-    use crate::arch::interrupt::ipi::{send_ipi, IpiType};
-
-    match addr {
-        Some(addr) => send_ipi(
-            move || {
-                flush(addr);
-            },
-            target,
-            true,
-            IpiType::TlbFlush,
-        ),
-        None => send_ipi(|| (), target, true, IpiType::TlbFlush),
-    }
-}
-
 /// Allows a kernel page to be accessible to the user.
 ///
 /// # Safety
@@ -0,0 +1,424 @@
+//! TLB shootdown.
+//!
+//! `invlpg` and CR3 reloads only affect the TLB of the CPU that executes them. When an address space that is loaded on
+//! several CPUs (e.g., threads of one process) loses mappings or permissions, the other CPUs may still use their stale
+//! translations, and must be told to drop them *before* the freed frames can be handed out again.
+//!
+//! A [`TlbGather`] is created for every operation that unmaps or downgrades pages of a [`MemoryManager`]. It records
+//! the modified ranges and holds back the frames freed in the meantime. When it is dropped, a single batch of
+//! invalidations is sent to the CPUs that have the page table loaded, and the frames are freed once they all have
+//! acknowledged it. The kernel half of the address space is shared by all page tables, so a gather for kernel
+//! mappings ([`TlbGather::kernel`]) goes to every CPU.
+//!
+//! If the CPU supports process-context identifiers (PCIDs), each CPU tags the TLB entries of the page tables it loads
+//! with a small per-CPU ID, so switching address spaces no longer flushes the TLB. The entries a CPU keeps for a page
+//! table that it does not run at the moment cannot be reached by the shootdown IPI, so a [`TlbGather`] also takes the
+//! PCID away from that page table on every other CPU; they flush it when they load the page table again.
+//!
+//! [`MemoryManager`]: crate::mm::MemoryManager
+
+use core::{
+    arch::asm,
+    ops::Range,
+    sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+
+use alloc::vec::Vec;
+use x86_64::{
+    instructions::{
+        interrupts::without_interrupts,
+        tlb::{flush, flush_pcid, InvPicdCommand, Pcid},
+    },
+    registers::control::{Cr4, Cr4Flags},
+};
+
+use crate::{
+    arch::{
+        cpu::{cpu_id, cpuid, CPUS, MAX_CPU_NUM},
+        interrupt::ipi::{send_ipi, IpiType},
+        PAGE_SIZE,
+    },
+    memory::free_deferred_frame,
+    sync::preempt::{preempt_disable, preempt_enable},
+};
+
+/// Invalidating more pages than this one by one is slower than flushing the whole TLB.
+const FLUSH_ALL_THRESHOLD: usize = 0x20;
+
+/// The number of PCIDs that each CPU hands out to page tables. PCID 0 is left to the page table the CPU booted with.
+const NR_PCIDS: usize = 0x10;
+/// Tells `mov cr3` to keep the TLB entries tagged with the new PCID.
+const CR3_NOFLUSH: u64 = 1 << 63;
+
+/// The `cr3` of a [`TlbGather`] for the kernel mappings, which every CPU may cache whatever page table it runs.
+const KERNEL_MAPPINGS: u64 = 0;
+
+/// Whether PCIDs are enabled on each CPU.
+static PCID_ENABLED: [AtomicBool; MAX_CPU_NUM] = {
+    const DISABLED: AtomicBool = AtomicBool::new(false);
+    [DISABLED; MAX_CPU_NUM]
+};
+/// Whether `invpcid` is supported.
+static INVPCID_SUPPORTED: AtomicBool = AtomicBool::new(false);
+/// The page table that owns each PCID on each CPU: PCID `i + 1` belongs to `PCID_OWNERS[cpu][i]`, and zero means that
+/// it is free. Other CPUs may clear an entry to invalidate what the TLB holds under that PCID.
+static PCID_OWNERS: [[AtomicU64; NR_PCIDS]; MAX_CPU_NUM] = {
+    const FREE: AtomicU64 = AtomicU64::new(0);
+    const OWNERS: [AtomicU64; NR_PCIDS] = [FREE; NR_PCIDS];
+    [OWNERS; MAX_CPU_NUM]
+};
+/// The next PCID each CPU evicts when none is free. Only touched by the owning CPU.
+static PCID_NEXT: [AtomicUsize; MAX_CPU_NUM] = {
+    const FIRST: AtomicUsize = AtomicUsize::new(0);
+    [FIRST; MAX_CPU_NUM]
+};
+/// Bumped whenever kernel mappings are removed. The kernel half is not global, so its entries are cached under every
+/// PCID, and a CPU that sees a new generation must drop all of them.
+static KERNEL_GENERATION: AtomicU64 = AtomicU64::new(0);
+/// The last [`KERNEL_GENERATION`] seen by each CPU.
+static SEEN_KERNEL_GENERATION: [AtomicU64; MAX_CPU_NUM] = {
+    const NONE: AtomicU64 = AtomicU64::new(0);
+    [NONE; MAX_CPU_NUM]
+};
+
+/// The page table currently loaded on each CPU.
+static LOADED_PAGE_TABLES: [AtomicU64; MAX_CPU_NUM] = {
+    const NONE: AtomicU64 = AtomicU64::new(0);
+    [NONE; MAX_CPU_NUM]
+};
+
+/// Frames freed on each CPU while a [`TlbGather`] is active. Only touched by the owning CPU with interrupts disabled.
+static mut DEFERRED_FRAMES: [Option<Vec<u64>>; MAX_CPU_NUM] = {
+    const NONE: Option<Vec<u64>> = None;
+    [NONE; MAX_CPU_NUM]
+};
+
+/// Only one shootdown is in flight at a time.
+static SHOOTDOWN_LOCK: AtomicBool = AtomicBool::new(false);
+/// The batch being shot down.
+static SHOOTDOWN: AtomicPtr<TlbGather> = AtomicPtr::new(core::ptr::null_mut());
+/// Whether each CPU still has to process [`SHOOTDOWN`].
+static SHOOTDOWN_PENDING: [AtomicBool; MAX_CPU_NUM] = {
+    const DONE: AtomicBool = AtomicBool::new(false);
+    [DONE; MAX_CPU_NUM]
+};
+/// The number of CPUs that have not acknowledged [`SHOOTDOWN`] yet.
+static SHOOTDOWN_REMAINING: AtomicUsize = AtomicUsize::new(0);
+
+/// Collects the pages of an address space whose translations become stale.
+pub struct TlbGather {
+    /// The page table being modified.
+    cr3: u64,
+    /// The virtual address ranges to invalidate.
+    ranges: Vec<Range<u64>>,
+    /// Whether an outer gather on this CPU owns the deferred frames.
+    nested: bool,
+}
+
+impl TlbGather {
+    /// Starts gathering for the page table at `cr3`. Frames freed on this CPU are held back until `self` is dropped.
+    pub fn new(cr3: u64) -> Self {
+        // The deferred frames are per-CPU.
+        preempt_disable();
+        let nested = without_interrupts(|| unsafe {
+            let deferred = &mut DEFERRED_FRAMES[cpu_id()];
+            match deferred {
+                Some(_) => true,
+                None => {
+                    deferred.replace(Vec::new());
+                    false
+                }
+            }
+        });
+
+        Self {
+            cr3,
+            ranges: Vec::new(),
+            nested,
+        }
+    }
+
+    /// Starts gathering for the kernel mappings, which are shared by all page tables.
+    pub fn kernel() -> Self {
+        Self::new(KERNEL_MAPPINGS)
+    }
+
+    /// Adds `range` to the pages to invalidate.
+    pub fn add(&mut self, range: Range<u64>) {
+        self.ranges.push(range);
+    }
+
+    fn pages(&self) -> usize {
+        self.ranges
+            .iter()
+            .map(|range| ((range.end - range.start) as usize + PAGE_SIZE - 1) / PAGE_SIZE)
+            .sum()
+    }
+
+    /// Returns true if `cpu` may cache the gathered pages in the PCID it is running with.
+    fn covers(&self, cpu: usize) -> bool {
+        self.cr3 == KERNEL_MAPPINGS || LOADED_PAGE_TABLES[cpu].load(Ordering::SeqCst) == self.cr3
+    }
+
+    /// Invalidates the gathered pages on the current CPU.
+    fn flush_local(&self) {
+        if self.pages() > FLUSH_ALL_THRESHOLD {
+            reload_cr3();
+            return;
+        }
+
+        for range in self.ranges.iter() {
+            for addr in (range.start..range.end).step_by(PAGE_SIZE) {
+                flush(virt!(addr));
+            }
+        }
+    }
+
+    /// Invalidates the gathered pages in the PCID `pcid` of the current CPU, which need not be the one in use.
+    fn flush_pcid(&self, pcid: usize) {
+        let pcid = Pcid::new(pcid as u16).unwrap();
+        unsafe {
+            if self.pages() > FLUSH_ALL_THRESHOLD {
+                flush_pcid(InvPicdCommand::Single(pcid));
+                return;
+            }
+
+            for range in self.ranges.iter() {
+                for addr in (range.start..range.end).step_by(PAGE_SIZE) {
+                    flush_pcid(InvPicdCommand::Address(virt!(addr), pcid));
+                }
+            }
+        }
+    }
+
+    /// Makes sure that no CPU keeps stale entries for `self.cr3` under a PCID it is not running with. Must be done
+    /// before [`TlbGather::shootdown`] looks for the CPUs that have the page table loaded.
+    fn invalidate_pcids(&self) {
+        if self.cr3 == KERNEL_MAPPINGS {
+            flush_kernel_mappings();
+            return;
+        }
+
+        let this_cpu = cpu_id();
+        let cpus = unsafe {
+            CPUS.iter()
+                .filter_map(|cpu| cpu.get())
+                .map(|cpu| cpu.cpu_id)
+                .filter(|&cpu| cpu != this_cpu)
+                .collect::<Vec<_>>()
+        };
+        for cpu in cpus {
+            for owner in PCID_OWNERS[cpu].iter() {
+                let _ = owner.compare_exchange(self.cr3, 0, Ordering::SeqCst, Ordering::Relaxed);
+            }
+        }
+
+        // The page table entries were invalidated with `invlpg` on this CPU, which only reaches the current PCID.
+        without_interrupts(|| {
+            if LOADED_PAGE_TABLES[this_cpu].load(Ordering::SeqCst) == self.cr3 {
+                return;
+            }
+
+            if let Some(index) = find_pcid(this_cpu, self.cr3) {
+                match INVPCID_SUPPORTED.load(Ordering::Relaxed) {
+                    true => self.flush_pcid(index + 1),
+                    false => PCID_OWNERS[this_cpu][index].store(0, Ordering::SeqCst),
+                }
+            }
+        });
+    }
+
+    /// Sends the batch to the other CPUs that have `self.cr3` loaded and waits for them to process it.
+    #[cfg(feature = "multiprocessor")]
+    fn shootdown(&self) {
+        let this_cpu = cpu_id();
+        // The page table entries must be visible before we look at which CPUs may cache them: a CPU that loads the page
+        // table after this point reads the new entries.
+        fence(Ordering::SeqCst);
+        let targets = unsafe {
+            CPUS.iter()
+                .filter_map(|cpu| cpu.get())
+                .map(|cpu| cpu.cpu_id)
+                .filter(|&cpu| cpu != this_cpu && self.covers(cpu))
+                .collect::<Vec<_>>()
+        };
+        if targets.is_empty() {
+            return;
+        }
+
+        // Someone else may be waiting for us while we wait for the lock.
+        while SHOOTDOWN_LOCK
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            handle_tlb_shootdown();
+            core::hint::spin_loop();
+        }
+
+        SHOOTDOWN.store(self as *const _ as *mut _, Ordering::Release);
+        SHOOTDOWN_REMAINING.store(targets.len(), Ordering::Release);
+        for &cpu in targets.iter() {
+            SHOOTDOWN_PENDING[cpu].store(true, Ordering::Release);
+        }
+        for &cpu in targets.iter() {
+            send_ipi(|| (), Some(cpu as _), false, IpiType::TlbFlush);
+        }
+
+        while SHOOTDOWN_REMAINING.load(Ordering::Acquire) != 0 {
+            core::hint::spin_loop();
+        }
+
+        SHOOTDOWN.store(core::ptr::null_mut(), Ordering::Release);
+        SHOOTDOWN_LOCK.store(false, Ordering::Release);
+    }
+}
+
+impl Drop for TlbGather {
+    fn drop(&mut self) {
+        if !self.ranges.is_empty() {
+            self.invalidate_pcids();
+            #[cfg(feature = "multiprocessor")]
+            self.shootdown();
+        }
+
+        let frames = match self.nested {
+            true => None,
+            false => without_interrupts(|| unsafe { DEFERRED_FRAMES[cpu_id()].take() }),
+        };
+        preempt_enable();
+
+        // No CPU can reach these frames any longer.
+        for frame in frames.into_iter().flatten() {
+            free_deferred_frame(frame);
+        }
+    }
+}
+
+/// Holds `frame` back if a [`TlbGather`] is active on this CPU. Returns false if the frame can be freed right away.
+pub fn defer_frame(frame: u64) -> bool {
+    without_interrupts(|| unsafe {
+        match DEFERRED_FRAMES[cpu_id()].as_mut() {
+            Some(frames) => {
+                frames.push(frame);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Enables PCIDs on the current CPU if they are supported. Must be called on every CPU.
+pub fn init_pcid() {
+    let cpuid = cpuid();
+    if !cpuid.get_feature_info().map_or(false, |fi| fi.has_pcid()) {
+        kinfo!("init_pcid(): PCID is not supported.");
+        return;
+    }
+
+    let invpcid = cpuid
+        .get_extended_feature_info()
+        .map_or(false, |fi| fi.has_invpcid());
+    INVPCID_SUPPORTED.store(invpcid, Ordering::Relaxed);
+
+    // CR4.PCIDE can only be set while CR3 uses PCID 0, which is the case since boot.
+    unsafe {
+        Cr4::update(|cr4| cr4.insert(Cr4Flags::PCID));
+    }
+    PCID_ENABLED[cpu_id()].store(true, Ordering::Release);
+    kinfo!("init_pcid(): enabled PCID; INVPCID supported: {}.", invpcid);
+}
+
+/// Returns the index of the PCID that `cpu` assigned to the page table at `cr3`.
+fn find_pcid(cpu: usize, cr3: u64) -> Option<usize> {
+    PCID_OWNERS[cpu]
+        .iter()
+        .position(|owner| owner.load(Ordering::SeqCst) == cr3)
+}
+
+/// Reloads CR3, which flushes the non-global entries of the current PCID.
+fn reload_cr3() {
+    unsafe {
+        let cr3: u64;
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+        asm!("mov cr3, {}", in(reg) cr3 & !CR3_NOFLUSH, options(nostack, preserves_flags));
+    }
+}
+
+/// Loads the page table at `cr3` on this CPU. With PCIDs, the entries that the TLB still holds for it are kept unless
+/// they became stale in the meantime.
+pub fn load_page_table(cr3: u64) {
+    // A shootdown must not be handled between recording the page table and loading it.
+    without_interrupts(|| {
+        let cpu = cpu_id();
+        // Must be visible before we look up the PCID; see `TlbGather::invalidate_pcids`.
+        LOADED_PAGE_TABLES[cpu].store(cr3, Ordering::SeqCst);
+
+        if !PCID_ENABLED[cpu].load(Ordering::Acquire) {
+            unsafe {
+                asm!("mov cr3, {}", in(reg) cr3, options(nostack, preserves_flags));
+            }
+            return;
+        }
+
+        let generation = KERNEL_GENERATION.load(Ordering::SeqCst);
+        if SEEN_KERNEL_GENERATION[cpu].swap(generation, Ordering::Relaxed) != generation {
+            // Every PCID is flushed when it is handed out again.
+            for owner in PCID_OWNERS[cpu].iter() {
+                owner.store(0, Ordering::SeqCst);
+            }
+        }
+
+        let (index, noflush) = match find_pcid(cpu, cr3) {
+            Some(index) => (index, CR3_NOFLUSH),
+            None => {
+                let index = find_pcid(cpu, 0)
+                    .unwrap_or_else(|| PCID_NEXT[cpu].fetch_add(1, Ordering::Relaxed) % NR_PCIDS);
+                PCID_OWNERS[cpu][index].store(cr3, Ordering::SeqCst);
+                // The PCID may hold entries of its previous owner.
+                (index, 0)
+            }
+        };
+
+        unsafe {
+            asm!(
+                "mov cr3, {}",
+                in(reg) cr3 | (index + 1) as u64 | noflush,
+                options(nostack, preserves_flags)
+            );
+        }
+    });
+}
+
+/// Takes the PCIDs away from the page table at `cr3`, which is about to be freed. Its frame may become another page
+/// table that must not see the old entries.
+pub fn forget_page_table(cr3: u64) {
+    for owners in PCID_OWNERS.iter() {
+        for owner in owners.iter() {
+            let _ = owner.compare_exchange(cr3, 0, Ordering::SeqCst, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Called after kernel mappings were removed and invalidated on the current PCID. Makes every CPU drop the copies it
+/// keeps under its other PCIDs before it loads one of them.
+pub fn flush_kernel_mappings() {
+    KERNEL_GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Processes the shootdown sent to this CPU, if any. Called by the IPI handler and by loops that spin with interrupts
+/// disabled, since the sender cannot make progress until we respond.
+pub fn handle_tlb_shootdown() {
+    let cpu = cpu_id();
+    if !SHOOTDOWN_PENDING[cpu].swap(false, Ordering::AcqRel) {
+        return;
+    }
+
+    // The batch stays alive until we acknowledge it.
+    let gather = unsafe { &*SHOOTDOWN.load(Ordering::Acquire) };
+    // Switching to another page table has flushed the stale translations already.
+    if gather.covers(cpu) {
+        gather.flush_local();
+    }
+
+    SHOOTDOWN_REMAINING.fetch_sub(1, Ordering::AcqRel);
+}
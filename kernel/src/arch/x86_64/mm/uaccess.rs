@@ -0,0 +1,92 @@
+//! Low-level user memory access.
+//!
+//! With SMEP (Supervisor Mode Execution Prevention) and SMAP (Supervisor Mode Access Prevention) enabled, the kernel
+//! can neither execute nor touch user pages by accident. The only legitimate way into user memory is `__copy_user`,
+//! which opens a short window with `stac` / `clac` around a `rep movsb`. A fault inside that window that the page fault
+//! handler cannot fix is redirected through the exception table so that the caller sees [`Errno::EFAULT`] instead of
+//! a dead kernel.
+
+use core::{
+    arch::global_asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::registers::control::{Cr4, Cr4Flags};
+
+use crate::{
+    arch::cpu::cpuid,
+    error::{Errno, KResult},
+};
+
+global_asm!(include_str!("uaccess.S"));
+
+/// Whether `stac` / `clac` are usable. Both instructions are `#UD` on CPUs without SMAP, so `__copy_user` checks this
+/// flag before executing them.
+#[no_mangle]
+pub static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// An entry of the exception table.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct ExceptionTableEntry {
+    /// The instruction that is allowed to fault.
+    insn: u64,
+    /// Where to resume if it does.
+    fixup: u64,
+}
+
+extern "C" {
+    /// Copies `len` bytes from `src` to `dst` and returns the number of bytes that could not be copied.
+    fn __copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+
+    static __ex_table_start: ExceptionTableEntry;
+    static __ex_table_end: ExceptionTableEntry;
+}
+
+/// Copies `len` bytes between the user space and the kernel space. The caller is responsible for checking that the
+/// user side of the copy lies below the kernel space.
+///
+/// # Safety
+///
+/// The kernel side of the copy must be valid for `len` bytes.
+pub unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> KResult<()> {
+    match __copy_user(dst, src, len) {
+        0 => Ok(()),
+        _ => Err(Errno::EFAULT),
+    }
+}
+
+/// Looks up the fixup address for a faulting instruction at `rip`.
+pub fn search_exception_table(rip: u64) -> Option<u64> {
+    let table = unsafe {
+        let start = &__ex_table_start as *const ExceptionTableEntry;
+        let end = &__ex_table_end as *const ExceptionTableEntry;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    };
+
+    table
+        .iter()
+        .find(|entry| entry.insn == rip)
+        .map(|entry| entry.fixup)
+}
+
+/// Enables SMEP and SMAP on the current CPU if they are supported. Must be called on every CPU.
+pub fn init_user_access() -> KResult<()> {
+    let features = cpuid().get_extended_feature_info().ok_or(Errno::EEXIST)?;
+
+    let mut flags = Cr4Flags::empty();
+    if features.has_smep() {
+        flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if features.has_smap() {
+        flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+        SMAP_ENABLED.store(true, Ordering::Release);
+    }
+
+    unsafe {
+        Cr4::update(|cr4| cr4.insert(flags));
+    }
+    kinfo!("init_user_access(): enabled {:?}.", flags);
+
+    Ok(())
+}
@@ -20,6 +20,12 @@ pub const KERNEL_HEAP_SIZE: usize = 0x0100_0000;
 pub const PHYSICAL_MEMORY_START: u64 = 0xffff_8880_0000_0000;
 // User space top.
 pub const USER_MEM_TOP: u64 = 0xffff_7fff_ffff_ffff;
+// The end of the canonical lower half; user pointers must stay below it.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+// Kernel stacks separated by unmapped guard pages. They live in the same PML4 entry as the kernel image so that every
+// page table sees them.
+pub const KERNEL_STACK_START: u64 = 0xffff_ff80_0000_0000;
+pub const KERNEL_STACK_END: u64 = 0xffff_ffc0_0000_0000;
 // VMALLOC / IOREMAP space (we do not use it currently.)
 #[allow(unused)]
 pub const VM_MEMORY_START: u64 = 0xffff_c900_0000_0000;
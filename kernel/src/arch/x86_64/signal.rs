@@ -1,7 +1,25 @@
 //! x86_64 support for signal handling.
 
+use x86_64::registers::rflags::RFlags;
+
+use crate::error::{Errno, KResult};
+
 use super::interrupt::Context;
 
+/// The flags that a signal handler may change in the saved context.
+const USER_RFLAGS: RFlags = RFlags::from_bits_truncate(
+    RFlags::CARRY_FLAG.bits()
+        | RFlags::PARITY_FLAG.bits()
+        | RFlags::AUXILIARY_CARRY_FLAG.bits()
+        | RFlags::ZERO_FLAG.bits()
+        | RFlags::SIGN_FLAG.bits()
+        | RFlags::TRAP_FLAG.bits()
+        | RFlags::DIRECTION_FLAG.bits()
+        | RFlags::OVERFLOW_FLAG.bits()
+        | RFlags::ALIGNMENT_CHECK.bits()
+        | RFlags::RESUME_FLAG.bits(),
+);
+
 #[derive(Debug, Clone)]
 #[repr(C)]
 pub struct SigContext {
@@ -72,4 +90,35 @@ impl SigContext {
             _reserved1: [0; 8],
         }
     }
+
+    /// Copies the registers back into `ctx` when returning from a signal handler. The handler may have modified them,
+    /// so only the user-controllable flags are taken and the instruction pointer must be a user address.
+    pub fn restore_uctx(&self, ctx: &mut Context) -> KResult<()> {
+        // Returning to a non-canonical address would fault in the kernel.
+        if self.rip as u64 >= 0x0000_8000_0000_0000 {
+            return Err(Errno::EFAULT);
+        }
+
+        ctx.regs.r8 = self.r8 as _;
+        ctx.regs.r9 = self.r9 as _;
+        ctx.regs.r10 = self.r10 as _;
+        ctx.regs.r11 = self.r11 as _;
+        ctx.regs.r12 = self.r12 as _;
+        ctx.regs.r13 = self.r13 as _;
+        ctx.regs.r14 = self.r14 as _;
+        ctx.regs.r15 = self.r15 as _;
+        ctx.regs.rdi = self.rdi as _;
+        ctx.regs.rsi = self.rsi as _;
+        ctx.regs.rax = self.rax as _;
+        ctx.regs.rbx = self.rbx as _;
+        ctx.regs.rcx = self.rcx as _;
+        ctx.regs.rdx = self.rdx as _;
+        ctx.regs.rbp = self.rbp as _;
+        ctx.regs.rsp = self.rsp as _;
+        ctx.regs.rip = self.rip as _;
+        ctx.regs.rflags =
+            (ctx.regs.rflags & !USER_RFLAGS.bits()) | (self.eflags as u64 & USER_RFLAGS.bits());
+
+        Ok(())
+    }
 }
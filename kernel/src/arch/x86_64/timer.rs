@@ -2,14 +2,22 @@ use core::{sync::atomic::Ordering, time::Duration};
 
 use atomic_enum::atomic_enum;
 use lazy_static::lazy_static;
+use spin::Once;
 
 use crate::error::{Errno, KResult};
 
 use super::{
     apic::LOCAL_APIC,
-    cpu::{cpu_id, CPU_FREQUENCY},
+    cpu::{cpu_feature_info, cpu_id, CPU_FREQUENCY, CPU_NUM},
+    hpet::HPET,
 };
 
+/// The shortest one-shot interval we are willing to program. Anything shorter would fire before we even halt.
+pub const MIN_ONESHOT_DELTA: Duration = Duration::from_micros(50);
+
+/// The initial count that makes the local APIC timer fire every 10 ms. Set once the timer has been initialized.
+pub static APIC_TIMER_TICKS: Once<u64> = Once::new();
+
 /// Local APIC timer modes.
 #[derive(Debug, Copy, Clone)]
 #[repr(u8)]
@@ -81,3 +89,60 @@ pub fn init_apic_timer() -> KResult<()> {
         _ => LOCAL_APIC.read().get(&cpu_id()).unwrap().init_timer(),
     }
 }
+
+/// Stops the periodic tick on the current CPU and arms a one-shot timer interrupt at `deadline`, which is measured by
+/// [`rdtsc_timer`].
+///
+/// We prefer the TSC-deadline mode because it takes an absolute time and needs no calibration; otherwise we fall back to
+/// the local APIC one-shot mode. If the HPET is the timer source, we use its comparator instead; since there is only one
+/// comparator driving the tick of all CPUs, this is only done on uniprocessor systems and `EBUSY` is returned otherwise.
+pub fn program_oneshot(deadline: Duration) -> KResult<()> {
+    let delta = deadline
+        .saturating_sub(rdtsc_timer())
+        .max(MIN_ONESHOT_DELTA);
+
+    if TIMER_SOURCE.load(Ordering::Acquire) == TimerSource::Hpet {
+        // Stopping the shared periodic tick would stall the CPUs that are still busy.
+        if CPU_NUM.get().copied().unwrap_or(1) > 1 {
+            return Err(Errno::EBUSY);
+        }
+        HPET.get().ok_or(Errno::ENODEV)?.start_oneshot(delta);
+        return Ok(());
+    }
+
+    let lock = LOCAL_APIC.read();
+    let lapic = lock.get(&cpu_id()).ok_or(Errno::ENODEV)?;
+
+    if cpu_feature_info()?.has_tsc_deadline() {
+        let freq = *CPU_FREQUENCY.get().ok_or(Errno::ENODEV)?;
+        let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+        lapic.set_timer(
+            TimerMode::TscDeadline,
+            tsc + (delta.as_nanos() as f64 * freq) as u64,
+        );
+    } else {
+        let ticks = *APIC_TIMER_TICKS.get().ok_or(Errno::ENODEV)? as u128;
+        // `ticks` is the count for 10 ms.
+        let count = (delta.as_nanos() * ticks / 10_000_000).max(1);
+        lapic.set_timer(TimerMode::OneShot, count as u64);
+    }
+
+    Ok(())
+}
+
+/// Resumes the periodic tick on the current CPU after [`program_oneshot`].
+pub fn restore_periodic() -> KResult<()> {
+    if TIMER_SOURCE.load(Ordering::Acquire) == TimerSource::Hpet {
+        HPET.get().ok_or(Errno::ENODEV)?.start_periodic();
+        return Ok(());
+    }
+
+    let ticks = *APIC_TIMER_TICKS.get().ok_or(Errno::ENODEV)?;
+    LOCAL_APIC
+        .read()
+        .get(&cpu_id())
+        .ok_or(Errno::ENODEV)?
+        .set_timer(TimerMode::Periodic, ticks);
+
+    Ok(())
+}
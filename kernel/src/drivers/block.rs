@@ -1,6 +1,6 @@
 //! This module implements block-like drivers.
 
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
 use log::debug;
 use rcore_fs::dev::{self, BlockDevice, DevError};
 
@@ -99,30 +99,34 @@ impl BlockDriver for AhciDriver {
     }
 }
 
+/// Registers a block driver for each port of the AHCI controller with a device attached, and returns the driver of
+/// the first one.
 pub fn init_ahci(header: usize, size: usize) -> KResult<Arc<AhciDriver>> {
     debug!(
         "init_ahci(): initializing AHCI at {:#x} with size {:#x}",
         header, size
     );
 
-    match AHCI::new(header, size) {
-        Some(ahci) => {
+    let drivers = AHCI::probe(header, size)
+        .into_iter()
+        .map(|ahci| {
             let ahci = Arc::new(AhciDriver {
                 inner: Mutex::new(ahci),
             });
             DRIVERS.write().push(ahci.clone());
             BLOCK_DRIVERS.write().push(ahci.clone());
 
-            Ok(ahci)
-        }
-        // No device!
-        None => Err(Errno::EACCES),
-    }
+            ahci
+        })
+        .collect::<Vec<_>>();
+
+    // No device!
+    drivers.into_iter().next().ok_or(Errno::EACCES)
 }
 
 pub struct BlockDriverWrapper(pub Arc<dyn BlockDriver>);
 
-#[cfg(feature = "sfs")]
+#[cfg(any(feature = "sfs", feature = "ext2", feature = "fat"))]
 impl BlockDevice for BlockDriverWrapper {
     const BLOCK_SIZE_LOG2: u8 = 9; // 512
     fn read_at(&self, block_id: usize, buf: &mut [u8]) -> dev::Result<()> {
@@ -147,23 +151,29 @@ impl BlockDevice for BlockDriverWrapper {
 #[cfg(feature = "apfs")]
 impl Device for BlockDriverWrapper {
     fn read_buf_at(&self, offset: usize, buf: &mut [u8]) -> KResult<usize> {
-        // Note that the block_size for AHCI driver is 512 bytes.
-        let start = offset / BLOCK_SIZE;
-        // How many AHCI blocks to be read.
-        let nblocks = (buf.len() as f64 / BLOCK_SIZE as f64).ceil() as usize;
-
-        for i in 0..nblocks {
-            // The buf range.
-            let range_start = i * BLOCK_SIZE;
-            let range_end = (range_start + BLOCK_SIZE).min(buf.len());
-
-            if !self
-                .0
-                .read_block(i + start, &mut buf[range_start..range_end])
-            {
+        // Note that the block_size for AHCI driver is 512 bytes. Partial blocks are read via a bounce buffer.
+        let mut sector = [0u8; BLOCK_SIZE];
+        let mut pos = 0;
+
+        while pos < buf.len() {
+            let block = (offset + pos) / BLOCK_SIZE;
+            let off = (offset + pos) % BLOCK_SIZE;
+            let len = (BLOCK_SIZE - off).min(buf.len() - pos);
+
+            let success = match off == 0 && len == BLOCK_SIZE {
+                true => self.0.read_block(block, &mut buf[pos..pos + len]),
+                false => {
+                    let success = self.0.read_block(block, &mut sector);
+                    buf[pos..pos + len].copy_from_slice(&sector[off..off + len]);
+                    success
+                }
+            };
+            if !success {
                 kerror!("read AHCI block error.");
-                return Err(Errno::ENODEV);
+                return Err(Errno::EIO);
             }
+
+            pos += len;
         }
 
         Ok(buf.len())
@@ -171,10 +181,41 @@ impl Device for BlockDriverWrapper {
 
     fn write_buf_at(&self, offset: usize, buf: &[u8]) -> KResult<usize> {
         #[cfg(feature = "apfs_write")]
-        unimplemented!();
+        {
+            // Partial blocks are read, modified and then written back.
+            let mut sector = [0u8; BLOCK_SIZE];
+            let mut pos = 0;
+
+            while pos < buf.len() {
+                let block = (offset + pos) / BLOCK_SIZE;
+                let off = (offset + pos) % BLOCK_SIZE;
+                let len = (BLOCK_SIZE - off).min(buf.len() - pos);
+
+                let success = match off == 0 && len == BLOCK_SIZE {
+                    true => self.0.write_block(block, &buf[pos..pos + len]),
+                    false => {
+                        self.0.read_block(block, &mut sector) && {
+                            sector[off..off + len].copy_from_slice(&buf[pos..pos + len]);
+                            self.0.write_block(block, &sector)
+                        }
+                    }
+                };
+                if !success {
+                    kerror!("write AHCI block error.");
+                    return Err(Errno::EIO);
+                }
+
+                pos += len;
+            }
+
+            Ok(buf.len())
+        }
 
         #[cfg(not(feature = "apfs_write"))]
-        panic!("cannot write to a read-only partition!");
+        {
+            let _ = (offset, buf);
+            Err(Errno::EROFS)
+        }
     }
 
     fn sync(&self) -> KResult<()> {
@@ -6,7 +6,13 @@ use core::{
     ops::DerefMut,
 };
 
-use alloc::{collections::VecDeque, string::String, sync::Arc, vec, vec::Vec};
+use alloc::{
+    collections::VecDeque,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
 use smoltcp::{
     iface::{Config, Interface, SocketHandle},
     phy::{DeviceCapabilities, RxToken, TxToken},
@@ -23,7 +29,8 @@ use crate::{
     error::{Errno, KResult},
     function, kerror, kinfo, kwarn,
     memory::{allocate_frame_contiguous, deallocate_frame, phys_to_virt, virt_to_phys},
-    net::{get_free_port, LISTEN_TABLE, SOCKET_SET},
+    net::{get_free_port, wait_for_network, LISTEN_TABLE, SOCKET_SET},
+    process::workqueue::Tasklet,
     sync::mutex::SpinLockNoInterrupt as Mutex,
 };
 
@@ -68,6 +75,10 @@ pub trait NetworkDriver: Driver {
     /// Polls this interface.
     fn poll(&self);
 
+    /// Asks the bottom half of this interface to poll it. Unlike [`NetworkDriver::poll`], this is cheap and never
+    /// touches the device, so syscalls call this and wait instead.
+    fn schedule_poll(&self);
+
     /// Connects to the tcp socket.
     fn connect(&self, addr: SocketAddr, socket_handle: SocketHandle) -> KResult<()>;
 
@@ -219,10 +230,18 @@ pub struct IntelEthernetController {
     irq: Option<u8>,
     /// The interface name. E.g., ens1f0.
     name: String,
+    /// The bottom half that feeds received packets into the network stack.
+    rx_tasklet: Tasklet,
 }
 
 impl IntelEthernetController {
-    pub fn new(irq: Option<u8>, header: usize, size: usize, name: String) -> Self {
+    pub fn new(
+        irq: Option<u8>,
+        header: usize,
+        size: usize,
+        name: String,
+        this: Weak<Self>,
+    ) -> Self {
         let mut config = Config::new();
         config.random_seed = 0xdeadbeef;
         config
@@ -268,6 +287,11 @@ impl IntelEthernetController {
             interface: Mutex::new(interface),
             irq,
             name,
+            rx_tasklet: Tasklet::new(None, move || {
+                if let Some(this) = this.upgrade() {
+                    this.poll();
+                }
+            }),
         }
     }
 }
@@ -277,7 +301,8 @@ impl Driver for IntelEthernetController {
         // By default we check by matching the irq.
         if irq.map(|irq| irq as u8).unwrap_or(u8::MIN) == self.irq.unwrap_or(u8::MAX) {
             self.driver.lock().inner.lock().handle_interrupt();
-            self.poll();
+            // Defer the heavy lifting out of the interrupt context.
+            self.rx_tasklet.schedule();
             true
         } else {
             false
@@ -323,6 +348,10 @@ impl NetworkDriver for IntelEthernetController {
         interface.poll(timestamp, driver.deref_mut(), &mut socket_set);
     }
 
+    fn schedule_poll(&self) {
+        self.rx_tasklet.schedule();
+    }
+
     fn connect(&self, addr: SocketAddr, socket_handle: SocketHandle) -> KResult<()> {
         if let SocketAddr::V4(addr) = addr {
             let mut interface = self.interface.lock();
@@ -342,13 +371,14 @@ impl NetworkDriver for IntelEthernetController {
                     // do something.
 
                     loop {
-                        self.poll();
-
                         // Is connected?
                         let mut socket_set = SOCKET_SET.lock();
                         let socket = socket_set.get_mut::<TcpSocket>(socket_handle);
                         match socket.state() {
-                            State::SynSent => continue,
+                            State::SynSent => {
+                                drop(socket_set);
+                                wait_for_network();
+                            }
                             State::Established => break,
                             _ => return Err(Errno::ECONNREFUSED),
                         }
@@ -377,7 +407,9 @@ pub fn init_network(
     size: usize,
     name: String,
 ) -> KResult<Arc<IntelEthernetController>> {
-    let driver = Arc::new(IntelEthernetController::new(irq, header, size, name));
+    let driver = Arc::new_cyclic(|this| {
+        IntelEthernetController::new(irq, header, size, name, this.clone())
+    });
     NETWORK_DRIVERS.write().push(driver.clone());
     DRIVERS.write().push(driver.clone());
 
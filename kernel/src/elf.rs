@@ -11,7 +11,8 @@ use rcore_fs::vfs::INode;
 
 use crate::{
     arch::{mm::paging::KernelPageTable, PAGE_SIZE},
-    error::{fserror_to_kerror, Errno, KResult},
+    error::{Errno, KResult},
+    fs::file::ReadAsFile,
     function, kdebug, kerror, kinfo,
     memory::KernelFrameAllocator,
     mm::{
@@ -37,7 +38,7 @@ impl ElfFile {
         kdebug!("loading ELF header");
 
         let mut buf = [0u8; 0x3c0];
-        let size = inode.read_at(0, &mut buf).map_err(fserror_to_kerror)?;
+        let size = INodeWrapper::new(inode.clone()).read_at(0, &mut buf)?;
         if size != buf.len() {
             kerror!("reading is wrong.");
             return Err(Errno::ENFILE);
@@ -104,11 +105,12 @@ impl ElfFile {
                         mmio: 0,
                     },
                     callback: Box::new(FileArenaCallback {
-                        file: INodeWrapper(self.inode.clone()),
+                        file: INodeWrapper::new(self.inode.clone()),
                         mem_start: mem_offset + ph.p_vaddr,
                         file_start: ph.p_paddr,
                         file_end: ph.p_paddr + ph.p_filesz,
                         frame_allocator: KernelFrameAllocator,
+                        shared: false,
                     }),
                     ty: ArenaType::Elf,
                     name: interpret_name.into(),
@@ -143,11 +145,12 @@ impl ElfFile {
                         mmio: 0,
                     },
                     callback: Box::new(FileArenaCallback {
-                        file: INodeWrapper(self.inode.clone()),
+                        file: INodeWrapper::new(self.inode.clone()),
                         mem_start: ph.p_vaddr,
                         file_start: ph.p_offset,
                         file_end: ph.p_offset + ph.p_filesz,
                         frame_allocator: KernelFrameAllocator,
+                        shared: false,
                     }),
                     ty: ArenaType::Elf,
                     name: name.into(),
@@ -190,7 +190,7 @@ pub fn fserror_to_kerror(err: FsError) -> Errno {
     match err {
         FsError::Again => Errno::EAGAIN,
         FsError::Busy => Errno::EBUSY,
-        FsError::DeviceError => Errno::EACCES,
+        FsError::DeviceError => Errno::EIO,
         FsError::DirNotEmpty => Errno::EINVAL,
         FsError::EntryExist => Errno::EEXIST,
         FsError::EntryNotFound => Errno::ENOENT,
@@ -203,6 +203,24 @@ pub fn fserror_to_kerror(err: FsError) -> Errno {
     }
 }
 
+/// Converts the kernel error back into the filesystem error for the VFS layer.
+pub fn kerror_to_fserror(err: Errno) -> FsError {
+    match err {
+        Errno::EAGAIN => FsError::Again,
+        Errno::EBUSY => FsError::Busy,
+        Errno::EEXIST => FsError::EntryExist,
+        Errno::ENOENT => FsError::EntryNotFound,
+        Errno::EISDIR => FsError::IsDir,
+        Errno::ENOTDIR => FsError::NotDir,
+        Errno::ENOSPC => FsError::NoDeviceSpace,
+        Errno::EINTR => FsError::Interrupted,
+        Errno::EXDEV => FsError::NotSameFs,
+        Errno::EIO | Errno::EACCES => FsError::DeviceError,
+        Errno::EOPNOTSUPP | Errno::EROFS => FsError::NotSupported,
+        _ => FsError::InvalidParam,
+    }
+}
+
 /// A simple wrapepr for conversion between unix error code and intermediate error status.
 #[macro_export]
 macro_rules! make_unix_error_code {
@@ -1,10 +1,918 @@
-//! Implements the in-memory representation of the APFS B-Tree.
+//! Implements the on-disk, copy-on-write representation of the APFS B-Tree.
 //!
 //! The reason why we need to manually implement a B-Tree is that we want to have full access to the internal structure
-//! of the B-Tree, while [`alloc::collections::BTreeMap`] does not allow us to access. Note however, that most components
-//! of this module are taken from its code to facilitate us.
+//! of the B-Tree, while [`alloc::collections::BTreeMap`] does not allow us to access. The in-memory maps in
+//! [`super::meta::FsMap`] still serve most lookups, and this module mirrors every modification into the on-disk tree.
+//! Directory entries are looked up by descending the file-system tree directly.
 //!
-//! TO BE CONTINUED...
+//! Nodes are loaded lazily and modified in memory. When a transaction commits, every modified node is written to a
+//! *newly allocated* block and its old block is only released after the new checkpoint reaches the disk, so the last
+//! checkpoint stays intact if we crash in between.
 
-#[cfg(feature = "apfs_write")]
-compile_error!("apfs_write is not supported yet!");
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+use core::cmp::Ordering;
+
+use crate::{
+    arch::QWORD_LEN,
+    error::{Errno, KResult},
+    function, kerror,
+};
+
+use super::{
+    meta::{
+        BTreeInfo, BTreeKey, BTreeNodeFlags, BTreeNodePhysical, BTreeValue, KvLoc, KvOff, Nloc,
+        ObjectMapKey, ObjectMapPhysical, ObjectMapValue, ObjectPhysical, ObjectTypeFlags,
+        ObjectTypes, Oid, Xid, APFS_TYPE_DIR_REC, APFS_TYPE_FILE_EXTENT, APFS_TYPE_SIBLING_LINK,
+        APFS_TYPE_SNAP_NAME, APFS_TYPE_XATTR, BLOCK_SIZE, BTREE_STORAGE_SIZE, J_DREC_HASH_MASK,
+        J_DREC_LEN_MASK, OBJ_ID_MASK, OBJ_TYPE_SHIFT,
+    },
+    read_object,
+    spaceman::SpaceManager,
+    write_object, Device,
+};
+
+/// The offset used by an empty free list.
+const BTOFF_INVALID: u16 = 0xffff;
+
+/// The state shared by all the objects written in one transaction.
+pub struct CommitContext<'a> {
+    pub device: &'a Arc<dyn Device>,
+    /// The transaction identifier of the checkpoint being written.
+    pub xid: Xid,
+    /// The next free virtual object identifier of the container.
+    pub next_oid: &'a mut Oid,
+    pub spaceman: &'a mut SpaceManager,
+}
+
+/// The kind of the B-Tree, which determines the layout of the keys and how the child nodes are addressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TreeKind {
+    /// An object map: keys and values are fixed-sized and the nodes are physical objects.
+    ObjectMap,
+    /// A file-system tree: keys and values are variable-sized and the nodes are virtual objects.
+    FsTree,
+}
+
+impl TreeKind {
+    #[inline]
+    fn is_fixed(&self) -> bool {
+        *self == Self::ObjectMap
+    }
+
+    #[inline]
+    fn key_size(&self) -> usize {
+        core::mem::size_of::<ObjectMapKey>()
+    }
+
+    #[inline]
+    fn value_size(&self) -> usize {
+        core::mem::size_of::<ObjectMapValue>()
+    }
+
+    #[inline]
+    fn toc_entry_size(&self) -> usize {
+        match self {
+            Self::ObjectMap => core::mem::size_of::<KvOff>(),
+            Self::FsTree => core::mem::size_of::<KvLoc>(),
+        }
+    }
+
+    #[inline]
+    fn subtype(&self) -> ObjectTypes {
+        match self {
+            Self::ObjectMap => ObjectTypes::OBJECT_TYPE_OMAP,
+            Self::FsTree => ObjectTypes::OBJECT_TYPE_FSTREE,
+        }
+    }
+
+    /// Compares two raw keys the way APFS sorts them on the disk.
+    fn compare(&self, lhs: &[u8], rhs: &[u8]) -> Ordering {
+        match self {
+            Self::ObjectMap => read_u64(lhs, 0)
+                .cmp(&read_u64(rhs, 0))
+                .then_with(|| read_u64(lhs, QWORD_LEN).cmp(&read_u64(rhs, QWORD_LEN))),
+            Self::FsTree => {
+                let (lhs_hdr, rhs_hdr) = (read_u64(lhs, 0), read_u64(rhs, 0));
+                // 1. Compare the object identifiers numerically.
+                // 2. Compare the object types numerically.
+                // 3. Compare the type-specific parts of the keys.
+                (lhs_hdr & OBJ_ID_MASK)
+                    .cmp(&(rhs_hdr & OBJ_ID_MASK))
+                    .then_with(|| (lhs_hdr >> OBJ_TYPE_SHIFT).cmp(&(rhs_hdr >> OBJ_TYPE_SHIFT)))
+                    .then_with(|| match (lhs_hdr >> OBJ_TYPE_SHIFT) as u8 {
+                        APFS_TYPE_DIR_REC => {
+                            let (lhs_hash, rhs_hash) = (read_u32(lhs, 8), read_u32(rhs, 8));
+                            (lhs_hash & J_DREC_HASH_MASK)
+                                .cmp(&(rhs_hash & J_DREC_HASH_MASK))
+                                .then_with(|| {
+                                    let lhs_name = name_of(lhs, 12, lhs_hash & J_DREC_LEN_MASK);
+                                    let rhs_name = name_of(rhs, 12, rhs_hash & J_DREC_LEN_MASK);
+                                    lhs_name.cmp(rhs_name)
+                                })
+                        }
+                        APFS_TYPE_XATTR | APFS_TYPE_SNAP_NAME => {
+                            let lhs_name = name_of(lhs, 10, read_u16(lhs, 8) as _);
+                            let rhs_name = name_of(rhs, 10, read_u16(rhs, 8) as _);
+                            lhs_name.cmp(rhs_name)
+                        }
+                        APFS_TYPE_FILE_EXTENT | APFS_TYPE_SIBLING_LINK => {
+                            read_u64(lhs, 8).cmp(&read_u64(rhs, 8))
+                        }
+                        _ => Ordering::Equal,
+                    })
+            }
+        }
+    }
+}
+
+#[inline]
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    buf.get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes(bytes.try_into().unwrap()))
+        .unwrap_or_default()
+}
+
+#[inline]
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    buf.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        .unwrap_or_default()
+}
+
+#[inline]
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    buf.get(offset..offset + QWORD_LEN)
+        .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+        .unwrap_or_default()
+}
+
+/// Gets the name stored in a key without the null terminator.
+#[inline]
+fn name_of(buf: &[u8], offset: usize, len: u32) -> &[u8] {
+    let end = (offset + len as usize).min(buf.len());
+    let name = buf.get(offset..end).unwrap_or_default();
+    match name.iter().position(|&c| c == 0) {
+        Some(nul) => &name[..nul],
+        None => name,
+    }
+}
+
+/// Keys and values are aligned to 8 bytes inside the node.
+#[inline]
+fn align_up(len: usize) -> usize {
+    (len + QWORD_LEN - 1) & !(QWORD_LEN - 1)
+}
+
+/// The bytes that a node can use for its entries.
+#[inline]
+fn capacity(is_root: bool) -> usize {
+    match is_root {
+        true => BTREE_STORAGE_SIZE - core::mem::size_of::<BTreeInfo>(),
+        false => BTREE_STORAGE_SIZE,
+    }
+}
+
+/// An in-memory B-Tree node.
+struct Node {
+    /// The virtual object identifier (file-system trees) or the physical address (object maps). Zero if the node has
+    /// never been written.
+    oid: Oid,
+    /// The block that holds the node on the disk. Zero if the node has never been written.
+    paddr: u64,
+    level: u16,
+    keys: Vec<Vec<u8>>,
+    /// For index nodes, the values are the object identifiers of the children.
+    values: Vec<Vec<u8>>,
+    /// The loaded children. Always has the same length as `keys`; leaf nodes only contain `None`.
+    children: Vec<Option<Box<Node>>>,
+    dirty: bool,
+}
+
+impl Node {
+    fn new(level: u16) -> Self {
+        Self {
+            oid: 0,
+            paddr: 0,
+            level,
+            keys: Vec::new(),
+            values: Vec::new(),
+            children: Vec::new(),
+            dirty: true,
+        }
+    }
+
+    #[inline]
+    fn is_leaf(&self) -> bool {
+        self.level == 0
+    }
+
+    /// Parses a node read from `paddr`.
+    fn parse(kind: TreeKind, buf: &[u8], paddr: u64) -> KResult<Self> {
+        let node = unsafe { &*(buf.as_ptr() as *const BTreeNodePhysical) };
+        let flags = BTreeNodeFlags::from_bits_truncate(node.btn_flags);
+        let data = &node.btn_data;
+        let leaf = node.btn_level == 0;
+
+        let toc_start = node.btn_table_space.off as usize;
+        let key_start = toc_start + node.btn_table_space.len as usize;
+        let value_end = capacity(flags.contains(BTreeNodeFlags::BTNODE_ROOT));
+
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        for idx in 0..node.btn_nkeys as usize {
+            let toc = toc_start + idx * kind.toc_entry_size();
+            if toc + kind.toc_entry_size() > key_start {
+                kerror!("the table of contents is corrupted.");
+                return Err(Errno::EINVAL);
+            }
+
+            let (key_off, key_len, value_off, value_len) = unsafe {
+                if kind.is_fixed() {
+                    let kv = &*(data.as_ptr().add(toc) as *const KvOff);
+                    let value_len = if leaf { kind.value_size() } else { QWORD_LEN };
+                    (kv.k as usize, kind.key_size(), kv.v, value_len)
+                } else {
+                    let kv = &*(data.as_ptr().add(toc) as *const KvLoc);
+                    (
+                        kv.k.off as usize,
+                        kv.k.len as usize,
+                        kv.v.off,
+                        kv.v.len as usize,
+                    )
+                }
+            };
+
+            // Ghost entries have no values; we never create them.
+            if value_off == BTOFF_INVALID {
+                continue;
+            }
+
+            let key = data.get(key_start + key_off..key_start + key_off + key_len);
+            let value = value_end
+                .checked_sub(value_off as usize)
+                .and_then(|start| data.get(start..start + value_len));
+            match (key, value) {
+                (Some(key), Some(value)) => {
+                    keys.push(key.to_vec());
+                    values.push(value.to_vec());
+                }
+                _ => {
+                    kerror!("the key or value is out of the node.");
+                    return Err(Errno::EINVAL);
+                }
+            }
+        }
+
+        Ok(Self {
+            oid: node.btn_o.o_oid,
+            paddr,
+            level: node.btn_level,
+            children: keys.iter().map(|_| None).collect(),
+            keys,
+            values,
+            dirty: false,
+        })
+    }
+
+    /// The bytes taken by the entry at `idx`, including its table of contents entry.
+    fn entry_size(&self, kind: TreeKind, idx: usize) -> usize {
+        if kind.is_fixed() {
+            let value_len = if self.is_leaf() {
+                kind.value_size()
+            } else {
+                QWORD_LEN
+            };
+            kind.toc_entry_size() + kind.key_size() + value_len
+        } else {
+            kind.toc_entry_size()
+                + align_up(self.keys[idx].len())
+                + align_up(self.values[idx].len())
+        }
+    }
+
+    fn size(&self, kind: TreeKind) -> usize {
+        (0..self.keys.len())
+            .map(|idx| self.entry_size(kind, idx))
+            .sum()
+    }
+
+    /// Moves the upper half of the entries into a new sibling.
+    fn split(&mut self, kind: TreeKind) -> Box<Node> {
+        let half = self.size(kind) / 2;
+        let mut acc = 0;
+        let mut at = self.keys.len() - 1;
+        for idx in 0..self.keys.len() {
+            acc += self.entry_size(kind, idx);
+            if acc >= half {
+                at = idx + 1;
+                break;
+            }
+        }
+        let at = at.clamp(1, self.keys.len() - 1);
+
+        let mut sibling = Node::new(self.level);
+        sibling.keys = self.keys.split_off(at);
+        sibling.values = self.values.split_off(at);
+        sibling.children = self.children.split_off(at);
+        Box::new(sibling)
+    }
+
+    /// Serializes the node into a block. `info` is only given for the root node.
+    fn serialize(&self, kind: TreeKind, xid: Xid, info: Option<&BTreeInfo>) -> KResult<Vec<u8>> {
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        let node = unsafe { &mut *(buf.as_mut_ptr() as *mut BTreeNodePhysical) };
+
+        let mut flags = BTreeNodeFlags::empty();
+        let mut ty = ObjectTypes::OBJECT_TYPE_BTREE_NODE;
+        if info.is_some() {
+            flags |= BTreeNodeFlags::BTNODE_ROOT;
+            ty = ObjectTypes::OBJECT_TYPE_BTREE;
+        }
+        if self.is_leaf() {
+            flags |= BTreeNodeFlags::BTNODE_LEAF;
+        }
+        if kind.is_fixed() {
+            flags |= BTreeNodeFlags::BTNODE_FIXED_KV_SIZE;
+        }
+        let storage = match kind {
+            TreeKind::ObjectMap => ObjectTypeFlags::OBJ_PHYSICAL,
+            TreeKind::FsTree => ObjectTypeFlags::OBJ_VIRTUAL,
+        };
+
+        let toc_len = self.keys.len() * kind.toc_entry_size();
+        let value_end = capacity(info.is_some());
+        let mut key_off = 0;
+        let mut value_off = 0;
+        for (idx, (key, value)) in self.keys.iter().zip(self.values.iter()).enumerate() {
+            let (key_len, value_len) = if kind.is_fixed() {
+                (key.len(), value.len())
+            } else {
+                (align_up(key.len()), align_up(value.len()))
+            };
+            value_off += value_len;
+            if toc_len + key_off + key_len + value_off > value_end {
+                kerror!("the node overflows.");
+                return Err(Errno::ENOSPC);
+            }
+
+            let key_start = toc_len + key_off;
+            let value_start = value_end - value_off;
+            node.btn_data[key_start..key_start + key.len()].copy_from_slice(key);
+            node.btn_data[value_start..value_start + value.len()].copy_from_slice(value);
+
+            let toc = idx * kind.toc_entry_size();
+            unsafe {
+                if kind.is_fixed() {
+                    *(node.btn_data.as_mut_ptr().add(toc) as *mut KvOff) = KvOff {
+                        k: key_off as _,
+                        v: value_off as _,
+                    };
+                } else {
+                    *(node.btn_data.as_mut_ptr().add(toc) as *mut KvLoc) = KvLoc {
+                        k: Nloc {
+                            off: key_off as _,
+                            len: key.len() as _,
+                        },
+                        v: Nloc {
+                            off: value_off as _,
+                            len: value.len() as _,
+                        },
+                    };
+                }
+            }
+            key_off += key_len;
+        }
+
+        node.btn_o = ObjectPhysical {
+            o_cksum: [0u8; 8],
+            o_oid: self.oid,
+            o_xid: xid,
+            o_type: storage.bits() | ty.bits() as u32,
+            o_subtype: kind.subtype().bits() as u32,
+        };
+        node.btn_flags = flags.bits();
+        node.btn_level = self.level;
+        node.btn_nkeys = self.keys.len() as _;
+        node.btn_table_space = Nloc {
+            off: 0,
+            len: toc_len as _,
+        };
+        node.btn_free_space = Nloc {
+            off: key_off as _,
+            len: (value_end - value_off - toc_len - key_off) as _,
+        };
+        node.btn_key_free_list = Nloc {
+            off: BTOFF_INVALID,
+            len: 0,
+        };
+        node.btn_val_free_list = Nloc {
+            off: BTOFF_INVALID,
+            len: 0,
+        };
+
+        // The root node stores the information about the tree at the end of the block.
+        if let Some(info) = info {
+            unsafe {
+                *(node.btn_data.as_mut_ptr().add(value_end) as *mut BTreeInfo) = info.clone();
+            }
+        }
+
+        Ok(buf)
+    }
+}
+
+/// Everything needed to walk the tree.
+struct Cursor<'a> {
+    kind: TreeKind,
+    device: &'a Arc<dyn Device>,
+    /// Translates the virtual identifiers of a file-system tree.
+    omap: Option<&'a mut ObjectMapTree>,
+    info: &'a mut BTreeInfo,
+    released: &'a mut Vec<(Oid, u64)>,
+}
+
+impl<'a> Cursor<'a> {
+    /// Gets the child at `idx` of an index node, loading it from the disk if needed.
+    fn child<'b>(&mut self, node: &'b mut Node, idx: usize) -> KResult<&'b mut Node> {
+        if node.children[idx].is_none() {
+            let oid = read_u64(&node.values[idx], 0);
+            let paddr = match self.kind {
+                TreeKind::ObjectMap => oid,
+                TreeKind::FsTree => {
+                    let omap = self.omap.as_deref_mut().ok_or(Errno::EINVAL)?;
+                    omap.lookup(oid, Xid::MAX)?.ok_or(Errno::ENOENT)?.ov_paddr
+                }
+            };
+
+            let buf = read_object(self.device, paddr)?;
+            node.children[idx] = Some(Box::new(Node::parse(self.kind, &buf, paddr)?));
+        }
+
+        Ok(node.children[idx].as_mut().unwrap())
+    }
+
+    /// Remembers a node dropped from the tree so that its block can be freed on commit.
+    fn release(&mut self, node: &Node) {
+        if node.paddr != 0 {
+            self.released.push((node.oid, node.paddr));
+        }
+        self.info.bt_node_count = self.info.bt_node_count.saturating_sub(1);
+    }
+
+    /// The index of the child that may contain `key`, or `None` if `key` is smaller than all keys in the subtree.
+    fn child_index(&self, node: &Node, key: &[u8]) -> Option<usize> {
+        let count = node
+            .keys
+            .partition_point(|cur| self.kind.compare(cur, key) != Ordering::Greater);
+        count.checked_sub(1)
+    }
+
+    /// Collects the entries of the subtree whose keys are in `begin..=end`.
+    fn range(
+        &mut self,
+        node: &mut Node,
+        begin: &[u8],
+        end: &[u8],
+        entries: &mut Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> KResult<()> {
+        // The child before the first key not smaller than `begin` may still contain `begin`.
+        let first = self.child_index(node, begin).unwrap_or_default();
+        for idx in first..node.keys.len() {
+            if self.kind.compare(&node.keys[idx], end) == Ordering::Greater {
+                break;
+            }
+
+            if !node.is_leaf() {
+                let child = self.child(node, idx)?;
+                self.range(child, begin, end, entries)?;
+            } else if self.kind.compare(&node.keys[idx], begin) != Ordering::Less {
+                entries.push((node.keys[idx].clone(), node.values[idx].clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn insert(
+        &mut self,
+        node: &mut Node,
+        key: &[u8],
+        value: &[u8],
+        is_root: bool,
+    ) -> KResult<(Option<Box<Node>>, bool)> {
+        node.dirty = true;
+
+        let added = if node.is_leaf() {
+            match node
+                .keys
+                .binary_search_by(|cur| self.kind.compare(cur, key))
+            {
+                Ok(idx) => {
+                    node.keys[idx] = key.to_vec();
+                    node.values[idx] = value.to_vec();
+                    false
+                }
+                Err(idx) => {
+                    node.keys.insert(idx, key.to_vec());
+                    node.values.insert(idx, value.to_vec());
+                    node.children.insert(idx, None);
+                    true
+                }
+            }
+        } else {
+            // Keys smaller than every key go to the leftmost child.
+            let idx = self.child_index(node, key).unwrap_or_default();
+            let child = self.child(node, idx)?;
+            let (sibling, added) = self.insert(child, key, value, false)?;
+            let first = child.keys[0].clone();
+
+            // Index keys always equal the first key of their children.
+            node.keys[idx] = first;
+            if let Some(sibling) = sibling {
+                self.info.bt_node_count += 1;
+                node.keys.insert(idx + 1, sibling.keys[0].clone());
+                node.values
+                    .insert(idx + 1, sibling.oid.to_le_bytes().to_vec());
+                node.children.insert(idx + 1, Some(sibling));
+            }
+            added
+        };
+
+        let sibling = match node.size(self.kind) > capacity(is_root) && node.keys.len() > 1 {
+            true => Some(node.split(self.kind)),
+            false => None,
+        };
+        Ok((sibling, added))
+    }
+
+    fn remove(&mut self, node: &mut Node, key: &[u8]) -> KResult<bool> {
+        if node.is_leaf() {
+            return match node
+                .keys
+                .binary_search_by(|cur| self.kind.compare(cur, key))
+            {
+                Ok(idx) => {
+                    node.keys.remove(idx);
+                    node.values.remove(idx);
+                    node.children.remove(idx);
+                    node.dirty = true;
+                    Ok(true)
+                }
+                Err(_) => Ok(false),
+            };
+        }
+
+        let idx = match self.child_index(node, key) {
+            Some(idx) => idx,
+            None => return Ok(false),
+        };
+        let child = self.child(node, idx)?;
+        if !self.remove(child, key)? {
+            return Ok(false);
+        }
+        let first = child.keys.first().cloned();
+
+        node.dirty = true;
+        match first {
+            Some(first) => {
+                node.keys[idx] = first;
+                self.rebalance(node, idx)?;
+            }
+            None => {
+                let child = node.children.remove(idx).unwrap();
+                node.keys.remove(idx);
+                node.values.remove(idx);
+                self.release(&child);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Merges the child at `idx` with one of its siblings if both fit into one node.
+    fn rebalance(&mut self, node: &mut Node, idx: usize) -> KResult<()> {
+        if node.keys.len() < 2 || self.child(node, idx)?.size(self.kind) >= capacity(false) / 4 {
+            return Ok(());
+        }
+
+        let (left, right) = match idx + 1 < node.keys.len() {
+            true => (idx, idx + 1),
+            false => (idx - 1, idx),
+        };
+        let size =
+            self.child(node, left)?.size(self.kind) + self.child(node, right)?.size(self.kind);
+        if size > capacity(false) {
+            return Ok(());
+        }
+
+        let mut right_node = node.children.remove(right).unwrap();
+        node.keys.remove(right);
+        node.values.remove(right);
+
+        let left_node = node.children[left].as_mut().unwrap();
+        left_node.keys.append(&mut right_node.keys);
+        left_node.values.append(&mut right_node.values);
+        left_node.children.append(&mut right_node.children);
+        left_node.dirty = true;
+        self.release(&right_node);
+
+        Ok(())
+    }
+}
+
+/// A B-Tree stored on the disk.
+pub struct BTree {
+    kind: TreeKind,
+    device: Arc<dyn Device>,
+    root: Box<Node>,
+    info: BTreeInfo,
+    /// Nodes dropped from the tree as `(oid, paddr)`; they are freed when the transaction commits.
+    released: Vec<(Oid, u64)>,
+}
+
+impl BTree {
+    /// Loads the tree whose root node is stored at `paddr`.
+    pub fn load(device: &Arc<dyn Device>, kind: TreeKind, paddr: u64) -> KResult<Self> {
+        let buf = read_object(device, paddr)?;
+        let root = Node::parse(kind, &buf, paddr)?;
+        let info = unsafe {
+            &*(buf
+                .as_ptr()
+                .add(BLOCK_SIZE - core::mem::size_of::<BTreeInfo>())
+                as *const BTreeInfo)
+        }
+        .clone();
+
+        Ok(Self {
+            kind,
+            device: device.clone(),
+            root: Box::new(root),
+            info,
+            released: Vec::new(),
+        })
+    }
+
+    /// Checks if the tree has changes that are not on the disk.
+    pub fn is_dirty(&self) -> bool {
+        self.root.dirty || !self.released.is_empty()
+    }
+
+    fn cursor<'a>(&'a mut self, omap: Option<&'a mut ObjectMapTree>) -> (Cursor<'a>, &'a mut Node) {
+        (
+            Cursor {
+                kind: self.kind,
+                device: &self.device,
+                omap,
+                info: &mut self.info,
+                released: &mut self.released,
+            },
+            &mut self.root,
+        )
+    }
+
+    /// Finds the entry with the largest key that is less than or equal to `key`.
+    pub fn floor(
+        &mut self,
+        key: &[u8],
+        omap: Option<&mut ObjectMapTree>,
+    ) -> KResult<Option<(Vec<u8>, Vec<u8>)>> {
+        let (mut cursor, mut node) = self.cursor(omap);
+        loop {
+            let idx = match cursor.child_index(node, key) {
+                Some(idx) => idx,
+                None => return Ok(None),
+            };
+
+            if node.is_leaf() {
+                return Ok(Some((node.keys[idx].clone(), node.values[idx].clone())));
+            }
+            node = cursor.child(node, idx)?;
+        }
+    }
+
+    /// Finds the entries whose keys are in `begin..=end`, in order.
+    pub fn range(
+        &mut self,
+        begin: &[u8],
+        end: &[u8],
+        omap: Option<&mut ObjectMapTree>,
+    ) -> KResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let (mut cursor, root) = self.cursor(omap);
+        let mut entries = Vec::new();
+        cursor.range(root, begin, end, &mut entries)?;
+        Ok(entries)
+    }
+
+    /// Inserts an entry, or replaces the value if the key exists.
+    pub fn insert(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        omap: Option<&mut ObjectMapTree>,
+    ) -> KResult<()> {
+        let (mut cursor, root) = self.cursor(omap);
+        let (sibling, added) = cursor.insert(root, key, value, true)?;
+
+        if added {
+            self.info.bt_key_count += 1;
+        }
+        self.info.bt_longest_key = self.info.bt_longest_key.max(key.len() as _);
+        self.info.bt_longest_val = self.info.bt_longest_val.max(value.len() as _);
+
+        if let Some(sibling) = sibling {
+            // The root is split: the new root takes over the identifier of the old one.
+            let level = self.root.level + 1;
+            let mut old_root = core::mem::replace(&mut self.root, Box::new(Node::new(level)));
+            self.root.oid = core::mem::take(&mut old_root.oid);
+            self.root.paddr = core::mem::take(&mut old_root.paddr);
+            self.root.keys = vec![old_root.keys[0].clone(), sibling.keys[0].clone()];
+            self.root.values = vec![vec![0u8; QWORD_LEN]; 2];
+            self.root.children = vec![Some(old_root), Some(sibling)];
+            self.info.bt_node_count += 2;
+        }
+
+        Ok(())
+    }
+
+    /// Removes an entry and returns whether it existed.
+    pub fn remove(&mut self, key: &[u8], omap: Option<&mut ObjectMapTree>) -> KResult<bool> {
+        let kind = self.kind;
+        let (mut cursor, root) = self.cursor(omap);
+        let found = cursor.remove(root, key)?;
+
+        // Shrink the tree while the root has a single child that fits into the root.
+        while !root.is_leaf() && root.keys.len() == 1 {
+            if cursor.child(root, 0)?.size(kind) > capacity(true) {
+                break;
+            }
+
+            let mut child = root.children[0].take().unwrap();
+            cursor.release(&child);
+            child.oid = root.oid;
+            child.paddr = root.paddr;
+            child.dirty = true;
+            *root = *child;
+        }
+
+        if found {
+            self.info.bt_key_count = self.info.bt_key_count.saturating_sub(1);
+        }
+        Ok(found)
+    }
+
+    /// Writes all the modified nodes to new blocks and returns the address of the root node.
+    ///
+    /// The nodes of a file-system tree are virtual, so `omap` must be given to record their new locations.
+    pub fn commit(
+        &mut self,
+        ctx: &mut CommitContext,
+        mut omap: Option<&mut ObjectMapTree>,
+    ) -> KResult<u64> {
+        for (oid, paddr) in core::mem::take(&mut self.released) {
+            ctx.spaceman.free_later(paddr, 1);
+            if self.kind == TreeKind::FsTree {
+                omap.as_deref_mut().ok_or(Errno::EINVAL)?.unmap(oid)?;
+            }
+        }
+
+        let info = self.info.clone();
+        Self::write_node(self.kind, &mut self.root, Some(&info), ctx, &mut omap)?;
+        Ok(self.root.paddr)
+    }
+
+    fn write_node(
+        kind: TreeKind,
+        node: &mut Node,
+        info: Option<&BTreeInfo>,
+        ctx: &mut CommitContext,
+        omap: &mut Option<&mut ObjectMapTree>,
+    ) -> KResult<()> {
+        if !node.dirty {
+            return Ok(());
+        }
+
+        // Children first, since the parent stores their identifiers.
+        for idx in 0..node.children.len() {
+            if let Some(child) = node.children[idx].as_mut() {
+                Self::write_node(kind, child, None, ctx, omap)?;
+                node.values[idx] = child.oid.to_le_bytes().to_vec();
+            }
+        }
+
+        let paddr = ctx.spaceman.allocate(ctx.device, 1)?;
+        if node.paddr != 0 {
+            ctx.spaceman.free_later(node.paddr, 1);
+        }
+        node.paddr = paddr;
+
+        match kind {
+            TreeKind::ObjectMap => node.oid = paddr,
+            TreeKind::FsTree => {
+                if node.oid == 0 {
+                    node.oid = *ctx.next_oid;
+                    *ctx.next_oid += 1;
+                }
+                omap.as_deref_mut()
+                    .ok_or(Errno::EINVAL)?
+                    .map(node.oid, ctx.xid, paddr)?;
+            }
+        }
+
+        let mut buf = node.serialize(kind, ctx.xid, info)?;
+        write_object(ctx.device, paddr, &mut buf)?;
+        node.dirty = false;
+
+        Ok(())
+    }
+}
+
+/// An object map that can be modified and written back.
+pub struct ObjectMapTree {
+    /// The location of the `omap_phys_t`.
+    pub paddr: u64,
+    pub phys: ObjectMapPhysical,
+    pub tree: BTree,
+}
+
+impl ObjectMapTree {
+    /// Loads the object map stored at `paddr`.
+    pub fn load(device: &Arc<dyn Device>, paddr: u64) -> KResult<Self> {
+        let buf = read_object(device, paddr)?;
+        let phys = unsafe { &*(buf.as_ptr() as *const ObjectMapPhysical) }.clone();
+        let tree = BTree::load(device, TreeKind::ObjectMap, phys.om_tree_oid)?;
+
+        Ok(Self { paddr, phys, tree })
+    }
+
+    /// Finds the latest version of `oid` that is not newer than `xid`.
+    pub fn lookup(&mut self, oid: Oid, xid: Xid) -> KResult<Option<ObjectMapValue>> {
+        let key = ObjectMapKey {
+            ok_oid: oid,
+            ok_xid: xid,
+        };
+
+        match self.tree.floor(&key.export(), None)? {
+            Some((key, value)) if read_u64(&key, 0) == oid => {
+                Ok(Some(ObjectMapValue::import(&value)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Maps `oid` to `paddr` starting from transaction `xid`.
+    pub fn map(&mut self, oid: Oid, xid: Xid, paddr: u64) -> KResult<()> {
+        self.unmap(oid)?;
+
+        let key = ObjectMapKey {
+            ok_oid: oid,
+            ok_xid: xid,
+        };
+        let value = ObjectMapValue {
+            ov_flags: 0,
+            ov_size: BLOCK_SIZE as _,
+            ov_paddr: paddr,
+        };
+        self.tree.insert(&key.export(), &value.export(), None)
+    }
+
+    /// Removes all the versions of `oid`. Older versions are useless without snapshots.
+    pub fn unmap(&mut self, oid: Oid) -> KResult<()> {
+        let key = ObjectMapKey {
+            ok_oid: oid,
+            ok_xid: Xid::MAX,
+        }
+        .export();
+
+        while let Some((cur, _)) = self.tree.floor(&key, None)? {
+            if read_u64(&cur, 0) != oid {
+                break;
+            }
+            self.tree.remove(&cur, None)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the tree and a new copy of the object map, and returns the address of the new copy.
+    pub fn commit(&mut self, ctx: &mut CommitContext) -> KResult<u64> {
+        if !self.tree.is_dirty() {
+            return Ok(self.paddr);
+        }
+
+        self.phys.om_tree_oid = self.tree.commit(ctx, None)?;
+
+        let paddr = ctx.spaceman.allocate(ctx.device, 1)?;
+        ctx.spaceman.free_later(self.paddr, 1);
+        self.paddr = paddr;
+        self.phys.om_o.o_oid = paddr;
+        self.phys.om_o.o_xid = ctx.xid;
+
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        unsafe {
+            *(buf.as_mut_ptr() as *mut ObjectMapPhysical) = self.phys.clone();
+        }
+        write_object(ctx.device, paddr, &mut buf)?;
+
+        Ok(paddr)
+    }
+}
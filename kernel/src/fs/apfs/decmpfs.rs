@@ -0,0 +1,367 @@
+//! Implements the transparent decompression of files compressed by macOS (decmpfs).
+//!
+//! A compressed file has the `UF_COMPRESSED` flag and no data stream. Its `com.apple.decmpfs` extended attribute starts
+//! with a [`DecmpfsHeader`] that tells the algorithm and the uncompressed size. Small files are compressed as a whole
+//! and stored right after the header (inline); larger ones are split into 64 KiB chunks which are compressed
+//! separately and stored in the `com.apple.ResourceFork` extended attribute together with a table of the chunks.
+//!
+//! We support zlib and LZVN. Decompressed chunks are kept in a small per-file cache because reads are usually much
+//! smaller than a chunk.
+
+use alloc::{collections::VecDeque, sync::Arc, vec, vec::Vec};
+use spin::RwLock;
+
+use crate::{
+    error::{Errno, KResult},
+    function, kerror,
+};
+
+use super::meta::{ApfsVolumn, JXattrVal, UF_COMPRESSED};
+
+/// The name of the extended attribute that holds the header.
+pub const DECMPFS_XATTR_NAME: &str = "com.apple.decmpfs";
+/// The name of the extended attribute that holds the chunks.
+pub const RESOURCE_FORK_XATTR_NAME: &str = "com.apple.ResourceFork";
+
+/// 'cmpf' in little endian.
+const DECMPFS_MAGIC: u32 = 0x636d7066;
+/// The uncompressed size of a chunk in the resource fork.
+const DECMPFS_CHUNK_SIZE: usize = 0x10000;
+/// The number of decompressed chunks cached for each file.
+const CHUNK_CACHE_SIZE: usize = 8;
+
+// Compression types.
+const CMP_ZLIB_INLINE: u32 = 3;
+const CMP_ZLIB_RESOURCE: u32 = 4;
+const CMP_LZVN_INLINE: u32 = 7;
+const CMP_LZVN_RESOURCE: u32 = 8;
+const CMP_UNCOMPRESSED_INLINE: u32 = 9;
+
+/// The header of the `com.apple.decmpfs` attribute.
+#[derive(Clone, Debug)]
+#[repr(C, packed)]
+pub struct DecmpfsHeader {
+    pub compression_magic: u32,
+    pub compression_type: u32,
+    pub uncompressed_size: u64,
+}
+
+/// Where the compressed data are stored.
+enum Payload {
+    /// The data following the header.
+    Inline(Vec<u8>),
+    /// The resource fork and its chunks as `(offset, length)`.
+    ResourceFork {
+        fork: JXattrVal,
+        chunks: Vec<(usize, usize)>,
+    },
+}
+
+/// A compressed file.
+pub struct Decmpfs {
+    /// The compression type.
+    ty: u32,
+    /// The uncompressed size of the file.
+    pub size: usize,
+    payload: Payload,
+    /// Recently decompressed chunks as `(index, data)`.
+    cache: RwLock<VecDeque<(usize, Arc<Vec<u8>>)>>,
+}
+
+impl Decmpfs {
+    /// Parses the compression information of inode `id`. Returns `None` if the file is not compressed.
+    pub fn load(volumn: &ApfsVolumn, id: u64, bsd_flags: u32) -> KResult<Option<Self>> {
+        if bsd_flags & UF_COMPRESSED == 0 {
+            return Ok(None);
+        }
+
+        let attr = volumn.read_xattr(&volumn.get_xattr(id, DECMPFS_XATTR_NAME)?)?;
+        let header_len = core::mem::size_of::<DecmpfsHeader>();
+        if attr.len() < header_len {
+            kerror!("the decmpfs header is truncated.");
+            return Err(Errno::EIO);
+        }
+        let header = unsafe { &*(attr.as_ptr() as *const DecmpfsHeader) }.clone();
+        if header.compression_magic != DECMPFS_MAGIC {
+            kerror!("invalid decmpfs magic.");
+            return Err(Errno::EIO);
+        }
+
+        let ty = header.compression_type;
+        let size = header.uncompressed_size as usize;
+        let payload = match ty {
+            // Inline payloads are decompressed as a single chunk.
+            CMP_ZLIB_INLINE | CMP_LZVN_INLINE | CMP_UNCOMPRESSED_INLINE => {
+                if size > DECMPFS_CHUNK_SIZE {
+                    kerror!("the inline decmpfs payload claims {size} bytes.");
+                    return Err(Errno::EIO);
+                }
+
+                Payload::Inline(attr[header_len..].to_vec())
+            }
+            CMP_ZLIB_RESOURCE | CMP_LZVN_RESOURCE => {
+                let fork = volumn.get_xattr(id, RESOURCE_FORK_XATTR_NAME)?;
+                let chunks = match ty {
+                    CMP_ZLIB_RESOURCE => Self::zlib_chunks(volumn, &fork)?,
+                    _ => Self::lzvn_chunks(volumn, &fork)?,
+                };
+                let needed = size / DECMPFS_CHUNK_SIZE + (size % DECMPFS_CHUNK_SIZE != 0) as usize;
+                if chunks.len() < needed {
+                    kerror!("the resource fork has too few chunks.");
+                    return Err(Errno::EIO);
+                }
+
+                Payload::ResourceFork { fork, chunks }
+            }
+            ty => {
+                kerror!("compression type {ty} is not supported.");
+                return Err(Errno::EOPNOTSUPP);
+            }
+        };
+
+        Ok(Some(Self {
+            ty,
+            size,
+            payload,
+            cache: RwLock::new(VecDeque::new()),
+        }))
+    }
+
+    /// Reads the uncompressed data at `offset`.
+    pub fn read_at(&self, volumn: &ApfsVolumn, offset: usize, buf: &mut [u8]) -> KResult<usize> {
+        if offset >= self.size {
+            return Ok(0);
+        }
+
+        let len = buf.len().min(self.size - offset);
+        let mut read = 0;
+        while read < len {
+            let pos = offset + read;
+            let (idx, off) = match self.payload {
+                Payload::Inline(_) => (0, pos),
+                Payload::ResourceFork { .. } => {
+                    (pos / DECMPFS_CHUNK_SIZE, pos % DECMPFS_CHUNK_SIZE)
+                }
+            };
+
+            let chunk = self.chunk(volumn, idx)?;
+            let n = chunk.len().saturating_sub(off).min(len - read);
+            if n == 0 {
+                kerror!("chunk {idx} is shorter than expected.");
+                return Err(Errno::EIO);
+            }
+            buf[read..read + n].copy_from_slice(&chunk[off..off + n]);
+            read += n;
+        }
+
+        Ok(len)
+    }
+
+    /// Gets the decompressed chunk `idx`.
+    fn chunk(&self, volumn: &ApfsVolumn, idx: usize) -> KResult<Arc<Vec<u8>>> {
+        if let Some((_, chunk)) = self.cache.read().iter().find(|(cur, _)| *cur == idx) {
+            return Ok(chunk.clone());
+        }
+
+        let chunk = match &self.payload {
+            Payload::Inline(data) => self.decompress(data, self.size)?,
+            Payload::ResourceFork { fork, chunks } => {
+                let (offset, len) = chunks[idx];
+                let mut data = vec![0u8; len];
+                Self::read_fork(volumn, fork, offset, &mut data)?;
+                let expected = DECMPFS_CHUNK_SIZE.min(self.size - idx * DECMPFS_CHUNK_SIZE);
+                self.decompress(&data, expected)?
+            }
+        };
+
+        let chunk = Arc::new(chunk);
+        let mut cache = self.cache.write();
+        cache.push_back((idx, chunk.clone()));
+        if cache.len() > CHUNK_CACHE_SIZE {
+            cache.pop_front();
+        }
+
+        Ok(chunk)
+    }
+
+    /// Decompresses a chunk whose uncompressed size is `expected`.
+    fn decompress(&self, data: &[u8], expected: usize) -> KResult<Vec<u8>> {
+        match self.ty {
+            CMP_UNCOMPRESSED_INLINE => Ok(data.to_vec()),
+            // Chunks that do not compress well are stored as is after a marker.
+            CMP_ZLIB_INLINE | CMP_ZLIB_RESOURCE => match data.first() {
+                Some(marker) if marker & 0x0f == 0x0f => Ok(data[1..].to_vec()),
+                _ => miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(data, expected)
+                    .map_err(|err| {
+                        kerror!("cannot inflate the chunk: {:?}", err.status);
+                        Errno::EIO
+                    }),
+            },
+            _ => match data.first() {
+                Some(0x06) => Ok(data[1..].to_vec()),
+                _ => lzvn_decode(data, expected),
+            },
+        }
+    }
+
+    /// Reads the resource fork at `offset`.
+    fn read_fork(
+        volumn: &ApfsVolumn,
+        fork: &JXattrVal,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> KResult<()> {
+        let len = match fork.get_dstream() {
+            Some(stream) => volumn.read_stream(
+                stream.xattr_obj_id,
+                stream.dstream.size as usize,
+                offset,
+                buf,
+            )?,
+            None => {
+                let data = fork.get_data().get(offset..).unwrap_or_default();
+                let len = data.len().min(buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                len
+            }
+        };
+
+        match len == buf.len() {
+            true => Ok(()),
+            false => {
+                kerror!("the resource fork is truncated.");
+                Err(Errno::EIO)
+            }
+        }
+    }
+
+    /// Reads the chunk table of a zlib resource fork. The fork starts with a big-endian resource header whose first
+    /// field is the offset of the resource data; the data begin with their length, the number of chunks and then
+    /// `(offset, length)` pairs relative to the number of chunks.
+    fn zlib_chunks(volumn: &ApfsVolumn, fork: &JXattrVal) -> KResult<Vec<(usize, usize)>> {
+        let mut word = [0u8; 4];
+        Self::read_fork(volumn, fork, 0, &mut word)?;
+        let base = u32::from_be_bytes(word) as usize + 4;
+        Self::read_fork(volumn, fork, base, &mut word)?;
+        let count = u32::from_le_bytes(word) as usize;
+
+        let mut table = vec![0u8; count * 8];
+        Self::read_fork(volumn, fork, base + 4, &mut table)?;
+        Ok(table
+            .chunks_exact(8)
+            .map(|entry| {
+                let offset = u32::from_le_bytes(entry[..4].try_into().unwrap()) as usize;
+                let len = u32::from_le_bytes(entry[4..].try_into().unwrap()) as usize;
+                (base + offset, len)
+            })
+            .collect())
+    }
+
+    /// Reads the chunk table of an LZVN resource fork, which is an array of the offsets of the chunks followed by the
+    /// end of the last one. The first offset is thus the size of the table.
+    fn lzvn_chunks(volumn: &ApfsVolumn, fork: &JXattrVal) -> KResult<Vec<(usize, usize)>> {
+        let mut word = [0u8; 4];
+        Self::read_fork(volumn, fork, 0, &mut word)?;
+        let table_len = u32::from_le_bytes(word) as usize;
+        if table_len < 8 || table_len % 4 != 0 {
+            kerror!("invalid LZVN chunk table.");
+            return Err(Errno::EIO);
+        }
+
+        let mut table = vec![0u8; table_len];
+        Self::read_fork(volumn, fork, 0, &mut table)?;
+        let offsets = table
+            .chunks_exact(4)
+            .map(|entry| u32::from_le_bytes(entry.try_into().unwrap()) as usize)
+            .collect::<Vec<_>>();
+        offsets
+            .windows(2)
+            .map(|pair| match pair[1].checked_sub(pair[0]) {
+                Some(len) => Ok((pair[0], len)),
+                None => Err(Errno::EIO),
+            })
+            .collect()
+    }
+}
+
+/// Decodes an LZVN stream whose uncompressed size is at most `max_len`.
+///
+/// Each opcode copies some literal bytes that follow it and then a match from a previous position. The match distance
+/// is reused by opcodes that do not specify one.
+fn lzvn_decode(src: &[u8], max_len: usize) -> KResult<Vec<u8>> {
+    let byte = |idx: usize| src.get(idx).copied().ok_or(Errno::EIO);
+    let mut dst = Vec::with_capacity(max_len);
+    let mut pos = 0;
+    let mut prev_distance = 0;
+
+    loop {
+        let opc = byte(pos)?;
+        // (opcode length, literal length, match length, match distance)
+        let (opc_len, literal, matched, distance) = match opc {
+            // End of stream.
+            0x06 => break,
+            // No operation.
+            0x0e | 0x16 => (1, 0, 0, prev_distance),
+            // Undefined.
+            0x1e | 0x26 | 0x2e | 0x36 | 0x3e | 0x70..=0x7f | 0xd0..=0xdf => {
+                kerror!("invalid LZVN opcode {opc:#x}.");
+                return Err(Errno::EIO);
+            }
+            // Medium distance.
+            0xa0..=0xbf => {
+                let operand = u16::from_le_bytes([byte(pos + 1)?, byte(pos + 2)?]) as usize;
+                let matched = ((((opc & 0x7) as usize) << 2) | (operand & 0x3)) + 3;
+                (3, ((opc >> 3) & 0x3) as usize, matched, operand >> 2)
+            }
+            // Large and small literals.
+            0xe0 => (2, byte(pos + 1)? as usize + 16, 0, prev_distance),
+            0xe1..=0xef => (1, (opc & 0xf) as usize, 0, prev_distance),
+            // Large and small matches with the previous distance.
+            0xf0 => (2, 0, byte(pos + 1)? as usize + 16, prev_distance),
+            0xf1..=0xff => (1, 0, (opc & 0xf) as usize, prev_distance),
+            _ => {
+                let literal = (opc >> 6) as usize;
+                let matched = ((opc >> 3) & 0x7) as usize + 3;
+                match opc & 0x7 {
+                    // Previous distance.
+                    6 => (1, literal, matched, prev_distance),
+                    // Large distance.
+                    7 => {
+                        let distance = u16::from_le_bytes([byte(pos + 1)?, byte(pos + 2)?]);
+                        (3, literal, matched, distance as usize)
+                    }
+                    // Small distance.
+                    _ => {
+                        let distance = (((opc & 0x7) as usize) << 8) | byte(pos + 1)? as usize;
+                        (2, literal, matched, distance)
+                    }
+                }
+            }
+        };
+        pos += opc_len;
+
+        if dst.len() + literal + matched > max_len {
+            kerror!("the LZVN stream is longer than expected.");
+            return Err(Errno::EIO);
+        }
+
+        let literals = src.get(pos..pos + literal).ok_or(Errno::EIO)?;
+        dst.extend_from_slice(literals);
+        pos += literal;
+
+        if matched != 0 {
+            if distance == 0 || distance > dst.len() {
+                kerror!("invalid LZVN match distance {distance:#x}.");
+                return Err(Errno::EIO);
+            }
+            // The match may overlap the bytes being copied.
+            let start = dst.len() - distance;
+            for idx in 0..matched {
+                dst.push(dst[start + idx]);
+            }
+        }
+        prev_distance = distance;
+    }
+
+    Ok(dst)
+}
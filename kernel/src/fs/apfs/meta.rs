@@ -21,10 +21,13 @@ use unicode_normalization::char::decompose_canonical;
 use crate::{
     arch::{DWORD_LEN, QWORD_LEN},
     error::{Errno, KResult},
-    function, kdebug, kerror, kinfo,
+    function, kdebug, kerror,
 };
 
-use super::{read_fs_tree, read_object, read_omap, AppleFileSystem, Device};
+use super::{read_fs_tree, read_object, read_omap, AppleFileSystem, DentryCache, Device};
+
+#[cfg(feature = "apfs_write")]
+use super::transaction::VolumnWriter;
 
 // Some type alias.
 
@@ -55,6 +58,7 @@ pub type DirRecordMap = BTreeMap<JDrecHashedKey, JDrecVal>;
 pub type PhysExtMap = BTreeMap<JPhysExtKey, JPhysExtVal>;
 pub type FileExtentMap = BTreeMap<JFileExtentKey, JFileExtentVal>;
 pub type DirStatMap = BTreeMap<JDirStatKey, JDirStatVal>;
+pub type XattrMap = BTreeMap<JXattrKey, JXattrVal>;
 
 // Some important constants.
 
@@ -64,6 +68,8 @@ pub const APFS_MAGIC: &[u8; 4] = b"APSB";
 pub const OBJECT_HDR_SIZE: usize = core::mem::size_of::<ObjectPhysical>();
 pub const MAX_ALLOWED_CHECKPOINT_MAP_SIZE: usize = 100;
 pub const ROOT_DIR_RECORD_ID: u64 = 0x1;
+pub const ROOT_DIR_INO_NUM: u64 = 0x2;
+pub const SNAP_DIR_INO_NUM: u64 = 0x6;
 
 // B-Tree constants.
 pub const BTREE_STORAGE_SIZE: usize =
@@ -88,6 +94,22 @@ pub const J_FILE_EXTENT_FLAG_SHIFT: u64 = 56;
 
 pub const DREC_TYPE_MASK: u16 = 0xf;
 
+// Volume incompatible features.
+pub const APFS_INCOMPAT_CASE_INSENSITIVE: u64 = 0x00000001;
+pub const APFS_INCOMPAT_NORMALIZATION_INSENSITIVE: u64 = 0x00000008;
+
+// Extended attribute constants.
+pub const XATTR_DATA_STREAM: u16 = 0x0001;
+pub const XATTR_DATA_EMBEDDED: u16 = 0x0002;
+pub const XATTR_FILE_SYSTEM_OWNED: u16 = 0x0004;
+pub const XATTR_MAX_EMBEDDED_SIZE: usize = 3804;
+pub const XATTR_MAX_NAME_LEN: usize = 255;
+/// The extended attribute that stores the target of a symbolic link.
+pub const SYMLINK_XATTR_NAME: &str = "com.apple.fs.symlink";
+
+/// The inode flag (`bsd_flags`) that denotes a file compressed by decmpfs.
+pub const UF_COMPRESSED: u32 = 0x00000020;
+
 // Key types.
 
 pub const APFS_TYPE_ANY: u8 = 0;
@@ -122,6 +144,8 @@ pub const S_IFWHT: u16 = 0o160000;
 pub const DEFAULT_XF_LEN: usize = 1024;
 pub const INO_EXT_TYPE_NAME: u8 = 4;
 pub const INO_EXT_TYPE_DSTREAM: u8 = 8;
+pub const XF_DO_NOT_COPY: u8 = 0x01;
+pub const XF_SYSTEM_FIELD: u8 = 0x20;
 
 pub const CASTAGNOLI: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
 
@@ -129,6 +153,71 @@ pub trait XFieldsInterepretable: Clone + Debug {
     /// Gets the raw byte array of the xfields.
     fn get_xfields(&self) -> &[u8];
 
+    /// Gets the mutable raw byte array of the xfields.
+    fn get_xfields_mut(&mut self) -> &mut [u8];
+
+    /// The number of bytes the xfields occupy on the disk.
+    fn xfields_len(&self) -> usize {
+        let xf_blob = unsafe { &*(self.get_xfields().as_ptr() as *const XfBlob) };
+        match xf_blob.xf_num_exts {
+            0 => 0,
+            num => {
+                DWORD_LEN
+                    + num as usize * core::mem::size_of::<Xfields>()
+                    + xf_blob.xf_used_data as usize
+            }
+        }
+    }
+
+    /// Replaces the extended field of type `ty` with `data`, or appends one if there is no such field.
+    fn set_xfield(&mut self, ty: u8, flags: u8, data: &[u8]) -> KResult<()> {
+        let xf_blob = unsafe { &*(self.get_xfields().as_ptr() as *const XfBlob) };
+        let xf_num_exts = xf_blob.xf_num_exts as usize;
+        let mut xf_data_offset = xf_num_exts * core::mem::size_of::<Xfields>();
+
+        // Collect all the fields and rebuild the blob.
+        let mut fields = Vec::new();
+        for idx in 0..xf_num_exts {
+            let xf = unsafe { &*(xf_blob.xf_data.as_ptr().add(idx * DWORD_LEN) as *const Xfields) };
+            let xf_size = xf.x_size as usize;
+            if xf.x_type != ty {
+                fields.push((
+                    xf.x_type,
+                    xf.x_flags,
+                    xf_blob.xf_data[xf_data_offset..xf_data_offset + xf_size].to_vec(),
+                ));
+            }
+            xf_data_offset += (xf_size + QWORD_LEN - 1) & !(QWORD_LEN - 1);
+        }
+        fields.push((ty, flags, data.to_vec()));
+
+        let header_len = fields.len() * core::mem::size_of::<Xfields>();
+        let used_data = fields
+            .iter()
+            .map(|(_, _, data)| (data.len() + QWORD_LEN - 1) & !(QWORD_LEN - 1))
+            .sum::<usize>();
+        if DWORD_LEN + header_len + used_data > DEFAULT_XF_LEN {
+            kerror!("xfields are too large.");
+            return Err(Errno::ENOSPC);
+        }
+
+        let xfields = self.get_xfields_mut();
+        xfields.fill(0);
+        xfields[..2].copy_from_slice(&(fields.len() as u16).to_le_bytes());
+        xfields[2..4].copy_from_slice(&(used_data as u16).to_le_bytes());
+        let mut data_offset = DWORD_LEN + header_len;
+        for (idx, (x_type, x_flags, data)) in fields.iter().enumerate() {
+            let entry = DWORD_LEN + idx * core::mem::size_of::<Xfields>();
+            xfields[entry] = *x_type;
+            xfields[entry + 1] = *x_flags;
+            xfields[entry + 2..entry + 4].copy_from_slice(&(data.len() as u16).to_le_bytes());
+            xfields[data_offset..data_offset + data.len()].copy_from_slice(data);
+            data_offset += (data.len() + QWORD_LEN - 1) & !(QWORD_LEN - 1);
+        }
+
+        Ok(())
+    }
+
     /// Interpret the xfields.
     fn interpret_xfields(&self, ty: u8) -> KResult<Vec<Vec<u8>>> {
         let xf_blob = unsafe { &*(self.get_xfields().as_ptr() as *const XfBlob) };
@@ -168,6 +257,17 @@ pub trait BTreeKey: Clone + Eq + Ord + PartialEq + PartialOrd + Sized {
         unsafe { &*(buf.as_ptr() as *const Self) }.clone()
     }
 
+    /// Exports `self` as the raw byte array stored in the B-Tree node.
+    fn export(&self) -> Vec<u8> {
+        unsafe {
+            core::slice::from_raw_parts(
+                self as *const Self as *const u8,
+                core::mem::size_of::<Self>(),
+            )
+        }
+        .to_vec()
+    }
+
     fn as_any(&self) -> &dyn Any;
 
     fn ty(&self) -> KeyType;
@@ -183,6 +283,17 @@ pub trait BTreeValue: Clone + Sized {
         // todo: check header? => dispatch to each implementation.
         unsafe { &*(buf.as_ptr() as *const Self) }.clone()
     }
+
+    /// Exports `self` as the raw byte array stored in the B-Tree node.
+    fn export(&self) -> Vec<u8> {
+        unsafe {
+            core::slice::from_raw_parts(
+                self as *const Self as *const u8,
+                core::mem::size_of::<Self>(),
+            )
+        }
+        .to_vec()
+    }
 }
 
 bitflags! {
@@ -614,7 +725,7 @@ pub struct JDrecKey {
 
 /// The key half of a directory entry record hashed.
 ///
-/// Not sure if we really need this thing, but we keep it here for future usage (perhaps)?
+/// The hash of the name allows looking up an entry without scanning the whole directory.
 #[derive(Clone)]
 #[repr(C, packed)]
 pub struct JDrecHashedKey {
@@ -625,12 +736,14 @@ pub struct JDrecHashedKey {
 }
 
 impl JDrecHashedKey {
-    pub fn new(id: u64, name: &str) -> Self {
+    /// Creates the key of the entry `name` in directory `id`. `case_fold` tells if the volume is case-insensitive.
+    pub fn new(id: u64, name: &str, case_fold: bool) -> Self {
         Self {
             hdr: JKey {
                 obj_id_and_type: ((APFS_TYPE_DIR_REC as u64) << OBJ_TYPE_SHIFT) | id,
             },
-            name_len_and_hash: (name.len() + 1) as _,
+            name_len_and_hash: (Self::hash_name(name, case_fold) << J_DREC_HASH_SHIFT)
+                | ((name.len() + 1) as u32 & J_DREC_LEN_MASK),
             name: {
                 let mut buf = [0u8; 255];
                 buf[..name.len().min(255)].copy_from_slice(name.as_bytes());
@@ -638,6 +751,41 @@ impl JDrecHashedKey {
             },
         }
     }
+
+    /// Gets the file name.
+    pub fn get_name(&self) -> &str {
+        CStr::from_bytes_until_nul(&self.name)
+            .unwrap_or_default()
+            .to_str()
+            .unwrap_or_default()
+    }
+
+    /// Gets the 22-bit hash of the file name.
+    pub fn get_hash(&self) -> u32 {
+        (self.name_len_and_hash & J_DREC_HASH_MASK) >> J_DREC_HASH_SHIFT
+    }
+
+    /// Normalizes a file name with a canonical decomposition, after case folding if `case_fold` is set.
+    pub fn normalize_name(name: &str, case_fold: bool) -> Vec<char> {
+        let mut nfd_name = Vec::new();
+        name.chars().for_each(|c| match case_fold {
+            true => c
+                .to_lowercase()
+                .for_each(|c| decompose_canonical(c, |c| nfd_name.push(c))),
+            false => decompose_canonical(c, |c| nfd_name.push(c)),
+        });
+        nfd_name
+    }
+
+    /// Computes the 22-bit hash of a file name: the CRC-32C of its normalized form encoded in UTF-32.
+    pub fn hash_name(name: &str, case_fold: bool) -> u32 {
+        let nfd_name = Self::normalize_name(name, case_fold)
+            .into_iter()
+            .flat_map(|c| (c as u32).to_le_bytes())
+            .collect::<Vec<_>>();
+
+        !CASTAGNOLI.checksum(&nfd_name) & (J_DREC_HASH_MASK >> J_DREC_HASH_SHIFT)
+    }
 }
 
 /// The key half of a physical extent record.
@@ -718,6 +866,150 @@ pub struct JDirStatVal {
     pub gen_count: u64,
 }
 
+/// The key half of an extended attribute record.
+#[derive(Clone)]
+#[repr(C, packed)]
+pub struct JXattrKey {
+    pub hdr: JKey,
+    /// The length of the name, including the final null character.
+    pub name_len: u16,
+    pub name: [u8; XATTR_MAX_NAME_LEN],
+}
+
+impl JXattrKey {
+    pub fn new(id: u64, name: &str) -> Self {
+        let len = name.len().min(XATTR_MAX_NAME_LEN - 1);
+        Self {
+            hdr: JKey {
+                obj_id_and_type: ((APFS_TYPE_XATTR as u64) << OBJ_TYPE_SHIFT) | id,
+            },
+            name_len: (len + 1) as _,
+            name: {
+                let mut buf = [0u8; XATTR_MAX_NAME_LEN];
+                buf[..len].copy_from_slice(&name.as_bytes()[..len]);
+                buf
+            },
+        }
+    }
+
+    /// Gets the name of the extended attribute.
+    pub fn get_name(&self) -> String {
+        CStr::from_bytes_until_nul(&self.name)
+            .unwrap_or_default()
+            .to_str()
+            .unwrap_or_default()
+            .to_string()
+    }
+}
+
+impl Debug for JXattrKey {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("JXattrKey")
+            .field("hdr", &self.hdr)
+            .field("name", &self.get_name())
+            .finish()
+    }
+}
+
+/// The value half of an extended attribute record.
+///
+/// Small values are embedded in the record; larger ones are stored in a data stream described by a
+/// [`JXattrDstream`].
+#[derive(Clone)]
+#[repr(C, packed)]
+pub struct JXattrVal {
+    pub flags: u16,
+    pub xdata_len: u16,
+    pub xdata: [u8; XATTR_MAX_EMBEDDED_SIZE],
+}
+
+impl JXattrVal {
+    /// Creates an embedded value.
+    pub fn new(data: &[u8]) -> KResult<Self> {
+        if data.len() > XATTR_MAX_EMBEDDED_SIZE {
+            return Err(Errno::E2BIG);
+        }
+
+        let mut xdata = [0u8; XATTR_MAX_EMBEDDED_SIZE];
+        xdata[..data.len()].copy_from_slice(data);
+        Ok(Self {
+            flags: XATTR_DATA_EMBEDDED,
+            xdata_len: data.len() as _,
+            xdata,
+        })
+    }
+
+    /// Gets the embedded data or the raw `j_xattr_dstream_t`.
+    pub fn get_data(&self) -> &[u8] {
+        &self.xdata[..(self.xdata_len as usize).min(XATTR_MAX_EMBEDDED_SIZE)]
+    }
+
+    /// Gets the data stream if the value is not embedded.
+    pub fn get_dstream(&self) -> Option<JXattrDstream> {
+        let data = self.get_data();
+        match self.flags & XATTR_DATA_STREAM != 0
+            && data.len() >= core::mem::size_of::<JXattrDstream>()
+        {
+            true => Some(unsafe { &*(data.as_ptr() as *const JXattrDstream) }.clone()),
+            false => None,
+        }
+    }
+}
+
+impl Debug for JXattrVal {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let flags = self.flags;
+        let xdata_len = self.xdata_len;
+        f.debug_struct("JXattrVal")
+            .field("flags", &flags)
+            .field("xdata_len", &xdata_len)
+            .finish()
+    }
+}
+
+/// The data stream of an extended attribute, whose extents are keyed by `xattr_obj_id`.
+#[derive(Clone, Debug)]
+#[repr(C, packed)]
+pub struct JXattrDstream {
+    pub xattr_obj_id: u64,
+    pub dstream: JDstream,
+}
+
+/// The value half of a snapshot metadata record. Its key is a [`JKey`] whose object identifier is the transaction
+/// identifier of the snapshot.
+#[derive(Clone)]
+#[repr(C, packed)]
+pub struct JSnapMetadataVal {
+    pub extentref_tree_oid: Oid,
+    /// The physical address of the copy of the volume superblock.
+    pub sblock_oid: Oid,
+    pub create_time: u64,
+    pub change_time: u64,
+    pub inum: u64,
+    pub extentref_tree_type: u32,
+    pub flags: u32,
+    pub name_len: u16,
+    /// The length is undetermined.
+    pub name: [u8; 255],
+}
+
+/// The key half of a snapshot name record.
+#[derive(Clone)]
+#[repr(C, packed)]
+pub struct JSnapNameKey {
+    pub hdr: JKey,
+    pub name_len: u16,
+    /// The length is undetermined.
+    pub name: [u8; 255],
+}
+
+/// The value half of a snapshot name record.
+#[derive(Clone)]
+#[repr(C, packed)]
+pub struct JSnapNameVal {
+    pub snap_xid: Xid,
+}
+
 /// Key types.
 #[derive(Debug)]
 pub enum KeyType {
@@ -728,6 +1020,7 @@ pub enum KeyType {
     JPhysExtKey,
     JFileExtentKey,
     JDirStatKey,
+    JXattrKey,
 }
 
 /// Value types.
@@ -749,6 +1042,7 @@ pub struct FsMap {
     pub phys_ext_map: PhysExtMap,
     pub file_extent_map: FileExtentMap,
     pub dir_stat_map: DirStatMap,
+    pub xattr_map: XattrMap,
 }
 
 impl core::fmt::Debug for JDrecHashedKey {
@@ -800,7 +1094,7 @@ pub struct JXttrDstream {
 }
 
 /// Information about a data stream.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 #[repr(C, packed)]
 pub struct JDstream {
     pub size: u64,
@@ -910,13 +1204,15 @@ impl PartialOrd for JDrecHashedKey {
 }
 
 impl Ord for JDrecHashedKey {
+    /// Entries in a directory are sorted by their hashes first, as on the disk, so an entry can be located by the
+    /// hash of its name.
     fn cmp(&self, other: &Self) -> Ordering {
         match self.hdr.cmp(&other.hdr) {
-            Ordering::Equal => {
+            Ordering::Equal => self.get_hash().cmp(&other.get_hash()).then_with(|| {
                 let self_name = CStr::from_bytes_until_nul(&self.name).unwrap_or_default();
                 let other_name = CStr::from_bytes_until_nul(&other.name).unwrap_or_default();
                 self_name.cmp(other_name)
-            }
+            }),
             res => res,
         }
     }
@@ -958,7 +1254,41 @@ impl PartialOrd for JFileExtentKey {
 
 impl Ord for JFileExtentKey {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.hdr.cmp(&other.hdr)
+        match self.hdr.cmp(&other.hdr) {
+            Ordering::Equal => {
+                let self_addr = self.logical_addr;
+                let other_addr = other.logical_addr;
+                self_addr.cmp(&other_addr)
+            }
+            res => res,
+        }
+    }
+}
+
+impl PartialEq for JXattrKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for JXattrKey {}
+
+impl PartialOrd for JXattrKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for JXattrKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match self.hdr.cmp(&other.hdr) {
+            Ordering::Equal => {
+                let self_name = CStr::from_bytes_until_nul(&self.name).unwrap_or_default();
+                let other_name = CStr::from_bytes_until_nul(&other.name).unwrap_or_default();
+                self_name.cmp(other_name)
+            }
+            res => res,
+        }
     }
 }
 
@@ -1021,40 +1351,41 @@ impl BTreeKey for JInodeKey {
 }
 
 impl BTreeKey for JDrecHashedKey {
+    fn import(buf: &[u8]) -> Self {
+        // The name is stored without padding, so the key is usually shorter than `Self`.
+        let mut key = Self::new(0, "", false);
+        let len = buf.len().min(core::mem::size_of::<Self>());
+        unsafe {
+            core::slice::from_raw_parts_mut(&mut key as *mut Self as *mut u8, len)
+                .copy_from_slice(&buf[..len]);
+        }
+        key
+    }
+
+    fn export(&self) -> Vec<u8> {
+        // The name is null-terminated and its length includes the terminator.
+        let len = core::mem::size_of::<JKey>()
+            + core::mem::size_of::<u32>()
+            + (self.name_len_and_hash & J_DREC_LEN_MASK) as usize;
+        unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, len) }.to_vec()
+    }
+
     fn check(&self) -> bool {
         if self.hdr.get_type() != APFS_TYPE_DIR_REC {
             return false;
         }
 
-        // We need to check the hash value.
-        // The hash is an unsigned 22-bit integer, accessed as
-        //    (name_len_and_hash & J_DREC_HASH_MASK) >> J_DREC_HASH_SHIFT.
-        let hash = (self.name_len_and_hash & J_DREC_HASH_MASK) >> J_DREC_HASH_SHIFT;
-        // 1. Start with the filename, represented as a null-terminated UTF-8 string.
-        let name_len = self.name_len_and_hash & J_DREC_LEN_MASK;
-        // 2. Normalize it with a canonical decomposition.
-        let mut nfd_name = String::new();
-        let name = match String::from_utf8(self.name[..name_len as usize].to_vec()) {
-            Ok(name) => name,
+        // The hash of the name depends on whether the volume is case-insensitive, so either form is accepted.
+        let name_len = ((self.name_len_and_hash & J_DREC_LEN_MASK) as usize).min(self.name.len());
+        let name = match core::str::from_utf8(&self.name[..name_len]) {
+            Ok(name) => name.trim_end_matches('\0'),
             Err(_) => return false,
         };
+        let hash = self.get_hash();
+        if hash != Self::hash_name(name, false) && hash != Self::hash_name(name, true) {
+            kdebug!("the hash of {name} does not match: {hash:#x}");
+        }
 
-        // 3. Represent it in a UTF-32 string.
-        name.chars().for_each(|c| {
-            decompose_canonical(c, |c| {
-                nfd_name.push(c);
-            })
-        });
-
-        // 4. Compute the CRC-32C hash of the UTF-32 string.
-        let nfd_name = nfd_name
-            .chars()
-            .flat_map(|c| (c as u32).to_be_bytes())
-            .collect::<Vec<_>>();
-        let hash_res = !CASTAGNOLI.checksum(nfd_name.as_slice()) & 0x7fffff;
-
-        // FIXME: The checksum is always incorrect?...
-        kinfo!("hash_res = {hash_res}, hash = {hash}");
         true
     }
 
@@ -1095,6 +1426,37 @@ impl BTreeKey for JFileExtentKey {
     }
 }
 
+impl BTreeKey for JXattrKey {
+    fn import(buf: &[u8]) -> Self {
+        let mut key = Self::new(0, "");
+        let len = buf.len().min(core::mem::size_of::<Self>());
+        unsafe {
+            core::slice::from_raw_parts_mut(&mut key as *mut Self as *mut u8, len)
+                .copy_from_slice(&buf[..len]);
+        }
+        key
+    }
+
+    fn export(&self) -> Vec<u8> {
+        let len = core::mem::size_of::<JKey>()
+            + core::mem::size_of::<u16>()
+            + (self.name_len as usize).min(XATTR_MAX_NAME_LEN);
+        unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, len) }.to_vec()
+    }
+
+    fn check(&self) -> bool {
+        self.hdr.get_type() == APFS_TYPE_XATTR
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn ty(&self) -> KeyType {
+        KeyType::JXattrKey
+    }
+}
+
 impl BTreeKey for JDirStatKey {
     fn check(&self) -> bool {
         self.hdr.get_type() == APFS_TYPE_DIR_STATS
@@ -1120,8 +1482,52 @@ impl BTreeKey for u64 {
 }
 
 impl BTreeValue for ObjectMapValue {}
-impl BTreeValue for JInodeVal {}
-impl BTreeValue for JDrecVal {}
+impl BTreeValue for JInodeVal {
+    fn export(&self) -> Vec<u8> {
+        // The xfields are stored in place, so only the used part goes to the disk.
+        let len = core::mem::size_of::<Self>() - DEFAULT_XF_LEN + self.xfields_len();
+        unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, len) }.to_vec()
+    }
+}
+
+impl BTreeValue for JDrecVal {
+    fn import(buf: &[u8]) -> Self {
+        let mut val = Self::new(0, 0, 0);
+        let len = buf.len().min(core::mem::size_of::<Self>());
+        unsafe {
+            core::slice::from_raw_parts_mut(&mut val as *mut Self as *mut u8, len)
+                .copy_from_slice(&buf[..len]);
+        }
+        val
+    }
+
+    fn export(&self) -> Vec<u8> {
+        let len = core::mem::size_of::<Self>() - DEFAULT_XF_LEN + self.xfields_len();
+        unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, len) }.to_vec()
+    }
+}
+
+impl BTreeValue for JXattrVal {
+    fn import(buf: &[u8]) -> Self {
+        let mut val = Self {
+            flags: 0,
+            xdata_len: 0,
+            xdata: [0u8; XATTR_MAX_EMBEDDED_SIZE],
+        };
+        let len = buf.len().min(core::mem::size_of::<Self>());
+        unsafe {
+            core::slice::from_raw_parts_mut(&mut val as *mut Self as *mut u8, len)
+                .copy_from_slice(&buf[..len]);
+        }
+        val
+    }
+
+    fn export(&self) -> Vec<u8> {
+        let len = 2 * core::mem::size_of::<u16>() + self.get_data().len();
+        unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, len) }.to_vec()
+    }
+}
+
 impl BTreeValue for JPhysExtVal {}
 impl BTreeValue for JFileExtentVal {}
 impl BTreeValue for JDirStatVal {}
@@ -1207,12 +1613,20 @@ impl XFieldsInterepretable for JInodeVal {
     fn get_xfields(&self) -> &[u8] {
         &self.xfields
     }
+
+    fn get_xfields_mut(&mut self) -> &mut [u8] {
+        &mut self.xfields
+    }
 }
 
 impl XFieldsInterepretable for JDrecVal {
     fn get_xfields(&self) -> &[u8] {
         &self.xfields
     }
+
+    fn get_xfields_mut(&mut self) -> &mut [u8] {
+        &mut self.xfields
+    }
 }
 
 impl JInodeVal {
@@ -1240,6 +1654,24 @@ impl JInodeVal {
             .unwrap_or_default()
             .to_string())
     }
+
+    /// Stores the data stream into the xfields.
+    pub fn set_dstream(&mut self, dstream: &JDstream) -> KResult<()> {
+        let dstream = unsafe {
+            core::slice::from_raw_parts(
+                dstream as *const JDstream as *const u8,
+                core::mem::size_of::<JDstream>(),
+            )
+        };
+        self.set_xfield(INO_EXT_TYPE_DSTREAM, XF_SYSTEM_FIELD, dstream)
+    }
+
+    /// Stores the name of the inode into the xfields.
+    pub fn set_name(&mut self, name: &str) -> KResult<()> {
+        let mut buf = name.as_bytes().to_vec();
+        buf.push(0);
+        self.set_xfield(INO_EXT_TYPE_NAME, XF_DO_NOT_COPY, &buf)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -1544,14 +1976,14 @@ impl BTreeNodePhysical {
                 .contains(BTreeNodeFlags::BTNODE_LEAF)
                 && node.btn_level != 0
             {
-                let values = self
+                let values = node
                     .interpret_as_values()?
                     .iter()
                     .map(|elem| Oid::from_le_bytes(elem.as_slice().try_into().unwrap()))
                     .collect::<Vec<_>>();
 
                 // Insert the next-level nodes.
-                values.iter().for_each(|value| {
+                for value in values.iter() {
                     let node_oid = match omap {
                         Some(omap) => omap
                             .get(&ObjectMapKey {
@@ -1562,15 +1994,15 @@ impl BTreeNodePhysical {
                             .unwrap_or(*value),
                         None => *value,
                     };
-                    let node_buf = read_object(device, node_oid).unwrap();
+                    let node_buf = read_object(device, node_oid)?;
                     let node_cur =
                         unsafe { &*(node_buf.as_ptr() as *const BTreeNodePhysical) }.clone();
-                    if ObjectTypes::from_bits_truncate((self.btn_o.o_type & 0xff) as _).intersects(
+                    if ObjectTypes::from_bits_truncate((node.btn_o.o_type & 0xff) as _).intersects(
                         ObjectTypes::OBJECT_TYPE_BTREE_NODE | ObjectTypes::OBJECT_TYPE_BTREE,
                     ) {
                         queue.push_back(node_cur);
                     }
-                });
+                }
             } else {
                 let keys = node.interpret_as_keys()?;
                 let values = node.interpret_as_values()?;
@@ -1757,6 +2189,11 @@ impl BTreeNodePhysical {
                     let val_obj = (*(val.as_ptr() as *const JDirStatVal)).clone();
                     fs_map.dir_stat_map.insert(key_obj, val_obj);
                 }
+                APFS_TYPE_XATTR => {
+                    fs_map
+                        .xattr_map
+                        .insert(JXattrKey::import(key), JXattrVal::import(val));
+                }
                 other => (),
             }
         })?;
@@ -1823,14 +2260,43 @@ pub struct Omap {
     pub btree_info: BTreeInfo,
 }
 
+/// Gets the object map as of transaction `xid`: for each object, only its latest version no newer than `xid` is kept
+/// and deleted objects are dropped.
+pub fn omap_at(omap: &ObjectMap, xid: Xid) -> ObjectMap {
+    let mut latest = BTreeMap::<Oid, (&ObjectMapKey, &ObjectMapValue)>::new();
+    omap.iter()
+        .filter(|(key, _)| key.ok_xid <= xid)
+        .for_each(|(key, val)| match latest.get(&key.ok_oid) {
+            Some((cur, _)) if cur.ok_xid >= key.ok_xid => (),
+            _ => {
+                latest.insert(key.ok_oid, (key, val));
+            }
+        });
+
+    latest
+        .into_values()
+        .filter(|(_, val)| {
+            !ObjectMapValueFlags::from_bits_truncate(val.ov_flags)
+                .contains(ObjectMapValueFlags::OMAP_VAL_DELETED)
+        })
+        .map(|(key, val)| (key.clone(), val.clone()))
+        .collect()
+}
+
 /// A simpler wrapper for the volumn.
 pub struct ApfsVolumn {
     pub name: String,
     pub superblock: ApfsSuperblock,
     pub object_map: ObjectMap,
     pub fs_map: RwLock<MaybeDirty<FsMap>>,
+    pub dentries: RwLock<DentryCache>,
     pub occupied_inode_numbers: RwLock<BTreeSet<u64>>,
     pub apfs: Arc<AppleFileSystem>,
+    /// The transaction identifier of the snapshot if this is a read-only snapshot of a volume.
+    pub snapshot: Option<Xid>,
+    /// The on-disk trees that record the modifications. Snapshots have none.
+    #[cfg(feature = "apfs_write")]
+    pub writer: Option<RwLock<VolumnWriter>>,
 }
 
 impl ApfsVolumn {
@@ -1839,15 +2305,55 @@ impl ApfsVolumn {
         device: &Arc<dyn Device>,
         apfs: Arc<AppleFileSystem>,
         apfs_superblock: ApfsSuperblock,
+        paddr: u64,
     ) -> KResult<Arc<Self>> {
-        let apfs_omap_oid = apfs_superblock.apfs_omap_oid;
+        // Read omap.
+        let apfs_omap = read_omap(device, apfs_superblock.apfs_omap_oid)?;
+        let name = CStr::from_bytes_until_nul(&apfs_superblock.apfs_volname)
+            .unwrap_or_default()
+            .to_str()
+            .unwrap_or_default()
+            .to_string();
+
+        #[cfg(feature = "apfs_write")]
+        let writer = Some(RwLock::new(VolumnWriter::load(
+            device,
+            &apfs_superblock,
+            paddr,
+        )?));
+        #[cfg(not(feature = "apfs_write"))]
+        let _ = paddr;
+
+        let volumn = Self::load(
+            device,
+            apfs,
+            apfs_superblock,
+            apfs_omap.omap,
+            Xid::MAX,
+            name,
+        )?;
+        Ok(Arc::new(Self {
+            #[cfg(feature = "apfs_write")]
+            writer,
+            ..volumn
+        }))
+    }
+
+    /// Reads the file-system tree of a volume as of transaction `xid`. The object map may contain older versions of
+    /// the objects that are kept for snapshots, so only the latest version no newer than `xid` is used.
+    pub fn load(
+        device: &Arc<dyn Device>,
+        apfs: Arc<AppleFileSystem>,
+        apfs_superblock: ApfsSuperblock,
+        object_map: ObjectMap,
+        xid: Xid,
+        name: String,
+    ) -> KResult<Self> {
         // Note that this is the *virtual object identifier*.
         let apfs_root_tree_oid = apfs_superblock.apfs_root_tree_oid;
-        // Read omap.
-        let apfs_omap = read_omap(device, apfs_omap_oid)?;
+        let omap = omap_at(&object_map, xid);
         // Read root node's oid.
-        let apfs_root_addr = apfs_omap
-            .omap
+        let apfs_root_addr = omap
             .get(&ObjectMapKey {
                 ok_oid: apfs_root_tree_oid,
                 ok_xid: Xid::MIN,
@@ -1856,7 +2362,7 @@ impl ApfsVolumn {
             .ov_paddr;
 
         // Read the file system tree.
-        let apfs_tree = read_fs_tree(device, apfs_root_addr, &apfs_omap.omap)?;
+        let apfs_tree = read_fs_tree(device, apfs_root_addr, &omap)?;
 
         kdebug!("apfs tree: {:x?}", apfs_tree);
 
@@ -1867,19 +2373,31 @@ impl ApfsVolumn {
             .map(|k| k.hdr.get_oid())
             .collect::<BTreeSet<_>>();
 
-        let name = CStr::from_bytes_until_nul(&apfs_superblock.apfs_volname)
-            .unwrap_or_default()
-            .to_str()
-            .unwrap_or_default()
-            .to_string();
-        Ok(Arc::new(Self {
+        Ok(Self {
             name,
             superblock: apfs_superblock,
-            object_map: apfs_omap.omap,
+            object_map,
             fs_map: RwLock::new(MaybeDirty::new(apfs_tree)),
+            dentries: RwLock::new(DentryCache::default()),
             occupied_inode_numbers: RwLock::new(occupied_inode_numbers),
             apfs,
-        }))
+            snapshot: None,
+            #[cfg(feature = "apfs_write")]
+            writer: None,
+        })
+    }
+
+    /// Checks if the volume can be modified. Snapshots are read-only.
+    pub fn check_writable(&self) -> KResult<()> {
+        match self.snapshot {
+            Some(_) => Err(Errno::EROFS),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks if file names are case-insensitive on this volume.
+    pub fn case_fold(&self) -> bool {
+        self.superblock.apfs_incompatible_features & APFS_INCOMPAT_CASE_INSENSITIVE != 0
     }
 }
 
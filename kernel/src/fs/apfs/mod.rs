@@ -17,7 +17,12 @@ use alloc::{
     vec,
     vec::Vec,
 };
-use core::{ffi::CStr, fmt::Debug, mem::MaybeUninit};
+use core::{
+    ffi::CStr,
+    fmt::Debug,
+    ops::Bound,
+    sync::atomic::{AtomicBool, Ordering},
+};
 use rcore_fs::{
     dirty::Dirty as MaybeDirty,
     vfs::{FileSystem, FileType, FsError, FsInfo, INode, Metadata, PollStatus},
@@ -26,26 +31,107 @@ use spin::RwLock;
 
 use crate::{
     arch::QWORD_LEN,
-    error::{Errno, KResult},
-    fs::apfs::meta::{ApfsVolumn, BTreeInfo, SpacemanPhysical, Xid, BLOCK_SIZE},
-    function, kdebug, kerror, kinfo, print, println,
+    error::{kerror_to_fserror, Errno, KResult},
+    fs::{
+        apfs::meta::{ApfsVolumn, BTreeInfo, Xid, BLOCK_SIZE},
+        xattr::XattrFlags,
+    },
+    function, kdebug, kerror, kinfo, kwarn, print, println,
     time::{SystemTime, UNIX_EPOCH},
     utils::calc_fletcher64,
 };
 
 use self::meta::{
-    get_timespec, get_timestamp, ApfsSuperblock, BTreeNodeFlags, BTreeNodePhysical,
+    get_timespec, get_timestamp, ApfsSuperblock, BTreeNodeFlags, BTreeNodePhysical, CheckpointMap,
     CheckpointMapPhysical, DrecFlags, FsMap, JDrecHashedKey, JDrecVal, JFileExtentKey,
-    JFileExtentVal, JInodeKey, JInodeVal, JKey, NxSuperBlock, ObjectMap, ObjectMapKey,
-    ObjectMapPhysical, ObjectPhysical, ObjectTypes, Oid, Omap, APFS_TYPE_DIR_REC,
-    APFS_TYPE_FILE_EXTENT, APFS_TYPE_INODE, DEFAULT_XF_LEN, J_DREC_LEN_MASK, OBJ_TYPE_SHIFT,
-    ROOT_DIR_RECORD_ID,
+    JFileExtentVal, JInodeKey, JInodeVal, JKey, JXattrKey, JXattrVal, NxSuperBlock, ObjectMap,
+    ObjectMapKey, ObjectMapPhysical, ObjectPhysical, ObjectTypes, Oid, Omap, APFS_TYPE_DIR_REC,
+    APFS_TYPE_FILE_EXTENT, APFS_TYPE_INODE, DEFAULT_XF_LEN, J_DREC_HASH_SHIFT, J_DREC_LEN_MASK,
+    MAX_ALLOWED_CHECKPOINT_MAP_SIZE, OBJ_TYPE_SHIFT, ROOT_DIR_INO_NUM, ROOT_DIR_RECORD_ID,
+    SYMLINK_XATTR_NAME, UF_COMPRESSED, XATTR_FILE_SYSTEM_OWNED,
+};
+use self::{
+    decmpfs::Decmpfs,
+    snapshot::{SnapshotDirInode, SNAPSHOT_DIR_NAME},
+    spaceman::SpaceManager,
 };
 
+#[cfg(feature = "apfs_write")]
+use self::{
+    btree::ObjectMapTree,
+    meta::{
+        BTreeKey, BTreeValue, JDstream, JXattrDstream, S_IFDIR, S_IFREG, XATTR_DATA_STREAM,
+        XATTR_MAX_EMBEDDED_SIZE,
+    },
+};
+
+pub mod decmpfs;
 pub mod meta;
+pub mod snapshot;
+pub mod spaceman;
 
 #[cfg(feature = "apfs_write")]
 pub mod btree;
+#[cfg(feature = "apfs_write")]
+pub mod transaction;
+
+/// The number of directory entries cached for each volume.
+const DENTRY_CACHE_SIZE: usize = 256;
+
+/// The recently looked up directory entries of a volume, keyed by the identifier of the directory and the name. All
+/// the inode objects of a directory share the entries, and the least recently used entry is evicted first.
+#[derive(Default)]
+pub struct DentryCache {
+    entries: BTreeMap<(u64, String), (u64, JDrecVal)>,
+    /// The keys of the entries ordered by the time they were last used.
+    lru: BTreeMap<u64, (u64, String)>,
+    tick: u64,
+}
+
+impl DentryCache {
+    /// Gets the entry `name` of directory `dir` and marks it as the most recently used one.
+    pub fn get(&mut self, dir: u64, name: &str) -> Option<JDrecVal> {
+        let key = (dir, name.to_string());
+        let (used, value) = self.entries.get_mut(&key)?;
+        let value = value.clone();
+
+        self.tick += 1;
+        self.lru.remove(used);
+        *used = self.tick;
+        self.lru.insert(self.tick, key);
+        Some(value)
+    }
+
+    /// Caches the entry `name` of directory `dir`.
+    pub fn insert(&mut self, dir: u64, name: &str, value: JDrecVal) {
+        self.tick += 1;
+        let key = (dir, name.to_string());
+        if let Some((used, _)) = self.entries.insert(key.clone(), (self.tick, value)) {
+            self.lru.remove(&used);
+        }
+        self.lru.insert(self.tick, key);
+
+        if self.entries.len() > DENTRY_CACHE_SIZE {
+            if let Some((_, key)) = self.lru.pop_first() {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    /// Forgets all the entries of directory `dir`.
+    pub fn invalidate(&mut self, dir: u64) {
+        let stale = self
+            .entries
+            .range((dir, String::new())..)
+            .take_while(|((cur, _), _)| *cur == dir)
+            .map(|(key, (used, _))| (key.clone(), *used))
+            .collect::<Vec<_>>();
+        for (key, used) in stale {
+            self.entries.remove(&key);
+            self.lru.remove(&used);
+        }
+    }
+}
 
 /// Denotes the disk driver backend the filesystem uses.
 pub trait Device: Send + Sync {
@@ -73,19 +159,8 @@ pub trait BlockLike: Device {
             let object = &*(buf.as_ptr() as *const T);
 
             // Do the checksum.
-            let header = &*(buf.as_ptr() as *const ObjectPhysical);
-            let checksum = calc_fletcher64(&buf[QWORD_LEN..])?;
-            match checksum.to_le_bytes() == header.o_cksum {
-                true => Ok(object.clone()),
-                false => {
-                    kerror!(
-                        "checksum mismatch. Expecting: {:x?}, got: {:x}",
-                        header.o_cksum,
-                        checksum
-                    );
-                    Err(Errno::EINVAL)
-                }
-            }
+            verify_object(&buf)?;
+            Ok(object.clone())
         }
     }
 
@@ -100,7 +175,8 @@ pub trait BlockLike: Device {
                 if len == buf.len() {
                     Ok(())
                 } else {
-                    Err(Errno::EINVAL)
+                    // The image is truncated.
+                    Err(Errno::EIO)
                 }
             }
             Err(errno) => {
@@ -160,23 +236,15 @@ pub struct AppleFileSystem {
     nx_omap: RwLock<Option<ObjectMap>>,
     /// The inode map. Volumn name to inode.
     inodes: RwLock<BTreeMap<String, BTreeMap<u64, Weak<AppleFileSystemInode>>>>,
-    /// The spaceman instance. TODO: make it a high-level struct.
-    spaceman: RwLock<SpacemanPhysical>,
-}
-
-/// The space manager allocates and frees blocks where objects and file data can be stored. Thereʼs exactly one
-/// instance of this structure in a container.
-///
-/// This is very useful when we want to write something onto the disk.
-pub struct SpaceManager {
-    // Its members are yet to be determined.
-}
-
-impl SpaceManager {
-    /// Parses a space manager instance from the disk content.
-    pub fn from_raw() -> KResult<Self> {
-        todo!()
-    }
+    /// The spaceman instance.
+    spaceman: RwLock<SpaceManager>,
+    /// The ephemeral objects of the latest checkpoint.
+    checkpoint: RwLock<Vec<CheckpointMap>>,
+    /// The mounted snapshots, which are read-only.
+    snapshots: RwLock<Vec<Arc<ApfsVolumn>>>,
+    /// The object map of the container that can be written back.
+    #[cfg(feature = "apfs_write")]
+    nx_omap_tree: RwLock<Option<ObjectMapTree>>,
 }
 
 impl AppleFileSystem {
@@ -197,7 +265,7 @@ impl AppleFileSystem {
         // Step 1: Read block zero of the partition. This block contains a copy of the container superblock
         // (an instance of `nx_superblock_t`). It might be a copy of the latest version or an old version,
         // depending on whether the drive was unmounted cleanly.
-        let mut nx_superblock = device.load_struct::<NxSuperBlock>(0)?;
+        let nx_superblock = device.load_struct::<NxSuperBlock>(0)?;
 
         // Verify the block.
         if !nx_superblock.verify() {
@@ -213,7 +281,6 @@ impl AppleFileSystem {
             kerror!("currently we do not support non-contiguous checkpoint descriptor area");
             return Err(Errno::EACCES);
         }
-        let nx_xp_data_base = nx_superblock.nx_xp_data_base;
         highest_bit = nx_superblock.nx_xp_data_blocks & (1 << 31);
         if highest_bit != 0 {
             kerror!("currently we do not support non-contiguous checkpoint data area");
@@ -221,73 +288,52 @@ impl AppleFileSystem {
         }
 
         // Step 3: Read the entries in the checkpoint descriptor area, which are instances of `checkpoint_map_phys_t`.
-        // or `nx_superblock_t`.
-        let mut best_superblock_xid = 0;
+        // or `nx_superblock_t`. Every valid container superblock denotes a checkpoint.
+        let mut candidates = Vec::new();
         for idx in 0..nx_superblock.nx_xp_desc_blocks {
             // Should check whether this object is a checkpoint mapping or another superblock.
             // This can be done by reading the header of the target block.
             let addr = nx_xp_desc_base + idx as u64;
             let object = match read_object(&device, addr) {
                 Ok(object) => object,
-                // Not a valid block (maybe continuguous to the previous one).
+                // Not a valid block (maybe continuguous to the previous one, or corrupted).
                 Err(_) => continue,
             };
 
             // Check the type.
             let hdr = unsafe { &*(object.as_ptr() as *const ObjectPhysical) };
             let object_type = ObjectTypes::from_bits_truncate((hdr.o_type & 0xff) as _);
-            match object_type {
-                // Find the container superblock that has the largest transaction identifier and isnʼt malformed.
-                ObjectTypes::OBJECT_TYPE_NX_SUPERBLOCK => {
-                    let cur_superblock = unsafe { &*(object.as_ptr() as *const NxSuperBlock) };
-                    // The checkpoint description area is a ring buffer stored as an array. So performing a modulo is
-                    // necessary at this timepoint.
-                    let map_addr = cur_superblock.nx_xp_desc_base
-                        + ((idx + cur_superblock.nx_xp_desc_blocks - 1)
-                            % cur_superblock.nx_xp_desc_blocks) as u64;
-                    let map_object = unsafe {
-                        let map_object = read_object(&device, map_addr)?;
-                        &*(map_object.as_ptr() as *const CheckpointMapPhysical)
-                    };
-
-                    // Find the latest superblock.
-                    if map_object.cpm_o.o_xid > best_superblock_xid {
-                        best_superblock_xid = map_object.cpm_o.o_xid;
-                        nx_superblock = cur_superblock.clone();
-                    }
+            if object_type == ObjectTypes::OBJECT_TYPE_NX_SUPERBLOCK {
+                let cur_superblock = unsafe { &*(object.as_ptr() as *const NxSuperBlock) };
+                if cur_superblock.verify() {
+                    candidates.push(cur_superblock.clone());
                 }
-
-                _ => continue,
             }
         }
 
-        // Step 4: Read the checkpoint data area.
-        let mut best_spaceman_xid = 0;
-        let mut spaceman = MaybeUninit::<SpacemanPhysical>::uninit();
-        for idx in 0..nx_superblock.nx_xp_data_blocks {
-            let addr = nx_xp_data_base + idx as u64;
-            let object = match read_object(&device, addr) {
-                Ok(object) => object,
-                Err(_) => continue,
-            };
-
-            let hdr = unsafe { &*(object.as_ptr() as *const ObjectPhysical) };
-            let ty = ObjectTypes::from_bits_truncate((hdr.o_type & 0xff) as _);
-            match ty {
-                ObjectTypes::OBJECT_TYPE_SPACEMAN => {
-                    // Always get the latest one.
-                    if best_spaceman_xid < hdr.o_xid {
-                        best_spaceman_xid = hdr.o_xid;
-                        let object = unsafe { &*(object.as_ptr() as *const SpacemanPhysical) };
-                        spaceman.write(object.clone());
+        // Step 4: Find the container superblock that has the largest transaction identifier and whose checkpoint is
+        // intact. If the latest checkpoint is corrupted (e.g., the image is truncated), we fall back to older ones.
+        candidates.sort_by_key(|superblock| core::cmp::Reverse(superblock.nx_o.o_xid));
+        let (nx_superblock, checkpoint, spaceman) = candidates
+            .into_iter()
+            .find_map(
+                |superblock| match Self::load_checkpoint(&device, &superblock) {
+                    Ok((checkpoint, spaceman)) => Some((superblock, checkpoint, spaceman)),
+                    Err(errno) => {
+                        kwarn!(
+                            "checkpoint {:#x} is corrupted ({errno:?}); trying an older one.",
+                            superblock.nx_o.o_xid
+                        );
+                        None
                     }
-                }
-                _ => (),
-            }
-        }
+                },
+            )
+            .ok_or_else(|| {
+                kerror!("no valid checkpoint is found.");
+                Errno::EIO
+            })?;
 
-        let spaceman = unsafe { spaceman.assume_init() };
-        kdebug!("get spaceman {:x?}", spaceman);
+        kdebug!("get spaceman {:x?}", spaceman.phys);
         kdebug!("mounted the superblock: {:x?}", nx_superblock);
         Self {
             self_ptr: Weak::default(),
@@ -298,10 +344,62 @@ impl AppleFileSystem {
             nx_omap: RwLock::new(None),
             inodes: RwLock::new(BTreeMap::new()),
             spaceman: RwLock::new(spaceman),
+            checkpoint: RwLock::new(checkpoint),
+            snapshots: RwLock::new(Vec::new()),
+            #[cfg(feature = "apfs_write")]
+            nx_omap_tree: RwLock::new(None),
         }
         .to_arc()
     }
 
+    /// Reads the ephemeral objects of the checkpoint described by `nx_superblock` and checks that the objects it
+    /// refers to are intact.
+    fn load_checkpoint(
+        device: &Arc<dyn Device>,
+        nx_superblock: &NxSuperBlock,
+    ) -> KResult<(Vec<CheckpointMap>, SpaceManager)> {
+        // Read the checkpoint-mapping blocks of this checkpoint. They precede the superblock in the checkpoint
+        // descriptor area and tell us where the ephemeral objects are in the checkpoint data area.
+        let mut checkpoint = Vec::new();
+        for idx in 0..nx_superblock.nx_xp_desc_len.saturating_sub(1) {
+            let addr = nx_superblock.nx_xp_desc_base
+                + ((nx_superblock.nx_xp_desc_index + idx) % nx_superblock.nx_xp_desc_blocks) as u64;
+            let object = read_object(device, addr)?;
+            let map_object = unsafe { &*(object.as_ptr() as *const CheckpointMapPhysical) };
+            let count = (map_object.cpm_count as usize).min(MAX_ALLOWED_CHECKPOINT_MAP_SIZE);
+            checkpoint.extend_from_slice(&map_object.cpm_map[..count]);
+        }
+
+        // Read the space manager.
+        let spaceman = checkpoint
+            .iter()
+            .find(|map| {
+                ObjectTypes::from_bits_truncate((map.cpm_type & 0xff) as _)
+                    == ObjectTypes::OBJECT_TYPE_SPACEMAN
+            })
+            .ok_or_else(|| {
+                kerror!("the checkpoint has no space manager.");
+                Errno::EIO
+            })?;
+        let mut raw = vec![0u8; spaceman.cpm_size as usize];
+        device.read_buf_at(spaceman.cpm_paddr as usize * BLOCK_SIZE, &mut raw)?;
+        verify_object(&raw)?;
+        let spaceman = SpaceManager::from_raw(raw)?;
+
+        // The object map and the volume superblocks must be readable, too.
+        let omap = read_omap(device, nx_superblock.nx_omap_oid)?;
+        for oid in nx_superblock.nx_fs_oid.iter().filter(|&&oid| oid != 0) {
+            let key = ObjectMapKey {
+                ok_oid: *oid,
+                ok_xid: Xid::MIN,
+            };
+            let entry = omap.omap.get(&key).ok_or(Errno::EIO)?;
+            read_object(device, entry.ov_paddr)?;
+        }
+
+        Ok((checkpoint, spaceman))
+    }
+
     /// Mounts all volumns
     pub fn mount_volumns_all(&self) -> KResult<()> {
         // Read from the nx_fs_oid.
@@ -369,6 +467,7 @@ impl AppleFileSystem {
             &self.device,
             self.self_ptr.upgrade().unwrap(),
             apfs_superblock,
+            entry.ov_paddr,
         )?);
 
         Ok(())
@@ -384,33 +483,40 @@ impl AppleFileSystem {
             .replace((omap.root_node.clone(), omap.btree_info.clone()));
         self.nx_omap.write().replace(omap.omap);
 
+        #[cfg(feature = "apfs_write")]
+        self.nx_omap_tree
+            .write()
+            .replace(ObjectMapTree::load(&self.device, omap_phys_oid)?);
+
         Ok(())
     }
 
+    /// Gets the names of the mounted volumes in the order they appear in the container. The first one is the root.
+    pub fn volumn_names(&self) -> Vec<String> {
+        self.volumn_lists
+            .read()
+            .iter()
+            .map(|volumn| volumn.name.clone())
+            .collect()
+    }
+
+    /// Finds a mounted volume or snapshot by its name.
+    fn find_volumn(&self, volumn_name: &str) -> Option<Arc<ApfsVolumn>> {
+        self.volumn_lists
+            .read()
+            .iter()
+            .chain(self.snapshots.read().iter())
+            .find(|volumn| volumn.name == volumn_name)
+            .cloned()
+    }
+
     /// Get the directory record from a directory record id. The APFS manages all the objects at the same level
     /// with a same id.
     pub fn get_drec(&self, drec_id: u64, dir_name: &str, volumn_name: &str) -> KResult<JDrecVal> {
-        let obj_id_and_type = ((APFS_TYPE_DIR_REC as u64) << OBJ_TYPE_SHIFT) | drec_id;
-        let mut name = [0u8; 255];
-        // Null terminated.
-        name[..dir_name.len()].copy_from_slice(dir_name.as_bytes());
-
-        let drec_key = JDrecHashedKey {
-            hdr: JKey { obj_id_and_type },
-            name_len_and_hash: u32::default(),
-            name,
-        };
-
         // Search the map.
-        match self.volumn_lists.read().iter().find(|&volumn| {
-            let name = CStr::from_bytes_until_nul(&volumn.superblock.apfs_volname)
-                .unwrap_or_default()
-                .to_str()
-                .unwrap_or_default();
-            name == volumn_name
-        }) {
-            Some(volumn) => match volumn.fs_map.read().dir_record_map.get(&drec_key) {
-                Some(e) => Ok(e.clone()),
+        match self.find_volumn(volumn_name) {
+            Some(volumn) => match volumn.lookup_drec(drec_id, dir_name)? {
+                Some((_, e)) => Ok(e),
                 None => Err(Errno::ENOENT),
             },
             None => Err(Errno::ENOENT),
@@ -435,13 +541,7 @@ impl AppleFileSystem {
         }
 
         // Otherwise, we create a new one from the volumn list's map.
-        match self.volumn_lists.read().iter().find(|&volumn| {
-            let name = CStr::from_bytes_until_nul(&volumn.superblock.apfs_volname)
-                .unwrap_or_default()
-                .to_str()
-                .unwrap_or_default();
-            name == volumn_name
-        }) {
+        match self.find_volumn(volumn_name) {
             Some(volumn) => {
                 let key = JInodeKey {
                     hdr: JKey {
@@ -465,17 +565,29 @@ impl AppleFileSystem {
                             obj_id_and_type: ((APFS_TYPE_FILE_EXTENT as u64) << OBJ_TYPE_SHIFT)
                                 | inode_val.private_id,
                         },
-                        logical_addr: inode_val.private_id,
+                        logical_addr: 0,
                     })
-                    .map(|val| RwLock::new(MaybeDirty::new(val.clone())));
+                    .cloned();
+
+                // A broken compressed file can still be listed; only reading it fails.
+                let decmpfs = Decmpfs::load(&volumn, drec.file_id, inode_val.bsd_flags)
+                    .unwrap_or_else(|errno| {
+                        kerror!(
+                            "cannot load the compressed file {}: {errno:?}",
+                            drec.file_id
+                        );
+                        None
+                    });
 
                 let inode = Arc::new(AppleFileSystemInode {
                     id: drec.file_id,
                     volumn: volumn.clone(),
                     apfs: self.self_ptr.upgrade().unwrap(),
                     inode_inner: RwLock::new(MaybeDirty::new(inode_val)),
-                    file_extent,
                     dir_record: RwLock::new(MaybeDirty::new(drec.clone())),
+                    file_extent: RwLock::new(file_extent),
+                    extent_owned: AtomicBool::new(false),
+                    decmpfs,
                 });
                 inode.init_dir();
                 Ok(inode)
@@ -518,7 +630,10 @@ impl AppleFileSystem {
             apfs: self.self_ptr.upgrade().unwrap(),
             inode_inner: RwLock::new(MaybeDirty::new(inode)),
             dir_record: RwLock::new(MaybeDirty::new(dir_record)),
-            file_extent: None, // Currently, it is `None` for newly created files. Or we may directly use memory?
+            // Newly created files have no data, so their extents are allocated on the first write.
+            file_extent: RwLock::new(None),
+            extent_owned: AtomicBool::new(false),
+            decmpfs: None,
         });
 
         self.inodes
@@ -534,12 +649,19 @@ impl AppleFileSystem {
 
 impl FileSystem for AppleFileSystem {
     fn sync(&self) -> rcore_fs::vfs::Result<()> {
+        #[cfg(feature = "apfs_write")]
+        self.commit().map_err(kerror_to_fserror)?;
+
         Ok(())
     }
 
     fn root_inode(&self) -> Arc<dyn INode> {
-        // FIXME: Better choice?
-        self.get_root_inode("untitled")
+        let name = self
+            .volumn_names()
+            .into_iter()
+            .next()
+            .expect("No volume is mounted");
+        self.get_root_inode(&name)
     }
 
     fn info(&self) -> FsInfo {
@@ -547,6 +669,35 @@ impl FileSystem for AppleFileSystem {
     }
 }
 
+/// A volume of the container that is mounted on its own. Its root is the root directory of the volume.
+pub struct ApfsVolumnFs {
+    apfs: Arc<AppleFileSystem>,
+    volumn_name: String,
+}
+
+impl ApfsVolumnFs {
+    pub fn new(apfs: Arc<AppleFileSystem>, volumn_name: &str) -> Arc<Self> {
+        Arc::new(Self {
+            apfs,
+            volumn_name: volumn_name.to_string(),
+        })
+    }
+}
+
+impl FileSystem for ApfsVolumnFs {
+    fn sync(&self) -> rcore_fs::vfs::Result<()> {
+        self.apfs.sync()
+    }
+
+    fn root_inode(&self) -> Arc<dyn INode> {
+        self.apfs.get_root_inode(&self.volumn_name)
+    }
+
+    fn info(&self) -> FsInfo {
+        self.apfs.info()
+    }
+}
+
 impl Drop for AppleFileSystem {
     fn drop(&mut self) {
         self.sync()
@@ -568,8 +719,13 @@ pub struct AppleFileSystemInode {
     inode_inner: RwLock<MaybeDirty<JInodeVal>>,
     /// The directory record of this inode.
     dir_record: RwLock<MaybeDirty<JDrecVal>>,
-    /// The file extent value (if any).
-    file_extent: Option<RwLock<MaybeDirty<JFileExtentVal>>>,
+    /// The file extent value (if any). We keep the data of a file in a single extent.
+    file_extent: RwLock<Option<JFileExtentVal>>,
+    /// Whether `file_extent` has been allocated since the volume was mounted. Only such extents are written in place;
+    /// the others may be shared with a snapshot or a clone, so they are copied first.
+    extent_owned: AtomicBool,
+    /// The compression information if the file is compressed by decmpfs.
+    decmpfs: Option<Decmpfs>,
 }
 
 impl AppleFileSystemInode {
@@ -656,15 +812,15 @@ impl AppleFileSystemInode {
 
         let all_keys_begin = JDrecHashedKey {
             hdr: JKey { obj_id_and_type },
-            // Can be ignored because we invoke `*_until_nul`.
+            // The smallest hash and name.
             name_len_and_hash: 0,
             name: [0u8; 255],
         };
 
         let all_keys_end = JDrecHashedKey {
             hdr: JKey { obj_id_and_type },
-            // Can be ignored because we invoke `*_until_nul`.
-            name_len_and_hash: 0,
+            // Entries are sorted by their hashes first.
+            name_len_and_hash: u32::MAX,
             name: {
                 let mut buf = [u8::MAX; 255];
                 *buf.last_mut().unwrap() = 0;
@@ -687,15 +843,255 @@ impl AppleFileSystemInode {
     /// Appends a directory record to the inode. This function is called *after* the corresponding INode is created
     /// and properly inserted into the filesystem B-Tree.
     pub fn append_dirrecord(&self, key: JDrecHashedKey, val: JDrecVal) -> KResult<()> {
-        // to be implemented.
+        self.volumn
+            .fs_map
+            .write()
+            .dir_record_map
+            .insert(key.clone(), val.clone());
+
+        #[cfg(feature = "apfs_write")]
+        self.volumn.persist(&key, &val)?;
+
+        let mut inode_inner = self.inode_inner.write();
+        inode_inner.nchildren_or_link += 1;
+        self.persist_inode(&inode_inner)
+    }
+
+    /// Removes a directory record from the inode and returns its value.
+    pub fn remove_dirrecord(&self, name: &str) -> KResult<JDrecVal> {
+        let (key, val) = self
+            .volumn
+            .lookup_drec(self.id, name)?
+            .ok_or(Errno::ENOENT)?;
+        self.volumn.fs_map.write().dir_record_map.remove(&key);
+        // The name may be cached in another case.
+        self.volumn.dentries.write().invalidate(self.id);
+
+        #[cfg(feature = "apfs_write")]
+        self.volumn.erase(&key)?;
+        #[cfg(not(feature = "apfs_write"))]
+        let _ = key;
+
+        let mut inode_inner = self.inode_inner.write();
+        inode_inner.nchildren_or_link = inode_inner.nchildren_or_link.saturating_sub(1);
+        self.persist_inode(&inode_inner)?;
+
+        Ok(val)
+    }
+
+    /// Gets the size of the file from its data stream.
+    fn size(&self) -> usize {
+        if let Some(decmpfs) = self.decmpfs.as_ref() {
+            return decmpfs.size;
+        }
+        if let Some(target) = self.symlink_target() {
+            return target.len();
+        }
+
+        self.inode_inner
+            .read()
+            .get_dstream()
+            .map(|dstream| dstream.size as usize)
+            // Empty files or directories won't set the data stream, so their sizes are zero.
+            .unwrap_or_default()
+    }
+
+    /// Gets the value of the extended attribute `name`.
+    pub fn get_xattr(&self, name: &str) -> KResult<Vec<u8>> {
+        self.volumn
+            .read_xattr(&self.volumn.get_xattr(self.id, name)?)
+    }
+
+    /// Lists the names of the extended attributes.
+    pub fn list_xattrs(&self) -> Vec<String> {
+        self.volumn.list_xattrs(self.id)
+    }
+
+    /// Sets the extended attribute `name` to `value`.
+    pub fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> KResult<()> {
+        self.volumn.set_xattr(self.id, name, value, flags)
+    }
+
+    /// Removes the extended attribute `name`.
+    pub fn remove_xattr(&self, name: &str) -> KResult<()> {
+        self.volumn.remove_xattr(self.id, name)
+    }
+
+    /// Gets the target of a symbolic link, which is stored in the `com.apple.fs.symlink` attribute without the trailing
+    /// null. Symbolic links without the attribute keep their targets in the data stream.
+    fn symlink_target(&self) -> Option<Vec<u8>> {
+        if DrecFlags::from_bits_truncate(self.dir_record.read().flags) != DrecFlags::DT_LNK {
+            return None;
+        }
+
+        let val = self.volumn.get_xattr(self.id, SYMLINK_XATTR_NAME).ok()?;
+        let mut target = self.volumn.read_xattr(&val).ok()?;
+        while target.last() == Some(&0) {
+            target.pop();
+        }
+        Some(target)
+    }
+
+    /// Sets the target of a symbolic link.
+    #[cfg(feature = "apfs_write")]
+    fn set_symlink_target(&self, target: &[u8]) -> KResult<()> {
+        let mut data = target.to_vec();
+        data.push(0);
+        let mut val = JXattrVal::new(&data).map_err(|_| Errno::ENAMETOOLONG)?;
+        val.flags |= XATTR_FILE_SYSTEM_OWNED;
+
+        let key = JXattrKey::new(self.id, SYMLINK_XATTR_NAME);
+        self.volumn
+            .fs_map
+            .write()
+            .xattr_map
+            .insert(key.clone(), val.clone());
+        self.volumn.persist(&key, &val)
+    }
+
+    /// Checks if the file is compressed by decmpfs. Compressed files have no data stream.
+    fn is_compressed(&self) -> bool {
+        self.inode_inner.read().bsd_flags & UF_COMPRESSED != 0
+    }
+
+    /// Updates the inode record in the map only. The filesystem B-Tree gets it with the next [`Self::persist_inode`].
+    fn cache_inode(&self, inode_inner: &JInodeVal) {
+        self.volumn
+            .fs_map
+            .write()
+            .inode_map
+            .insert(JInodeKey::new(self.id), inode_inner.clone());
+    }
+
+    /// Updates the inode record in the map and, if the filesystem is writable, in the filesystem B-Tree.
+    fn persist_inode(&self, inode_inner: &JInodeVal) -> KResult<()> {
+        self.cache_inode(inode_inner);
+
+        #[cfg(feature = "apfs_write")]
+        self.volumn.persist(&JInodeKey::new(self.id), inode_inner)?;
+
+        Ok(())
+    }
+
+    /// Makes sure that the extent of the file can hold `size` bytes and may be written in place. The data are moved to
+    /// a new extent if it is too small, if the file is sparse or fragmented, or if the extent was not allocated by us
+    /// and may thus be shared with a snapshot or a clone (copy-on-write).
+    #[cfg(feature = "apfs_write")]
+    fn ensure_capacity(&self, size: usize) -> KResult<()> {
+        let mut file_extent = self.file_extent.write();
+        let private_id = self.inode_inner.read().private_id;
+        let extents = self.volumn.file_extents(private_id);
+        let contiguous = match extents.as_slice() {
+            [] => true,
+            [(key, val)] => key.logical_addr == 0 && val.phys_block_num != 0,
+            _ => false,
+        };
+        let old_blocks = file_extent
+            .as_ref()
+            .map(|extent| extent.block_len())
+            .unwrap_or_default();
+        let needed = (size + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        let owned = self.extent_owned.load(Ordering::Acquire);
+        if contiguous && needed <= old_blocks && (owned || extents.is_empty()) {
+            return Ok(());
+        }
+
+        // Grow geometrically so that appending to a file does not copy it every time.
+        let blocks = match needed > old_blocks {
+            true => needed.max(old_blocks * 2),
+            false => old_blocks,
+        };
+        let paddr = self.volumn.allocate_blocks(blocks as _)?;
+        let old_size = self.size();
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        for idx in 0..blocks {
+            buf.fill(0);
+            self.volumn
+                .read_stream(private_id, old_size, idx * BLOCK_SIZE, &mut buf)?;
+            self.apfs.device.write_block(paddr + idx as u64, 0, &buf)?;
+        }
+        for (key, val) in extents {
+            if val.phys_block_num != 0 {
+                self.volumn
+                    .release_extent(val.phys_block_num, val.block_len() as _, owned)?;
+            }
+            // The extent at zero is replaced below.
+            if key.logical_addr != 0 {
+                self.volumn.fs_map.write().file_extent_map.remove(&key);
+                self.volumn.erase(&key)?;
+            }
+        }
+
+        let key = JFileExtentKey {
+            hdr: JKey {
+                obj_id_and_type: ((APFS_TYPE_FILE_EXTENT as u64) << OBJ_TYPE_SHIFT) | private_id,
+            },
+            logical_addr: 0,
+        };
+        let val = JFileExtentVal {
+            len_and_flags: (blocks * BLOCK_SIZE) as _,
+            phys_block_num: paddr,
+            crypto_id: 0,
+        };
+        self.volumn
+            .fs_map
+            .write()
+            .file_extent_map
+            .insert(key.clone(), val.clone());
+        self.volumn.persist(&key, &val)?;
+        file_extent.replace(val);
+        self.extent_owned.store(true, Ordering::Release);
+
         Ok(())
     }
 
-    /// Removes a directory record from the inode.
-    pub fn remove_dirrecord(&self, name: &str) -> KResult<()> {
-        // to be implemented.
+    /// Zeroes the bytes in `[from, to)`, which may contain stale data when the file grows.
+    #[cfg(feature = "apfs_write")]
+    fn zero_fill(&self, from: usize, to: usize) -> KResult<()> {
+        let file_extent = self.file_extent.read();
+        let file_extent = match file_extent.as_ref() {
+            Some(file_extent) => file_extent,
+            None => return Ok(()),
+        };
+
+        let zeros = vec![0u8; BLOCK_SIZE];
+        let mut pos = from;
+        while pos < to.min(file_extent.len()) {
+            let off = pos % BLOCK_SIZE;
+            let n = (BLOCK_SIZE - off).min(to - pos);
+            self.apfs.device.write_block(
+                file_extent.phys_block_num + (pos / BLOCK_SIZE) as u64,
+                off,
+                &zeros[..n],
+            )?;
+            pos += n;
+        }
+
         Ok(())
     }
+
+    /// Sets the size of the file in its data stream.
+    #[cfg(feature = "apfs_write")]
+    fn set_size(&self, size: usize, written: usize) -> KResult<()> {
+        let alloced_size = self
+            .file_extent
+            .read()
+            .as_ref()
+            .map(|extent| extent.len())
+            .unwrap_or_default();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+
+        let mut inode_inner = self.inode_inner.write();
+        let mut dstream = inode_inner.get_dstream().unwrap_or_default();
+        dstream.size = size as _;
+        dstream.alloced_size = alloced_size as _;
+        dstream.total_bytes_written += written as u64;
+        inode_inner.set_dstream(&dstream)?;
+        inode_inner.mod_time = now.as_nanos() as _;
+        inode_inner.change_time = now.as_nanos() as _;
+
+        self.persist_inode(&inode_inner)
+    }
 }
 
 impl Debug for AppleFileSystemInode {
@@ -710,18 +1106,63 @@ impl Debug for AppleFileSystemInode {
 
 impl INode for AppleFileSystemInode {
     fn set_metadata(&self, metadata: &Metadata) -> rcore_fs::vfs::Result<()> {
+        // Snapshots are read-only, so the access times are not updated either.
+        if self.volumn.snapshot.is_some() {
+            return Ok(());
+        }
+
         // Mainly update the time?
         let mut inode_inner = self.inode_inner.write();
-        inode_inner.mod_time = get_timestamp(metadata.mtime).as_nanos() as _;
+        let mod_time = get_timestamp(metadata.mtime).as_nanos() as u64;
+        let change_time = get_timestamp(metadata.ctime).as_nanos() as u64;
+        let atime_only = inode_inner.mod_time == mod_time && inode_inner.change_time == change_time;
+        inode_inner.mod_time = mod_time;
         inode_inner.access_time = get_timestamp(metadata.atime).as_nanos() as _;
-        inode_inner.change_time = get_timestamp(metadata.ctime).as_nanos() as _;
+        inode_inner.change_time = change_time;
 
-        Ok(())
+        // Every read updates the access time, which must not cost a B-Tree update and a checkpoint each time. It goes
+        // to the disk with the next change of the inode instead.
+        if atime_only {
+            self.cache_inode(&inode_inner);
+            return Ok(());
+        }
+
+        self.persist_inode(&inode_inner).map_err(kerror_to_fserror)
     }
 
     fn sync_all(&self) -> rcore_fs::vfs::Result<()> {
-        // TODO: need to synchronize the metadata as well as the content of the file.
-        Ok(())
+        // The data are written to their blocks directly, so we only need to commit the metadata.
+        self.apfs.sync()
+    }
+
+    fn sync_data(&self) -> rcore_fs::vfs::Result<()> {
+        self.sync_all()
+    }
+
+    fn resize(&self, len: usize) -> rcore_fs::vfs::Result<()> {
+        self.volumn.check_writable().map_err(kerror_to_fserror)?;
+        match DrecFlags::from_bits_truncate(self.dir_record.read().flags) {
+            DrecFlags::DT_REG | DrecFlags::DT_LNK => (),
+            _ => return Err(FsError::NotFile),
+        }
+
+        #[cfg(feature = "apfs_write")]
+        {
+            if self.is_compressed() {
+                return Err(FsError::NotSupported);
+            }
+
+            let size = self.size();
+            self.ensure_capacity(len).map_err(kerror_to_fserror)?;
+            self.zero_fill(size, len).map_err(kerror_to_fserror)?;
+            self.set_size(len, 0).map_err(kerror_to_fserror)
+        }
+
+        #[cfg(not(feature = "apfs_write"))]
+        {
+            let _ = len;
+            Err(FsError::NotSupported)
+        }
     }
 
     fn move_(
@@ -730,6 +1171,7 @@ impl INode for AppleFileSystemInode {
         target: &Arc<dyn INode>,
         new_name: &str,
     ) -> rcore_fs::vfs::Result<()> {
+        self.volumn.check_writable().map_err(kerror_to_fserror)?;
         let info = self.metadata()?;
         if info.type_ != FileType::Dir {
             kerror!("Do not invoke `mv` on a non-directory source inode.");
@@ -759,6 +1201,34 @@ impl INode for AppleFileSystemInode {
         if dst_info.nlinks == 0 {
             return Err(FsError::DirRemoved);
         }
+        if dst.find(new_name).is_ok() {
+            // We cannot unlink the replaced inode yet.
+            return Err(FsError::EntryExist);
+        }
+
+        let inode = self.find(old_name)?;
+        let inode = inode
+            .as_any_ref()
+            .downcast_ref::<Self>()
+            .ok_or(FsError::NotSameFs)?;
+
+        let drec = self.remove_dirrecord(old_name).map_err(kerror_to_fserror)?;
+        dst.append_dirrecord(
+            JDrecHashedKey::new(dst.id, new_name, dst.volumn.case_fold()),
+            drec,
+        )
+        .map_err(kerror_to_fserror)?;
+
+        {
+            let mut inode_inner = inode.inode_inner.write();
+            inode_inner.parent_id = dst.id;
+            inode_inner.set_name(new_name).map_err(kerror_to_fserror)?;
+            inode
+                .persist_inode(&inode_inner)
+                .map_err(kerror_to_fserror)?;
+        }
+        // Update `..`.
+        inode.init_dir();
 
         Ok(())
     }
@@ -772,18 +1242,39 @@ impl INode for AppleFileSystemInode {
             return Err(FsError::NotDir);
         }
 
-        let (key, value) = self
-            .get_all()
-            .map_err(|_| FsError::NotDir)?
-            .into_iter()
-            .find(|(k, v)| {
-                let cur = CStr::from_bytes_until_nul(&k.name)
-                    .unwrap_or_default()
-                    .to_str()
-                    .unwrap_or_default();
-                cur == name
-            })
-            .ok_or(FsError::EntryNotFound)?;
+        let cached = self.volumn.dentries.write().get(self.id, name);
+        let value = match cached {
+            Some(value) => value,
+            None => {
+                let entry = self
+                    .volumn
+                    .lookup_drec(self.id, name)
+                    .map_err(kerror_to_fserror)?;
+                let (_, value) = match entry {
+                    Some(entry) => entry,
+                    // The snapshots of the volume are listed in a directory under the root.
+                    None if name == SNAPSHOT_DIR_NAME
+                        && self.id == ROOT_DIR_INO_NUM
+                        && self.volumn.snapshot.is_none() =>
+                    {
+                        let root = self
+                            .apfs
+                            .get_inode(&dir_record, &self.volumn.name)
+                            .map_err(kerror_to_fserror)?;
+                        return Ok(SnapshotDirInode::new(self.volumn.clone(), root));
+                    }
+                    None => return Err(FsError::EntryNotFound),
+                };
+                // `.` and `..` are not cached because moving a directory changes its parent.
+                if name != "." && name != ".." {
+                    self.volumn
+                        .dentries
+                        .write()
+                        .insert(self.id, name, value.clone());
+                }
+                value
+            }
+        };
 
         // Read the INode from the map.
         let inode = self
@@ -797,43 +1288,32 @@ impl INode for AppleFileSystemInode {
         let dir_record = self.dir_record.read();
         match DrecFlags::from_bits_truncate(dir_record.flags) {
             DrecFlags::DT_LNK | DrecFlags::DT_REG => {
-                // Read the file extent map first to locate the data stream of this file.
-                let file_extent = self
-                    .file_extent
-                    .as_ref()
-                    .ok_or(FsError::InvalidParam)?
-                    .read();
-
                 // Check if `offset` is valid.
-                if offset >= file_extent.len() {
-                    // ringbuf_log!(
-                    //         "`offset` is larger than the file length: got {:#x}, expected < {:#x}. Nothing is done.",
-                    //         offset,
-                    //         file_extent.len()
-                    //     );
-                    return Ok(0);
-                } else if buf.len() == 0 {
+                let size = self.size();
+                if offset >= size || buf.is_empty() {
                     return Ok(0);
                 }
 
-                // Calculate the block number to be read.
-                let buf_len = buf.len().min(file_extent.len());
-                let block_len = file_extent
-                    .block_len()
-                    .min((buf_len as f64 / BLOCK_SIZE as f64).ceil() as usize);
-                let file_start = file_extent.phys_block_num + (offset / BLOCK_SIZE) as u64;
-
-                for i in 0..block_len {
-                    let blk = file_start as usize + i;
-                    let start = i * BLOCK_SIZE;
-                    let end = (start + BLOCK_SIZE).min(buf_len);
-                    self.apfs
-                        .device
-                        .read_block(blk as _, 0, &mut buf[start..end])
-                        .map_err(|_| FsError::InvalidParam)?;
+                if self.is_compressed() {
+                    return match self.decmpfs.as_ref() {
+                        Some(decmpfs) => decmpfs
+                            .read_at(&self.volumn, offset, buf)
+                            .map_err(kerror_to_fserror),
+                        None => Err(FsError::DeviceError),
+                    };
+                }
+
+                if let Some(target) = self.symlink_target() {
+                    let len = buf.len().min(target.len() - offset);
+                    buf[..len].copy_from_slice(&target[offset..offset + len]);
+                    return Ok(len);
                 }
 
-                Ok(buf.len())
+                // Sparse files have holes without physical blocks, which are read as zeros.
+                let private_id = self.inode_inner.read().private_id;
+                self.volumn
+                    .read_stream(private_id, size, offset, buf)
+                    .map_err(kerror_to_fserror)
             }
 
             DrecFlags::DT_CHR => {
@@ -845,6 +1325,8 @@ impl INode for AppleFileSystemInode {
     }
 
     fn write_at(&self, offset: usize, buf: &[u8]) -> rcore_fs::vfs::Result<usize> {
+        self.volumn.check_writable().map_err(kerror_to_fserror)?;
+
         #[cfg(not(feature = "apfs_write"))]
         {
             crate::logging::ringbuf_log_raw(buf);
@@ -866,10 +1348,7 @@ impl INode for AppleFileSystemInode {
             let dir_record = self.dir_record.read();
 
             match DrecFlags::from_bits_truncate(dir_record.flags) {
-                DrecFlags::DT_REG | DrecFlags::DT_LNK => match self.file_extent {
-                    Some(ref file_extent) => Ok(buf.len()),
-                    None => Ok(buf.len()),
-                },
+                DrecFlags::DT_REG | DrecFlags::DT_LNK => Ok(buf.len()),
 
                 ty => {
                     kerror!("writing to {ty:?} is not supported");
@@ -878,7 +1357,62 @@ impl INode for AppleFileSystemInode {
             }
         }
         #[cfg(feature = "apfs_write")]
-        compile_error!("write is currently unsupported.");
+        {
+            match DrecFlags::from_bits_truncate(self.dir_record.read().flags) {
+                DrecFlags::DT_REG | DrecFlags::DT_LNK => (),
+                ty => {
+                    kerror!("writing to {ty:?} is not supported");
+                    return Err(FsError::NotSupported);
+                }
+            }
+            if self.is_compressed() {
+                kerror!("writing to compressed files is not supported");
+                return Err(FsError::NotSupported);
+            }
+
+            if buf.is_empty() {
+                return Ok(0);
+            }
+
+            // Symbolic links keep their targets in an extended attribute like macOS does.
+            if DrecFlags::from_bits_truncate(self.dir_record.read().flags) == DrecFlags::DT_LNK {
+                let mut target = self.symlink_target().unwrap_or_default();
+                let end = offset + buf.len();
+                if target.len() < end {
+                    target.resize(end, 0);
+                }
+                target[offset..end].copy_from_slice(buf);
+                self.set_symlink_target(&target)
+                    .map_err(kerror_to_fserror)?;
+                return Ok(buf.len());
+            }
+
+            let old_size = self.size();
+            let size = old_size.max(offset + buf.len());
+            self.ensure_capacity(size).map_err(kerror_to_fserror)?;
+            self.zero_fill(old_size, offset)
+                .map_err(kerror_to_fserror)?;
+            {
+                let file_extent = self.file_extent.read();
+                let file_extent = file_extent.as_ref().ok_or(FsError::DeviceError)?;
+
+                let mut written = 0;
+                while written < buf.len() {
+                    let pos = offset + written;
+                    let blk = file_extent.phys_block_num + (pos / BLOCK_SIZE) as u64;
+                    let off = pos % BLOCK_SIZE;
+                    let n = (BLOCK_SIZE - off).min(buf.len() - written);
+                    self.apfs
+                        .device
+                        .write_block(blk, off, &buf[written..written + n])
+                        .map_err(kerror_to_fserror)?;
+                    written += n;
+                }
+            }
+            self.set_size(size, buf.len()).map_err(kerror_to_fserror)?;
+
+            Ok(buf.len())
+        }
     }
 
     fn poll(&self) -> rcore_fs::vfs::Result<PollStatus> {
@@ -930,13 +1464,8 @@ impl INode for AppleFileSystemInode {
         let ty = DrecFlags::from_bits_truncate(drec.flags);
         let (size, blocks) = match ty {
             DrecFlags::DT_REG | DrecFlags::DT_LNK => {
-                let size = self
-                    .inode_inner
-                    .read()
-                    .get_dstream()
-                    .map(|dstream| dstream.size as usize)
-                    // Empty files or directories won't set the data stream, so their sizes are zero.
-                    .unwrap_or_default();
+                // Compressed files report their uncompressed sizes.
+                let size = self.size();
                 let block_len = (size as f64 / BLOCK_SIZE as f64).ceil() as usize;
 
                 (size, block_len)
@@ -979,6 +1508,7 @@ impl INode for AppleFileSystemInode {
         mode: u32,
         _data: usize,
     ) -> rcore_fs::vfs::Result<Arc<dyn INode>> {
+        self.volumn.check_writable().map_err(kerror_to_fserror)?;
         let info = self.metadata()?;
         if info.type_ != FileType::Dir {
             kerror!("trying to create something in a non-directory inode.");
@@ -1006,6 +1536,22 @@ impl INode for AppleFileSystemInode {
         }
         .map_err(|_| FsError::NotSupported)?;
 
+        #[cfg(feature = "apfs_write")]
+        {
+            let drec = new_inode
+                .as_any_ref()
+                .downcast_ref::<Self>()
+                .ok_or(FsError::NotSameFs)?
+                .dir_record
+                .read()
+                .clone();
+            self.append_dirrecord(
+                JDrecHashedKey::new(self.id, name, self.volumn.case_fold()),
+                drec,
+            )
+            .map_err(kerror_to_fserror)?;
+        }
+
         Ok(new_inode)
     }
 }
@@ -1054,7 +1600,46 @@ impl ApfsVolumn {
 
         #[cfg(feature = "apfs_write")]
         {
-            // Ask the spaceman for space allocation!
+            // The data blocks are allocated on the first write, and the directory record is persisted by the parent.
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+            let inode_id = self.next_obj_id()?;
+            let is_dir = ty == DrecFlags::DT_DIR;
+            let mode = match is_dir {
+                true => S_IFDIR | 0o755,
+                false => S_IFREG | 0o644,
+            };
+
+            let j_drec_val = JDrecVal::new(inode_id, now.as_nanos() as _, ty.bits());
+            let j_inode_key = JInodeKey::new(inode_id);
+            let mut j_inode_val =
+                JInodeVal::new(parent_id, inode_id, now.as_nanos() as _, 0, 0, 0, mode);
+            // For files, this is the number of hard links.
+            if !is_dir {
+                j_inode_val.nchildren_or_link = 1;
+            }
+            j_inode_val.set_name(name)?;
+
+            self.persist(&j_inode_key, &j_inode_val)?;
+            self.count_object(is_dir, true);
+            self.fs_map
+                .write()
+                .inode_map
+                .insert(j_inode_key, j_inode_val.clone());
+            self.occupied_inode_numbers.write().insert(inode_id);
+
+            kdebug!("after creation: {j_drec_val:x?}, {j_inode_val:x?}");
+
+            let inode = AppleFileSystemInode {
+                id: inode_id,
+                volumn: self_ptr,
+                apfs: self.apfs.clone(),
+                inode_inner: RwLock::new(MaybeDirty::new(j_inode_val)),
+                dir_record: RwLock::new(MaybeDirty::new(j_drec_val)),
+                file_extent: RwLock::new(None),
+                extent_owned: AtomicBool::new(false),
+                decmpfs: None,
+            };
+            Ok(inode)
         }
 
         #[cfg(not(feature = "apfs_write"))]
@@ -1063,7 +1648,7 @@ impl ApfsVolumn {
             let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
             let inode_id = self.get_free_inode_id()?;
 
-            let j_drec_hashed_key = JDrecHashedKey::new(parent_id, name);
+            let j_drec_hashed_key = JDrecHashedKey::new(parent_id, name, self.case_fold());
             let j_drec_val = JDrecVal::new(inode_id, now.as_nanos() as _, ty.bits());
             let j_inode_key = JInodeKey::new(inode_id);
             let j_inode_val =
@@ -1083,12 +1668,340 @@ impl ApfsVolumn {
                 apfs: self.apfs.clone(),
                 inode_inner: RwLock::new(MaybeDirty::new(j_inode_val)),
                 dir_record: RwLock::new(MaybeDirty::new(j_drec_val)),
-                file_extent: None,
+                file_extent: RwLock::new(None),
+                extent_owned: AtomicBool::new(false),
+                decmpfs: None,
             };
             Ok(inode)
         }
     }
 
+    /// Looks up the entry `name` in directory `parent_id`. Only the entries with the same hash are compared.
+    ///
+    /// The entries are found by descending the file-system tree, which also holds the modifications that are not
+    /// committed yet. Snapshots and volumes mounted without `apfs_write` keep their modifications in memory only, so the
+    /// preloaded entries are searched instead.
+    pub fn lookup_drec(
+        &self,
+        parent_id: u64,
+        name: &str,
+    ) -> KResult<Option<(JDrecHashedKey, JDrecVal)>> {
+        let case_fold = self.case_fold();
+        // `.` and `..` are only kept in memory and are not hashed.
+        let in_memory = matches!(name, "." | "..");
+        let hash = match in_memory {
+            true => 0,
+            false => JDrecHashedKey::hash_name(name, case_fold),
+        };
+        let hdr = JKey {
+            obj_id_and_type: ((APFS_TYPE_DIR_REC as u64) << OBJ_TYPE_SHIFT) | parent_id,
+        };
+        let begin = JDrecHashedKey {
+            hdr,
+            name_len_and_hash: hash << J_DREC_HASH_SHIFT,
+            name: [0u8; 255],
+        };
+        let end = JDrecHashedKey {
+            hdr,
+            name_len_and_hash: (hash << J_DREC_HASH_SHIFT) | J_DREC_LEN_MASK,
+            name: {
+                let mut buf = [u8::MAX; 255];
+                *buf.last_mut().unwrap() = 0;
+                buf
+            },
+        };
+
+        let normalized = JDrecHashedKey::normalize_name(name, case_fold);
+        let same_name = |key: &JDrecHashedKey| {
+            key.get_name() == name
+                || JDrecHashedKey::normalize_name(key.get_name(), case_fold) == normalized
+        };
+
+        #[cfg(feature = "apfs_write")]
+        if let (Some(writer), false) = (self.writer.as_ref(), in_memory) {
+            // The raw keys do not pad the names, so the end of the range carries the largest possible name.
+            let len_and_hash = end.name_len_and_hash;
+            let begin = begin.export();
+            let mut end = begin.clone();
+            end[begin.len() - 4..].copy_from_slice(&len_and_hash.to_le_bytes());
+            end.extend_from_slice(&[u8::MAX; 255]);
+
+            let mut writer = writer.write();
+            let writer = &mut *writer;
+            return Ok(writer
+                .fs_tree
+                .range(&begin, &end, Some(&mut writer.omap))?
+                .into_iter()
+                .map(|(key, val)| (JDrecHashedKey::import(&key), JDrecVal::import(&val)))
+                .find(|(key, _)| same_name(key)));
+        }
+
+        Ok(self
+            .fs_map
+            .read()
+            .dir_record_map
+            .range(begin..=end)
+            .find(|(key, _)| same_name(key))
+            .map(|(key, val)| (key.clone(), val.clone())))
+    }
+
+    /// Reads the data stream of object `obj_id`, i.e., the private identifier of a file or the identifier of an extended
+    /// attribute stream, whose size is `size`. Holes without extents are read as zeros.
+    pub fn read_stream(
+        &self,
+        obj_id: u64,
+        size: usize,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> KResult<usize> {
+        if offset >= size {
+            return Ok(0);
+        }
+
+        let len = buf.len().min(size - offset);
+        let fs_map = self.fs_map.read();
+        let mut read = 0;
+        while read < len {
+            let pos = offset + read;
+            let key = JFileExtentKey {
+                hdr: JKey {
+                    obj_id_and_type: ((APFS_TYPE_FILE_EXTENT as u64) << OBJ_TYPE_SHIFT) | obj_id,
+                },
+                logical_addr: pos as _,
+            };
+
+            // Find the extent with the largest logical address that is not larger than `pos`.
+            let extent = fs_map
+                .file_extent_map
+                .range(..=&key)
+                .next_back()
+                .filter(|(k, v)| k.hdr == key.hdr && pos < k.logical_addr as usize + v.len());
+            let n = match extent {
+                Some((k, v)) => {
+                    let start = pos - k.logical_addr as usize;
+                    let n = (v.len() - start).min(len - read);
+                    match v.phys_block_num {
+                        0 => buf[read..read + n].fill(0),
+                        paddr => {
+                            if self.apfs.device.read_buf_at(
+                                paddr as usize * BLOCK_SIZE + start,
+                                &mut buf[read..read + n],
+                            )? != n
+                            {
+                                return Err(Errno::EIO);
+                            }
+                        }
+                    }
+                    n
+                }
+                None => {
+                    // A hole: zero until the next extent.
+                    let next = fs_map
+                        .file_extent_map
+                        .range((Bound::Excluded(&key), Bound::Unbounded))
+                        .next()
+                        .filter(|(k, _)| k.hdr == key.hdr)
+                        .map(|(k, _)| k.logical_addr as usize)
+                        .unwrap_or(usize::MAX);
+                    let n = (next - pos).min(len - read);
+                    buf[read..read + n].fill(0);
+                    n
+                }
+            };
+            read += n;
+        }
+
+        Ok(len)
+    }
+
+    /// Gets the file extents of the data stream of object `obj_id` in the order of their logical addresses.
+    pub fn file_extents(&self, obj_id: u64) -> Vec<(JFileExtentKey, JFileExtentVal)> {
+        let start = JFileExtentKey {
+            hdr: JKey {
+                obj_id_and_type: ((APFS_TYPE_FILE_EXTENT as u64) << OBJ_TYPE_SHIFT) | obj_id,
+            },
+            logical_addr: 0,
+        };
+        self.fs_map
+            .read()
+            .file_extent_map
+            .range(&start..)
+            .take_while(|(key, _)| key.hdr == start.hdr)
+            .map(|(key, val)| (key.clone(), val.clone()))
+            .collect()
+    }
+
+    /// Gets the extended attribute `name` of object `obj_id`.
+    pub fn get_xattr(&self, obj_id: u64, name: &str) -> KResult<JXattrVal> {
+        self.fs_map
+            .read()
+            .xattr_map
+            .get(&JXattrKey::new(obj_id, name))
+            .cloned()
+            .ok_or(Errno::ENODATA)
+    }
+
+    /// Reads the whole value of an extended attribute.
+    pub fn read_xattr(&self, val: &JXattrVal) -> KResult<Vec<u8>> {
+        match val.get_dstream() {
+            Some(stream) => {
+                let mut buf = vec![0u8; stream.dstream.size as usize];
+                self.read_stream(stream.xattr_obj_id, buf.len(), 0, &mut buf)?;
+                Ok(buf)
+            }
+            None => Ok(val.get_data().to_vec()),
+        }
+    }
+
+    /// Lists the names of the extended attributes of object `obj_id`. Attributes owned by the filesystem are hidden.
+    pub fn list_xattrs(&self, obj_id: u64) -> Vec<String> {
+        let start = JXattrKey::new(obj_id, "");
+        self.fs_map
+            .read()
+            .xattr_map
+            .range(&start..)
+            .take_while(|(key, _)| key.hdr == start.hdr)
+            .filter(|(_, val)| val.flags & XATTR_FILE_SYSTEM_OWNED == 0)
+            .map(|(key, _)| key.get_name())
+            .collect()
+    }
+
+    /// Sets the extended attribute `name` of object `obj_id`. Values that do not fit in the record are stored in a
+    /// data stream.
+    pub fn set_xattr(
+        &self,
+        obj_id: u64,
+        name: &str,
+        value: &[u8],
+        flags: XattrFlags,
+    ) -> KResult<()> {
+        self.check_writable()?;
+        let key = JXattrKey::new(obj_id, name);
+        let old = self.fs_map.read().xattr_map.get(&key).cloned();
+        match old.as_ref() {
+            Some(_) if flags.contains(XattrFlags::XATTR_CREATE) => return Err(Errno::EEXIST),
+            Some(old) if old.flags & XATTR_FILE_SYSTEM_OWNED != 0 => return Err(Errno::EPERM),
+            None if flags.contains(XattrFlags::XATTR_REPLACE) => return Err(Errno::ENODATA),
+            _ => (),
+        }
+
+        #[cfg(feature = "apfs_write")]
+        {
+            let val = match value.len() > XATTR_MAX_EMBEDDED_SIZE {
+                true => self.write_xattr_stream(value)?,
+                false => JXattrVal::new(value)?,
+            };
+            if let Some(old) = old.as_ref() {
+                self.free_xattr_stream(old)?;
+            }
+
+            self.fs_map
+                .write()
+                .xattr_map
+                .insert(key.clone(), val.clone());
+            self.persist(&key, &val)
+        }
+
+        #[cfg(not(feature = "apfs_write"))]
+        {
+            let _ = value;
+            Err(Errno::EROFS)
+        }
+    }
+
+    /// Removes the extended attribute `name` of object `obj_id` together with its data stream.
+    pub fn remove_xattr(&self, obj_id: u64, name: &str) -> KResult<()> {
+        self.check_writable()?;
+        let key = JXattrKey::new(obj_id, name);
+        let old = self.get_xattr(obj_id, name)?;
+        if old.flags & XATTR_FILE_SYSTEM_OWNED != 0 {
+            return Err(Errno::EPERM);
+        }
+
+        #[cfg(feature = "apfs_write")]
+        {
+            self.free_xattr_stream(&old)?;
+            self.fs_map.write().xattr_map.remove(&key);
+            self.erase(&key)
+        }
+
+        #[cfg(not(feature = "apfs_write"))]
+        {
+            let _ = key;
+            Err(Errno::EROFS)
+        }
+    }
+
+    /// Writes `value` to a new data stream and returns the extended attribute value that refers to it.
+    #[cfg(feature = "apfs_write")]
+    fn write_xattr_stream(&self, value: &[u8]) -> KResult<JXattrVal> {
+        let xattr_obj_id = self.next_obj_id()?;
+        let blocks = (value.len() + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        let paddr = self.allocate_blocks(blocks as _)?;
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        for (idx, chunk) in value.chunks(BLOCK_SIZE).enumerate() {
+            buf.fill(0);
+            buf[..chunk.len()].copy_from_slice(chunk);
+            self.apfs.device.write_block(paddr + idx as u64, 0, &buf)?;
+        }
+
+        let key = JFileExtentKey {
+            hdr: JKey {
+                obj_id_and_type: ((APFS_TYPE_FILE_EXTENT as u64) << OBJ_TYPE_SHIFT) | xattr_obj_id,
+            },
+            logical_addr: 0,
+        };
+        let val = JFileExtentVal {
+            len_and_flags: (blocks * BLOCK_SIZE) as _,
+            phys_block_num: paddr,
+            crypto_id: 0,
+        };
+        self.fs_map
+            .write()
+            .file_extent_map
+            .insert(key.clone(), val.clone());
+        self.persist(&key, &val)?;
+
+        let stream = JXattrDstream {
+            xattr_obj_id,
+            dstream: JDstream {
+                size: value.len() as _,
+                alloced_size: (blocks * BLOCK_SIZE) as _,
+                default_crypto_id: 0,
+                total_bytes_written: value.len() as _,
+                total_bytes_read: 0,
+            },
+        };
+        let raw = unsafe {
+            core::slice::from_raw_parts(
+                &stream as *const JXattrDstream as *const u8,
+                core::mem::size_of::<JXattrDstream>(),
+            )
+        };
+        let mut val = JXattrVal::new(raw)?;
+        val.flags = XATTR_DATA_STREAM;
+        Ok(val)
+    }
+
+    /// Frees the data stream of an extended attribute value, if any.
+    #[cfg(feature = "apfs_write")]
+    fn free_xattr_stream(&self, val: &JXattrVal) -> KResult<()> {
+        let stream = match val.get_dstream() {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+
+        for (key, val) in self.file_extents(stream.xattr_obj_id) {
+            if val.phys_block_num != 0 {
+                self.free_blocks(val.phys_block_num, val.block_len() as _);
+            }
+            self.fs_map.write().file_extent_map.remove(&key);
+            self.erase(&key)?;
+        }
+
+        Ok(())
+    }
+
     /// Gets a free Inode id.
     pub fn get_free_inode_id(&self) -> KResult<u64> {
         // Should be a monotonic counter?
@@ -1156,23 +2069,46 @@ pub fn read_fs_tree(device: &Arc<dyn Device>, oid: Oid, omap: &ObjectMap) -> KRe
     fs_tree.parse_as_fs_tree(device, omap)
 }
 
-/// Reads a file object from the disk at a given address.
-pub fn read_object(device: &Arc<dyn Device>, addr: u64) -> KResult<Vec<u8>> {
-    let mut buf = vec![0u8; BLOCK_SIZE];
-    device.read_block(addr, 0, &mut buf)?;
+/// Checks the Fletcher-64 checksum in the header of an object, which covers the rest of the object. Corrupted
+/// objects are reported as `EIO`.
+pub fn verify_object(buf: &[u8]) -> KResult<()> {
+    if buf.len() < core::mem::size_of::<ObjectPhysical>() {
+        return Err(Errno::EIO);
+    }
 
     let hdr = unsafe { &*(buf.as_ptr() as *const ObjectPhysical) };
-    let cs = calc_fletcher64(&buf[QWORD_LEN..])?.to_le_bytes();
-
-    if cs != hdr.o_cksum {
-        Err(Errno::EINVAL)
-    } else {
-        Ok(buf)
+    let checksum = calc_fletcher64(&buf[QWORD_LEN..])?;
+    match checksum.to_le_bytes() == hdr.o_cksum {
+        true => Ok(()),
+        false => {
+            kerror!(
+                "checksum mismatch. Expecting: {:x?}, got: {:x}",
+                hdr.o_cksum,
+                checksum
+            );
+            Err(Errno::EIO)
+        }
     }
 }
 
-pub fn read_object_unchecked(device: &Arc<dyn Device>, addr: u64) -> KResult<Vec<u8>> {
+/// Reads a file object from the disk at a given address.
+pub fn read_object(device: &Arc<dyn Device>, addr: u64) -> KResult<Vec<u8>> {
     let mut buf = vec![0u8; BLOCK_SIZE];
     device.read_block(addr, 0, &mut buf)?;
+    verify_object(&buf)?;
+
     Ok(buf)
 }
+
+/// Computes the checksum of an object and writes it onto the disk at a given address. The object may span several
+/// contiguous blocks.
+#[cfg(feature = "apfs_write")]
+pub fn write_object(device: &Arc<dyn Device>, addr: u64, buf: &mut [u8]) -> KResult<()> {
+    let cs = calc_fletcher64(&buf[QWORD_LEN..])?;
+    buf[..QWORD_LEN].copy_from_slice(&cs.to_le_bytes());
+
+    match device.write_buf_at(addr as usize * BLOCK_SIZE, buf)? == buf.len() {
+        true => Ok(()),
+        false => Err(Errno::EIO),
+    }
+}
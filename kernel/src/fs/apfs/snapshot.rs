@@ -0,0 +1,297 @@
+//! Implements the read-only access to the snapshots of the APFS volumes.
+//!
+//! The snapshot metadata tree of a volume maps the transaction identifier of each snapshot to its metadata, which
+//! includes a copy of the volume superblock (`APFS_TYPE_SNAP_METADATA`), and the name of each snapshot to its transaction
+//! identifier (`APFS_TYPE_SNAP_NAME`). The objects of a snapshot are kept in the object map of the volume under their
+//! old transaction identifiers, so a snapshot is mounted by resolving the object map at its transaction identifier.
+//!
+//! The snapshots of a volume are exposed under `/.snapshots/<name>` and are mounted on their first access.
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::ffi::CStr;
+use rcore_fs::vfs::{FileSystem, FileType, FsError, INode, Metadata, PollStatus, Result, Timespec};
+
+use crate::{
+    error::{kerror_to_fserror, Errno, KResult},
+    function, kerror, kinfo, kwarn,
+};
+
+use super::{
+    meta::{
+        ApfsSuperblock, ApfsVolumn, BTreeNodePhysical, JKey, JSnapMetadataVal, JSnapNameKey,
+        JSnapNameVal, ObjectMapPhysical, ObjectMapSnapshot, ObjectMapSnapshotFlags, ObjectTypes,
+        Oid, Xid, APFS_TYPE_SNAP_METADATA, APFS_TYPE_SNAP_NAME, ROOT_DIR_INO_NUM,
+        ROOT_DIR_RECORD_ID, SNAP_DIR_INO_NUM,
+    },
+    read_object, AppleFileSystem,
+};
+
+/// The name of the directory under the root of a volume that contains its snapshots.
+pub const SNAPSHOT_DIR_NAME: &str = ".snapshots";
+
+/// A snapshot of a volume.
+#[derive(Clone, Debug)]
+pub struct SnapshotInfo {
+    /// The transaction identifier of the snapshot.
+    pub xid: Xid,
+    pub name: String,
+    /// The physical address of the copy of the volume superblock.
+    pub sblock_oid: Oid,
+    pub create_time: u64,
+}
+
+/// Copies a record of variable length. The missing bytes are zeros.
+fn import<T: Clone>(buf: &[u8]) -> T {
+    let mut val = unsafe { core::mem::zeroed::<T>() };
+    let len = buf.len().min(core::mem::size_of::<T>());
+    unsafe {
+        core::slice::from_raw_parts_mut(&mut val as *mut T as *mut u8, len)
+            .copy_from_slice(&buf[..len]);
+    }
+    val
+}
+
+/// Reads the root node of a physical B-Tree.
+fn read_root(apfs: &AppleFileSystem, oid: Oid) -> KResult<BTreeNodePhysical> {
+    let buf = read_object(&apfs.device, oid)?;
+    Ok(unsafe { &*(buf.as_ptr() as *const BTreeNodePhysical) }.clone())
+}
+
+impl ApfsVolumn {
+    /// Enumerates the snapshots of the volume in the order they were taken.
+    pub fn snapshots(&self) -> KResult<Vec<SnapshotInfo>> {
+        let oid = self.superblock.apfs_snap_meta_tree_oid;
+        if oid == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut metadata = BTreeMap::new();
+        let mut names = BTreeMap::new();
+        read_root(&self.apfs, oid)?.level_traverse(&self.apfs.device, None, |key, val| {
+            let hdr = import::<JKey>(key);
+            match hdr.get_type() {
+                APFS_TYPE_SNAP_METADATA => {
+                    metadata.insert(hdr.get_oid(), import::<JSnapMetadataVal>(val));
+                }
+                APFS_TYPE_SNAP_NAME => {
+                    let key = import::<JSnapNameKey>(key);
+                    let xid = import::<JSnapNameVal>(val).snap_xid;
+                    let name = CStr::from_bytes_until_nul(&key.name)
+                        .unwrap_or_default()
+                        .to_str()
+                        .unwrap_or_default()
+                        .to_string();
+                    names.insert(xid, name);
+                }
+                _ => (),
+            }
+        })?;
+
+        let deleted = self.deleted_snapshots();
+        Ok(names
+            .into_iter()
+            .filter(|(xid, _)| !deleted.contains(xid))
+            .filter_map(|(xid, name)| {
+                let metadata = metadata.get(&xid)?;
+                Some(SnapshotInfo {
+                    xid,
+                    name,
+                    sblock_oid: metadata.sblock_oid,
+                    create_time: metadata.create_time,
+                })
+            })
+            .collect())
+    }
+
+    /// Gets the snapshots that are marked as deleted in the snapshot tree of the object map. Their objects may have
+    /// been reclaimed already.
+    fn deleted_snapshots(&self) -> BTreeSet<Xid> {
+        let mut deleted = BTreeSet::new();
+        let res = read_object(&self.apfs.device, self.superblock.apfs_omap_oid).and_then(|buf| {
+            let omap = unsafe { &*(buf.as_ptr() as *const ObjectMapPhysical) }.clone();
+            if omap.om_snapshot_tree_oid == 0 {
+                return Ok(());
+            }
+
+            read_root(&self.apfs, omap.om_snapshot_tree_oid)?.level_traverse(
+                &self.apfs.device,
+                None,
+                |key, val| {
+                    let xid = import::<Xid>(key);
+                    let snapshot = import::<ObjectMapSnapshot>(val);
+                    if ObjectMapSnapshotFlags::from_bits_truncate(snapshot.oms_flags)
+                        .contains(ObjectMapSnapshotFlags::OMAP_SNAPSHOT_DELETED)
+                    {
+                        deleted.insert(xid);
+                    }
+                },
+            )
+        });
+
+        if let Err(errno) = res {
+            kwarn!("cannot read the snapshot tree of the object map: {errno:?}");
+        }
+        deleted
+    }
+
+    /// Loads the file-system tree of a snapshot of this volume. The snapshot is read-only.
+    pub fn load_snapshot(&self, snapshot: &SnapshotInfo) -> KResult<Arc<Self>> {
+        let buf = read_object(&self.apfs.device, snapshot.sblock_oid)?;
+        let superblock = unsafe { &*(buf.as_ptr() as *const ApfsSuperblock) }.clone();
+        if !ObjectTypes::from_bits_truncate((superblock.apfs_o.o_type & 0xff) as _)
+            .contains(ObjectTypes::OBJECT_TYPE_FS)
+        {
+            kerror!("the superblock of snapshot {} is corrupted.", snapshot.name);
+            return Err(Errno::EIO);
+        }
+
+        // The object map of the snapshot superblock may have been freed, so we use the current one.
+        let volumn = Self::load(
+            &self.apfs.device,
+            self.apfs.clone(),
+            superblock,
+            self.object_map.clone(),
+            snapshot.xid,
+            format!("{}@{}", self.name, snapshot.name),
+        )?;
+
+        Ok(Arc::new(Self {
+            snapshot: Some(snapshot.xid),
+            ..volumn
+        }))
+    }
+}
+
+impl AppleFileSystem {
+    /// Mounts the snapshot `name` of `volumn` read-only. A snapshot is only mounted once.
+    pub fn mount_snapshot(&self, volumn: &ApfsVolumn, name: &str) -> KResult<Arc<ApfsVolumn>> {
+        let full_name = format!("{}@{name}", volumn.name);
+        let mut snapshots = self.snapshots.write();
+        if let Some(snapshot) = snapshots.iter().find(|snapshot| snapshot.name == full_name) {
+            return Ok(snapshot.clone());
+        }
+
+        let info = volumn
+            .snapshots()?
+            .into_iter()
+            .find(|snapshot| snapshot.name == name)
+            .ok_or(Errno::ENOENT)?;
+        let snapshot = volumn.load_snapshot(&info)?;
+        kinfo!(
+            "mounted snapshot {full_name} at transaction {:#x}.",
+            info.xid
+        );
+
+        snapshots.push(snapshot.clone());
+        Ok(snapshot)
+    }
+}
+
+/// The `.snapshots` directory under the root of a volume. Its entries are the root directories of the snapshots.
+pub struct SnapshotDirInode {
+    /// The volume whose snapshots are listed.
+    volumn: Arc<ApfsVolumn>,
+    /// The root directory of the volume.
+    parent: Arc<dyn INode>,
+}
+
+impl SnapshotDirInode {
+    pub fn new(volumn: Arc<ApfsVolumn>, parent: Arc<dyn INode>) -> Arc<Self> {
+        Arc::new(Self { volumn, parent })
+    }
+
+    /// Gets the names of the entries including `.` and `..`.
+    fn entries(&self) -> Result<Vec<String>> {
+        let snapshots = self.volumn.snapshots().map_err(kerror_to_fserror)?;
+        Ok([".".to_string(), "..".to_string()]
+            .into_iter()
+            .chain(snapshots.into_iter().map(|snapshot| snapshot.name))
+            .collect())
+    }
+}
+
+impl INode for SnapshotDirInode {
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Err(FsError::IsDir)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::IsDir)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Err(FsError::IsDir)
+    }
+
+    fn as_any_ref(&self) -> &dyn core::any::Any {
+        self
+    }
+
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        self.volumn.apfs.clone()
+    }
+
+    fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        match name {
+            "." => Ok(Self::new(self.volumn.clone(), self.parent.clone())),
+            ".." => Ok(self.parent.clone()),
+            name => {
+                let apfs = &self.volumn.apfs;
+                let snapshot = apfs
+                    .mount_snapshot(&self.volumn, name)
+                    .map_err(kerror_to_fserror)?;
+                let drec = apfs
+                    .get_drec(ROOT_DIR_RECORD_ID, "root", &snapshot.name)
+                    .map_err(kerror_to_fserror)?;
+                Ok(apfs
+                    .get_inode(&drec, &snapshot.name)
+                    .map_err(kerror_to_fserror)?)
+            }
+        }
+    }
+
+    fn get_entry(&self, id: usize) -> Result<String> {
+        self.entries()?
+            .into_iter()
+            .nth(id)
+            .ok_or(FsError::EntryNotFound)
+    }
+
+    fn list(&self) -> Result<Vec<(usize, String)>> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .map(|name| match name.as_str() {
+                "." => (SNAP_DIR_INO_NUM as usize, name),
+                // `..` and the snapshots are all root directories.
+                _ => (ROOT_DIR_INO_NUM as usize, name),
+            })
+            .collect())
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        let now = Timespec { sec: 0, nsec: 0 };
+        Ok(Metadata {
+            dev: 0,
+            inode: SNAP_DIR_INO_NUM as _,
+            size: self.entries()?.len(),
+            blk_size: 0,
+            blocks: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            type_: FileType::Dir,
+            mode: 0o555,
+            nlinks: 2,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+        })
+    }
+}
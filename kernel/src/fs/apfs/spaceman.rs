@@ -0,0 +1,297 @@
+//! Implements the space manager.
+//!
+//! The space manager allocates and frees blocks where objects and file data can be stored. Thereʼs exactly one
+//! instance of this structure in a container. The blocks of the main device are grouped into chunks, and each chunk
+//! has a bitmap in which a set bit means the block is in use. The chunks are described by chunk-info blocks (CIBs),
+//! whose addresses follow the `spaceman_phys_t` structure in the same ephemeral object.
+
+use alloc::vec::Vec;
+
+use crate::error::KResult;
+
+use super::meta::SpacemanPhysical;
+
+#[cfg(feature = "apfs_write")]
+use alloc::{sync::Arc, vec};
+
+#[cfg(feature = "apfs_write")]
+use crate::{error::Errno, function, kerror};
+
+#[cfg(feature = "apfs_write")]
+use super::{
+    meta::{ChunkInfo, ChunkInfoBlock, CibAddrBlock, Xid, BLOCK_SIZE},
+    read_object, write_object, BlockLike, Device,
+};
+
+/// The main device; we do not support Fusion drives.
+#[cfg(feature = "apfs_write")]
+const SD_MAIN: usize = 0;
+/// The mask of the block count in `ci_block_count`.
+#[cfg(feature = "apfs_write")]
+const CI_COUNT_MASK: u32 = 0x000fffff;
+
+/// A chunk-info block loaded into the memory.
+#[cfg(feature = "apfs_write")]
+struct LoadedCib {
+    addr: u64,
+    block: Vec<u8>,
+    dirty: bool,
+}
+
+/// A chunk whose information lives in `cibs[cib].cib_chunk_info[index]`.
+#[cfg(feature = "apfs_write")]
+struct Chunk {
+    cib: usize,
+    index: usize,
+    /// The bitmap, loaded on demand.
+    bitmap: Option<Vec<u8>>,
+    dirty: bool,
+}
+
+/// The space manager of the container.
+pub struct SpaceManager {
+    /// The on-disk header.
+    pub phys: SpacemanPhysical,
+    /// The raw ephemeral object, which also contains the addresses of the chunk-info blocks.
+    raw: Vec<u8>,
+    #[cfg(feature = "apfs_write")]
+    cibs: Vec<LoadedCib>,
+    #[cfg(feature = "apfs_write")]
+    chunks: Vec<Chunk>,
+    /// Blocks freed in the current transaction as `(paddr, count)`. They are still used by the last checkpoint and
+    /// must not be reused before the next one is written.
+    #[cfg(feature = "apfs_write")]
+    pending: Vec<(u64, u64)>,
+    #[cfg(feature = "apfs_write")]
+    dirty: bool,
+}
+
+impl SpaceManager {
+    /// Parses a space manager instance from the disk content.
+    pub fn from_raw(raw: Vec<u8>) -> KResult<Self> {
+        let phys = unsafe { &*(raw.as_ptr() as *const SpacemanPhysical) }.clone();
+
+        Ok(Self {
+            phys,
+            raw,
+            #[cfg(feature = "apfs_write")]
+            cibs: Vec::new(),
+            #[cfg(feature = "apfs_write")]
+            chunks: Vec::new(),
+            #[cfg(feature = "apfs_write")]
+            pending: Vec::new(),
+            #[cfg(feature = "apfs_write")]
+            dirty: false,
+        })
+    }
+
+    /// The size of the ephemeral object in bytes.
+    pub fn size(&self) -> usize {
+        self.raw.len()
+    }
+}
+
+#[cfg(feature = "apfs_write")]
+impl SpaceManager {
+    /// Checks if there are changes that are not on the disk.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty || !self.pending.is_empty()
+    }
+
+    fn info(&self, chunk: usize) -> &ChunkInfo {
+        let chunk = &self.chunks[chunk];
+        let cib = unsafe { &*(self.cibs[chunk.cib].block.as_ptr() as *const ChunkInfoBlock) };
+        &cib.cib_chunk_info[chunk.index]
+    }
+
+    fn info_mut(&mut self, chunk: usize) -> &mut ChunkInfo {
+        let chunk = &self.chunks[chunk];
+        let cib = &mut self.cibs[chunk.cib];
+        cib.dirty = true;
+        let cib = unsafe { &mut *(cib.block.as_mut_ptr() as *mut ChunkInfoBlock) };
+        &mut cib.cib_chunk_info[chunk.index]
+    }
+
+    /// Reads all the chunk-info blocks of the main device.
+    fn load_chunks(&mut self, device: &Arc<dyn Device>) -> KResult<()> {
+        if !self.chunks.is_empty() {
+            return Ok(());
+        }
+
+        let dev = &self.phys.sm_dev[SD_MAIN];
+        let offset = dev.sm_addr_offset as usize;
+        // If there are address blocks (CABs), the array contains their addresses instead.
+        let count = match dev.sm_cab_count {
+            0 => dev.sm_cib_count,
+            cab_count => cab_count,
+        } as usize;
+        let addrs = (0..count)
+            .map(|idx| {
+                let start = offset + idx * core::mem::size_of::<u64>();
+                self.raw
+                    .get(start..start + core::mem::size_of::<u64>())
+                    .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+                    .ok_or(Errno::EINVAL)
+            })
+            .collect::<KResult<Vec<_>>>()?;
+
+        let cib_addrs = match dev.sm_cab_count {
+            0 => addrs,
+            _ => {
+                let mut cib_addrs = Vec::new();
+                for addr in addrs {
+                    let buf = read_object(device, addr)?;
+                    let cab = unsafe { &*(buf.as_ptr() as *const CibAddrBlock) };
+                    cib_addrs.extend_from_slice(&cab.cab_cib_addr[..cab.cab_cib_count as usize]);
+                }
+                cib_addrs
+            }
+        };
+
+        for addr in cib_addrs {
+            let block = read_object(device, addr)?;
+            let cib = unsafe { &*(block.as_ptr() as *const ChunkInfoBlock) };
+            for index in 0..cib.cib_chunk_info_count as usize {
+                self.chunks.push(Chunk {
+                    cib: self.cibs.len(),
+                    index,
+                    bitmap: None,
+                    dirty: false,
+                });
+            }
+            self.cibs.push(LoadedCib {
+                addr,
+                block,
+                dirty: false,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn load_bitmap(&mut self, device: &Arc<dyn Device>, chunk: usize) -> KResult<()> {
+        if self.chunks[chunk].bitmap.is_some() {
+            return Ok(());
+        }
+
+        let mut bitmap = vec![0u8; BLOCK_SIZE];
+        device.read_block(self.info(chunk).ci_bitmap_addr, 0, &mut bitmap)?;
+        self.chunks[chunk].bitmap.replace(bitmap);
+        Ok(())
+    }
+
+    /// Allocates `count` contiguous blocks and returns the address of the first one.
+    pub fn allocate(&mut self, device: &Arc<dyn Device>, count: u64) -> KResult<u64> {
+        self.load_chunks(device)?;
+
+        for chunk in 0..self.chunks.len() {
+            let info = self.info(chunk);
+            // A chunk without a bitmap is entirely free, but its bitmap must be created in the internal pool,
+            // which we do not manage.
+            if info.ci_bitmap_addr == 0 || (info.ci_free_count as u64) < count {
+                continue;
+            }
+            let block_count = (info.ci_block_count & CI_COUNT_MASK) as usize;
+            let chunk_addr = info.ci_addr;
+
+            self.load_bitmap(device, chunk)?;
+            let bitmap = self.chunks[chunk].bitmap.as_mut().unwrap();
+
+            let mut run = 0;
+            for bit in 0..block_count {
+                if bitmap[bit / 8] & (1 << (bit % 8)) != 0 {
+                    run = 0;
+                    continue;
+                }
+
+                run += 1;
+                if run == count as usize {
+                    let start = bit + 1 - run;
+                    (start..=bit).for_each(|bit| bitmap[bit / 8] |= 1 << (bit % 8));
+                    self.chunks[chunk].dirty = true;
+                    self.info_mut(chunk).ci_free_count -= count as u32;
+                    self.phys.sm_dev[SD_MAIN].sm_free_count -= count;
+                    self.dirty = true;
+
+                    return Ok(chunk_addr + start as u64);
+                }
+            }
+        }
+
+        kerror!("no space left for {count} blocks.");
+        Err(Errno::ENOSPC)
+    }
+
+    /// Frees the blocks once the current transaction commits.
+    pub fn free_later(&mut self, paddr: u64, count: u64) {
+        if paddr != 0 && count != 0 {
+            self.pending.push((paddr, count));
+        }
+    }
+
+    /// Returns the blocks freed in the committed transaction to the free space.
+    pub fn release_pending(&mut self, device: &Arc<dyn Device>) -> KResult<()> {
+        self.load_chunks(device)?;
+
+        for (paddr, count) in core::mem::take(&mut self.pending) {
+            for block in paddr..paddr + count {
+                let chunk = (0..self.chunks.len()).find(|&chunk| {
+                    let info = self.info(chunk);
+                    let block_count = (info.ci_block_count & CI_COUNT_MASK) as u64;
+                    info.ci_bitmap_addr != 0
+                        && (info.ci_addr..info.ci_addr + block_count).contains(&block)
+                });
+                let chunk = match chunk {
+                    Some(chunk) => chunk,
+                    None => {
+                        kerror!("block {block:#x} does not belong to any chunk.");
+                        continue;
+                    }
+                };
+
+                let bit = (block - self.info(chunk).ci_addr) as usize;
+                self.load_bitmap(device, chunk)?;
+                let bitmap = self.chunks[chunk].bitmap.as_mut().unwrap();
+                if bitmap[bit / 8] & (1 << (bit % 8)) != 0 {
+                    bitmap[bit / 8] &= !(1 << (bit % 8));
+                    self.chunks[chunk].dirty = true;
+                    self.info_mut(chunk).ci_free_count += 1;
+                    self.phys.sm_dev[SD_MAIN].sm_free_count += 1;
+                    self.dirty = true;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the modified bitmaps and chunk-info blocks, and returns the new content of the ephemeral object.
+    pub fn flush(&mut self, device: &Arc<dyn Device>, xid: Xid) -> KResult<Vec<u8>> {
+        for chunk in 0..self.chunks.len() {
+            if !self.chunks[chunk].dirty {
+                continue;
+            }
+
+            let addr = self.info(chunk).ci_bitmap_addr;
+            self.info_mut(chunk).ci_xid = xid;
+            device.write_block(addr, 0, self.chunks[chunk].bitmap.as_ref().unwrap())?;
+            self.chunks[chunk].dirty = false;
+        }
+
+        for cib in self.cibs.iter_mut().filter(|cib| cib.dirty) {
+            unsafe { &mut *(cib.block.as_mut_ptr() as *mut ChunkInfoBlock) }
+                .cib_o
+                .o_xid = xid;
+            write_object(device, cib.addr, &mut cib.block)?;
+            cib.dirty = false;
+        }
+
+        self.phys.sm_o.o_xid = xid;
+        unsafe {
+            *(self.raw.as_mut_ptr() as *mut SpacemanPhysical) = self.phys.clone();
+        }
+        self.dirty = false;
+
+        Ok(self.raw.clone())
+    }
+}
@@ -0,0 +1,367 @@
+//! Implements the transactions of the APFS.
+//!
+//! Every modification to a volume is applied to the in-memory maps first and then mirrored into the on-disk B-Tree of
+//! the volume via [`ApfsVolumn::persist`] and [`ApfsVolumn::erase`]. Nothing is visible on the disk until the
+//! filesystem is synchronized: [`AppleFileSystem::commit`] writes the modified nodes, the object maps and the volume
+//! superblocks to new blocks, and then writes a new checkpoint that makes all of them visible at once.
+//!
+//! The lock order is: container superblock -> container object map -> volume writers -> space manager.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use spin::RwLockWriteGuard;
+
+use crate::{
+    error::{Errno, KResult},
+    function, kdebug, kerror,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    btree::{BTree, CommitContext, ObjectMapTree, TreeKind},
+    meta::{
+        ApfsSuperblock, ApfsVolumn, BTreeKey, BTreeValue, CheckpointFlags, CheckpointMapPhysical,
+        JKey, JPhysExtKey, NxSuperBlock, ObjectPhysical, ObjectTypeFlags, ObjectTypes, Oid, Xid,
+        APFS_TYPE_EXTENT, BLOCK_SIZE, MAX_ALLOWED_CHECKPOINT_MAP_SIZE, OBJ_TYPE_SHIFT,
+    },
+    verify_object, write_object, AppleFileSystem, Device,
+};
+
+/// The writable state of a volume.
+pub struct VolumnWriter {
+    /// The physical address of the volume superblock.
+    pub paddr: u64,
+    /// The latest copy of the volume superblock.
+    pub superblock: ApfsSuperblock,
+    /// The object map of the volume.
+    pub omap: ObjectMapTree,
+    /// The file-system tree of the volume.
+    pub fs_tree: BTree,
+    /// Whether the superblock has been modified.
+    pub dirty: bool,
+}
+
+impl VolumnWriter {
+    /// Loads the object map and the file-system tree of the volume whose superblock is stored at `paddr`.
+    pub fn load(
+        device: &Arc<dyn Device>,
+        superblock: &ApfsSuperblock,
+        paddr: u64,
+    ) -> KResult<Self> {
+        let mut omap = ObjectMapTree::load(device, superblock.apfs_omap_oid)?;
+        let root = omap
+            .lookup(superblock.apfs_root_tree_oid, Xid::MAX)?
+            .ok_or_else(|| {
+                kerror!("the root of the file-system tree is not mapped.");
+                Errno::ENOENT
+            })?;
+        let fs_tree = BTree::load(device, TreeKind::FsTree, root.ov_paddr)?;
+
+        Ok(Self {
+            paddr,
+            superblock: superblock.clone(),
+            omap,
+            fs_tree,
+            dirty: false,
+        })
+    }
+
+    /// Checks if there are changes that are not on the disk.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty || self.fs_tree.is_dirty() || self.omap.tree.is_dirty()
+    }
+
+    /// Writes the file-system tree, the object map and the superblock of the volume, and records the new location of
+    /// the superblock in the container object map.
+    pub fn commit(&mut self, ctx: &mut CommitContext, nx_omap: &mut ObjectMapTree) -> KResult<()> {
+        if !self.is_dirty() {
+            return Ok(());
+        }
+
+        // The root node keeps its virtual identifier, so `apfs_root_tree_oid` never changes.
+        self.fs_tree.commit(ctx, Some(&mut self.omap))?;
+        self.superblock.apfs_omap_oid = self.omap.commit(ctx)?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        self.superblock.apfs_last_mod_time = now.as_nanos() as _;
+        self.superblock.apfs_o.o_xid = ctx.xid;
+
+        let paddr = ctx.spaceman.allocate(ctx.device, 1)?;
+        ctx.spaceman.free_later(self.paddr, 1);
+        self.paddr = paddr;
+
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        unsafe {
+            *(buf.as_mut_ptr() as *mut ApfsSuperblock) = self.superblock.clone();
+        }
+        write_object(ctx.device, paddr, &mut buf)?;
+        nx_omap.map(self.superblock.apfs_o.o_oid, ctx.xid, paddr)?;
+
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl ApfsVolumn {
+    /// Gets the writable state of the volume. Snapshots are read-only and have none.
+    fn writer(&self) -> KResult<RwLockWriteGuard<'_, VolumnWriter>> {
+        match self.writer.as_ref() {
+            Some(writer) => Ok(writer.write()),
+            None => {
+                kerror!("snapshots are read-only.");
+                Err(Errno::EROFS)
+            }
+        }
+    }
+
+    /// Inserts or replaces a record in the file-system tree.
+    pub fn persist<K: BTreeKey, V: BTreeValue>(&self, key: &K, value: &V) -> KResult<()> {
+        let mut writer = self.writer()?;
+        let writer = &mut *writer;
+        writer
+            .fs_tree
+            .insert(&key.export(), &value.export(), Some(&mut writer.omap))
+    }
+
+    /// Removes a record from the file-system tree.
+    pub fn erase<K: BTreeKey>(&self, key: &K) -> KResult<()> {
+        let mut writer = self.writer()?;
+        let writer = &mut *writer;
+        if !writer
+            .fs_tree
+            .remove(&key.export(), Some(&mut writer.omap))?
+        {
+            kdebug!("erasing a record that is not on the disk.");
+        }
+
+        Ok(())
+    }
+
+    /// Allocates a new file-system object identifier.
+    pub fn next_obj_id(&self) -> KResult<u64> {
+        let mut writer = self.writer()?;
+        let id = writer.superblock.apfs_next_obj_id;
+        writer.superblock.apfs_next_obj_id += 1;
+        writer.dirty = true;
+        Ok(id)
+    }
+
+    /// Updates the file and directory counters of the superblock.
+    pub fn count_object(&self, is_dir: bool, created: bool) {
+        let mut writer = match self.writer() {
+            Ok(writer) => writer,
+            Err(_) => return,
+        };
+        let counter = match is_dir {
+            true => &mut writer.superblock.apfs_num_directories,
+            false => &mut writer.superblock.apfs_num_files,
+        };
+        *counter = match created {
+            true => *counter + 1,
+            false => counter.saturating_sub(1),
+        };
+        writer.dirty = true;
+    }
+
+    /// Allocates `count` contiguous blocks for the file data.
+    pub fn allocate_blocks(&self, count: u64) -> KResult<u64> {
+        let mut writer = self.writer()?;
+        let paddr = self
+            .apfs
+            .spaceman
+            .write()
+            .allocate(&self.apfs.device, count)?;
+        writer.superblock.apfs_fs_alloc_count += count;
+        writer.dirty = true;
+        Ok(paddr)
+    }
+
+    /// Frees `count` blocks of file data once the current transaction commits.
+    pub fn free_blocks(&self, paddr: u64, count: u64) {
+        let mut writer = match self.writer() {
+            Ok(writer) => writer,
+            Err(_) => return,
+        };
+        self.apfs.spaceman.write().free_later(paddr, count);
+        writer.superblock.apfs_fs_alloc_count =
+            writer.superblock.apfs_fs_alloc_count.saturating_sub(count);
+        writer.dirty = true;
+    }
+
+    /// Drops the reference of a file to the `count` blocks of file data at `paddr`. They are only freed if nobody else
+    /// refers to them: extents cloned into several files have a reference count in their physical extent record, and
+    /// the blocks that existed when a snapshot was taken belong to the snapshot as well. `owned` tells that the extent
+    /// has been allocated since the volume was mounted, i.e., after the latest snapshot.
+    pub fn release_extent(&self, paddr: u64, count: u64, owned: bool) -> KResult<()> {
+        let key = JPhysExtKey {
+            hdr: JKey {
+                obj_id_and_type: ((APFS_TYPE_EXTENT as u64) << OBJ_TYPE_SHIFT) | paddr,
+            },
+        };
+        let record = self.fs_map.read().phys_ext_map.get(&key).cloned();
+        if let Some(mut val) = record.clone().filter(|val| val.refcnt > 1) {
+            val.refcnt -= 1;
+            self.fs_map
+                .write()
+                .phys_ext_map
+                .insert(key.clone(), val.clone());
+            return self.persist(&key, &val);
+        }
+
+        if !owned && self.writer()?.superblock.apfs_num_snapshots != 0 {
+            kdebug!("keeping the extent @ {paddr:#x} for the snapshots.");
+            return Ok(());
+        }
+
+        if record.is_some() {
+            self.fs_map.write().phys_ext_map.remove(&key);
+            self.erase(&key)?;
+        }
+        self.free_blocks(paddr, count);
+        Ok(())
+    }
+}
+
+impl AppleFileSystem {
+    /// Writes all the modifications to the disk and makes them visible by writing a new checkpoint.
+    pub fn commit(&self) -> KResult<()> {
+        let mut superblock = self.superblock.write();
+        let mut nx_omap = self.nx_omap_tree.write();
+        let nx_omap = nx_omap.as_mut().ok_or(Errno::ENODEV)?;
+        let volumns = self.volumn_lists.read();
+        let mut writers = volumns
+            .iter()
+            .filter_map(|volumn| volumn.writer.as_ref())
+            .map(|writer| writer.write())
+            .collect::<Vec<_>>();
+        let mut spaceman = self.spaceman.write();
+
+        if !writers.iter().any(|writer| writer.is_dirty())
+            && !nx_omap.tree.is_dirty()
+            && !spaceman.is_dirty()
+        {
+            return Ok(());
+        }
+
+        let xid = superblock.nx_next_xid;
+        let mut next_oid = superblock.nx_next_oid;
+        let mut ctx = CommitContext {
+            device: &self.device,
+            xid,
+            next_oid: &mut next_oid,
+            spaceman: &mut spaceman,
+        };
+
+        for writer in writers.iter_mut() {
+            writer.commit(&mut ctx, nx_omap)?;
+        }
+        let nx_omap_oid = nx_omap.commit(&mut ctx)?;
+
+        let superblock = &mut **superblock;
+        superblock.nx_omap_oid = nx_omap_oid;
+        superblock.nx_next_oid = next_oid;
+        self.write_checkpoint(superblock, &mut spaceman, xid)?;
+
+        // The blocks used by the previous checkpoint are free now.
+        spaceman.release_pending(&self.device)?;
+        kdebug!("committed transaction {xid:#x}.");
+
+        Ok(())
+    }
+
+    /// Writes the ephemeral objects, the checkpoint-mapping blocks and the container superblock of transaction `xid`
+    /// after those of the latest checkpoint, and then updates block zero.
+    fn write_checkpoint(
+        &self,
+        superblock: &mut NxSuperBlock,
+        spaceman: &mut super::spaceman::SpaceManager,
+        xid: Xid,
+    ) -> KResult<()> {
+        let desc_blocks = superblock.nx_xp_desc_blocks;
+        let data_blocks = superblock.nx_xp_data_blocks;
+
+        // Step 1: Write the ephemeral objects into the checkpoint data area.
+        let mut checkpoint = self.checkpoint.write();
+        let data_index = (superblock.nx_xp_data_index + superblock.nx_xp_data_len) % data_blocks;
+        let mut data_len = 0;
+        for map in checkpoint.iter_mut() {
+            let mut object = match ObjectTypes::from_bits_truncate((map.cpm_type & 0xff) as _) {
+                ObjectTypes::OBJECT_TYPE_SPACEMAN => spaceman.flush(&self.device, xid)?,
+                _ => {
+                    let mut object = vec![0u8; map.cpm_size as usize];
+                    self.device
+                        .read_buf_at(map.cpm_paddr as usize * BLOCK_SIZE, &mut object)?;
+                    verify_object(&object)?;
+                    unsafe { &mut *(object.as_mut_ptr() as *mut ObjectPhysical) }.o_xid = xid;
+                    object
+                }
+            };
+
+            // An object cannot wrap around the end of the area.
+            let len = ((object.len() + BLOCK_SIZE - 1) / BLOCK_SIZE) as u32;
+            let mut index = (data_index + data_len) % data_blocks;
+            if index + len > data_blocks {
+                data_len += data_blocks - index;
+                index = 0;
+            }
+            if data_len + len > data_blocks {
+                kerror!("the checkpoint data area is too small.");
+                return Err(Errno::ENOSPC);
+            }
+
+            object.resize(len as usize * BLOCK_SIZE, 0);
+            map.cpm_paddr = superblock.nx_xp_data_base + index as u64;
+            write_object(&self.device, map.cpm_paddr, &mut object)?;
+            data_len += len;
+        }
+
+        // Step 2: Write the checkpoint-mapping blocks and then the superblock into the descriptor area.
+        let chunks = checkpoint
+            .chunks(MAX_ALLOWED_CHECKPOINT_MAP_SIZE)
+            .collect::<Vec<_>>();
+        let desc_index = (superblock.nx_xp_desc_index + superblock.nx_xp_desc_len) % desc_blocks;
+        let desc_len = chunks.len() as u32 + 1;
+        if desc_len > desc_blocks {
+            kerror!("the checkpoint descriptor area is too small.");
+            return Err(Errno::ENOSPC);
+        }
+
+        for (idx, maps) in chunks.iter().enumerate() {
+            let paddr =
+                superblock.nx_xp_desc_base + ((desc_index + idx as u32) % desc_blocks) as u64;
+            let mut buf = vec![0u8; BLOCK_SIZE];
+            let block = unsafe { &mut *(buf.as_mut_ptr() as *mut CheckpointMapPhysical) };
+            block.cpm_o.o_oid = paddr as Oid;
+            block.cpm_o.o_xid = xid;
+            block.cpm_o.o_type = ObjectTypes::OBJECT_TYPE_CHECKPOINT_MAP.bits() as u32
+                | ObjectTypeFlags::OBJ_PHYSICAL.bits();
+            block.cpm_flags = match idx + 1 == chunks.len() {
+                true => CheckpointFlags::CHECKPOINT_MAP_LAST.bits(),
+                false => 0,
+            };
+            block.cpm_count = maps.len() as u32;
+            block.cpm_map[..maps.len()].clone_from_slice(maps);
+            write_object(&self.device, paddr, &mut buf)?;
+        }
+
+        superblock.nx_o.o_xid = xid;
+        superblock.nx_next_xid = xid + 1;
+        superblock.nx_xp_desc_index = desc_index;
+        superblock.nx_xp_desc_len = desc_len;
+        superblock.nx_xp_desc_next = (desc_index + desc_len) % desc_blocks;
+        superblock.nx_xp_data_index = data_index;
+        superblock.nx_xp_data_len = data_len;
+        superblock.nx_xp_data_next = (data_index + data_len) % data_blocks;
+
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        unsafe {
+            *(buf.as_mut_ptr() as *mut NxSuperBlock) = superblock.clone();
+        }
+        let paddr = superblock.nx_xp_desc_base + ((desc_index + desc_len - 1) % desc_blocks) as u64;
+        write_object(&self.device, paddr, &mut buf)?;
+
+        // Step 3: Update block zero only after the checkpoint is on the disk.
+        self.device.sync()?;
+        write_object(&self.device, 0, &mut buf)?;
+        self.device.sync()
+    }
+}
@@ -21,6 +21,7 @@ use crate::{
     },
     signal::{send_signal, SigInfo, Signal},
     sync::mutex::SpinLockNoInterrupt as Mutex,
+    utils::ptr::Ptr,
 };
 
 lazy_static! {
@@ -257,42 +258,40 @@ impl INode for TtyInode {
     fn io_control(&self, cmd: u32, data: usize) -> rcore_fs::vfs::Result<usize> {
         match cmd {
             TIOCGWINSZ => {
-                let ptr = data as *mut Winsize;
-                unsafe {
-                    ptr.write(WINSIZE.read().clone());
-                }
+                let ptr = Ptr::<Winsize>::new(data as _);
+                unsafe { ptr.write(WINSIZE.read().clone()) }.map_err(|_| FsError::InvalidParam)?;
 
                 Ok(0)
             }
 
             TCGETS => {
-                let ptr = data as *mut Termios;
-                unsafe {
-                    ptr.write(self.termios.read().clone());
-                }
+                let ptr = Ptr::<Termios>::new(data as _);
+                unsafe { ptr.write(self.termios.read().clone()) }
+                    .map_err(|_| FsError::InvalidParam)?;
 
                 Ok(0)
             }
 
             TCSETS => {
-                let termios = unsafe { (data as *const Termios).read() };
+                let termios = unsafe { Ptr::<Termios>::new(data as _).read() }
+                    .map_err(|_| FsError::InvalidParam)?;
                 *self.termios.write() = termios;
 
                 Ok(0)
             }
 
             TIOCSPGRP => {
-                let pid = unsafe { (data as *const i32).read() };
+                let pid = unsafe { Ptr::<i32>::new(data as _).read() }
+                    .map_err(|_| FsError::InvalidParam)?;
                 *self.fg_pid.write() = pid as _;
 
                 Ok(0)
             }
 
             TIOCGPGRP => {
-                let ptr = data as *mut i32;
-                unsafe {
-                    ptr.write(*self.fg_pid.read() as i32);
-                }
+                let ptr = Ptr::<i32>::new(data as _);
+                unsafe { ptr.write(*self.fg_pid.read() as i32) }
+                    .map_err(|_| FsError::InvalidParam)?;
 
                 Ok(0)
             }
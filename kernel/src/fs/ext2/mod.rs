@@ -0,0 +1,1418 @@
+//! Implements the second extended filesystem (ext2), so that the disk images made by `mke2fs` on the host can be
+//! shared with the kernel.
+//!
+//! The disk is divided into block groups, each of which has a block bitmap, an inode bitmap and a slice of the inode
+//! table. Files map their blocks through 12 direct blocks and an indirect, a double indirect and a triple indirect
+//! block; a block entry of 0 is a hole. Metadata is cached in memory with `Dirty` until `sync()`, while the bitmaps,
+//! the indirect blocks and the directory blocks are written immediately, as in SFS.
+//!
+//! Some useful links:
+//! * <https://www.nongnu.org/ext2-doc/ext2.html>
+
+use core::{
+    any::Any,
+    fmt::{Debug, Error, Formatter},
+};
+
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use rcore_fs::{
+    dev::Device,
+    dirty::Dirty,
+    util::{uninit_memory, BlockIter},
+    vfs::{self, FsError, INode, MMapArea, Metadata, Timespec},
+};
+use spin::RwLock;
+
+pub mod structs;
+pub use structs::*;
+
+use crate::{
+    function, kinfo, ktrace, kwarn,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+trait DeviceExt: Device {
+    fn read_exact_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<()> {
+        match self.read_at(offset, buf) {
+            Ok(len) if len == buf.len() => Ok(()),
+            _ => Err(FsError::DeviceError),
+        }
+    }
+
+    fn write_all_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<()> {
+        match self.write_at(offset, buf) {
+            Ok(len) if len == buf.len() => Ok(()),
+            _ => Err(FsError::DeviceError),
+        }
+    }
+
+    /// Load struct `T` from given offset in device
+    fn load_struct<T: AsBuf>(&self, offset: usize) -> vfs::Result<T> {
+        let mut s: T = unsafe { uninit_memory() };
+        self.read_exact_at(offset, s.as_buf_mut())?;
+        Ok(s)
+    }
+}
+
+impl DeviceExt for dyn Device {}
+
+/// Gets the current time in seconds, as stored in the inodes.
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() as u32)
+        .unwrap_or_default()
+}
+
+/// Finds the first clear bit among the first `len` bits of `bitmap`.
+fn find_clear_bit(bitmap: &[u8], len: usize) -> Option<usize> {
+    bitmap
+        .iter()
+        .take((len + 7) / 8)
+        .enumerate()
+        .find(|(_, &byte)| byte != 0xff)
+        .map(|(i, &byte)| i * 8 + (!byte).trailing_zeros() as usize)
+        .filter(|&bit| bit < len)
+}
+
+fn test_bit(bitmap: &[u8], bit: usize) -> bool {
+    bitmap[bit / 8] & (1 << (bit % 8)) != 0
+}
+
+fn set_bit(bitmap: &mut [u8], bit: usize, value: bool) {
+    match value {
+        true => bitmap[bit / 8] |= 1 << (bit % 8),
+        false => bitmap[bit / 8] &= !(1 << (bit % 8)),
+    }
+}
+
+fn read_u32(buf: &[u8], index: usize) -> u32 {
+    let offset = index * ENTRY_SIZE;
+    u32::from_le_bytes(buf[offset..offset + ENTRY_SIZE].try_into().unwrap())
+}
+
+fn write_u32(buf: &mut [u8], index: usize, value: u32) {
+    let offset = index * ENTRY_SIZE;
+    buf[offset..offset + ENTRY_SIZE].copy_from_slice(&value.to_le_bytes());
+}
+
+fn to_timespec(sec: u32) -> Timespec {
+    Timespec {
+        sec: sec as _,
+        nsec: 0,
+    }
+}
+
+fn to_vfs_type(mode: u16) -> vfs::FileType {
+    match mode & S_IFMT {
+        S_IFDIR => vfs::FileType::Dir,
+        S_IFLNK => vfs::FileType::SymLink,
+        S_IFCHR => vfs::FileType::CharDevice,
+        S_IFBLK => vfs::FileType::BlockDevice,
+        S_IFIFO => vfs::FileType::NamedPipe,
+        S_IFSOCK => vfs::FileType::Socket,
+        _ => vfs::FileType::File,
+    }
+}
+
+/// Gets the mode and the directory entry type of a new file of type `type_`.
+fn from_vfs_type(type_: vfs::FileType) -> (u16, u8) {
+    match type_ {
+        vfs::FileType::File => (S_IFREG, EXT2_FT_REG_FILE),
+        vfs::FileType::Dir => (S_IFDIR, EXT2_FT_DIR),
+        vfs::FileType::SymLink => (S_IFLNK, EXT2_FT_SYMLINK),
+        vfs::FileType::CharDevice => (S_IFCHR, EXT2_FT_CHRDEV),
+        vfs::FileType::BlockDevice => (S_IFBLK, EXT2_FT_BLKDEV),
+        vfs::FileType::NamedPipe => (S_IFIFO, EXT2_FT_FIFO),
+        vfs::FileType::Socket => (S_IFSOCK, EXT2_FT_SOCK),
+    }
+}
+
+/// Gets the directory entry type of a file with `mode`.
+fn dir_entry_type(mode: u16) -> u8 {
+    match mode & S_IFMT {
+        S_IFREG => EXT2_FT_REG_FILE,
+        S_IFDIR => EXT2_FT_DIR,
+        S_IFLNK => EXT2_FT_SYMLINK,
+        S_IFCHR => EXT2_FT_CHRDEV,
+        S_IFBLK => EXT2_FT_BLKDEV,
+        S_IFIFO => EXT2_FT_FIFO,
+        S_IFSOCK => EXT2_FT_SOCK,
+        _ => EXT2_FT_UNKNOWN,
+    }
+}
+
+/// Splits a directory block into the offsets and the headers of its entries.
+fn parse_dir_block(buf: &[u8]) -> vfs::Result<Vec<(usize, DiskDirEntry)>> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while pos + DIRENT_HEADER_SIZE <= buf.len() {
+        let entry = DiskDirEntry::parse(&buf[pos..]);
+        let rec_len = entry.rec_len as usize;
+        if rec_len < DIRENT_HEADER_SIZE
+            || pos + rec_len > buf.len()
+            || DIRENT_HEADER_SIZE + entry.name_len as usize > rec_len
+        {
+            kwarn!("corrupted directory entry at offset {:#x}", pos);
+            return Err(FsError::DeviceError);
+        }
+        entries.push((pos, entry));
+        pos += rec_len;
+    }
+    Ok(entries)
+}
+
+/// Gets the name of the entry at `pos` of a directory block.
+fn entry_name(buf: &[u8], pos: usize, entry: &DiskDirEntry) -> &[u8] {
+    &buf[pos + DIRENT_HEADER_SIZE..pos + DIRENT_HEADER_SIZE + entry.name_len as usize]
+}
+
+/// An entry in use in a directory
+struct DirEntry {
+    /// inode number
+    inode: INodeId,
+    /// file name
+    name: String,
+}
+
+pub struct Ext2FileSystem {
+    /// on-disk superblock
+    super_block: RwLock<Dirty<SuperBlock>>,
+    /// block group descriptors
+    groups: RwLock<Vec<Dirty<GroupDesc>>>,
+    /// inode list
+    inodes: RwLock<BTreeMap<INodeId, Weak<Ext2INode>>>,
+    /// device
+    device: Arc<dyn Device>,
+    /// Pointer to self, used by INodes
+    self_ptr: Weak<Ext2FileSystem>,
+    /// size of a block
+    block_size: usize,
+    /// log2( size of block )
+    block_size_log2: u8,
+    /// size of an inode record in the inode table
+    inode_size: usize,
+    /// number of blocks in fs
+    blocks_count: usize,
+    /// number of inodes in fs
+    inodes_count: usize,
+    /// number of blocks in each group
+    blocks_per_group: usize,
+    /// number of inodes in each group
+    inodes_per_group: usize,
+    /// block of the superblock, where the groups start
+    first_data_block: usize,
+    /// whether directory entries carry file types
+    has_filetype: bool,
+    /// mounted read-only because of unsupported read-only compatible features
+    read_only: bool,
+}
+
+impl vfs::FileSystem for Ext2FileSystem {
+    /// Write back super block and group descriptors if dirty
+    fn sync(&self) -> vfs::Result<()> {
+        // Same order as in the allocators.
+        let mut groups = self.groups.write();
+        let mut super_block = self.super_block.write();
+        let gdt = (self.first_data_block + 1) * self.block_size;
+        for (i, group) in groups.iter_mut().enumerate() {
+            if group.dirty() {
+                self.device
+                    .write_all_at(gdt + i * GROUP_DESC_SIZE, group.as_buf())?;
+                group.sync();
+            }
+        }
+        if super_block.dirty() {
+            super_block.wtime = now();
+            self.device
+                .write_all_at(SUPERBLOCK_OFFSET, super_block.as_buf())?;
+            super_block.sync();
+        }
+        drop(super_block);
+        drop(groups);
+
+        self.flush_weak_inodes();
+        for inode in self.inodes.read().values() {
+            if let Some(inode) = inode.upgrade() {
+                inode.sync_all()?;
+            }
+        }
+        self.device.sync()?;
+        Ok(())
+    }
+
+    fn root_inode(&self) -> Arc<dyn vfs::INode> {
+        self.get_inode(ROOT_INO)
+            .expect("cannot load the root inode of ext2")
+    }
+
+    fn info(&self) -> vfs::FsInfo {
+        let sb = self.super_block.read();
+        vfs::FsInfo {
+            bsize: self.block_size,
+            frsize: self.block_size,
+            blocks: sb.blocks_count as usize,
+            bfree: sb.free_blocks_count as usize,
+            bavail: sb.free_blocks_count.saturating_sub(sb.r_blocks_count) as usize,
+            files: sb.inodes_count as usize,
+            ffree: sb.free_inodes_count as usize,
+            namemax: MAX_NAME_LEN,
+        }
+    }
+}
+
+impl Ext2FileSystem {
+    /// Load ext2 from device
+    pub fn open(device: Arc<dyn Device>) -> vfs::Result<Arc<Self>> {
+        let super_block = device.load_struct::<SuperBlock>(SUPERBLOCK_OFFSET)?;
+        if !super_block.check() {
+            return Err(FsError::WrongFs);
+        }
+        let incompat = super_block.feature_incompat & !SUPPORTED_INCOMPAT;
+        if incompat != 0 {
+            kwarn!("unsupported incompatible features {:#x}", incompat);
+            return Err(FsError::WrongFs);
+        }
+        let ro_compat = super_block.feature_ro_compat & !SUPPORTED_RO_COMPAT;
+        if ro_compat != 0 {
+            kwarn!(
+                "unsupported read-only compatible features {:#x}; mounting read-only",
+                ro_compat
+            );
+        }
+        if super_block.state & EXT2_VALID_FS == 0 {
+            kwarn!("the filesystem was not cleanly unmounted; consider running e2fsck");
+        }
+
+        let block_size = super_block.block_size();
+        let gdt = (super_block.first_data_block as usize + 1) * block_size;
+        let groups = (0..super_block.groups_count())
+            .map(|i| {
+                device
+                    .load_struct::<GroupDesc>(gdt + i * GROUP_DESC_SIZE)
+                    .map(Dirty::new)
+            })
+            .collect::<vfs::Result<Vec<_>>>()?;
+        kinfo!(
+            "ext2: {} blocks of {} bytes in {} groups.",
+            super_block.blocks_count,
+            block_size,
+            groups.len()
+        );
+
+        Ok(Ext2FileSystem {
+            block_size,
+            block_size_log2: (10 + super_block.log_block_size) as u8,
+            inode_size: super_block.inode_size(),
+            blocks_count: super_block.blocks_count as usize,
+            inodes_count: super_block.inodes_count as usize,
+            blocks_per_group: super_block.blocks_per_group as usize,
+            inodes_per_group: super_block.inodes_per_group as usize,
+            first_data_block: super_block.first_data_block as usize,
+            has_filetype: super_block.feature_incompat & FEATURE_INCOMPAT_FILETYPE != 0,
+            read_only: ro_compat != 0,
+            super_block: RwLock::new(Dirty::new(super_block)),
+            groups: RwLock::new(groups),
+            inodes: RwLock::new(BTreeMap::new()),
+            device,
+            self_ptr: Weak::default(),
+        }
+        .wrap())
+    }
+    /// Wrap pure Ext2FileSystem with Arc
+    /// Used in constructors
+    fn wrap(self) -> Arc<Self> {
+        let fs = Arc::new(self);
+        let weak = Arc::downgrade(&fs);
+        let ptr = Arc::into_raw(fs) as *mut Self;
+        unsafe {
+            (*ptr).self_ptr = weak;
+        }
+        unsafe { Arc::from_raw(ptr) }
+    }
+
+    /// Gets the max size of a file.
+    pub fn max_file_size(&self) -> usize {
+        let n = self.block_size / ENTRY_SIZE;
+        (NDIR_BLOCKS + n + n * n + n * n * n) * self.block_size
+    }
+
+    fn check_writable(&self) -> vfs::Result<()> {
+        match self.read_only {
+            true => Err(FsError::NotSupported),
+            false => Ok(()),
+        }
+    }
+
+    fn read_block(&self, id: BlockId) -> vfs::Result<Vec<u8>> {
+        let mut buf = vec![0u8; self.block_size];
+        self.device.read_exact_at(id * self.block_size, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn write_block(&self, id: BlockId, buf: &[u8]) -> vfs::Result<()> {
+        debug_assert!(buf.len() == self.block_size);
+        self.device.write_all_at(id * self.block_size, buf)
+    }
+
+    /// Gets the group of inode `id`.
+    fn inode_group(&self, id: INodeId) -> usize {
+        (id - 1) / self.inodes_per_group
+    }
+
+    /// Gets the offset of inode `id` in the inode table.
+    fn inode_offset(&self, id: INodeId) -> usize {
+        let group = self.inode_group(id);
+        let index = (id - 1) % self.inodes_per_group;
+        let inode_table = self.groups.read()[group].inode_table as usize;
+        inode_table * self.block_size + index * self.inode_size
+    }
+
+    /// Allocate a zeroed block, preferably in group `goal`
+    fn alloc_block(&self, goal: usize) -> vfs::Result<u32> {
+        let mut groups = self.groups.write();
+        let count = groups.len();
+        for group_id in (goal..count).chain(0..goal) {
+            if groups[group_id].free_blocks_count == 0 {
+                continue;
+            }
+            let first = self.first_data_block + group_id * self.blocks_per_group;
+            let len = self.blocks_per_group.min(self.blocks_count - first);
+            let bitmap_block = groups[group_id].block_bitmap as usize;
+            let mut bitmap = self.read_block(bitmap_block)?;
+            let bit = match find_clear_bit(&bitmap, len) {
+                Some(bit) => bit,
+                None => {
+                    kwarn!("the free block count of group {} is wrong", group_id);
+                    continue;
+                }
+            };
+            set_bit(&mut bitmap, bit, true);
+            self.write_block(bitmap_block, &bitmap)?;
+            groups[group_id].free_blocks_count -= 1;
+            self.super_block.write().free_blocks_count -= 1;
+
+            let block_id = first + bit;
+            self.write_block(block_id, &vec![0u8; self.block_size])?;
+            ktrace!("alloc block {:#x}", block_id);
+            return Ok(block_id as u32);
+        }
+        Err(FsError::NoDeviceSpace)
+    }
+    /// Free a block
+    fn free_block(&self, block_id: u32) -> vfs::Result<()> {
+        let mut groups = self.groups.write();
+        let group_id = (block_id as usize - self.first_data_block) / self.blocks_per_group;
+        let bit = (block_id as usize - self.first_data_block) % self.blocks_per_group;
+        let bitmap_block = groups[group_id].block_bitmap as usize;
+        let mut bitmap = self.read_block(bitmap_block)?;
+        if !test_bit(&bitmap, bit) {
+            kwarn!("block {:#x} is already free", block_id);
+            return Ok(());
+        }
+        set_bit(&mut bitmap, bit, false);
+        self.write_block(bitmap_block, &bitmap)?;
+        groups[group_id].free_blocks_count += 1;
+        self.super_block.write().free_blocks_count += 1;
+        ktrace!("free block {:#x}", block_id);
+        Ok(())
+    }
+    /// Allocate an inode, preferably in group `goal`
+    fn alloc_inode(&self, goal: usize, is_dir: bool) -> vfs::Result<INodeId> {
+        let mut groups = self.groups.write();
+        let count = groups.len();
+        for group_id in (goal..count).chain(0..goal) {
+            if groups[group_id].free_inodes_count == 0 {
+                continue;
+            }
+            let bitmap_block = groups[group_id].inode_bitmap as usize;
+            let mut bitmap = self.read_block(bitmap_block)?;
+            let bit = match find_clear_bit(&bitmap, self.inodes_per_group) {
+                Some(bit) => bit,
+                None => {
+                    kwarn!("the free inode count of group {} is wrong", group_id);
+                    continue;
+                }
+            };
+            set_bit(&mut bitmap, bit, true);
+            self.write_block(bitmap_block, &bitmap)?;
+            let group = &mut groups[group_id];
+            group.free_inodes_count -= 1;
+            if is_dir {
+                group.used_dirs_count += 1;
+            }
+            self.super_block.write().free_inodes_count -= 1;
+
+            let id = group_id * self.inodes_per_group + bit + 1;
+            ktrace!("alloc inode {}", id);
+            return Ok(id);
+        }
+        Err(FsError::NoDeviceSpace)
+    }
+    /// Free an inode
+    fn free_inode(&self, id: INodeId, is_dir: bool) -> vfs::Result<()> {
+        let mut groups = self.groups.write();
+        let group_id = self.inode_group(id);
+        let bit = (id - 1) % self.inodes_per_group;
+        let bitmap_block = groups[group_id].inode_bitmap as usize;
+        let mut bitmap = self.read_block(bitmap_block)?;
+        if !test_bit(&bitmap, bit) {
+            kwarn!("inode {} is already free", id);
+            return Ok(());
+        }
+        set_bit(&mut bitmap, bit, false);
+        self.write_block(bitmap_block, &bitmap)?;
+        let group = &mut groups[group_id];
+        group.free_inodes_count += 1;
+        if is_dir {
+            group.used_dirs_count -= 1;
+        }
+        self.super_block.write().free_inodes_count += 1;
+        ktrace!("free inode {}", id);
+        Ok(())
+    }
+    /// Release the extended attribute block of an inode, which may be shared by several inodes
+    fn release_xattr_block(&self, block_id: u32) -> vfs::Result<()> {
+        let mut buf = self.read_block(block_id as usize)?;
+        if read_u32(&buf, 0) != XATTR_MAGIC {
+            kwarn!("block {:#x} is not an extended attribute block", block_id);
+            return Ok(());
+        }
+        match read_u32(&buf, 1) {
+            0 | 1 => self.free_block(block_id),
+            refcount => {
+                write_u32(&mut buf, 1, refcount - 1);
+                self.write_block(block_id as usize, &buf)
+            }
+        }
+    }
+    /// Record that a regular file has grown beyond 2 GiB
+    fn set_large_file(&self) {
+        let mut super_block = self.super_block.write();
+        if super_block.feature_ro_compat & FEATURE_RO_COMPAT_LARGE_FILE == 0 {
+            super_block.feature_ro_compat |= FEATURE_RO_COMPAT_LARGE_FILE;
+        }
+    }
+
+    /// Create a new INode struct, then insert it to self.inodes
+    /// Private used for load or create INode
+    fn _new_inode(&self, id: INodeId, disk_inode: Dirty<DiskINode>) -> Arc<Ext2INode> {
+        let inode = Arc::new(Ext2INode {
+            id,
+            disk_inode: RwLock::new(disk_inode),
+            fs: self.self_ptr.upgrade().unwrap(),
+        });
+        self.inodes.write().insert(id, Arc::downgrade(&inode));
+        inode
+    }
+    /// Get inode by id. Load if not in memory.
+    fn get_inode(&self, id: INodeId) -> vfs::Result<Arc<Ext2INode>> {
+        if id == 0 || id > self.inodes_count {
+            return Err(FsError::InvalidParam);
+        }
+
+        // In the BTreeSet and not weak.
+        if let Some(inode) = self.inodes.read().get(&id) {
+            if let Some(inode) = inode.upgrade() {
+                return Ok(inode);
+            }
+        }
+        // Load if not in set, or is weak ref.
+        let disk_inode = Dirty::new(
+            self.device
+                .load_struct::<DiskINode>(self.inode_offset(id))?,
+        );
+        Ok(self._new_inode(id, disk_inode))
+    }
+    /// Create a new INode with `mode` in group `goal` if possible
+    fn new_inode(&self, goal: usize, mode: u16) -> vfs::Result<Arc<Ext2INode>> {
+        let id = self.alloc_inode(goal, mode & S_IFMT == S_IFDIR)?;
+        // Clear the whole record, including the fields beyond the first 128 bytes.
+        self.device
+            .write_all_at(self.inode_offset(id), &vec![0u8; self.inode_size])?;
+        let disk_inode = Dirty::new_dirty(DiskINode::new(mode, now()));
+        Ok(self._new_inode(id, disk_inode))
+    }
+    fn flush_weak_inodes(&self) {
+        let mut inodes = self.inodes.write();
+        let remove_ids: Vec<_> = inodes
+            .iter()
+            .filter(|(_, inode)| inode.upgrade().is_none())
+            .map(|(&id, _)| id)
+            .collect();
+        for id in remove_ids.iter() {
+            inodes.remove(id);
+        }
+    }
+}
+
+/// INode for ext2
+pub struct Ext2INode {
+    /// INode number
+    id: INodeId,
+    /// On-disk INode
+    disk_inode: RwLock<Dirty<DiskINode>>,
+    /// Reference to ext2, used by almost all operations
+    fs: Arc<Ext2FileSystem>,
+}
+
+impl Ext2INode {
+    /// Gets the max size of a file on this filesystem.
+    pub fn max_file_size(&self) -> usize {
+        self.fs.max_file_size()
+    }
+    /// Number of block entries in an indirect block
+    fn entries_per_block(&self) -> usize {
+        self.fs.block_size / ENTRY_SIZE
+    }
+    /// Split file block `index` into its slot in `DiskINode::block` and the entries to follow in the indirect
+    /// blocks
+    fn block_path(&self, index: usize) -> vfs::Result<(usize, Vec<usize>)> {
+        let n = self.entries_per_block();
+        if index < NDIR_BLOCKS {
+            return Ok((index, vec![]));
+        }
+        let index = index - NDIR_BLOCKS;
+        if index < n {
+            return Ok((IND_BLOCK, vec![index]));
+        }
+        let index = index - n;
+        if index < n * n {
+            return Ok((DIND_BLOCK, vec![index / n, index % n]));
+        }
+        let index = index - n * n;
+        if index < n * n * n {
+            return Ok((TIND_BLOCK, vec![index / (n * n), index / n % n, index % n]));
+        }
+        Err(FsError::InvalidParam)
+    }
+    /// Read the `index`-th entry of an indirect block
+    fn read_entry(&self, block_id: u32, index: usize) -> vfs::Result<u32> {
+        let mut entry: u32 = 0;
+        self.fs.device.read_exact_at(
+            block_id as usize * self.fs.block_size + index * ENTRY_SIZE,
+            entry.as_buf_mut(),
+        )?;
+        Ok(entry)
+    }
+    /// Write the `index`-th entry of an indirect block
+    fn write_entry(&self, block_id: u32, index: usize, entry: u32) -> vfs::Result<()> {
+        self.fs.device.write_all_at(
+            block_id as usize * self.fs.block_size + index * ENTRY_SIZE,
+            entry.as_buf(),
+        )
+    }
+    /// Map file block `index` to disk block id, 0 for a hole
+    fn get_block(&self, disk_inode: &DiskINode, index: usize) -> vfs::Result<u32> {
+        let (slot, path) = self.block_path(index)?;
+        let mut block_id = disk_inode.block[slot];
+        for entry in path {
+            if block_id == 0 {
+                break;
+            }
+            block_id = self.read_entry(block_id, entry)?;
+        }
+        Ok(block_id)
+    }
+    /// Map file block `index` to disk block id, allocating it and the indirect blocks on the way if needed
+    fn map_block(&self, disk_inode: &mut DiskINode, index: usize) -> vfs::Result<u32> {
+        let (slot, path) = self.block_path(index)?;
+        if disk_inode.block[slot] == 0 {
+            disk_inode.block[slot] = self.alloc_block(disk_inode)?;
+        }
+        let mut block_id = disk_inode.block[slot];
+        for entry in path {
+            let mut next = self.read_entry(block_id, entry)?;
+            if next == 0 {
+                next = self.alloc_block(disk_inode)?;
+                self.write_entry(block_id, entry, next)?;
+            }
+            block_id = next;
+        }
+        Ok(block_id)
+    }
+    /// Allocate a block for this inode
+    fn alloc_block(&self, disk_inode: &mut DiskINode) -> vfs::Result<u32> {
+        let block_id = self.fs.alloc_block(self.fs.inode_group(self.id))?;
+        disk_inode.blocks += (self.fs.block_size / SECTOR_SIZE) as u32;
+        Ok(block_id)
+    }
+    /// Free a block of this inode
+    fn free_block(&self, disk_inode: &mut DiskINode, block_id: u32) -> vfs::Result<()> {
+        self.fs.free_block(block_id)?;
+        disk_inode.blocks -= (self.fs.block_size / SECTOR_SIZE) as u32;
+        Ok(())
+    }
+    /// Free the blocks from file block `from` on, together with the indirect blocks no longer needed
+    fn free_blocks_from(&self, disk_inode: &mut DiskINode, from: usize) -> vfs::Result<()> {
+        for i in from.min(NDIR_BLOCKS)..NDIR_BLOCKS {
+            let block_id = disk_inode.block[i];
+            if block_id != 0 {
+                self.free_block(disk_inode, block_id)?;
+                disk_inode.block[i] = 0;
+            }
+        }
+
+        let n = self.entries_per_block();
+        let trees = [
+            (IND_BLOCK, 1, NDIR_BLOCKS),
+            (DIND_BLOCK, 2, NDIR_BLOCKS + n),
+            (TIND_BLOCK, 3, NDIR_BLOCKS + n + n * n),
+        ];
+        for (slot, level, base) in trees {
+            let block_id = disk_inode.block[slot];
+            if block_id != 0 && self.free_tree(disk_inode, block_id, level, base, from)? {
+                disk_inode.block[slot] = 0;
+            }
+        }
+        Ok(())
+    }
+    /// Free the blocks from file block `from` on under `block_id`, an indirect block of `level` whose first file
+    /// block is `base`. Returns whether `block_id` itself is freed.
+    fn free_tree(
+        &self,
+        disk_inode: &mut DiskINode,
+        block_id: u32,
+        level: u32,
+        base: usize,
+        from: usize,
+    ) -> vfs::Result<bool> {
+        let n = self.entries_per_block();
+        // Number of file blocks under each entry.
+        let span = n.pow(level - 1);
+        if from >= base + span * n {
+            return Ok(false);
+        }
+
+        let mut entries = self.fs.read_block(block_id as usize)?;
+        let mut changed = false;
+        for i in from.saturating_sub(base) / span..n {
+            let entry = read_u32(&entries, i);
+            if entry == 0 {
+                continue;
+            }
+            let freed = match level {
+                1 => {
+                    self.free_block(disk_inode, entry)?;
+                    true
+                }
+                _ => self.free_tree(disk_inode, entry, level - 1, base + i * span, from)?,
+            };
+            if freed {
+                write_u32(&mut entries, i, 0);
+                changed = true;
+            }
+        }
+
+        if from <= base {
+            self.free_block(disk_inode, block_id)?;
+            return Ok(true);
+        }
+        if changed {
+            self.fs.write_block(block_id as usize, &entries)?;
+        }
+        Ok(false)
+    }
+    /// Set the size, marking the filesystem as having large files if needed
+    fn set_size(&self, disk_inode: &mut DiskINode, len: usize) {
+        disk_inode.set_size(len);
+        if disk_inode.file_type() == S_IFREG && len > i32::MAX as usize {
+            self.fs.set_large_file();
+        }
+    }
+    /// Resize content size. Growing leaves a hole.
+    fn _resize(&self, disk_inode: &mut DiskINode, len: usize) -> vfs::Result<()> {
+        if len > self.fs.max_file_size() {
+            return Err(FsError::InvalidParam);
+        }
+        let block_size = self.fs.block_size;
+        if len < disk_inode.size() {
+            self.free_blocks_from(disk_inode, (len + block_size - 1) / block_size)?;
+            // Clear the tail of the last block so that it reads as zeros if the file grows again.
+            if len % block_size != 0 {
+                let block_id = self.get_block(disk_inode, len / block_size)?;
+                if block_id != 0 {
+                    let tail = vec![0u8; block_size - len % block_size];
+                    self.fs
+                        .device
+                        .write_all_at(block_id as usize * block_size + len % block_size, &tail)?;
+                }
+            }
+        }
+        self.set_size(disk_inode, len);
+        Ok(())
+    }
+    // Note: the _\w*_at method always return begin>size?0:begin<end?0:(min(size,end)-begin) when success
+    /// Read content, holes are read as zeros
+    fn _read_at(
+        &self,
+        disk_inode: &DiskINode,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> vfs::Result<usize> {
+        let size = disk_inode.size();
+        let iter = BlockIter {
+            begin: size.min(offset),
+            end: size.min(offset + buf.len()),
+            block_size_log2: self.fs.block_size_log2,
+        };
+
+        let mut buf_offset = 0usize;
+        for range in iter {
+            let dst = &mut buf[buf_offset..buf_offset + range.len()];
+            match self.get_block(disk_inode, range.block)? {
+                0 => dst.fill(0),
+                block_id => self
+                    .fs
+                    .device
+                    .read_exact_at(block_id as usize * self.fs.block_size + range.begin, dst)?,
+            }
+            buf_offset += range.len();
+        }
+        Ok(buf_offset)
+    }
+    /// Write content, growing the file if needed
+    fn _write_at(
+        &self,
+        disk_inode: &mut DiskINode,
+        offset: usize,
+        buf: &[u8],
+    ) -> vfs::Result<usize> {
+        let end = offset + buf.len();
+        if end > self.fs.max_file_size() {
+            return Err(FsError::InvalidParam);
+        }
+        let iter = BlockIter {
+            begin: offset,
+            end,
+            block_size_log2: self.fs.block_size_log2,
+        };
+
+        let mut buf_offset = 0usize;
+        for range in iter {
+            let block_id = self.map_block(disk_inode, range.block)?;
+            self.fs.device.write_all_at(
+                block_id as usize * self.fs.block_size + range.begin,
+                &buf[buf_offset..buf_offset + range.len()],
+            )?;
+            buf_offset += range.len();
+        }
+        if end > disk_inode.size() {
+            self.set_size(disk_inode, end);
+        }
+        Ok(buf_offset)
+    }
+    /// Checks if the symlink keeps its target in `DiskINode::block`
+    fn is_fast_symlink(&self, disk_inode: &DiskINode) -> bool {
+        let xattr_sectors = match disk_inode.file_acl {
+            0 => 0,
+            _ => (self.fs.block_size / SECTOR_SIZE) as u32,
+        };
+        disk_inode.file_type() == S_IFLNK && disk_inode.blocks == xattr_sectors
+    }
+    /// Read the target of a symlink
+    fn read_symlink(
+        &self,
+        disk_inode: &DiskINode,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> vfs::Result<usize> {
+        if !self.is_fast_symlink(disk_inode) {
+            return self._read_at(disk_inode, offset, buf);
+        }
+        let target = &disk_inode.block.as_buf()[..disk_inode.size().min(FAST_SYMLINK_MAX_LEN)];
+        let begin = offset.min(target.len());
+        let end = (offset + buf.len()).min(target.len());
+        buf[..end - begin].copy_from_slice(&target[begin..end]);
+        Ok(end - begin)
+    }
+    /// Write the target of a symlink. Short targets are kept in the inode.
+    fn write_symlink(
+        &self,
+        disk_inode: &mut DiskINode,
+        offset: usize,
+        buf: &[u8],
+    ) -> vfs::Result<usize> {
+        let mut target = vec![0u8; disk_inode.size()];
+        self.read_symlink(disk_inode, 0, &mut target)?;
+        let end = offset + buf.len();
+        if target.len() < end {
+            target.resize(end, 0);
+        }
+        target[offset..end].copy_from_slice(buf);
+
+        match self.is_fast_symlink(disk_inode) {
+            true => disk_inode.block = [0; N_BLOCKS],
+            false => self.free_blocks_from(disk_inode, 0)?,
+        }
+        disk_inode.set_size(0);
+        if target.len() < FAST_SYMLINK_MAX_LEN {
+            disk_inode.block.as_buf_mut()[..target.len()].copy_from_slice(&target);
+            disk_inode.set_size(target.len());
+        } else {
+            self._write_at(disk_inode, 0, &target)?;
+        }
+        Ok(buf.len())
+    }
+    /// Read the `index`-th block of a directory
+    fn read_dir_block(&self, disk_inode: &DiskINode, index: usize) -> vfs::Result<(u32, Vec<u8>)> {
+        match self.get_block(disk_inode, index)? {
+            0 => {
+                kwarn!("directory {} has a hole at block {}", self.id, index);
+                Err(FsError::DeviceError)
+            }
+            block_id => Ok((block_id, self.fs.read_block(block_id as usize)?)),
+        }
+    }
+    /// Only for Dir
+    fn entries(&self, disk_inode: &DiskINode) -> vfs::Result<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+        for index in 0..disk_inode.size() / self.fs.block_size {
+            let (_, buf) = self.read_dir_block(disk_inode, index)?;
+            for (pos, entry) in parse_dir_block(&buf)? {
+                if entry.inode != 0 {
+                    entries.push(DirEntry {
+                        inode: entry.inode as INodeId,
+                        name: String::from_utf8_lossy(entry_name(&buf, pos, &entry)).into_owned(),
+                    });
+                }
+            }
+        }
+        Ok(entries)
+    }
+    fn find_entry(&self, disk_inode: &DiskINode, name: &str) -> vfs::Result<Option<INodeId>> {
+        Ok(self
+            .entries(disk_inode)?
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| entry.inode))
+    }
+    /// Insert an entry into the first gap large enough, or into a new block
+    fn add_entry(
+        &self,
+        disk_inode: &mut DiskINode,
+        name: &str,
+        inode: INodeId,
+        file_type: u8,
+    ) -> vfs::Result<()> {
+        let block_size = self.fs.block_size;
+        let needed = DiskDirEntry::rec_len_of(name.len());
+        let new_entry = |buf: &mut [u8], rec_len: usize| {
+            DiskDirEntry {
+                inode: inode as u32,
+                rec_len: rec_len as u16,
+                name_len: name.len() as u8,
+                file_type: match self.fs.has_filetype {
+                    true => file_type,
+                    false => 0,
+                },
+            }
+            .write(buf);
+            buf[DIRENT_HEADER_SIZE..DIRENT_HEADER_SIZE + name.len()]
+                .copy_from_slice(name.as_bytes());
+        };
+        // The hashed index is not maintained.
+        disk_inode.flags &= !EXT2_INDEX_FL;
+
+        let blocks = disk_inode.size() / block_size;
+        for index in 0..blocks {
+            let (block_id, mut buf) = self.read_dir_block(disk_inode, index)?;
+            for (pos, mut entry) in parse_dir_block(&buf)? {
+                let used = match entry.inode {
+                    0 => 0,
+                    _ => DiskDirEntry::rec_len_of(entry.name_len as usize),
+                };
+                let rec_len = entry.rec_len as usize;
+                if rec_len - used < needed {
+                    continue;
+                }
+                if used != 0 {
+                    entry.rec_len = used as u16;
+                    entry.write(&mut buf[pos..]);
+                }
+                new_entry(&mut buf[pos + used..], rec_len - used);
+                return self.fs.write_block(block_id as usize, &buf);
+            }
+        }
+
+        let block_id = self.map_block(disk_inode, blocks)?;
+        let mut buf = vec![0u8; block_size];
+        new_entry(&mut buf, block_size);
+        self.fs.write_block(block_id as usize, &buf)?;
+        disk_inode.set_size((blocks + 1) * block_size);
+        Ok(())
+    }
+    /// Remove an entry by merging it into the previous one
+    fn remove_entry(&self, disk_inode: &mut DiskINode, name: &str) -> vfs::Result<()> {
+        disk_inode.flags &= !EXT2_INDEX_FL;
+        for index in 0..disk_inode.size() / self.fs.block_size {
+            let (block_id, mut buf) = self.read_dir_block(disk_inode, index)?;
+            let entries = parse_dir_block(&buf)?;
+            for (i, &(pos, mut entry)) in entries.iter().enumerate() {
+                if entry.inode == 0 || entry_name(&buf, pos, &entry) != name.as_bytes() {
+                    continue;
+                }
+                match i {
+                    // The first entry of a block cannot be merged.
+                    0 => {
+                        entry.inode = 0;
+                        entry.write(&mut buf[pos..]);
+                    }
+                    _ => {
+                        let (prev_pos, mut prev) = entries[i - 1];
+                        prev.rec_len += entry.rec_len;
+                        prev.write(&mut buf[prev_pos..]);
+                    }
+                }
+                return self.fs.write_block(block_id as usize, &buf);
+            }
+        }
+        Err(FsError::EntryNotFound)
+    }
+    /// Point an existing entry to another inode
+    fn update_entry(&self, disk_inode: &DiskINode, name: &str, inode: INodeId) -> vfs::Result<()> {
+        for index in 0..disk_inode.size() / self.fs.block_size {
+            let (block_id, mut buf) = self.read_dir_block(disk_inode, index)?;
+            for (pos, mut entry) in parse_dir_block(&buf)? {
+                if entry.inode != 0 && entry_name(&buf, pos, &entry) == name.as_bytes() {
+                    entry.inode = inode as u32;
+                    entry.write(&mut buf[pos..]);
+                    return self.fs.write_block(block_id as usize, &buf);
+                }
+            }
+        }
+        Err(FsError::EntryNotFound)
+    }
+    /// Init dir content. Insert 2 init entries.
+    fn init_dir(&self, disk_inode: &mut DiskINode, parent: INodeId) -> vfs::Result<()> {
+        let block_size = self.fs.block_size;
+        let block_id = self.map_block(disk_inode, 0)?;
+        let mut buf = vec![0u8; block_size];
+        let dot_len = DiskDirEntry::rec_len_of(1);
+        for (pos, rec_len, inode, name) in [
+            (0, dot_len, self.id, "."),
+            (dot_len, block_size - dot_len, parent, ".."),
+        ] {
+            DiskDirEntry {
+                inode: inode as u32,
+                rec_len: rec_len as u16,
+                name_len: name.len() as u8,
+                file_type: match self.fs.has_filetype {
+                    true => EXT2_FT_DIR,
+                    false => 0,
+                },
+            }
+            .write(&mut buf[pos..]);
+            buf[pos + DIRENT_HEADER_SIZE..pos + DIRENT_HEADER_SIZE + name.len()]
+                .copy_from_slice(name.as_bytes());
+        }
+        self.fs.write_block(block_id as usize, &buf)?;
+        disk_inode.set_size(block_size);
+        Ok(())
+    }
+    /// Checks that this is a directory which can still get entries
+    fn check_dir(disk_inode: &DiskINode) -> vfs::Result<()> {
+        if disk_inode.file_type() != S_IFDIR {
+            return Err(FsError::NotDir);
+        }
+        if disk_inode.links_count == 0 {
+            return Err(FsError::DirRemoved);
+        }
+        Ok(())
+    }
+    /// Remove entry `name` pointing to `child` and drop the links it holds
+    fn unlink_child(
+        &self,
+        disk_inode: &mut DiskINode,
+        name: &str,
+        child: &Ext2INode,
+    ) -> vfs::Result<()> {
+        let mut child_inode = child.disk_inode.write();
+        let is_dir = child_inode.file_type() == S_IFDIR;
+        if is_dir && child.entries(&child_inode)?.len() > 2 {
+            return Err(FsError::DirNotEmpty);
+        }
+        self.remove_entry(disk_inode, name)?;
+
+        let now = now();
+        child_inode.links_count = child_inode.links_count.saturating_sub(1);
+        if is_dir {
+            // for .
+            child_inode.links_count = 0;
+            // for ..
+            disk_inode.links_count -= 1;
+        }
+        child_inode.ctime = now;
+        disk_inode.mtime = now;
+        disk_inode.ctime = now;
+        Ok(())
+    }
+}
+
+impl vfs::INode for Ext2INode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
+        let disk_inode = self.disk_inode.read();
+        match disk_inode.file_type() {
+            S_IFREG => self._read_at(&disk_inode, offset, buf),
+            S_IFLNK => self.read_symlink(&disk_inode, offset, buf),
+            S_IFDIR => Err(FsError::IsDir),
+            _ => Err(FsError::NotFile),
+        }
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
+        self.fs.check_writable()?;
+        let mut disk_inode = self.disk_inode.write();
+        let len = match disk_inode.file_type() {
+            S_IFREG => self._write_at(&mut disk_inode, offset, buf)?,
+            S_IFLNK => self.write_symlink(&mut disk_inode, offset, buf)?,
+            S_IFDIR => return Err(FsError::IsDir),
+            _ => return Err(FsError::NotFile),
+        };
+        disk_inode.mtime = now();
+        Ok(len)
+    }
+    fn poll(&self) -> vfs::Result<vfs::PollStatus> {
+        Ok(vfs::PollStatus {
+            read: true,
+            write: true,
+            error: false,
+        })
+    }
+    fn metadata(&self) -> vfs::Result<vfs::Metadata> {
+        let disk_inode = self.disk_inode.read();
+        let rdev = match disk_inode.file_type() {
+            S_IFCHR | S_IFBLK => match disk_inode.block[0] {
+                0 => disk_inode.block[1] as usize,
+                rdev => rdev as usize,
+            },
+            _ => 0,
+        };
+        Ok(vfs::Metadata {
+            dev: 0,
+            inode: self.id,
+            size: disk_inode.size(),
+            mode: disk_inode.mode & !S_IFMT,
+            type_: to_vfs_type(disk_inode.mode),
+            // In sectors, including the indirect blocks.
+            blocks: disk_inode.blocks as usize,
+            atime: to_timespec(disk_inode.atime),
+            mtime: to_timespec(disk_inode.mtime),
+            ctime: to_timespec(disk_inode.ctime),
+            nlinks: disk_inode.links_count as usize,
+            uid: disk_inode.uid as usize | ((disk_inode.uid_high as usize) << 16),
+            gid: disk_inode.gid as usize | ((disk_inode.gid_high as usize) << 16),
+            blk_size: self.fs.block_size,
+            rdev,
+        })
+    }
+    fn set_metadata(&self, metadata: &vfs::Metadata) -> vfs::Result<()> {
+        if self.fs.read_only {
+            return Ok(());
+        }
+        let mut disk_inode = self.disk_inode.write();
+        disk_inode.atime = metadata.atime.sec as u32;
+        disk_inode.mtime = metadata.mtime.sec as u32;
+        disk_inode.ctime = metadata.ctime.sec as u32;
+        disk_inode.mode = (disk_inode.mode & S_IFMT) | (metadata.mode & !S_IFMT);
+        disk_inode.uid = metadata.uid as u16;
+        disk_inode.uid_high = (metadata.uid >> 16) as u16;
+        disk_inode.gid = metadata.gid as u16;
+        disk_inode.gid_high = (metadata.gid >> 16) as u16;
+        Ok(())
+    }
+    fn sync_all(&self) -> vfs::Result<()> {
+        let mut disk_inode = self.disk_inode.write();
+        if disk_inode.dirty() {
+            self.fs
+                .device
+                .write_all_at(self.fs.inode_offset(self.id), disk_inode.as_buf())?;
+            disk_inode.sync();
+        }
+        Ok(())
+    }
+    fn sync_data(&self) -> vfs::Result<()> {
+        self.sync_all()
+    }
+    fn resize(&self, len: usize) -> vfs::Result<()> {
+        self.fs.check_writable()?;
+        let mut disk_inode = self.disk_inode.write();
+        if disk_inode.file_type() != S_IFREG {
+            return Err(FsError::NotFile);
+        }
+        self._resize(&mut disk_inode, len)?;
+        disk_inode.mtime = now();
+        Ok(())
+    }
+    fn create2(
+        &self,
+        name: &str,
+        type_: vfs::FileType,
+        mode: u32,
+        data: usize,
+    ) -> vfs::Result<Arc<dyn vfs::INode>> {
+        self.fs.check_writable()?;
+        if name.len() > MAX_NAME_LEN {
+            return Err(FsError::InvalidParam);
+        }
+        let mut disk_inode = self.disk_inode.write();
+        Self::check_dir(&disk_inode)?;
+
+        // Ensure the name is not exist
+        if self.find_entry(&disk_inode, name)?.is_some() {
+            return Err(FsError::EntryExist);
+        }
+
+        // Create new INode
+        let (file_type, entry_type) = from_vfs_type(type_);
+        let perm = match type_ {
+            vfs::FileType::SymLink => 0o777,
+            _ => mode as u16 & !S_IFMT,
+        };
+        let inode = self
+            .fs
+            .new_inode(self.fs.inode_group(self.id), file_type | perm)?;
+        {
+            let mut child_inode = inode.disk_inode.write();
+            child_inode.links_count = 1;
+            match type_ {
+                vfs::FileType::Dir => {
+                    // for .
+                    child_inode.links_count = 2;
+                    inode.init_dir(&mut child_inode, self.id)?;
+                }
+                vfs::FileType::CharDevice | vfs::FileType::BlockDevice => match data {
+                    // old encoding
+                    0..=0xffff => child_inode.block[0] = data as u32,
+                    _ => child_inode.block[1] = data as u32,
+                },
+                _ => {}
+            }
+        }
+
+        // Write new entry
+        if let Err(err) = self.add_entry(&mut disk_inode, name, inode.id, entry_type) {
+            // Freed on drop.
+            inode.disk_inode.write().links_count = 0;
+            return Err(err);
+        }
+        if type_ == vfs::FileType::Dir {
+            // for ..
+            disk_inode.links_count += 1;
+        }
+        let now = now();
+        disk_inode.mtime = now;
+        disk_inode.ctime = now;
+
+        Ok(inode)
+    }
+    fn link(&self, name: &str, other: &Arc<dyn INode>) -> vfs::Result<()> {
+        self.fs.check_writable()?;
+        let child = other
+            .downcast_ref::<Ext2INode>()
+            .ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs, &child.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        let child_mode = child.disk_inode.read().mode;
+        if child_mode & S_IFMT == S_IFDIR {
+            return Err(FsError::IsDir);
+        }
+
+        let mut disk_inode = self.disk_inode.write();
+        Self::check_dir(&disk_inode)?;
+        if self.find_entry(&disk_inode, name)?.is_some() {
+            return Err(FsError::EntryExist);
+        }
+        self.add_entry(&mut disk_inode, name, child.id, dir_entry_type(child_mode))?;
+
+        let now = now();
+        let mut child_inode = child.disk_inode.write();
+        child_inode.links_count += 1;
+        child_inode.ctime = now;
+        disk_inode.mtime = now;
+        disk_inode.ctime = now;
+        Ok(())
+    }
+    fn unlink(&self, name: &str) -> vfs::Result<()> {
+        self.fs.check_writable()?;
+        if name == "." || name == ".." {
+            return Err(FsError::IsDir);
+        }
+
+        let mut disk_inode = self.disk_inode.write();
+        Self::check_dir(&disk_inode)?;
+        let inode_id = self
+            .find_entry(&disk_inode, name)?
+            .ok_or(FsError::EntryNotFound)?;
+        let inode = self.fs.get_inode(inode_id)?;
+        self.unlink_child(&mut disk_inode, name, &inode)
+    }
+    fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> vfs::Result<()> {
+        self.fs.check_writable()?;
+        if old_name == "." || old_name == ".." {
+            return Err(FsError::IsDir);
+        }
+        if new_name.len() > MAX_NAME_LEN {
+            return Err(FsError::InvalidParam);
+        }
+        let dest = target
+            .downcast_ref::<Ext2INode>()
+            .ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs, &dest.fs) {
+            return Err(FsError::NotSameFs);
+        }
+
+        let mut disk_inode = self.disk_inode.write();
+        Self::check_dir(&disk_inode)?;
+        let inode_id = self
+            .find_entry(&disk_inode, old_name)?
+            .ok_or(FsError::EntryNotFound)?;
+        let inode = self.fs.get_inode(inode_id)?;
+        let mode = inode.disk_inode.read().mode;
+        let is_dir = mode & S_IFMT == S_IFDIR;
+        if inode_id == dest.id {
+            // Cannot move a directory into itself.
+            return Err(FsError::InvalidParam);
+        }
+
+        if dest.id == self.id {
+            // rename
+            if old_name == new_name {
+                return Ok(());
+            }
+            if let Some(existing) = self.find_entry(&disk_inode, new_name)? {
+                let existing = self.fs.get_inode(existing)?;
+                self.unlink_child(&mut disk_inode, new_name, &existing)?;
+            }
+            self.remove_entry(&mut disk_inode, old_name)?;
+            self.add_entry(&mut disk_inode, new_name, inode_id, dir_entry_type(mode))?;
+        } else {
+            // move
+            let mut dest_inode = dest.disk_inode.write();
+            Self::check_dir(&dest_inode)?;
+            if let Some(existing) = dest.find_entry(&dest_inode, new_name)? {
+                let existing = self.fs.get_inode(existing)?;
+                dest.unlink_child(&mut dest_inode, new_name, &existing)?;
+            }
+            dest.add_entry(&mut dest_inode, new_name, inode_id, dir_entry_type(mode))?;
+            self.remove_entry(&mut disk_inode, old_name)?;
+            if is_dir {
+                inode.update_entry(&inode.disk_inode.read(), "..", dest.id)?;
+                disk_inode.links_count -= 1;
+                dest_inode.links_count += 1;
+            }
+            let now = now();
+            dest_inode.mtime = now;
+            dest_inode.ctime = now;
+        }
+
+        let now = now();
+        disk_inode.mtime = now;
+        disk_inode.ctime = now;
+        inode.disk_inode.write().ctime = now;
+        Ok(())
+    }
+    fn find(&self, name: &str) -> vfs::Result<Arc<dyn vfs::INode>> {
+        let disk_inode = self.disk_inode.read();
+        if disk_inode.file_type() != S_IFDIR {
+            return Err(FsError::NotDir);
+        }
+        let inode_id = self
+            .find_entry(&disk_inode, name)?
+            .ok_or(FsError::EntryNotFound)?;
+        Ok(self.fs.get_inode(inode_id)?)
+    }
+    fn get_entry(&self, id: usize) -> vfs::Result<String> {
+        let disk_inode = self.disk_inode.read();
+        if disk_inode.file_type() != S_IFDIR {
+            return Err(FsError::NotDir);
+        }
+        self.entries(&disk_inode)?
+            .into_iter()
+            .nth(id)
+            .map(|entry| entry.name)
+            .ok_or(FsError::EntryNotFound)
+    }
+    fn get_entry_with_metadata(&self, id: usize) -> vfs::Result<(Metadata, String)> {
+        let entry = {
+            let disk_inode = self.disk_inode.read();
+            if disk_inode.file_type() != S_IFDIR {
+                return Err(FsError::NotDir);
+            }
+            self.entries(&disk_inode)?
+                .into_iter()
+                .nth(id)
+                .ok_or(FsError::EntryNotFound)?
+        };
+        Ok((self.fs.get_inode(entry.inode)?.metadata()?, entry.name))
+    }
+    fn list(&self) -> vfs::Result<Vec<(usize, String)>> {
+        let disk_inode = self.disk_inode.read();
+        if disk_inode.file_type() != S_IFDIR {
+            return Err(FsError::NotDir);
+        }
+        Ok(self
+            .entries(&disk_inode)?
+            .into_iter()
+            .map(|entry| (entry.inode, entry.name))
+            .collect())
+    }
+    fn io_control(&self, _cmd: u32, _data: usize) -> vfs::Result<usize> {
+        Err(FsError::IOCTLError)
+    }
+    fn mmap(&self, _area: MMapArea) -> vfs::Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn fs(&self) -> Arc<dyn vfs::FileSystem> {
+        self.fs.clone()
+    }
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Debug for Ext2INode {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(
+            f,
+            "INode {{ id: {}, disk: {:?} }}",
+            self.id, self.disk_inode
+        )
+    }
+}
+
+impl Drop for Ext2INode {
+    /// Auto sync when drop; free the inode and its blocks if it has no links left
+    fn drop(&mut self) {
+        if self.disk_inode.read().links_count == 0 && !self.fs.read_only {
+            let mut disk_inode = self.disk_inode.write();
+            if !self.is_fast_symlink(&disk_inode) {
+                self.free_blocks_from(&mut disk_inode, 0)
+                    .expect("Failed to free the blocks of the ext2 inode");
+            }
+            if disk_inode.file_acl != 0 {
+                self.fs
+                    .release_xattr_block(disk_inode.file_acl)
+                    .expect("Failed to release the extended attributes of the ext2 inode");
+                disk_inode.file_acl = 0;
+            }
+            disk_inode.dtime = now();
+            let is_dir = disk_inode.file_type() == S_IFDIR;
+            drop(disk_inode);
+            self.sync_all()
+                .expect("Failed to sync when dropping the ext2 inode");
+            self.fs
+                .free_inode(self.id, is_dir)
+                .expect("Failed to free the ext2 inode");
+        } else {
+            self.sync_all()
+                .expect("Failed to sync when dropping the ext2 inode");
+        }
+    }
+}
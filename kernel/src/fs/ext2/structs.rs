@@ -0,0 +1,386 @@
+//! On-disk structures of ext2 (revision 1, as created by `mke2fs -t ext2`).
+
+use core::mem::size_of_val;
+use core::slice;
+
+/// superblock (on disk), always at byte 1024 of the device
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct SuperBlock {
+    /// total number of inodes
+    pub inodes_count: u32,
+    /// total number of blocks
+    pub blocks_count: u32,
+    /// number of blocks reserved for the superuser
+    pub r_blocks_count: u32,
+    /// number of free blocks
+    pub free_blocks_count: u32,
+    /// number of free inodes
+    pub free_inodes_count: u32,
+    /// id of the block containing the superblock (1 for 1K blocks, 0 otherwise)
+    pub first_data_block: u32,
+    /// block size is `1024 << log_block_size`
+    pub log_block_size: u32,
+    /// fragment size (unused)
+    pub log_frag_size: u32,
+    /// number of blocks in each group
+    pub blocks_per_group: u32,
+    /// number of fragments in each group (unused)
+    pub frags_per_group: u32,
+    /// number of inodes in each group
+    pub inodes_per_group: u32,
+    /// time of last mount
+    pub mtime: u32,
+    /// time of last write
+    pub wtime: u32,
+    /// number of mounts since the last check
+    pub mnt_count: u16,
+    /// number of mounts allowed before a check
+    pub max_mnt_count: i16,
+    /// magic number, should be EXT2_MAGIC
+    pub magic: u16,
+    /// one of EXT2_*_FS
+    pub state: u16,
+    /// what to do on errors
+    pub errors: u16,
+    /// minor revision level
+    pub minor_rev_level: u16,
+    /// time of last check
+    pub lastcheck: u32,
+    /// max time between checks
+    pub checkinterval: u32,
+    /// OS that created the filesystem
+    pub creator_os: u32,
+    /// revision level
+    pub rev_level: u32,
+    /// default uid for reserved blocks
+    pub def_resuid: u16,
+    /// default gid for reserved blocks
+    pub def_resgid: u16,
+    /// first non-reserved inode
+    pub first_ino: u32,
+    /// size of an inode
+    pub inode_size: u16,
+    /// group of this superblock copy
+    pub block_group_nr: u16,
+    /// compatible features
+    pub feature_compat: u32,
+    /// incompatible features, the filesystem must not be mounted if unknown
+    pub feature_incompat: u32,
+    /// read-only compatible features, the filesystem must be mounted read-only if unknown
+    pub feature_ro_compat: u32,
+    /// volume uuid
+    pub uuid: [u8; 16],
+    /// volume name
+    pub volume_name: [u8; 16],
+    /// directory where last mounted
+    pub last_mounted: [u8; 64],
+    /// compression algorithms (unused)
+    pub algorithm_usage_bitmap: u32,
+    /// number of blocks to preallocate for files
+    pub prealloc_blocks: u8,
+    /// number of blocks to preallocate for directories
+    pub prealloc_dir_blocks: u8,
+    pub padding: u16,
+    /// uuid of the journal (ext3)
+    pub journal_uuid: [u8; 16],
+    /// inode of the journal (ext3)
+    pub journal_inum: u32,
+    /// device of the journal (ext3)
+    pub journal_dev: u32,
+    /// head of the orphan inode list
+    pub last_orphan: u32,
+    /// seeds of the directory hash
+    pub hash_seed: [u32; 4],
+    /// default directory hash
+    pub def_hash_version: u8,
+    pub reserved_char_pad: u8,
+    /// size of a group descriptor (64bit only)
+    pub desc_size: u16,
+    /// default mount options
+    pub default_mount_opts: u32,
+    /// first metablock group
+    pub first_meta_bg: u32,
+    pub reserved: [u8; 760],
+}
+
+/// block group descriptor (on disk), in the blocks right after the superblock
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct GroupDesc {
+    /// block of the block bitmap
+    pub block_bitmap: u32,
+    /// block of the inode bitmap
+    pub inode_bitmap: u32,
+    /// 1st block of the inode table
+    pub inode_table: u32,
+    /// number of free blocks in the group
+    pub free_blocks_count: u16,
+    /// number of free inodes in the group
+    pub free_inodes_count: u16,
+    /// number of directories in the group
+    pub used_dirs_count: u16,
+    pub pad: u16,
+    pub reserved: [u32; 3],
+}
+
+/// inode (on disk)
+/// Only the first 128 bytes are used; larger inodes keep the rest untouched.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct DiskINode {
+    /// file type and permissions
+    pub mode: u16,
+    /// low 16 bits of the owner
+    pub uid: u16,
+    /// low 32 bits of the size
+    pub size: u32,
+    /// time of last access
+    pub atime: u32,
+    /// time of last change
+    pub ctime: u32,
+    /// time of last modification
+    pub mtime: u32,
+    /// time of deletion
+    pub dtime: u32,
+    /// low 16 bits of the group
+    pub gid: u16,
+    /// number of hard links
+    pub links_count: u16,
+    /// number of 512-byte sectors used, including the indirect blocks
+    pub blocks: u32,
+    /// EXT2_*_FL
+    pub flags: u32,
+    pub osd1: u32,
+    /// direct, indirect, double and triple indirect blocks; the target of a fast symlink
+    pub block: [u32; N_BLOCKS],
+    /// file version (NFS)
+    pub generation: u32,
+    /// block of the extended attributes
+    pub file_acl: u32,
+    /// high 32 bits of the size of a regular file
+    pub size_high: u32,
+    /// fragment address (unused)
+    pub faddr: u32,
+    pub frag: u8,
+    pub fsize: u8,
+    pub pad1: u16,
+    /// high 16 bits of the owner
+    pub uid_high: u16,
+    /// high 16 bits of the group
+    pub gid_high: u16,
+    pub reserved2: u32,
+}
+
+/// header of a directory entry (on disk), followed by the name
+/// Entries are 4-byte aligned and never cross a block; `rec_len` covers the unused space after the name.
+#[derive(Debug, Clone, Copy)]
+pub struct DiskDirEntry {
+    /// inode number, 0 if the entry is unused
+    pub inode: u32,
+    /// distance to the next entry
+    pub rec_len: u16,
+    /// length of the name
+    pub name_len: u8,
+    /// one of EXT2_FT_*, if the filesystem has EXT2_FEATURE_INCOMPAT_FILETYPE
+    pub file_type: u8,
+}
+
+impl SuperBlock {
+    pub fn check(&self) -> bool {
+        self.magic == EXT2_MAGIC
+    }
+    /// Gets the size of a block.
+    pub fn block_size(&self) -> usize {
+        1024 << self.log_block_size
+    }
+    /// Gets the size of an inode.
+    pub fn inode_size(&self) -> usize {
+        match self.rev_level {
+            GOOD_OLD_REV => GOOD_OLD_INODE_SIZE,
+            _ => self.inode_size as usize,
+        }
+    }
+    /// Gets the first inode that is not reserved.
+    pub fn first_ino(&self) -> u32 {
+        match self.rev_level {
+            GOOD_OLD_REV => GOOD_OLD_FIRST_INO,
+            _ => self.first_ino,
+        }
+    }
+    /// Gets the number of block groups.
+    pub fn groups_count(&self) -> usize {
+        let blocks = (self.blocks_count - self.first_data_block) as usize;
+        (blocks + self.blocks_per_group as usize - 1) / self.blocks_per_group as usize
+    }
+}
+
+impl DiskINode {
+    /// Creates an empty inode with the given mode.
+    pub fn new(mode: u16, now: u32) -> Self {
+        DiskINode {
+            mode,
+            uid: 0,
+            size: 0,
+            atime: now,
+            ctime: now,
+            mtime: now,
+            dtime: 0,
+            gid: 0,
+            links_count: 0,
+            blocks: 0,
+            flags: 0,
+            osd1: 0,
+            block: [0; N_BLOCKS],
+            generation: 0,
+            file_acl: 0,
+            size_high: 0,
+            faddr: 0,
+            frag: 0,
+            fsize: 0,
+            pad1: 0,
+            uid_high: 0,
+            gid_high: 0,
+            reserved2: 0,
+        }
+    }
+    /// Gets the type of the file.
+    pub fn file_type(&self) -> u16 {
+        self.mode & S_IFMT
+    }
+    /// Gets the size of the file (in bytes).
+    pub fn size(&self) -> usize {
+        match self.file_type() {
+            S_IFREG => self.size as usize | ((self.size_high as usize) << 32),
+            _ => self.size as usize,
+        }
+    }
+    /// Sets the size of the file (in bytes).
+    pub fn set_size(&mut self, size: usize) {
+        self.size = size as u32;
+        if self.file_type() == S_IFREG {
+            self.size_high = (size >> 32) as u32;
+        }
+    }
+}
+
+impl DiskDirEntry {
+    /// Parses the header at the beginning of `buf`.
+    pub fn parse(buf: &[u8]) -> Self {
+        DiskDirEntry {
+            inode: u32::from_le_bytes(buf[0..4].try_into().unwrap()),
+            rec_len: u16::from_le_bytes(buf[4..6].try_into().unwrap()),
+            name_len: buf[6],
+            file_type: buf[7],
+        }
+    }
+    /// Writes the header to the beginning of `buf`.
+    pub fn write(&self, buf: &mut [u8]) {
+        buf[0..4].copy_from_slice(&self.inode.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.rec_len.to_le_bytes());
+        buf[6] = self.name_len;
+        buf[7] = self.file_type;
+    }
+    /// Gets the space needed by an entry whose name has `name_len` bytes.
+    pub const fn rec_len_of(name_len: usize) -> usize {
+        (DIRENT_HEADER_SIZE + name_len + 3) & !3
+    }
+}
+
+/// Convert structs to [u8] slice
+pub trait AsBuf {
+    fn as_buf(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const _ as *const u8, size_of_val(self)) }
+    }
+    fn as_buf_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self as *mut _ as *mut u8, size_of_val(self)) }
+    }
+}
+
+impl AsBuf for SuperBlock {}
+
+impl AsBuf for GroupDesc {}
+
+impl AsBuf for DiskINode {}
+
+impl AsBuf for [u32; N_BLOCKS] {}
+
+impl AsBuf for u32 {}
+
+pub type BlockId = usize;
+pub type INodeId = usize;
+
+/// magic number for ext2
+pub const EXT2_MAGIC: u16 = 0xef53;
+/// byte offset of the superblock
+pub const SUPERBLOCK_OFFSET: usize = 1024;
+/// size of the superblock
+pub const SUPERBLOCK_SIZE: usize = 1024;
+/// size of a group descriptor
+pub const GROUP_DESC_SIZE: usize = 32;
+/// the original revision with fixed inode sizes
+pub const GOOD_OLD_REV: u32 = 0;
+/// size of an inode in the original revision
+pub const GOOD_OLD_INODE_SIZE: usize = 128;
+/// first non-reserved inode in the original revision
+pub const GOOD_OLD_FIRST_INO: u32 = 11;
+/// inode of the root directory
+pub const ROOT_INO: INodeId = 2;
+/// the filesystem was unmounted cleanly
+pub const EXT2_VALID_FS: u16 = 1;
+/// number of direct blocks in inode
+pub const NDIR_BLOCKS: usize = 12;
+/// index of the indirect block in `DiskINode::block`
+pub const IND_BLOCK: usize = NDIR_BLOCKS;
+/// index of the double indirect block in `DiskINode::block`
+pub const DIND_BLOCK: usize = IND_BLOCK + 1;
+/// index of the triple indirect block in `DiskINode::block`
+pub const TIND_BLOCK: usize = DIND_BLOCK + 1;
+/// number of blocks in `DiskINode::block`
+pub const N_BLOCKS: usize = TIND_BLOCK + 1;
+/// max length of the target of a fast symlink
+pub const FAST_SYMLINK_MAX_LEN: usize = N_BLOCKS * 4;
+/// max length of filename
+pub const MAX_NAME_LEN: usize = 255;
+/// size of a directory entry without the name
+pub const DIRENT_HEADER_SIZE: usize = 8;
+/// size of a block entry in the indirect blocks
+pub const ENTRY_SIZE: usize = 4;
+/// size of a sector, the unit of `DiskINode::blocks`
+pub const SECTOR_SIZE: usize = 512;
+/// magic number of an extended attribute block
+pub const XATTR_MAGIC: u32 = 0xea020000;
+
+/// directory entries carry file types
+pub const FEATURE_INCOMPAT_FILETYPE: u32 = 0x2;
+/// incompatible features this driver understands
+pub const SUPPORTED_INCOMPAT: u32 = FEATURE_INCOMPAT_FILETYPE;
+/// only some groups keep backups of the superblock
+pub const FEATURE_RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+/// regular files may be larger than 2 GiB
+pub const FEATURE_RO_COMPAT_LARGE_FILE: u32 = 0x2;
+/// read-only compatible features this driver can write
+pub const SUPPORTED_RO_COMPAT: u32 = FEATURE_RO_COMPAT_SPARSE_SUPER | FEATURE_RO_COMPAT_LARGE_FILE;
+
+/// the directory has a hashed index, which is only valid until modified by a driver not maintaining it
+pub const EXT2_INDEX_FL: u32 = 0x1000;
+
+/// file types in `DiskINode::mode`
+pub const S_IFMT: u16 = 0xf000;
+pub const S_IFSOCK: u16 = 0xc000;
+pub const S_IFLNK: u16 = 0xa000;
+pub const S_IFREG: u16 = 0x8000;
+pub const S_IFBLK: u16 = 0x6000;
+pub const S_IFDIR: u16 = 0x4000;
+pub const S_IFCHR: u16 = 0x2000;
+pub const S_IFIFO: u16 = 0x1000;
+
+/// file types in `DiskDirEntry::file_type`
+pub const EXT2_FT_UNKNOWN: u8 = 0;
+pub const EXT2_FT_REG_FILE: u8 = 1;
+pub const EXT2_FT_DIR: u8 = 2;
+pub const EXT2_FT_CHRDEV: u8 = 3;
+pub const EXT2_FT_BLKDEV: u8 = 4;
+pub const EXT2_FT_FIFO: u8 = 5;
+pub const EXT2_FT_SOCK: u8 = 6;
+pub const EXT2_FT_SYMLINK: u8 = 7;
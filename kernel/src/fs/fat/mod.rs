@@ -0,0 +1,1442 @@
+//! Implements FAT12, FAT16 and FAT32 with long file names, so that the EFI system partition can be mounted at
+//! `/boot` and the files read by the bootloader (`boot.cfg` and `kernel.img`) can be updated from inside the kernel.
+//!
+//! The volume starts with the reserved sectors (including the boot sector), followed by the FATs, the fixed root
+//! directory of FAT12/16 and the data clusters. A file is a chain of clusters linked through the FAT. The FAT is
+//! kept in memory and the modified sectors are written to every copy on `sync()`, while the directory entries are
+//! cached in the inodes until `sync_all()`.
+//!
+//! FAT has no inode numbers, so an inode is identified by the location of its short directory entry.
+//!
+//! Some useful links:
+//! * <https://academy.cba.mit.edu/classes/networking_communications/SD/FAT.pdf>
+
+use core::{
+    any::Any,
+    fmt::{Debug, Error, Formatter},
+    ptr,
+};
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use rcore_fs::{
+    dev::Device,
+    dirty::Dirty,
+    vfs::{self, FsError, INode, MMapArea, Metadata, Timespec},
+};
+use spin::RwLock;
+
+pub mod structs;
+pub use structs::*;
+
+use crate::{
+    function, kinfo, kwarn,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Key of the root directory in `FatFileSystem::inodes`; other inodes are keyed by the offsets of their entries.
+const ROOT_KEY: usize = 0;
+/// Inode number of the root directory.
+const ROOT_ID: usize = 1;
+
+trait DeviceExt: Device {
+    fn read_exact_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<()> {
+        match self.read_at(offset, buf) {
+            Ok(len) if len == buf.len() => Ok(()),
+            _ => Err(FsError::DeviceError),
+        }
+    }
+
+    fn write_all_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<()> {
+        match self.write_at(offset, buf) {
+            Ok(len) if len == buf.len() => Ok(()),
+            _ => Err(FsError::DeviceError),
+        }
+    }
+}
+
+impl DeviceExt for dyn Device {}
+
+/// Converts days since the epoch to (year, month, day).
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + (month <= 2) as i64, month, day)
+}
+
+/// Converts (year, month, day) to days since the epoch.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Converts seconds since the epoch to a FAT date and time, clamped to 1980-2107.
+fn to_fat_time(secs: u64) -> (u16, u16) {
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs = secs % 86400;
+    match year {
+        ..=1979 => ((1 << 5) | 1, 0),
+        2108.. => ((127 << 9) | (12 << 5) | 31, (23 << 11) | (59 << 5) | 29),
+        _ => (
+            (((year - 1980) as u16) << 9) | ((month as u16) << 5) | day as u16,
+            (((secs / 3600) as u16) << 11)
+                | (((secs / 60 % 60) as u16) << 5)
+                | (secs % 60 / 2) as u16,
+        ),
+    }
+}
+
+/// Converts a FAT date and time to a timestamp.
+fn from_fat_time(date: u16, time: u16) -> Timespec {
+    if date == 0 {
+        return Timespec { sec: 0, nsec: 0 };
+    }
+    let year = 1980 + (date >> 9) as i64;
+    let month = ((date >> 5) & 0xf).max(1) as i64;
+    let day = (date & 0x1f).max(1) as i64;
+    let secs = days_from_civil(year, month, day) * 86400
+        + (time >> 11) as i64 * 3600
+        + ((time >> 5) & 0x3f) as i64 * 60
+        + (time & 0x1f) as i64 * 2;
+    Timespec {
+        sec: secs as _,
+        nsec: 0,
+    }
+}
+
+/// Gets the current date and time.
+fn now() -> (u16, u16) {
+    to_fat_time(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs())
+            .unwrap_or_default(),
+    )
+}
+
+/// Checks if `c` may appear in a short name.
+fn is_short_char(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+/// Compares two names case-insensitively, as FAT does.
+fn name_eq(a: &str, b: &str) -> bool {
+    a.chars()
+        .flat_map(char::to_lowercase)
+        .eq(b.chars().flat_map(char::to_lowercase))
+}
+
+fn is_dot(name: &str) -> bool {
+    name == "." || name == ".."
+}
+
+/// Checks that `name` can be stored as a long name.
+fn check_name(name: &str) -> vfs::Result<()> {
+    if is_dot(name) {
+        return Err(FsError::EntryExist);
+    }
+    if name.is_empty()
+        || name.encode_utf16().count() > MAX_NAME_LEN
+        || name.ends_with(['.', ' '])
+        || name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c))
+    {
+        return Err(FsError::InvalidParam);
+    }
+    Ok(())
+}
+
+/// Gets the short name of `name` if it is a valid 8.3 name with a single case in each part.
+fn exact_short_name(name: &str) -> Option<([u8; SHORT_NAME_LEN], u8)> {
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || ext.contains('.') {
+        return None;
+    }
+
+    let mut nt_res = 0;
+    for (part, lower) in [(base, NT_LOWER_BASE), (ext, NT_LOWER_EXT)] {
+        if !part.chars().all(|c| is_short_char(c.to_ascii_uppercase())) {
+            return None;
+        }
+        match (
+            part.chars().any(|c| c.is_ascii_lowercase()),
+            part.chars().any(|c| c.is_ascii_uppercase()),
+        ) {
+            (true, true) => return None,
+            (true, false) => nt_res |= lower,
+            _ => {}
+        }
+    }
+
+    let mut short = [b' '; SHORT_NAME_LEN];
+    short[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+    Some((short, nt_res))
+}
+
+/// Gets the name of a short entry.
+fn short_name_to_string(entry: &DiskDirEntry) -> String {
+    let mut name = entry.name;
+    if name[0] == KANJI_MARK {
+        name[0] = DELETED_MARK;
+    }
+    let decode = |part: &[u8], lower: bool| {
+        let len = part.iter().rposition(|&c| c != b' ').map_or(0, |i| i + 1);
+        part[..len]
+            .iter()
+            .map(|&c| match lower {
+                true => c.to_ascii_lowercase() as char,
+                false => c as char,
+            })
+            .collect::<String>()
+    };
+
+    let mut string = decode(&name[..8], entry.nt_res & NT_LOWER_BASE != 0);
+    let ext = decode(&name[8..], entry.nt_res & NT_LOWER_EXT != 0);
+    if !ext.is_empty() {
+        string.push('.');
+        string.push_str(&ext);
+    }
+    string
+}
+
+/// The slots of a directory read into memory
+struct DirBuf {
+    data: Vec<u8>,
+    /// clusters of the directory, empty for the fixed root directory
+    clusters: Vec<u32>,
+    /// offset of each region (the fixed root directory, or each cluster) on the device
+    regions: Vec<usize>,
+    region_size: usize,
+}
+
+impl DirBuf {
+    fn slots(&self) -> usize {
+        self.data.len() / DIR_ENTRY_SIZE
+    }
+    fn slot(&self, index: usize) -> &[u8] {
+        &self.data[index * DIR_ENTRY_SIZE..(index + 1) * DIR_ENTRY_SIZE]
+    }
+    /// Gets the offset of a slot on the device
+    fn slot_offset(&self, index: usize) -> usize {
+        let pos = index * DIR_ENTRY_SIZE;
+        self.regions[pos / self.region_size] + pos % self.region_size
+    }
+    /// Parses the entries in use, including `.` and `..`
+    fn entries(&self) -> Vec<DirEntry> {
+        let mut entries = Vec::new();
+        // (characters, checksum, first slot, next order expected)
+        let mut long: Option<(Vec<u16>, u8, usize, u8)> = None;
+        for index in 0..self.slots() {
+            let slot = self.slot(index);
+            match slot[0] {
+                0 => break,
+                DELETED_MARK => {
+                    long = None;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let mut short = DiskDirEntry::default();
+            short.as_buf_mut().copy_from_slice(slot);
+            if short.is_long_name() {
+                let part = LongNameEntry(slot);
+                let order = part.order() & !LAST_LONG_ENTRY;
+                if part.order() & LAST_LONG_ENTRY != 0 {
+                    long = (1..=20).contains(&order).then(|| {
+                        let chars = vec![0u16; order as usize * LONG_NAME_CHARS];
+                        (chars, part.checksum(), index, order)
+                    });
+                }
+                match long.as_mut() {
+                    Some((chars, checksum, _, next))
+                        if *next == order && order != 0 && *checksum == part.checksum() =>
+                    {
+                        let begin = (order as usize - 1) * LONG_NAME_CHARS;
+                        for (i, c) in part.chars().enumerate() {
+                            chars[begin + i] = c;
+                        }
+                        *next -= 1;
+                    }
+                    _ => long = None,
+                }
+                continue;
+            }
+            if short.attr & ATTR_VOLUME_ID != 0 {
+                long = None;
+                continue;
+            }
+
+            let (name, first_slot) = match long.take() {
+                Some((chars, checksum, first_slot, 0)) if checksum == short.checksum() => {
+                    let len = chars.iter().position(|&c| c == 0).unwrap_or(chars.len());
+                    (String::from_utf16_lossy(&chars[..len]), first_slot)
+                }
+                _ => (short_name_to_string(&short), index),
+            };
+            entries.push(DirEntry {
+                name,
+                short,
+                first_slot,
+                slot: index,
+            });
+        }
+        entries
+    }
+}
+
+/// An entry in use in a directory
+struct DirEntry {
+    /// long name if there is one, otherwise the short name
+    name: String,
+    short: DiskDirEntry,
+    /// first slot, which holds the last part of the long name
+    first_slot: usize,
+    /// slot of the short entry
+    slot: usize,
+}
+
+/// The File Allocation Table
+struct FatTable {
+    fat_type: FatType,
+    bytes_per_sector: usize,
+    /// the active FAT
+    bytes: Vec<u8>,
+    /// sectors modified since the last sync
+    dirty: BTreeSet<usize>,
+    /// number of free clusters
+    free: usize,
+    /// where to start searching for a free cluster
+    next_free: u32,
+}
+
+impl FatTable {
+    fn get(&self, cluster: u32) -> u32 {
+        let c = cluster as usize;
+        match self.fat_type {
+            FatType::Fat12 => {
+                let offset = c + c / 2;
+                let value = u16::from_le_bytes([self.bytes[offset], self.bytes[offset + 1]]);
+                match c % 2 {
+                    1 => (value >> 4) as u32,
+                    _ => (value & 0xfff) as u32,
+                }
+            }
+            FatType::Fat16 => u16::from_le_bytes([self.bytes[2 * c], self.bytes[2 * c + 1]]) as u32,
+            FatType::Fat32 => {
+                u32::from_le_bytes(self.bytes[4 * c..4 * c + 4].try_into().unwrap()) & 0x0fff_ffff
+            }
+        }
+    }
+
+    fn set(&mut self, cluster: u32, value: u32) {
+        let c = cluster as usize;
+        let (offset, len) = match self.fat_type {
+            FatType::Fat12 => {
+                let offset = c + c / 2;
+                let old = u16::from_le_bytes([self.bytes[offset], self.bytes[offset + 1]]);
+                let new = match c % 2 {
+                    1 => (old & 0x000f) | ((value as u16) << 4),
+                    _ => (old & 0xf000) | (value as u16 & 0x0fff),
+                };
+                self.bytes[offset..offset + 2].copy_from_slice(&new.to_le_bytes());
+                (offset, 2)
+            }
+            FatType::Fat16 => {
+                self.bytes[2 * c..2 * c + 2].copy_from_slice(&(value as u16).to_le_bytes());
+                (2 * c, 2)
+            }
+            FatType::Fat32 => {
+                // The high 4 bits are reserved.
+                let old = u32::from_le_bytes(self.bytes[4 * c..4 * c + 4].try_into().unwrap());
+                let new = (old & 0xf000_0000) | (value & 0x0fff_ffff);
+                self.bytes[4 * c..4 * c + 4].copy_from_slice(&new.to_le_bytes());
+                (4 * c, 4)
+            }
+        };
+        self.dirty.insert(offset / self.bytes_per_sector);
+        self.dirty
+            .insert((offset + len - 1) / self.bytes_per_sector);
+    }
+}
+
+pub struct FatFileSystem {
+    device: Arc<dyn Device>,
+    /// Pointer to self, used by INodes
+    self_ptr: Weak<FatFileSystem>,
+    fat_type: FatType,
+    /// size of a cluster
+    cluster_size: usize,
+    /// number of data clusters
+    clusters: usize,
+    /// offsets of the FATs to write back; the first one is the active FAT
+    fat_offsets: Vec<usize>,
+    /// offset of the fixed root directory of FAT12/16
+    root_offset: usize,
+    /// size of the fixed root directory of FAT12/16
+    root_size: usize,
+    /// first cluster of the root directory of FAT32
+    root_cluster: u32,
+    /// offset of cluster 2
+    data_offset: usize,
+    /// offset of the FSInfo sector of FAT32
+    fs_info_offset: Option<usize>,
+    fat: RwLock<FatTable>,
+    /// inode list
+    inodes: RwLock<BTreeMap<usize, Weak<FatINode>>>,
+    /// held for writing while the directories are being changed
+    tree: RwLock<()>,
+}
+
+impl vfs::FileSystem for FatFileSystem {
+    /// Write back the FAT, the FSInfo and the directory entries if dirty
+    fn sync(&self) -> vfs::Result<()> {
+        {
+            let mut fat = self.fat.write();
+            let bytes_per_sector = fat.bytes_per_sector;
+            for sector in core::mem::take(&mut fat.dirty) {
+                let offset = sector * bytes_per_sector;
+                for fat_offset in self.fat_offsets.iter() {
+                    self.device.write_all_at(
+                        fat_offset + offset,
+                        &fat.bytes[offset..offset + bytes_per_sector],
+                    )?;
+                }
+            }
+            if let Some(offset) = self.fs_info_offset {
+                let mut buf = [0u8; 8];
+                buf[..4].copy_from_slice(&(fat.free as u32).to_le_bytes());
+                buf[4..].copy_from_slice(&fat.next_free.to_le_bytes());
+                self.device
+                    .write_all_at(offset + FS_INFO_FREE_COUNT_OFFSET, &buf)?;
+            }
+        }
+
+        self.flush_weak_inodes();
+        for inode in self.inodes.read().values() {
+            if let Some(inode) = inode.upgrade() {
+                inode.sync_all()?;
+            }
+        }
+        self.device.sync()?;
+        Ok(())
+    }
+
+    fn root_inode(&self) -> Arc<dyn vfs::INode> {
+        let mut entry = DiskDirEntry {
+            attr: ATTR_DIRECTORY,
+            ..Default::default()
+        };
+        entry.set_cluster(self.root_cluster);
+        self.get_inode(ROOT_KEY, &entry, None)
+    }
+
+    fn info(&self) -> vfs::FsInfo {
+        let free = self.fat.read().free;
+        vfs::FsInfo {
+            bsize: self.cluster_size,
+            frsize: self.cluster_size,
+            blocks: self.clusters,
+            bfree: free,
+            bavail: free,
+            files: 0,
+            ffree: 0,
+            namemax: MAX_NAME_LEN,
+        }
+    }
+}
+
+impl FatFileSystem {
+    /// Load a FAT volume from device
+    pub fn open(device: Arc<dyn Device>) -> vfs::Result<Arc<Self>> {
+        let mut boot_sector = vec![0u8; SECTOR_SIZE];
+        device.read_exact_at(0, &mut boot_sector)?;
+        let bpb = BiosParameterBlock::parse(&boot_sector).ok_or(FsError::WrongFs)?;
+        let fat_type = FatType::from_clusters(bpb.clusters());
+        if (fat_type == FatType::Fat32) != (bpb.root_entries == 0) {
+            return Err(FsError::WrongFs);
+        }
+
+        let bytes_per_sector = bpb.bytes_per_sector as usize;
+        let fat_size = bpb.sectors_per_fat() * bytes_per_sector;
+        let fat_offset = |i: usize| bpb.reserved_sectors as usize * bytes_per_sector + i * fat_size;
+        // FAT32 may update only one FAT.
+        let fat_offsets = match fat_type == FatType::Fat32 && bpb.ext_flags & 0x80 != 0 {
+            true => match (bpb.ext_flags & 0xf) as usize {
+                active if active < bpb.fats as usize => vec![fat_offset(active)],
+                _ => return Err(FsError::WrongFs),
+            },
+            false => (0..bpb.fats as usize).map(fat_offset).collect(),
+        };
+        let mut bytes = vec![0u8; fat_size];
+        device.read_exact_at(fat_offsets[0], &mut bytes)?;
+
+        // The clusters that are not covered by the FAT cannot be used.
+        let capacity = match fat_type {
+            FatType::Fat12 => fat_size * 2 / 3,
+            FatType::Fat16 => fat_size / 2,
+            FatType::Fat32 => fat_size / 4,
+        };
+        let clusters = bpb
+            .clusters()
+            .min(capacity.saturating_sub(FIRST_CLUSTER as usize));
+        let mut fat = FatTable {
+            fat_type,
+            bytes_per_sector,
+            bytes,
+            dirty: BTreeSet::new(),
+            free: 0,
+            next_free: FIRST_CLUSTER,
+        };
+        fat.free = (FIRST_CLUSTER..FIRST_CLUSTER + clusters as u32)
+            .filter(|&cluster| fat.get(cluster) == 0)
+            .count();
+
+        let mut fs_info_offset = match fat_type {
+            FatType::Fat32 if bpb.fs_info_sector != 0 && bpb.fs_info_sector != 0xffff => {
+                Some(bpb.fs_info_sector as usize * bytes_per_sector)
+            }
+            _ => None,
+        };
+        if let Some(offset) = fs_info_offset {
+            let mut fs_info = vec![0u8; SECTOR_SIZE];
+            device.read_exact_at(offset, &mut fs_info)?;
+            let u32_at =
+                |offset: usize| u32::from_le_bytes(fs_info[offset..offset + 4].try_into().unwrap());
+            if u32_at(0) != FS_INFO_LEAD_SIGNATURE
+                || u32_at(FS_INFO_STRUCT_SIGNATURE_OFFSET) != FS_INFO_STRUCT_SIGNATURE
+            {
+                kwarn!("the FSInfo sector is corrupted; ignoring it");
+                fs_info_offset = None;
+            } else if u32_at(FS_INFO_NEXT_FREE_OFFSET) != FS_INFO_UNKNOWN {
+                fat.next_free = u32_at(FS_INFO_NEXT_FREE_OFFSET);
+            }
+        }
+
+        let cluster_size = bpb.sectors_per_cluster as usize * bytes_per_sector;
+        kinfo!(
+            "{:?}: {} clusters of {} bytes, {} free.",
+            fat_type,
+            clusters,
+            cluster_size,
+            fat.free
+        );
+
+        Ok(FatFileSystem {
+            device,
+            self_ptr: Weak::default(),
+            fat_type,
+            cluster_size,
+            clusters,
+            fat_offsets,
+            root_offset: fat_offset(bpb.fats as usize),
+            root_size: bpb.root_entries as usize * DIR_ENTRY_SIZE,
+            root_cluster: match fat_type {
+                FatType::Fat32 => bpb.root_cluster,
+                _ => 0,
+            },
+            data_offset: bpb.first_data_sector() * bytes_per_sector,
+            fs_info_offset,
+            fat: RwLock::new(fat),
+            inodes: RwLock::new(BTreeMap::new()),
+            tree: RwLock::new(()),
+        }
+        .wrap())
+    }
+    /// Wrap pure FatFileSystem with Arc
+    /// Used in constructors
+    fn wrap(self) -> Arc<Self> {
+        let fs = Arc::new(self);
+        let weak = Arc::downgrade(&fs);
+        let ptr = Arc::into_raw(fs) as *mut Self;
+        unsafe {
+            (*ptr).self_ptr = weak;
+        }
+        unsafe { Arc::from_raw(ptr) }
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> usize {
+        self.data_offset + (cluster - FIRST_CLUSTER) as usize * self.cluster_size
+    }
+    fn is_data_cluster(&self, cluster: u32) -> bool {
+        cluster >= FIRST_CLUSTER && ((cluster - FIRST_CLUSTER) as usize) < self.clusters
+    }
+    /// Get the clusters of the chain starting from `start`, which is 0 for an empty chain
+    fn chain(&self, start: u32) -> vfs::Result<Vec<u32>> {
+        let fat = self.fat.read();
+        let mut chain = Vec::new();
+        let mut cluster = start;
+        while cluster != 0 && cluster < self.fat_type.end_of_chain() {
+            if !self.is_data_cluster(cluster) || chain.len() >= self.clusters {
+                kwarn!("broken cluster chain from {:#x}", start);
+                return Err(FsError::DeviceError);
+            }
+            chain.push(cluster);
+            cluster = fat.get(cluster);
+        }
+        Ok(chain)
+    }
+    /// Allocate a zeroed cluster and append it to the chain ending at `prev` (0 for a new chain)
+    fn alloc_cluster(&self, prev: u32) -> vfs::Result<u32> {
+        let cluster = {
+            let mut fat = self.fat.write();
+            let end = FIRST_CLUSTER + self.clusters as u32;
+            let next = match (FIRST_CLUSTER..end).contains(&fat.next_free) {
+                true => fat.next_free,
+                false => FIRST_CLUSTER,
+            };
+            let cluster = (next..end)
+                .chain(FIRST_CLUSTER..next)
+                .find(|&cluster| fat.get(cluster) == 0)
+                .ok_or(FsError::NoDeviceSpace)?;
+            fat.set(cluster, self.fat_type.mask());
+            if prev != 0 {
+                fat.set(prev, cluster);
+            }
+            fat.free -= 1;
+            fat.next_free = cluster + 1;
+            cluster
+        };
+        self.device
+            .write_all_at(self.cluster_offset(cluster), &vec![0u8; self.cluster_size])?;
+        Ok(cluster)
+    }
+    /// Free clusters
+    fn free_clusters(&self, clusters: &[u32]) {
+        let mut fat = self.fat.write();
+        for &cluster in clusters {
+            fat.set(cluster, 0);
+        }
+        fat.free += clusters.len();
+    }
+    /// Mark `cluster` as the last one of its chain
+    fn set_end_of_chain(&self, cluster: u32) {
+        self.fat.write().set(cluster, self.fat_type.mask());
+    }
+
+    /// Get the inode of the entry at `key`. Load if not in memory.
+    fn get_inode(
+        &self,
+        key: usize,
+        entry: &DiskDirEntry,
+        parent: Option<Arc<FatINode>>,
+    ) -> Arc<FatINode> {
+        let mut inodes = self.inodes.write();
+        if let Some(inode) = inodes.get(&key).and_then(Weak::upgrade) {
+            return inode;
+        }
+        let inode = Arc::new_cyclic(|self_ptr| FatINode {
+            fs: self.self_ptr.upgrade().unwrap(),
+            self_ptr: self_ptr.clone(),
+            inner: RwLock::new(FatINodeInner {
+                key,
+                entry: Dirty::new(*entry),
+                parent,
+                removed: false,
+            }),
+        });
+        inodes.insert(key, Arc::downgrade(&inode));
+        inode
+    }
+    fn flush_weak_inodes(&self) {
+        let mut inodes = self.inodes.write();
+        let remove_keys: Vec<_> = inodes
+            .iter()
+            .filter(|(_, inode)| inode.upgrade().is_none())
+            .map(|(&key, _)| key)
+            .collect();
+        for key in remove_keys.iter() {
+            inodes.remove(key);
+        }
+    }
+}
+
+struct FatINodeInner {
+    /// offset of the short entry on the device, or ROOT_KEY
+    key: usize,
+    /// the short entry
+    entry: Dirty<DiskDirEntry>,
+    /// parent directory, None for the root
+    parent: Option<Arc<FatINode>>,
+    /// the entry is removed, and the clusters are freed on drop
+    removed: bool,
+}
+
+impl FatINodeInner {
+    fn id(&self) -> usize {
+        match self.key {
+            ROOT_KEY => ROOT_ID,
+            key => key / DIR_ENTRY_SIZE,
+        }
+    }
+    /// Update the modification time
+    fn touch(&mut self) {
+        if self.key != ROOT_KEY {
+            let (date, time) = now();
+            self.entry.wrt_date = date;
+            self.entry.wrt_time = time;
+            self.entry.attr |= ATTR_ARCHIVE;
+        }
+    }
+}
+
+/// INode for FAT
+pub struct FatINode {
+    /// Reference to FAT, used by almost all operations
+    fs: Arc<FatFileSystem>,
+    self_ptr: Weak<FatINode>,
+    inner: RwLock<FatINodeInner>,
+}
+
+impl FatINode {
+    fn arc(&self) -> Arc<FatINode> {
+        self.self_ptr.upgrade().unwrap()
+    }
+    fn is_fixed_root(&self, inner: &FatINodeInner) -> bool {
+        inner.key == ROOT_KEY && self.fs.fat_type != FatType::Fat32
+    }
+    fn check_dir(inner: &FatINodeInner) -> vfs::Result<()> {
+        if !inner.entry.is_dir() {
+            return Err(FsError::NotDir);
+        }
+        if inner.removed {
+            return Err(FsError::DirRemoved);
+        }
+        Ok(())
+    }
+    /// Gets the bytes allocated to the file
+    fn allocated(&self, inner: &FatINodeInner) -> vfs::Result<usize> {
+        match self.is_fixed_root(inner) {
+            true => Ok(self.fs.root_size),
+            false => Ok(self.fs.chain(inner.entry.cluster())?.len() * self.fs.cluster_size),
+        }
+    }
+    // Note: the _\w*_at method always return begin>size?0:begin<end?0:(min(size,end)-begin) when success
+    /// Read content
+    fn _read_at(&self, inner: &FatINodeInner, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
+        let size = inner.entry.size as usize;
+        let begin = size.min(offset);
+        let end = size.min(offset + buf.len());
+        let chain = self.fs.chain(inner.entry.cluster())?;
+        let cluster_size = self.fs.cluster_size;
+
+        let mut pos = begin;
+        while pos < end {
+            let cluster = *chain.get(pos / cluster_size).ok_or(FsError::DeviceError)?;
+            let len = (cluster_size - pos % cluster_size).min(end - pos);
+            self.fs.device.read_exact_at(
+                self.fs.cluster_offset(cluster) + pos % cluster_size,
+                &mut buf[pos - begin..pos - begin + len],
+            )?;
+            pos += len;
+        }
+        Ok(end - begin)
+    }
+    /// Make the chain at least `count` clusters long
+    fn extend_chain(&self, inner: &mut FatINodeInner, count: usize) -> vfs::Result<Vec<u32>> {
+        let mut chain = self.fs.chain(inner.entry.cluster())?;
+        while chain.len() < count {
+            let cluster = self.fs.alloc_cluster(chain.last().copied().unwrap_or(0))?;
+            if chain.is_empty() {
+                inner.entry.set_cluster(cluster);
+            }
+            chain.push(cluster);
+        }
+        Ok(chain)
+    }
+    /// Resize content size, zero-filling when growing
+    fn _resize(&self, inner: &mut FatINodeInner, len: usize) -> vfs::Result<()> {
+        if len > MAX_FILE_SIZE {
+            return Err(FsError::InvalidParam);
+        }
+        let cluster_size = self.fs.cluster_size;
+        let size = inner.entry.size as usize;
+        let count = (len + cluster_size - 1) / cluster_size;
+        if len > size {
+            // The tail of the last cluster may hold stale data.
+            if size % cluster_size != 0 {
+                let chain = self.fs.chain(inner.entry.cluster())?;
+                let cluster = *chain.get(size / cluster_size).ok_or(FsError::DeviceError)?;
+                let tail = vec![0u8; cluster_size - size % cluster_size];
+                self.fs
+                    .device
+                    .write_all_at(self.fs.cluster_offset(cluster) + size % cluster_size, &tail)?;
+            }
+            self.extend_chain(inner, count)?;
+        } else {
+            let chain = self.fs.chain(inner.entry.cluster())?;
+            if chain.len() > count {
+                if count == 0 {
+                    inner.entry.set_cluster(0);
+                } else {
+                    self.fs.set_end_of_chain(chain[count - 1]);
+                }
+                self.fs.free_clusters(&chain[count..]);
+            }
+        }
+        inner.entry.size = len as u32;
+        Ok(())
+    }
+    /// Write content, growing the file if needed
+    fn _write_at(
+        &self,
+        inner: &mut FatINodeInner,
+        offset: usize,
+        buf: &[u8],
+    ) -> vfs::Result<usize> {
+        let end = offset + buf.len();
+        if end > MAX_FILE_SIZE {
+            return Err(FsError::InvalidParam);
+        }
+        if offset > inner.entry.size as usize {
+            self._resize(inner, offset)?;
+        }
+        let cluster_size = self.fs.cluster_size;
+        let chain = self.extend_chain(inner, (end + cluster_size - 1) / cluster_size)?;
+
+        let mut pos = offset;
+        while pos < end {
+            let len = (cluster_size - pos % cluster_size).min(end - pos);
+            self.fs.device.write_all_at(
+                self.fs.cluster_offset(chain[pos / cluster_size]) + pos % cluster_size,
+                &buf[pos - offset..pos - offset + len],
+            )?;
+            pos += len;
+        }
+        if end > inner.entry.size as usize {
+            inner.entry.size = end as u32;
+        }
+        Ok(buf.len())
+    }
+    /// Read the slots of a directory
+    fn read_dir(&self, inner: &FatINodeInner) -> vfs::Result<DirBuf> {
+        if self.is_fixed_root(inner) {
+            let mut data = vec![0u8; self.fs.root_size];
+            self.fs
+                .device
+                .read_exact_at(self.fs.root_offset, &mut data)?;
+            return Ok(DirBuf {
+                data,
+                clusters: Vec::new(),
+                regions: vec![self.fs.root_offset],
+                region_size: self.fs.root_size,
+            });
+        }
+
+        let cluster_size = self.fs.cluster_size;
+        let clusters = self.fs.chain(inner.entry.cluster())?;
+        let mut data = vec![0u8; clusters.len() * cluster_size];
+        for (i, &cluster) in clusters.iter().enumerate() {
+            self.fs.device.read_exact_at(
+                self.fs.cluster_offset(cluster),
+                &mut data[i * cluster_size..(i + 1) * cluster_size],
+            )?;
+        }
+        Ok(DirBuf {
+            data,
+            regions: clusters
+                .iter()
+                .map(|&cluster| self.fs.cluster_offset(cluster))
+                .collect(),
+            clusters,
+            region_size: cluster_size,
+        })
+    }
+    /// Only for Dir
+    fn entries(&self, inner: &FatINodeInner) -> vfs::Result<(DirBuf, Vec<DirEntry>)> {
+        let buf = self.read_dir(inner)?;
+        let entries = buf.entries();
+        Ok((buf, entries))
+    }
+    /// Find the entry `name` other than `.` and `..`
+    fn lookup<'a>(entries: &'a [DirEntry], name: &str) -> Option<&'a DirEntry> {
+        entries
+            .iter()
+            .find(|entry| !is_dot(&entry.name) && name_eq(&entry.name, name))
+    }
+    /// Get the inode of an entry of this directory
+    fn child(&self, buf: &DirBuf, entry: &DirEntry) -> Arc<FatINode> {
+        self.fs
+            .get_inode(buf.slot_offset(entry.slot), &entry.short, Some(self.arc()))
+    }
+    /// Pick the short name of a new entry, and whether a long name is needed
+    fn short_name_for(
+        entries: &[DirEntry],
+        name: &str,
+    ) -> vfs::Result<([u8; SHORT_NAME_LEN], u8, bool)> {
+        let used =
+            |short: &[u8; SHORT_NAME_LEN]| entries.iter().any(|entry| entry.short.name == *short);
+        if let Some((short, nt_res)) = exact_short_name(name) {
+            if !used(&short) {
+                return Ok((short, nt_res, false));
+            }
+        }
+
+        // Generate a numbered short name like "LONGNA~1.TXT".
+        let upper = name.to_uppercase();
+        let (base, ext) = match upper.rfind('.') {
+            Some(dot) if dot > 0 => (&upper[..dot], &upper[dot + 1..]),
+            _ => (&upper[..], ""),
+        };
+        let convert = |part: &str| {
+            part.chars()
+                .filter(|&c| c != ' ' && c != '.')
+                .map(|c| match is_short_char(c) {
+                    true => c as u8,
+                    false => b'_',
+                })
+                .collect::<Vec<_>>()
+        };
+        let base = convert(base);
+        let ext = convert(ext);
+        let ext = &ext[..ext.len().min(3)];
+        for n in 1..1_000_000 {
+            let tail = format!("~{n}");
+            let len = base.len().min(8 - tail.len());
+            let mut short = [b' '; SHORT_NAME_LEN];
+            short[..len].copy_from_slice(&base[..len]);
+            short[len..len + tail.len()].copy_from_slice(tail.as_bytes());
+            short[8..8 + ext.len()].copy_from_slice(ext);
+            if !used(&short) {
+                return Ok((short, 0, true));
+            }
+        }
+        Err(FsError::NoDeviceSpace)
+    }
+    /// Write a new entry with the long name `name`, growing the directory if needed. Returns the key of the inode
+    /// and the short entry written.
+    fn add_entry(
+        &self,
+        inner: &mut FatINodeInner,
+        mut buf: DirBuf,
+        entries: &[DirEntry],
+        name: &str,
+        mut short: DiskDirEntry,
+    ) -> vfs::Result<(usize, DiskDirEntry)> {
+        let (short_name, nt_res, has_long_name) = Self::short_name_for(entries, name)?;
+        short.name = short_name;
+        short.nt_res = nt_res;
+        let long_name: Vec<u16> = match has_long_name {
+            true => name.encode_utf16().collect(),
+            false => Vec::new(),
+        };
+        let count = (long_name.len() + LONG_NAME_CHARS - 1) / LONG_NAME_CHARS + 1;
+
+        // Find enough free slots in a row.
+        let mut run = 0;
+        let mut found = None;
+        for index in 0..buf.slots() {
+            match buf.slot(index)[0] {
+                0 | DELETED_MARK => run += 1,
+                _ => run = 0,
+            }
+            if run == count {
+                found = Some(index + 1 - count);
+                break;
+            }
+        }
+        let start = match found {
+            Some(start) => start,
+            None if self.is_fixed_root(inner) => return Err(FsError::NoDeviceSpace),
+            None => {
+                // Append clusters after the free slots at the end.
+                let start = buf.slots() - run;
+                while buf.slots() < start + count {
+                    let cluster = self
+                        .fs
+                        .alloc_cluster(buf.clusters.last().copied().unwrap_or(0))?;
+                    if buf.clusters.is_empty() {
+                        inner.entry.set_cluster(cluster);
+                    }
+                    buf.clusters.push(cluster);
+                    buf.regions.push(self.fs.cluster_offset(cluster));
+                    buf.data.resize(buf.data.len() + buf.region_size, 0);
+                }
+                start
+            }
+        };
+
+        let checksum = short.checksum();
+        let mut slot = [0u8; DIR_ENTRY_SIZE];
+        for i in 0..count - 1 {
+            let order = count - 1 - i;
+            LongNameEntry::write(&mut slot, &long_name, order, i == 0, checksum);
+            self.fs
+                .device
+                .write_all_at(buf.slot_offset(start + i), &slot)?;
+        }
+        let key = buf.slot_offset(start + count - 1);
+        self.fs.device.write_all_at(key, short.as_buf())?;
+        Ok((key, short))
+    }
+    /// Mark the slots of an entry as free
+    fn remove_entry(&self, buf: &DirBuf, entry: &DirEntry) -> vfs::Result<()> {
+        for index in entry.first_slot..=entry.slot {
+            self.fs
+                .device
+                .write_all_at(buf.slot_offset(index), &[DELETED_MARK])?;
+        }
+        Ok(())
+    }
+    /// Write back the slots of a removed entry
+    fn restore_entry(&self, buf: &DirBuf, entry: &DirEntry) -> vfs::Result<()> {
+        for index in entry.first_slot..=entry.slot {
+            self.fs
+                .device
+                .write_all_at(buf.slot_offset(index), buf.slot(index))?;
+        }
+        Ok(())
+    }
+    /// Checks that the directory has no entries other than `.` and `..`
+    fn check_empty(&self) -> vfs::Result<()> {
+        let inner = self.inner.read();
+        let (_, entries) = self.entries(&inner)?;
+        match entries.iter().all(|entry| is_dot(&entry.name)) {
+            true => Ok(()),
+            false => Err(FsError::DirNotEmpty),
+        }
+    }
+    /// Mark the inode as removed and forget its key
+    fn detach(&self) {
+        let mut inner = self.inner.write();
+        inner.removed = true;
+        self.fs.inodes.write().remove(&inner.key);
+    }
+    /// Point `..` to the directory starting at `cluster` (0 for the root)
+    fn set_parent_cluster(&self, cluster: u32) -> vfs::Result<()> {
+        let inner = self.inner.read();
+        let offset = self.fs.cluster_offset(inner.entry.cluster()) + DIR_ENTRY_SIZE;
+        let mut dotdot = DiskDirEntry::default();
+        self.fs.device.read_exact_at(offset, dotdot.as_buf_mut())?;
+        if &dotdot.name[..2] != b".." {
+            kwarn!("the second entry of the directory is not `..`");
+            return Err(FsError::DeviceError);
+        }
+        dotdot.set_cluster(cluster);
+        self.fs.device.write_all_at(offset, dotdot.as_buf())
+    }
+    /// Get the cluster recorded in `..` of the subdirectories
+    fn dir_cluster(inner: &FatINodeInner) -> u32 {
+        match inner.key {
+            ROOT_KEY => 0,
+            _ => inner.entry.cluster(),
+        }
+    }
+}
+
+impl vfs::INode for FatINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
+        let inner = self.inner.read();
+        if inner.entry.is_dir() {
+            return Err(FsError::IsDir);
+        }
+        self._read_at(&inner, offset, buf)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
+        let mut inner = self.inner.write();
+        if inner.entry.is_dir() {
+            return Err(FsError::IsDir);
+        }
+        let len = self._write_at(&mut inner, offset, buf)?;
+        inner.touch();
+        Ok(len)
+    }
+    fn poll(&self) -> vfs::Result<vfs::PollStatus> {
+        Ok(vfs::PollStatus {
+            read: true,
+            write: true,
+            error: false,
+        })
+    }
+    fn metadata(&self) -> vfs::Result<vfs::Metadata> {
+        let inner = self.inner.read();
+        let entry = &inner.entry;
+        let allocated = self.allocated(&inner)?;
+        let mtime = from_fat_time(entry.wrt_date, entry.wrt_time);
+        Ok(vfs::Metadata {
+            dev: 0,
+            inode: inner.id(),
+            size: match entry.is_dir() {
+                true => allocated,
+                false => entry.size as usize,
+            },
+            mode: match entry.attr & ATTR_READ_ONLY {
+                0 => 0o777,
+                _ => 0o555,
+            },
+            type_: match entry.is_dir() {
+                true => vfs::FileType::Dir,
+                false => vfs::FileType::File,
+            },
+            blocks: allocated / SECTOR_SIZE,
+            atime: from_fat_time(entry.acc_date, 0),
+            mtime,
+            // FAT has no change time.
+            ctime: mtime,
+            nlinks: match entry.is_dir() {
+                true => 2,
+                false => 1,
+            },
+            uid: 0,
+            gid: 0,
+            blk_size: self.fs.cluster_size,
+            rdev: 0,
+        })
+    }
+    fn set_metadata(&self, metadata: &vfs::Metadata) -> vfs::Result<()> {
+        let mut inner = self.inner.write();
+        if inner.key == ROOT_KEY {
+            return Ok(());
+        }
+        let (date, time) = to_fat_time(metadata.mtime.sec.max(0) as u64);
+        inner.entry.wrt_date = date;
+        inner.entry.wrt_time = time;
+        inner.entry.acc_date = to_fat_time(metadata.atime.sec.max(0) as u64).0;
+        match metadata.mode & 0o222 {
+            0 => inner.entry.attr |= ATTR_READ_ONLY,
+            _ => inner.entry.attr &= !ATTR_READ_ONLY,
+        }
+        Ok(())
+    }
+    fn sync_all(&self) -> vfs::Result<()> {
+        let mut inner = self.inner.write();
+        if inner.entry.dirty() && inner.key != ROOT_KEY && !inner.removed {
+            self.fs
+                .device
+                .write_all_at(inner.key, inner.entry.as_buf())?;
+            inner.entry.sync();
+        }
+        Ok(())
+    }
+    fn sync_data(&self) -> vfs::Result<()> {
+        self.sync_all()
+    }
+    fn resize(&self, len: usize) -> vfs::Result<()> {
+        let mut inner = self.inner.write();
+        if inner.entry.is_dir() {
+            return Err(FsError::IsDir);
+        }
+        self._resize(&mut inner, len)?;
+        inner.touch();
+        Ok(())
+    }
+    fn create2(
+        &self,
+        name: &str,
+        type_: vfs::FileType,
+        mode: u32,
+        _data: usize,
+    ) -> vfs::Result<Arc<dyn vfs::INode>> {
+        check_name(name)?;
+        let is_dir = match type_ {
+            vfs::FileType::File => false,
+            vfs::FileType::Dir => true,
+            _ => return Err(FsError::NotSupported),
+        };
+
+        let _tree = self.fs.tree.write();
+        let mut inner = self.inner.write();
+        Self::check_dir(&inner)?;
+        let (buf, entries) = self.entries(&inner)?;
+        // Ensure the name is not exist
+        if Self::lookup(&entries, name).is_some() {
+            return Err(FsError::EntryExist);
+        }
+
+        let (date, time) = now();
+        let mut short = DiskDirEntry {
+            attr: match is_dir {
+                true => ATTR_DIRECTORY,
+                false => ATTR_ARCHIVE,
+            },
+            crt_date: date,
+            crt_time: time,
+            acc_date: date,
+            wrt_date: date,
+            wrt_time: time,
+            ..Default::default()
+        };
+        if mode & 0o222 == 0 {
+            short.attr |= ATTR_READ_ONLY;
+        }
+        if is_dir {
+            // Insert 2 init entries.
+            let cluster = self.fs.alloc_cluster(0)?;
+            short.set_cluster(cluster);
+            let mut dot = short;
+            dot.name = *b".          ";
+            let mut dotdot = short;
+            dotdot.name = *b"..         ";
+            dotdot.set_cluster(Self::dir_cluster(&inner));
+            let offset = self.fs.cluster_offset(cluster);
+            self.fs.device.write_all_at(offset, dot.as_buf())?;
+            self.fs
+                .device
+                .write_all_at(offset + DIR_ENTRY_SIZE, dotdot.as_buf())?;
+        }
+
+        let (key, short) = match self.add_entry(&mut inner, buf, &entries, name, short) {
+            Ok(entry) => entry,
+            Err(err) => {
+                if is_dir {
+                    self.fs.free_clusters(&[short.cluster()]);
+                }
+                return Err(err);
+            }
+        };
+        inner.touch();
+
+        Ok(self.fs.get_inode(key, &short, Some(self.arc())))
+    }
+    fn link(&self, _name: &str, _other: &Arc<dyn INode>) -> vfs::Result<()> {
+        // FAT has no hard links.
+        Err(FsError::NotSupported)
+    }
+    fn unlink(&self, name: &str) -> vfs::Result<()> {
+        if is_dot(name) {
+            return Err(FsError::IsDir);
+        }
+
+        let _tree = self.fs.tree.write();
+        let mut inner = self.inner.write();
+        Self::check_dir(&inner)?;
+        let (buf, entries) = self.entries(&inner)?;
+        let entry = Self::lookup(&entries, name).ok_or(FsError::EntryNotFound)?;
+        let inode = self.child(&buf, entry);
+        if entry.short.is_dir() {
+            inode.check_empty()?;
+        }
+        self.remove_entry(&buf, entry)?;
+        inode.detach();
+        inner.touch();
+        Ok(())
+    }
+    fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> vfs::Result<()> {
+        if is_dot(old_name) {
+            return Err(FsError::IsDir);
+        }
+        check_name(new_name)?;
+        let dest = target
+            .downcast_ref::<FatINode>()
+            .ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs, &dest.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        let same_dir = ptr::eq(self, dest);
+
+        let _tree = self.fs.tree.write();
+        let mut inner = self.inner.write();
+        Self::check_dir(&inner)?;
+        let (buf, entries) = self.entries(&inner)?;
+        let entry = Self::lookup(&entries, old_name).ok_or(FsError::EntryNotFound)?;
+        let inode = self.child(&buf, entry);
+
+        // Cannot move a directory into itself or its subdirectories.
+        let mut ancestor = Some(dest.arc());
+        while let Some(dir) = ancestor {
+            if Arc::ptr_eq(&dir, &inode) {
+                return Err(FsError::InvalidParam);
+            }
+            if ptr::eq(dir.as_ref(), self) {
+                break;
+            }
+            ancestor = dir.inner.read().parent.clone();
+        }
+
+        let mut dest_inner = match same_dir {
+            true => None,
+            false => Some(dest.inner.write()),
+        };
+        if let Some(dest_inner) = dest_inner.as_ref() {
+            Self::check_dir(dest_inner)?;
+        }
+
+        // Replace the existing target.
+        let (dest_buf, dest_entries) = match dest_inner.as_ref() {
+            Some(dest_inner) => dest.entries(dest_inner)?,
+            None => self.entries(&inner)?,
+        };
+        if let Some(existing) = Self::lookup(&dest_entries, new_name) {
+            // Renaming to another case of the same name finds the entry itself.
+            if !(same_dir && existing.slot == entry.slot) {
+                let victim = dest.child(&dest_buf, existing);
+                match (entry.short.is_dir(), existing.short.is_dir()) {
+                    (true, false) => return Err(FsError::NotDir),
+                    (false, true) => return Err(FsError::IsDir),
+                    (true, true) => victim.check_empty()?,
+                    (false, false) => {}
+                }
+                dest.remove_entry(&dest_buf, existing)?;
+                victim.detach();
+            }
+        }
+
+        self.remove_entry(&buf, entry)?;
+        let short = *inode.inner.read().entry;
+        let added = match dest_inner.as_mut() {
+            Some(dest_inner) => {
+                let (dest_buf, dest_entries) = dest.entries(dest_inner)?;
+                dest.add_entry(dest_inner, dest_buf, &dest_entries, new_name, short)
+            }
+            None => {
+                let (dest_buf, dest_entries) = self.entries(&inner)?;
+                self.add_entry(&mut inner, dest_buf, &dest_entries, new_name, short)
+            }
+        };
+        let (key, short) = match added {
+            Ok(entry) => entry,
+            Err(err) => {
+                self.restore_entry(&buf, entry)?;
+                return Err(err);
+            }
+        };
+
+        {
+            let mut node = inode.inner.write();
+            let mut inodes = self.fs.inodes.write();
+            inodes.remove(&node.key);
+            inodes.insert(key, Arc::downgrade(&inode));
+            node.key = key;
+            node.entry = Dirty::new(short);
+            node.parent = Some(dest.arc());
+        }
+        inner.touch();
+        if let Some(dest_inner) = dest_inner.as_mut() {
+            if short.is_dir() {
+                inode.set_parent_cluster(Self::dir_cluster(dest_inner))?;
+            }
+            dest_inner.touch();
+        }
+        Ok(())
+    }
+    fn find(&self, name: &str) -> vfs::Result<Arc<dyn vfs::INode>> {
+        let _tree = self.fs.tree.read();
+        let inner = self.inner.read();
+        if !inner.entry.is_dir() {
+            return Err(FsError::NotDir);
+        }
+        match name {
+            "." => return Ok(self.arc()),
+            ".." => return Ok(inner.parent.clone().unwrap_or_else(|| self.arc())),
+            _ => {}
+        }
+        let (buf, entries) = self.entries(&inner)?;
+        let entry = Self::lookup(&entries, name).ok_or(FsError::EntryNotFound)?;
+        Ok(self.child(&buf, entry))
+    }
+    fn get_entry(&self, id: usize) -> vfs::Result<String> {
+        self.list()?
+            .into_iter()
+            .nth(id)
+            .map(|(_, name)| name)
+            .ok_or(FsError::EntryNotFound)
+    }
+    fn get_entry_with_metadata(&self, id: usize) -> vfs::Result<(Metadata, String)> {
+        let (inode, name) = {
+            let _tree = self.fs.tree.read();
+            let inner = self.inner.read();
+            if !inner.entry.is_dir() {
+                return Err(FsError::NotDir);
+            }
+            match id {
+                0 => (self.arc(), String::from(".")),
+                1 => (
+                    inner.parent.clone().unwrap_or_else(|| self.arc()),
+                    String::from(".."),
+                ),
+                _ => {
+                    let (buf, entries) = self.entries(&inner)?;
+                    let entry = entries
+                        .iter()
+                        .filter(|entry| !is_dot(&entry.name))
+                        .nth(id - 2)
+                        .ok_or(FsError::EntryNotFound)?;
+                    (self.child(&buf, entry), entry.name.clone())
+                }
+            }
+        };
+        Ok((inode.metadata()?, name))
+    }
+    fn list(&self) -> vfs::Result<Vec<(usize, String)>> {
+        let _tree = self.fs.tree.read();
+        let inner = self.inner.read();
+        if !inner.entry.is_dir() {
+            return Err(FsError::NotDir);
+        }
+        let parent_id = inner
+            .parent
+            .as_ref()
+            .map_or(ROOT_ID, |parent| parent.inner.read().id());
+
+        let (buf, entries) = self.entries(&inner)?;
+        let mut list = vec![
+            (inner.id(), String::from(".")),
+            (parent_id, String::from("..")),
+        ];
+        list.extend(
+            entries
+                .into_iter()
+                .filter(|entry| !is_dot(&entry.name))
+                .map(|entry| (buf.slot_offset(entry.slot) / DIR_ENTRY_SIZE, entry.name)),
+        );
+        Ok(list)
+    }
+    fn io_control(&self, _cmd: u32, _data: usize) -> vfs::Result<usize> {
+        Err(FsError::IOCTLError)
+    }
+    fn mmap(&self, _area: MMapArea) -> vfs::Result<()> {
+        Err(FsError::NotSupported)
+    }
+    fn fs(&self) -> Arc<dyn vfs::FileSystem> {
+        self.fs.clone()
+    }
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Debug for FatINode {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        let inner = self.inner.read();
+        write!(
+            f,
+            "INode {{ key: {:#x}, entry: {:?} }}",
+            inner.key, inner.entry
+        )
+    }
+}
+
+impl Drop for FatINode {
+    /// Auto sync when drop; free the clusters if the entry is removed
+    fn drop(&mut self) {
+        let (removed, cluster) = {
+            let inner = self.inner.read();
+            (inner.removed, inner.entry.cluster())
+        };
+        if removed {
+            match self.fs.chain(cluster) {
+                Ok(chain) => self.fs.free_clusters(&chain),
+                Err(_) => kwarn!("cannot free the clusters from {:#x}", cluster),
+            }
+        } else {
+            self.sync_all()
+                .expect("Failed to sync when dropping the FAT inode");
+        }
+    }
+}
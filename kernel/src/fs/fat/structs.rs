@@ -0,0 +1,305 @@
+//! On-disk structures of FAT12, FAT16 and FAT32 (Microsoft FAT specification, 2005).
+
+use core::mem::size_of_val;
+use core::slice;
+
+/// The type of the FAT, determined by the number of clusters only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// Gets the type of a volume with `clusters` data clusters.
+    pub fn from_clusters(clusters: usize) -> Self {
+        match clusters {
+            0..=4084 => Self::Fat12,
+            4085..=65524 => Self::Fat16,
+            _ => Self::Fat32,
+        }
+    }
+
+    /// Gets the smallest entry value marking the end of a cluster chain.
+    pub fn end_of_chain(&self) -> u32 {
+        match self {
+            Self::Fat12 => 0xff8,
+            Self::Fat16 => 0xfff8,
+            Self::Fat32 => 0x0fff_fff8,
+        }
+    }
+
+    /// Gets the entry value marking a bad cluster.
+    pub fn bad_cluster(&self) -> u32 {
+        self.end_of_chain() - 1
+    }
+
+    /// Gets the bits of an entry that hold the value.
+    pub fn mask(&self) -> u32 {
+        match self {
+            Self::Fat12 => 0xfff,
+            Self::Fat16 => 0xffff,
+            Self::Fat32 => 0x0fff_ffff,
+        }
+    }
+}
+
+/// The BIOS parameter block in the boot sector, with the FAT32 extension.
+#[derive(Debug, Clone)]
+pub struct BiosParameterBlock {
+    /// bytes per sector: 512, 1024, 2048 or 4096
+    pub bytes_per_sector: u16,
+    /// sectors per cluster, a power of 2
+    pub sectors_per_cluster: u8,
+    /// sectors before the first FAT, including the boot sector
+    pub reserved_sectors: u16,
+    /// number of FATs
+    pub fats: u8,
+    /// number of entries in the root directory, 0 on FAT32
+    pub root_entries: u16,
+    /// total number of sectors if it fits in 16 bits, otherwise 0
+    pub total_sectors_16: u16,
+    /// sectors per FAT on FAT12/16, 0 on FAT32
+    pub sectors_per_fat_16: u16,
+    /// total number of sectors if `total_sectors_16` is 0
+    pub total_sectors_32: u32,
+    /// sectors per FAT on FAT32
+    pub sectors_per_fat_32: u32,
+    /// FAT32: bit 7 disables mirroring, and bits 0-3 select the active FAT then
+    pub ext_flags: u16,
+    /// FAT32: first cluster of the root directory
+    pub root_cluster: u32,
+    /// FAT32: sector of the FSInfo structure
+    pub fs_info_sector: u16,
+}
+
+impl BiosParameterBlock {
+    /// Parses the boot sector in `buf`. Returns `None` if it does not look like a FAT volume.
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        let u16_at = |offset: usize| u16::from_le_bytes([buf[offset], buf[offset + 1]]);
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
+
+        if buf.len() < SECTOR_SIZE
+            || u16_at(510) != BOOT_SIGNATURE
+            || !matches!(buf[0], 0xeb | 0xe9)
+        {
+            return None;
+        }
+        let bpb = Self {
+            bytes_per_sector: u16_at(11),
+            sectors_per_cluster: buf[13],
+            reserved_sectors: u16_at(14),
+            fats: buf[16],
+            root_entries: u16_at(17),
+            total_sectors_16: u16_at(19),
+            sectors_per_fat_16: u16_at(22),
+            total_sectors_32: u32_at(32),
+            sectors_per_fat_32: u32_at(36),
+            ext_flags: u16_at(40),
+            root_cluster: u32_at(44),
+            fs_info_sector: u16_at(48),
+        };
+        let valid = matches!(bpb.bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            && bpb.sectors_per_cluster.is_power_of_two()
+            && bpb.reserved_sectors != 0
+            && bpb.fats != 0
+            && bpb.total_sectors() != 0
+            && bpb.sectors_per_fat() != 0;
+        valid.then_some(bpb)
+    }
+
+    /// Gets the total number of sectors.
+    pub fn total_sectors(&self) -> usize {
+        match self.total_sectors_16 {
+            0 => self.total_sectors_32 as usize,
+            sectors => sectors as usize,
+        }
+    }
+
+    /// Gets the number of sectors of each FAT.
+    pub fn sectors_per_fat(&self) -> usize {
+        match self.sectors_per_fat_16 {
+            0 => self.sectors_per_fat_32 as usize,
+            sectors => sectors as usize,
+        }
+    }
+
+    /// Gets the number of sectors of the fixed root directory of FAT12/16.
+    pub fn root_dir_sectors(&self) -> usize {
+        let bytes_per_sector = self.bytes_per_sector as usize;
+        (self.root_entries as usize * DIR_ENTRY_SIZE + bytes_per_sector - 1) / bytes_per_sector
+    }
+
+    /// Gets the first sector of the data region, where cluster 2 starts.
+    pub fn first_data_sector(&self) -> usize {
+        self.reserved_sectors as usize
+            + self.fats as usize * self.sectors_per_fat()
+            + self.root_dir_sectors()
+    }
+
+    /// Gets the number of data clusters.
+    pub fn clusters(&self) -> usize {
+        self.total_sectors()
+            .saturating_sub(self.first_data_sector())
+            / self.sectors_per_cluster as usize
+    }
+}
+
+/// short directory entry (on disk)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiskDirEntry {
+    /// 8.3 name, padded with spaces; `name[0]` is DELETED_MARK for a free entry and 0 after the last entry
+    pub name: [u8; SHORT_NAME_LEN],
+    /// ATTR_*
+    pub attr: u8,
+    /// NT_LOWER_BASE and NT_LOWER_EXT
+    pub nt_res: u8,
+    /// tenths of a second of the creation time
+    pub crt_time_tenth: u8,
+    /// creation time
+    pub crt_time: u16,
+    /// creation date
+    pub crt_date: u16,
+    /// last access date
+    pub acc_date: u16,
+    /// high 16 bits of the first cluster (FAT32)
+    pub cluster_high: u16,
+    /// last modification time
+    pub wrt_time: u16,
+    /// last modification date
+    pub wrt_date: u16,
+    /// low 16 bits of the first cluster
+    pub cluster_low: u16,
+    /// size of the file in bytes, 0 for directories
+    pub size: u32,
+}
+
+impl DiskDirEntry {
+    /// Gets the first cluster, 0 if nothing is allocated.
+    pub fn cluster(&self) -> u32 {
+        ((self.cluster_high as u32) << 16) | self.cluster_low as u32
+    }
+    /// Sets the first cluster.
+    pub fn set_cluster(&mut self, cluster: u32) {
+        self.cluster_high = (cluster >> 16) as u16;
+        self.cluster_low = cluster as u16;
+    }
+    /// Checks if the entry is a directory.
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+    /// Checks if the entry is part of a long name.
+    pub fn is_long_name(&self) -> bool {
+        self.attr & ATTR_LONG_NAME_MASK == ATTR_LONG_NAME
+    }
+    /// Checksum of the short name, stored in each of its long name entries.
+    pub fn checksum(&self) -> u8 {
+        self.name
+            .iter()
+            .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+    }
+}
+
+/// long file name entry (on disk), holding 13 UCS-2 characters
+///
+/// The characters are not aligned, so the entry is handled as bytes.
+pub struct LongNameEntry<'a>(pub &'a [u8]);
+
+impl LongNameEntry<'_> {
+    /// Gets the sequence number; the last entry of a name (stored first) has LAST_LONG_ENTRY set.
+    pub fn order(&self) -> u8 {
+        self.0[0]
+    }
+    /// Gets the checksum of the short name.
+    pub fn checksum(&self) -> u8 {
+        self.0[13]
+    }
+    /// Gets the characters, including the terminator and the padding.
+    pub fn chars(&self) -> impl Iterator<Item = u16> + '_ {
+        LONG_NAME_OFFSETS
+            .iter()
+            .map(|&offset| u16::from_le_bytes([self.0[offset], self.0[offset + 1]]))
+    }
+    /// Writes the `order`-th (from 1) part of `name` to `buf`.
+    pub fn write(buf: &mut [u8], name: &[u16], order: usize, last: bool, checksum: u8) {
+        buf.fill(0);
+        buf[0] = order as u8 | if last { LAST_LONG_ENTRY } else { 0 };
+        buf[11] = ATTR_LONG_NAME;
+        buf[13] = checksum;
+        let part = (order - 1) * LONG_NAME_CHARS;
+        for (i, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+            let c = match (part + i).cmp(&name.len()) {
+                core::cmp::Ordering::Less => name[part + i],
+                core::cmp::Ordering::Equal => 0,
+                core::cmp::Ordering::Greater => 0xffff,
+            };
+            buf[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+        }
+    }
+}
+
+/// Convert structs to [u8] slice
+pub trait AsBuf {
+    fn as_buf(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const _ as *const u8, size_of_val(self)) }
+    }
+    fn as_buf_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self as *mut _ as *mut u8, size_of_val(self)) }
+    }
+}
+
+impl AsBuf for DiskDirEntry {}
+
+/// size of a sector assumed before the boot sector is parsed
+pub const SECTOR_SIZE: usize = 512;
+/// signature at the end of the boot sector
+pub const BOOT_SIGNATURE: u16 = 0xaa55;
+/// size of a directory entry
+pub const DIR_ENTRY_SIZE: usize = 32;
+/// length of a short name
+pub const SHORT_NAME_LEN: usize = 11;
+/// max length of a long name in UCS-2 characters
+pub const MAX_NAME_LEN: usize = 255;
+/// number of characters in each long name entry
+pub const LONG_NAME_CHARS: usize = 13;
+/// offsets of the characters in a long name entry
+pub const LONG_NAME_OFFSETS: [usize; LONG_NAME_CHARS] =
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// max size of a file
+pub const MAX_FILE_SIZE: usize = u32::MAX as usize;
+/// first data cluster
+pub const FIRST_CLUSTER: u32 = 2;
+
+/// `name[0]` of a free entry
+pub const DELETED_MARK: u8 = 0xe5;
+/// `name[0]` of an entry whose name starts with 0xe5
+pub const KANJI_MARK: u8 = 0x05;
+/// order flag of the last long name entry
+pub const LAST_LONG_ENTRY: u8 = 0x40;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+pub const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+pub const ATTR_LONG_NAME_MASK: u8 = ATTR_LONG_NAME | ATTR_DIRECTORY | ATTR_ARCHIVE;
+
+/// the base of the short name is in lower case (Windows NT)
+pub const NT_LOWER_BASE: u8 = 0x08;
+/// the extension of the short name is in lower case (Windows NT)
+pub const NT_LOWER_EXT: u8 = 0x10;
+
+/// FSInfo signatures (FAT32)
+pub const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+pub const FS_INFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+/// offsets in the FSInfo sector
+pub const FS_INFO_STRUCT_SIGNATURE_OFFSET: usize = 484;
+pub const FS_INFO_FREE_COUNT_OFFSET: usize = 488;
+pub const FS_INFO_NEXT_FREE_OFFSET: usize = 492;
+/// unknown free count or next free cluster in FSInfo
+pub const FS_INFO_UNKNOWN: u32 = 0xffff_ffff;
@@ -13,6 +13,7 @@ use alloc::{
 use bitflags::bitflags;
 use rcore_fs::vfs::{INode, MMapArea, Metadata, PollStatus, Result};
 use spin::RwLock;
+use x86_64::PhysAddr;
 
 use crate::{
     error::{fserror_to_kerror, Errno, KResult},
@@ -20,15 +21,16 @@ use crate::{
     memory::KernelFrameAllocator,
     mm::{
         callback::{FileArenaCallback, INodeWrapper},
+        page_cache::{PageCache, PageCacheHandle},
         Arena, ArenaFlags, ArenaType,
     },
     net::Socket,
     process::thread::{current, Thread},
-    sys::FcntlCommand,
+    sys::{FcntlCommand, Prot, MAP_SHARED},
     time::{SystemTime, UNIX_EPOCH},
 };
 
-use super::{apfs::meta::get_timespec, epoll::EpollInstance};
+use super::{apfs::meta::get_timespec, epoll::EpollInstance, max_file_size};
 
 bitflags! {
         #[derive(Default)]
@@ -46,6 +48,24 @@ pub trait ReadAsFile: Clone + Sync + Send + 'static {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> KResult<usize>;
 
     fn inode(&self) -> u64;
+
+    /// Returns the shared frame that caches the page at `offset` so that it can be mapped directly, `writable` if the
+    /// mapping is. Files without a page cache return `None` and are copied into private frames instead.
+    fn map_page(&self, offset: usize, writable: bool) -> KResult<Option<PhysAddr>> {
+        Ok(None)
+    }
+
+    /// Called when `frame` that was mapped at `offset` is unmapped. Returns true if `frame` is owned by the page cache
+    /// and must not be freed.
+    fn unmap_page(&self, offset: usize, frame: PhysAddr, writable: bool) -> bool {
+        false
+    }
+
+    /// Called when the mapping of `frame` at `offset` becomes `writable` or read-only. Returns true if `frame` is owned
+    /// by the page cache.
+    fn protect_page(&self, offset: usize, frame: PhysAddr, writable: bool) -> bool {
+        false
+    }
 }
 
 /// flock - apply or remove an advisory lock on an open file.
@@ -110,6 +130,8 @@ pub struct File {
     pub ty: FileType,
     /// A pre-loaded array of entries if this File is a directory.
     pub entries: Option<Vec<(usize, String)>>,
+    /// The page cache if this File is a regular file on a cached filesystem.
+    pub page_cache: Option<PageCacheHandle>,
 }
 
 impl File {
@@ -129,6 +151,7 @@ impl File {
             } else {
                 None
             },
+            page_cache: PageCache::of(&inode),
             inode,
             path: path.to_string(),
             fd_cloexec,
@@ -149,6 +172,7 @@ impl File {
             file_option: self.file_option.clone(),
             ty: self.ty.clone(),
             entries: self.entries.clone(),
+            page_cache: self.page_cache.clone(),
         }
     }
 
@@ -158,22 +182,28 @@ impl File {
             return Err(Errno::EACCES);
         }
 
+        // Stores to a shared mapping reach the file, so it is only writable if asked to.
+        let shared = area.flags as u64 & MAP_SHARED != 0;
+        let writable =
+            !shared || Prot::from_bits_truncate(area.prot as _).contains(Prot::PROT_WRITE);
+
         let thread = current().unwrap();
         let mut vm = thread.vm.lock();
         vm.add(Arena {
             range: area.start_vaddr as u64..area.end_vaddr as u64,
             flags: ArenaFlags {
-                writable: true,
+                writable,
                 user_accessible: true,
                 non_executable: false,
                 mmio: 0,
             },
             callback: Box::new(FileArenaCallback {
-                file: INodeWrapper(self.inode.clone()),
+                file: INodeWrapper::new(self.inode.clone()),
                 mem_start: area.start_vaddr as _,
                 file_start: area.offset as _,
                 file_end: (area.offset + area.end_vaddr - area.start_vaddr) as _,
                 frame_allocator: KernelFrameAllocator,
+                shared,
             }),
             // Heap ?!
             ty: ArenaType::Heap,
@@ -211,6 +241,11 @@ impl File {
             return Err(Errno::EBADF);
         }
         let file_offset = file_option.offset as usize + offset;
+        let non_blocking = file_option
+            .open_option
+            .contains(FileOpenOption::NON_BLOCKING);
+        // The read may be preempted; release the lock so that others can seek.
+        drop(file_option);
         // Get the timestamp.
         let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?;
         let mut metadata = self.inode.metadata().map_err(fserror_to_kerror)?;
@@ -219,10 +254,11 @@ impl File {
             .set_metadata(&metadata)
             .map_err(fserror_to_kerror)?;
 
-        if !file_option
-            .open_option
-            .contains(FileOpenOption::NON_BLOCKING)
-        {
+        if let Some(cache) = &self.page_cache {
+            return cache.read_at(file_offset, buf);
+        }
+
+        if !non_blocking {
             // Block.
             loop {
                 match self.inode.read_at(file_offset, buf) {
@@ -283,11 +319,17 @@ impl File {
         {
             return Err(Errno::EBADF);
         }
+        if offset.saturating_add(buf.len()) > max_file_size(&self.inode) {
+            return Err(Errno::EFBIG);
+        }
 
-        let len = self
-            .inode
-            .write_at(offset, buf)
-            .map_err(fserror_to_kerror)?;
+        let len = match &self.page_cache {
+            Some(cache) => cache.write_at(offset, buf)?,
+            None => self
+                .inode
+                .write_at(offset, buf)
+                .map_err(fserror_to_kerror)?,
+        };
 
         // Modify the time.
         let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?;
@@ -327,8 +369,15 @@ impl File {
             .contains(FileOpenOption::WRITE)
         {
             Err(Errno::EPERM)
+        } else if size > max_file_size(&self.inode) {
+            Err(Errno::EFBIG)
         } else {
-            self.inode.resize(size).map_err(fserror_to_kerror)
+            self.inode.resize(size).map_err(fserror_to_kerror)?;
+            if let Some(cache) = &self.page_cache {
+                cache.truncate(size);
+            }
+
+            Ok(())
         }
     }
 
@@ -337,10 +386,16 @@ impl File {
     }
 
     pub fn sync_all(&self) -> KResult<()> {
+        if let Some(cache) = &self.page_cache {
+            cache.sync()?;
+        }
         self.inode.sync_all().map_err(fserror_to_kerror)
     }
 
     pub fn sync_data(&self) -> KResult<()> {
+        if let Some(cache) = &self.page_cache {
+            cache.sync()?;
+        }
         self.inode.sync_data().map_err(fserror_to_kerror)
     }
 
@@ -519,6 +574,7 @@ impl FileObject {
                 file_option: file.file_option.clone(),
                 ty: file.ty.clone(),
                 entries: file.entries.clone(),
+                page_cache: file.page_cache.clone(),
             })),
             // Do not duplicate other file descriptors.
             _ => Err(Errno::EBADF),
@@ -534,7 +590,7 @@ pub fn do_dup(thread: &Arc<Thread>, oldfd: u64, newfd: u64, flags: Option<u64>)
     }
 
     let file_clone = proc.get_fd_ref(oldfd)?.dup(flags.unwrap_or_default())?;
-    proc.opened_files.insert(newfd, file_clone);
+    proc.set_file(newfd, file_clone);
 
     Ok(newfd as _)
 }
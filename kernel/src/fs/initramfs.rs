@@ -0,0 +1,211 @@
+//! Unpacks the initramfs, a cpio archive in the "newc" format loaded by the bootloader, so that the kernel can boot
+//! without a disk.
+//!
+//! Each member of the archive is a 110-byte ASCII header of 13 hexadecimal fields, followed by the NUL-terminated
+//! path name and the file data, both padded to 4 bytes. The archive ends with a member named `TRAILER!!!`. Hard links
+//! share the inode number, and only the last of them carries the data.
+//!
+//! Some useful links:
+//! * <https://www.kernel.org/doc/html/latest/driver-api/early-userspace/buffer-format.html>
+//! * <https://man.archlinux.org/man/cpio.5>
+
+use alloc::{collections::BTreeMap, sync::Arc};
+use rcore_fs::vfs::{FileType, INode, Timespec};
+use rcore_fs_mountfs::MNode;
+use spin::Once;
+
+use crate::{
+    error::{fserror_to_kerror, Errno, KResult},
+    function, kwarn,
+};
+
+use super::find_or_create_dir;
+
+/// The archive loaded by the bootloader, if any.
+pub static INITRD: Once<&'static [u8]> = Once::new();
+
+const NEWC_MAGIC: &[u8] = b"070701";
+/// The same as "newc" but with checksums of the data, which we do not verify.
+const CRC_MAGIC: &[u8] = b"070702";
+const HEADER_LEN: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFBLK: u32 = 0o060000;
+
+const fn align_up(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// The header of a member.
+#[derive(Debug)]
+struct CpioHeader {
+    ino: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    mtime: u32,
+    filesize: u32,
+    devmajor: u32,
+    devminor: u32,
+    rdevmajor: u32,
+    rdevminor: u32,
+    namesize: u32,
+}
+
+impl CpioHeader {
+    fn parse(buf: &[u8]) -> KResult<Self> {
+        let magic = &buf[..NEWC_MAGIC.len()];
+        if magic != NEWC_MAGIC && magic != CRC_MAGIC {
+            return Err(Errno::EINVAL);
+        }
+
+        let field = |index: usize| {
+            let start = NEWC_MAGIC.len() + index * 8;
+            core::str::from_utf8(&buf[start..start + 8])
+                .ok()
+                .and_then(|field| u32::from_str_radix(field, 16).ok())
+                .ok_or(Errno::EINVAL)
+        };
+
+        Ok(Self {
+            ino: field(0)?,
+            mode: field(1)?,
+            uid: field(2)?,
+            gid: field(3)?,
+            nlink: field(4)?,
+            mtime: field(5)?,
+            filesize: field(6)?,
+            devmajor: field(7)?,
+            devminor: field(8)?,
+            rdevmajor: field(9)?,
+            rdevminor: field(10)?,
+            namesize: field(11)?,
+        })
+    }
+
+    fn file_type(&self) -> Option<FileType> {
+        match self.mode & S_IFMT {
+            S_IFDIR => Some(FileType::Dir),
+            S_IFREG => Some(FileType::File),
+            S_IFLNK => Some(FileType::SymLink),
+            S_IFCHR => Some(FileType::CharDevice),
+            S_IFBLK => Some(FileType::BlockDevice),
+            _ => None,
+        }
+    }
+
+    /// Encodes the device number as Linux does.
+    fn rdev(&self) -> usize {
+        let (major, minor) = (self.rdevmajor as usize, self.rdevminor as usize);
+        (minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12)
+    }
+}
+
+/// Unpacks `archive` into the directory `root` and returns the number of the members.
+///
+/// The missing parent directories are created with mode 0755, and an existing directory is kept when the archive
+/// contains it again, so that several archives can be unpacked into the same tree.
+pub fn unpack(archive: &[u8], root: &Arc<MNode>) -> KResult<usize> {
+    // Maps (dev, ino) to the first link of the files with multiple links.
+    let mut links = BTreeMap::<(u32, u32, u32), Arc<dyn INode>>::new();
+    let mut offset = 0;
+    let mut count = 0;
+
+    loop {
+        let header = archive
+            .get(offset..offset + HEADER_LEN)
+            .ok_or(Errno::EINVAL)?;
+        let header = CpioHeader::parse(header)?;
+        let name_start = offset + HEADER_LEN;
+        let name = archive
+            .get(name_start..name_start + header.namesize as usize)
+            .ok_or(Errno::EINVAL)?;
+        let name = core::str::from_utf8(name.strip_suffix(&[0]).unwrap_or(name))
+            .map_err(|_| Errno::EINVAL)?;
+        let data_start = align_up(name_start + header.namesize as usize);
+        let data = archive
+            .get(data_start..data_start + header.filesize as usize)
+            .ok_or(Errno::EINVAL)?;
+        offset = align_up(data_start + header.filesize as usize);
+
+        if name == TRAILER {
+            break;
+        }
+        unpack_member(root, &header, name, data, &mut links)?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+fn unpack_member(
+    root: &Arc<MNode>,
+    header: &CpioHeader,
+    path: &str,
+    data: &[u8],
+    links: &mut BTreeMap<(u32, u32, u32), Arc<dyn INode>>,
+) -> KResult<()> {
+    let mut components = path
+        .split('/')
+        .filter(|component| !component.is_empty() && *component != ".");
+    let name = match components.next_back() {
+        Some(name) => name,
+        // The root itself.
+        None => return Ok(()),
+    };
+    if name == ".." || components.clone().any(|component| component == "..") {
+        return Err(Errno::EINVAL);
+    }
+    let mut dir = root.clone();
+    for component in components {
+        dir = find_or_create_dir(&dir, component).map_err(fserror_to_kerror)?;
+    }
+
+    let type_ = match header.file_type() {
+        Some(type_) => type_,
+        None => {
+            kwarn!("skipping {path} of an unsupported type: {:#o}", header.mode);
+            return Ok(());
+        }
+    };
+    let key = (header.devmajor, header.devminor, header.ino);
+    let inode: Arc<dyn INode> = match type_ {
+        FileType::Dir => find_or_create_dir(&dir, name).map_err(fserror_to_kerror)?,
+        FileType::File if header.nlink > 1 && links.contains_key(&key) => {
+            let inode = links[&key].clone();
+            dir.link(name, &inode).map_err(fserror_to_kerror)?;
+            inode
+        }
+        _ => {
+            let inode = dir
+                .create2(name, type_, header.mode & !S_IFMT, header.rdev())
+                .map_err(fserror_to_kerror)?;
+            if type_ == FileType::File && header.nlink > 1 {
+                links.insert(key, inode.clone());
+            }
+            inode
+        }
+    };
+    if !data.is_empty() {
+        inode.write_at(0, data).map_err(fserror_to_kerror)?;
+    }
+
+    let mut metadata = inode.metadata().map_err(fserror_to_kerror)?;
+    let mtime = Timespec {
+        sec: header.mtime as _,
+        nsec: 0,
+    };
+    metadata.mode = (header.mode & !S_IFMT) as _;
+    metadata.uid = header.uid as _;
+    metadata.gid = header.gid as _;
+    metadata.atime = mtime;
+    metadata.mtime = mtime;
+    metadata.ctime = mtime;
+    inode.set_metadata(&metadata).map_err(fserror_to_kerror)
+}
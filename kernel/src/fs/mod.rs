@@ -6,31 +6,49 @@
 
 use core::sync::atomic::AtomicU64;
 
-use alloc::{sync::Arc, vec::Vec};
+use alloc::{string::String, sync::Arc, vec::Vec};
 use bitflags::bitflags;
 use lazy_static::lazy_static;
-use rcore_fs::vfs::INode;
-use rcore_fs_mountfs::MountFS;
+#[cfg(feature = "fat")]
+use rcore_fs::dev::Device;
+use rcore_fs::vfs::{FileType, FsError, INode};
+use rcore_fs_mountfs::{MNode, MountFS};
+use spin::Once;
 
+#[cfg(any(feature = "mount_sfs", feature = "mount_apfs", feature = "mount_ext2"))]
+use crate::mm::page_cache::enable_page_cache;
 use crate::{
     drivers::{block::BlockDriverWrapper, BLOCK_DRIVERS},
-    error::KResult,
-    fs::{devfs::DEV_FS, proc::PROC_FS},
+    error::{fserror_to_kerror, Errno, KResult},
+    fs::{devfs::DEV_FS, proc::PROC_FS, tmpfs::TMP_FS},
+    function, kinfo, kwarn,
+    memory::free_frame_count,
 };
 
 pub mod devfs;
 pub mod epoll;
 pub mod file;
+pub mod initramfs;
 pub mod proc;
+pub mod tmpfs;
+pub mod xattr;
 
 #[cfg(feature = "apfs")]
 pub mod apfs;
+#[cfg(feature = "ext2")]
+pub mod ext2;
+#[cfg(feature = "fat")]
+pub mod fat;
+#[cfg(feature = "fat")]
+pub mod partition;
 #[cfg(feature = "sfs")]
 pub mod sfs;
 
 pub const AT_FDCWD: isize = -100;
 /// Shared for pseudo filesystems.
 pub static INODE_COUNT: AtomicU64 = AtomicU64::new(0);
+/// The mount options of the root filesystem given by `rootflags=` on the kernel command line.
+pub static ROOT_FLAGS: Once<String> = Once::new();
 
 bitflags! {
     #[derive(Default)]
@@ -41,14 +59,61 @@ bitflags! {
     }
 }
 
-#[cfg(not(any(feature = "sfs", feature = "apfs")))]
-compile_error!("Must specify one filesystem type: apfs or sfs.");
+#[cfg(not(any(feature = "sfs", feature = "apfs", feature = "ext2")))]
+compile_error!("Must specify one filesystem type: apfs, sfs or ext2.");
 
-#[cfg(all(feature = "mount_sfs", feature = "mount_apfs"))]
-compile_error!("You cannot mount both filesystems!");
+#[cfg(any(
+    all(feature = "mount_sfs", feature = "mount_apfs"),
+    all(feature = "mount_sfs", feature = "mount_ext2"),
+    all(feature = "mount_apfs", feature = "mount_ext2"),
+))]
+compile_error!("You cannot mount more than one filesystem!");
 
 pub const MAXIMUM_FOLLOW: usize = 0x4;
 
+/// Gets the inode of the filesystem that `inode` belongs to. The VFS only sees the inodes wrapped by [`MountFS`], which
+/// never downcast to the inodes of a concrete filesystem.
+pub fn inner_inode(inode: &Arc<dyn INode>) -> &Arc<dyn INode> {
+    match inode.as_any_ref().downcast_ref::<MNode>() {
+        Some(mnode) => &mnode.inode,
+        None => inode,
+    }
+}
+
+/// Gets the maximum size of the files on the filesystem of `inode`.
+#[cfg_attr(
+    not(any(feature = "sfs", feature = "ext2", feature = "fat")),
+    allow(unused_variables)
+)]
+pub fn max_file_size(inode: &Arc<dyn INode>) -> usize {
+    let inode = inner_inode(inode);
+
+    #[cfg(feature = "sfs")]
+    if inode
+        .as_any_ref()
+        .downcast_ref::<sfs::INodeImpl>()
+        .is_some()
+    {
+        return sfs::MAX_FILE_SIZE;
+    }
+
+    #[cfg(feature = "ext2")]
+    if let Some(inode) = inode.as_any_ref().downcast_ref::<ext2::Ext2INode>() {
+        return inode.max_file_size();
+    }
+
+    #[cfg(feature = "fat")]
+    if inode.as_any_ref().downcast_ref::<fat::FatINode>().is_some() {
+        return fat::MAX_FILE_SIZE;
+    }
+
+    if let Some(inode) = inode.as_any_ref().downcast_ref::<tmpfs::TmpINode>() {
+        return inode.max_file_size();
+    }
+
+    usize::MAX
+}
+
 #[cfg(feature = "apfs")]
 // A debugging implementation.
 impl apfs::Device for Vec<u8> {
@@ -66,39 +131,194 @@ impl apfs::Device for Vec<u8> {
     }
 }
 
-#[cfg(feature = "mount_sfs")]
 lazy_static! {
-    use rcore_fs_mountfs::MountFS;
-
-    use crate::fs::sfs::SimpleFileSystem;
-    /// Mounts the simple filesystem and returns a root inode.
+    /// The root of the filesystem tree. The root filesystem is mounted from the first disk; if there is no disk or the
+    /// disk cannot be mounted, the initramfs loaded by the bootloader is used instead.
     pub static ref ROOT_INODE: Arc<dyn INode> = {
-        let device = Arc::new( BlockDriverWrapper(BLOCK_DRIVERS.read().iter().next().unwrap().clone()));
-        let sfs = SimpleFileSystem::open(device).expect("failed to open SFS");
-        let rootfs = MountFS::new(sfs);
-        let root = rootfs.root_inode();
+        let disk = BLOCK_DRIVERS.read().iter().next().cloned();
+        let root = match disk {
+            Some(disk) => mount_disk_root(Arc::new(BlockDriverWrapper(disk))),
+            None => Err(Errno::ENODEV),
+        };
 
-        root
+        match root {
+            Ok(root) => root,
+            Err(errno) if initramfs::INITRD.get().is_some() => {
+                kwarn!("cannot mount the root disk: {errno:?}; falling back to the initramfs.");
+                mount_initramfs_root().expect("failed to mount the initramfs")
+            }
+            Err(errno) => panic!("cannot mount the root filesystem: {errno:?}"),
+        }
     };
 }
 
+/// Mounts the simple filesystem on `device` and returns a root inode.
+#[cfg(feature = "mount_sfs")]
+fn mount_disk_root(device: Arc<BlockDriverWrapper>) -> KResult<Arc<dyn INode>> {
+    use crate::fs::sfs::{JournalMode, SimpleFileSystem};
+
+    let mode = JournalMode::from_options(ROOT_FLAGS.get().map_or("", |flags| flags.as_str()));
+    let sfs = SimpleFileSystem::open(device, mode).map_err(fserror_to_kerror)?;
+    let rootfs = MountFS::new(sfs);
+    enable_page_cache(rootfs.clone());
+    let root = rootfs.mountpoint_root_inode();
+    if let Err(errno) = mount_tmpfs(&root) {
+        kwarn!("cannot mount tmpfs at /tmp and /run: {errno:?}");
+    }
+
+    Ok(root)
+}
+
+/// Mounts the APFS container on `device` and returns the root inode of its first volume.
 #[cfg(feature = "mount_apfs")]
-lazy_static! {
-    pub static ref ROOT_INODE: Arc<dyn INode> = {
-        let device = Arc::new(BlockDriverWrapper(
-            BLOCK_DRIVERS.read().iter().next().unwrap().clone(),
-        ));
-        let apfs = apfs::AppleFileSystem::mount_container(device).unwrap();
-        apfs.load_nx_object_map().unwrap();
-        apfs.mount_volumns_all().unwrap();
-
-        let apfs = MountFS::new(apfs);
-        let root = apfs.mountpoint_root_inode();
-        let dev = root.find(true, "dev").unwrap();
-        dev.mount(DEV_FS.clone()).unwrap();
-        let proc = root.find(true, "proc").unwrap();
-        proc.mount(PROC_FS.clone()).unwrap();
-
-        root
-    };
+fn mount_disk_root(device: Arc<BlockDriverWrapper>) -> KResult<Arc<dyn INode>> {
+    let apfs = apfs::AppleFileSystem::mount_container(device)?;
+    apfs.load_nx_object_map()?;
+    apfs.mount_volumns_all()?;
+
+    let rootfs = MountFS::new(apfs.clone());
+    enable_page_cache(rootfs.clone());
+    let root = rootfs.mountpoint_root_inode();
+    mount_pseudo_filesystems(&root)?;
+    if let Err(errno) = mount_apfs_volumns(&root, &apfs) {
+        kwarn!("cannot mount the APFS volumes under {APFS_VOLUMES_PATH}: {errno:?}");
+    }
+    #[cfg(feature = "fat")]
+    if let Err(errno) = mount_boot(&root, 1) {
+        kwarn!("cannot mount the boot partition at /boot: {errno:?}");
+    }
+
+    Ok(root)
+}
+
+/// Mounts the ext2 filesystem on `device` and returns a root inode.
+#[cfg(feature = "mount_ext2")]
+fn mount_disk_root(device: Arc<BlockDriverWrapper>) -> KResult<Arc<dyn INode>> {
+    let ext2 = ext2::Ext2FileSystem::open(device).map_err(fserror_to_kerror)?;
+    let rootfs = MountFS::new(ext2);
+    enable_page_cache(rootfs.clone());
+    let root = rootfs.mountpoint_root_inode();
+    mount_pseudo_filesystems(&root)?;
+    #[cfg(feature = "fat")]
+    if let Err(errno) = mount_boot(&root, 1) {
+        kwarn!("cannot mount the boot partition at /boot: {errno:?}");
+    }
+
+    Ok(root)
+}
+
+/// No disk filesystem is mounted as the root, so the kernel always boots from the initramfs.
+#[cfg(not(any(feature = "mount_sfs", feature = "mount_apfs", feature = "mount_ext2")))]
+fn mount_disk_root(_device: Arc<BlockDriverWrapper>) -> KResult<Arc<dyn INode>> {
+    Err(Errno::ENODEV)
+}
+
+/// Unpacks the initramfs into a RAM filesystem limited to the free memory and returns its root inode.
+fn mount_initramfs_root() -> KResult<Arc<dyn INode>> {
+    let archive = initramfs::INITRD.get().ok_or(Errno::ENOENT)?;
+    let ramfs = tmpfs::TmpFileSystem::new(free_frame_count());
+    let rootfs = MountFS::new(ramfs);
+    let root = rootfs.mountpoint_root_inode();
+    let count = initramfs::unpack(archive, &root)?;
+    kinfo!("unpacked {count} files from the initramfs.");
+
+    mount_pseudo_filesystems(&root)?;
+    // The ESP is not used by the root, so it can be any of the disks.
+    #[cfg(feature = "fat")]
+    if let Err(errno) = mount_boot(&root, 0) {
+        kwarn!("cannot mount the boot partition at /boot: {errno:?}");
+    }
+
+    Ok(root)
+}
+
+/// Mounts `DEV_FS` at `/dev`, `PROC_FS` at `/proc` and tmpfs at `/tmp` and `/run`.
+fn mount_pseudo_filesystems(root: &Arc<MNode>) -> KResult<()> {
+    find_or_create_dir(root, "dev")
+        .and_then(|dev| dev.mount(DEV_FS.clone()))
+        .map_err(fserror_to_kerror)?;
+    find_or_create_dir(root, "proc")
+        .and_then(|proc| proc.mount(PROC_FS.clone()))
+        .map_err(fserror_to_kerror)?;
+    if let Err(errno) = mount_tmpfs(root) {
+        kwarn!("cannot mount tmpfs at /tmp and /run: {errno:?}");
+    }
+
+    Ok(())
+}
+
+/// The directory under which the APFS volumes other than the root volume are mounted. It can be changed by setting
+/// `OS_APFS_VOLUMES_PATH` when building the kernel.
+#[cfg(feature = "mount_apfs")]
+pub const APFS_VOLUMES_PATH: &str = match option_env!("OS_APFS_VOLUMES_PATH") {
+    Some(path) => path,
+    None => "/Volumes",
+};
+
+/// Finds the directory `name` under `dir` and creates it if it does not exist.
+fn find_or_create_dir(dir: &Arc<MNode>, name: &str) -> rcore_fs::vfs::Result<Arc<MNode>> {
+    match dir.find(false, name) {
+        Err(FsError::EntryNotFound) => {
+            dir.create(name, FileType::Dir, 0o755)?;
+            dir.find(false, name)
+        }
+        res => res,
+    }
+}
+
+/// Mounts `TMP_FS` at `/tmp` and a separate tmpfs of the same size at `/run`.
+fn mount_tmpfs(root: &Arc<MNode>) -> rcore_fs::vfs::Result<()> {
+    find_or_create_dir(root, "tmp")?.mount(TMP_FS.clone())?;
+    find_or_create_dir(root, "run")?.mount(tmpfs::TmpFileSystem::new(tmpfs::TMPFS_MAX_PAGES))?;
+
+    Ok(())
+}
+
+/// Mounts each volume of the container except the root volume at `APFS_VOLUMES_PATH/<volume name>`.
+#[cfg(feature = "mount_apfs")]
+fn mount_apfs_volumns(
+    root: &Arc<MNode>,
+    apfs: &Arc<apfs::AppleFileSystem>,
+) -> rcore_fs::vfs::Result<()> {
+    let mut dir = root.clone();
+    for name in APFS_VOLUMES_PATH.split('/').filter(|name| !name.is_empty()) {
+        dir = find_or_create_dir(&dir, name)?;
+    }
+
+    for name in apfs.volumn_names().into_iter().skip(1) {
+        find_or_create_dir(&dir, &name)?.mount(apfs::ApfsVolumnFs::new(apfs.clone(), &name))?;
+        kinfo!("mounted volume {name} at {APFS_VOLUMES_PATH}/{name}.");
+    }
+
+    Ok(())
+}
+
+/// Mounts the first FAT volume on the disks other than the first `root_disks` ones, which hold the root, at `/boot`,
+/// preferring the EFI system partitions.
+#[cfg(feature = "fat")]
+fn mount_boot(root: &Arc<MNode>, root_disks: usize) -> rcore_fs::vfs::Result<()> {
+    let mut devices: Vec<Arc<dyn Device>> = Vec::new();
+    for driver in BLOCK_DRIVERS.read().iter().skip(root_disks) {
+        let disk: Arc<dyn Device> = Arc::new(BlockDriverWrapper(driver.clone()));
+        let mut partitions = partition::read_partitions(&disk).unwrap_or_default();
+        partitions.sort_by_key(|partition| !partition.is_esp());
+        devices.extend(
+            partitions
+                .into_iter()
+                .filter(|partition| partition.is_fat())
+                .map(|partition| Arc::new(partition) as Arc<dyn Device>),
+        );
+        // The volume may also take the whole disk.
+        devices.push(disk);
+    }
+
+    for device in devices {
+        if let Ok(fat) = fat::FatFileSystem::open(device) {
+            find_or_create_dir(root, "boot")?.mount(fat)?;
+            kinfo!("mounted the boot partition at /boot.");
+            return Ok(());
+        }
+    }
+
+    Err(FsError::EntryNotFound)
 }
@@ -0,0 +1,162 @@
+//! Reads the partition table of a disk (MBR or GPT) and exposes each partition as a device.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use rcore_fs::dev::{self, DevError, Device};
+
+/// Sector size assumed by the partition tables.
+const SECTOR_SIZE: usize = 512;
+/// Offset of the partition entries in the MBR.
+const MBR_ENTRIES_OFFSET: usize = 446;
+/// Size of a partition entry in the MBR.
+const MBR_ENTRY_SIZE: usize = 16;
+/// Number of partition entries in the MBR.
+const MBR_ENTRIES: usize = 4;
+/// Partition type of the protective MBR of GPT.
+const MBR_TYPE_GPT: u8 = 0xee;
+/// Partition type of the EFI system partition in the MBR.
+const MBR_TYPE_ESP: u8 = 0xef;
+/// Partition types of FAT volumes in the MBR.
+const MBR_TYPES_FAT: [u8; 6] = [0x01, 0x04, 0x06, 0x0b, 0x0c, 0x0e];
+/// Signature of the GPT header at LBA 1.
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Type GUID of the EFI system partition (C12A7328-F81F-11D2-BA4B-00A0C93EC93B), as stored on disk.
+const GPT_TYPE_ESP: [u8; 16] = [
+    0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b,
+];
+/// Type GUID of the Microsoft basic data partition (EBD0A0A2-B9E5-4433-87C0-68B6B72699C7), as stored on disk.
+const GPT_TYPE_BASIC_DATA: [u8; 16] = [
+    0xa2, 0xa0, 0xd0, 0xeb, 0xe5, 0xb9, 0x33, 0x44, 0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99, 0xc7,
+];
+/// Upper bound on the GPT entries read, as in Linux.
+const GPT_MAX_ENTRIES: usize = 128;
+
+/// The type of a partition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    /// A partition in the MBR.
+    Mbr(u8),
+    /// A partition in the GPT, identified by the type GUID.
+    Gpt([u8; 16]),
+}
+
+/// A partition of a disk, which reads and writes within its own range.
+pub struct Partition {
+    device: Arc<dyn Device>,
+    /// first byte
+    start: usize,
+    /// size in bytes
+    size: usize,
+    ty: PartitionType,
+}
+
+impl Partition {
+    /// Checks if this is an EFI system partition.
+    pub fn is_esp(&self) -> bool {
+        match self.ty {
+            PartitionType::Mbr(ty) => ty == MBR_TYPE_ESP,
+            PartitionType::Gpt(guid) => guid == GPT_TYPE_ESP,
+        }
+    }
+
+    /// Checks if the partition is expected to hold a FAT volume.
+    pub fn is_fat(&self) -> bool {
+        self.is_esp()
+            || match self.ty {
+                PartitionType::Mbr(ty) => MBR_TYPES_FAT.contains(&ty),
+                PartitionType::Gpt(guid) => guid == GPT_TYPE_BASIC_DATA,
+            }
+    }
+
+    /// Gets the range of bytes of `offset..offset + len` that falls in this partition.
+    fn clamp(&self, offset: usize, len: usize) -> usize {
+        len.min(self.size.saturating_sub(offset))
+    }
+}
+
+impl Device for Partition {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> dev::Result<usize> {
+        let len = self.clamp(offset, buf.len());
+        self.device.read_at(self.start + offset, &mut buf[..len])
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> dev::Result<usize> {
+        let len = self.clamp(offset, buf.len());
+        self.device.write_at(self.start + offset, &buf[..len])
+    }
+
+    fn sync(&self) -> dev::Result<()> {
+        self.device.sync()
+    }
+}
+
+fn read_sector(device: &Arc<dyn Device>, lba: usize, buf: &mut [u8]) -> dev::Result<()> {
+    match device.read_at(lba * SECTOR_SIZE, buf)? {
+        len if len == buf.len() => Ok(()),
+        _ => Err(DevError),
+    }
+}
+
+/// Reads the partitions of `device`. Returns an empty list if there is no partition table.
+pub fn read_partitions(device: &Arc<dyn Device>) -> dev::Result<Vec<Partition>> {
+    let mut mbr = vec![0u8; SECTOR_SIZE];
+    read_sector(device, 0, &mut mbr)?;
+    if mbr[510..512] != [0x55, 0xaa] {
+        return Ok(Vec::new());
+    }
+
+    let mut partitions = Vec::new();
+    for i in 0..MBR_ENTRIES {
+        let entry = &mbr[MBR_ENTRIES_OFFSET + i * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        let ty = entry[4];
+        let start = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as usize;
+        let sectors = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as usize;
+        match ty {
+            0 => continue,
+            MBR_TYPE_GPT => return read_gpt(device),
+            _ => partitions.push(Partition {
+                device: device.clone(),
+                start: start * SECTOR_SIZE,
+                size: sectors * SECTOR_SIZE,
+                ty: PartitionType::Mbr(ty),
+            }),
+        }
+    }
+
+    Ok(partitions)
+}
+
+/// Reads the partitions in the GPT behind a protective MBR.
+fn read_gpt(device: &Arc<dyn Device>) -> dev::Result<Vec<Partition>> {
+    let mut header = vec![0u8; SECTOR_SIZE];
+    read_sector(device, 1, &mut header)?;
+    if &header[..8] != GPT_SIGNATURE {
+        return Ok(Vec::new());
+    }
+
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap()) as usize;
+    let entries = u32::from_le_bytes(header[80..84].try_into().unwrap()) as usize;
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as usize;
+    if entry_size < 128 {
+        return Ok(Vec::new());
+    }
+
+    let entries = entries.min(GPT_MAX_ENTRIES);
+    let mut table = vec![0u8; (entries * entry_size + SECTOR_SIZE - 1) / SECTOR_SIZE * SECTOR_SIZE];
+    read_sector(device, entries_lba, &mut table)?;
+
+    Ok(table
+        .chunks_exact(entry_size)
+        .take(entries)
+        .filter_map(|entry| {
+            let guid: [u8; 16] = entry[..16].try_into().unwrap();
+            let first = u64::from_le_bytes(entry[32..40].try_into().unwrap()) as usize;
+            let last = u64::from_le_bytes(entry[40..48].try_into().unwrap()) as usize;
+            (guid != [0; 16] && last >= first).then(|| Partition {
+                device: device.clone(),
+                start: first * SECTOR_SIZE,
+                size: (last - first + 1) * SECTOR_SIZE,
+                ty: PartitionType::Gpt(guid),
+            })
+        })
+        .collect())
+}
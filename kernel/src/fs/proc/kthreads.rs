@@ -0,0 +1,70 @@
+//! Prints the kernel threads that are currently alive.
+
+use core::any::Any;
+
+use rcore_fs::vfs::{make_rdev, FileType, FsError, INode, Metadata, PollStatus, Result, Timespec};
+
+use crate::process::kthread::kthread_info;
+
+pub struct KernelThreads {
+    inode: u64,
+}
+
+impl KernelThreads {
+    pub fn new(inode: u64) -> Self {
+        Self { inode }
+    }
+}
+
+impl INode for KernelThreads {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let content = kthread_info();
+        if offset >= content.len() {
+            return Ok(0);
+        }
+
+        let len = buf.len().min(content.len() - offset);
+        buf[..len].copy_from_slice(&content.as_bytes()[offset..offset + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: true,
+            write: false,
+            error: false,
+        })
+    }
+
+    fn set_metadata(&self, _metadata: &Metadata) -> Result<()> {
+        Ok(())
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            dev: 0,
+            inode: self.inode as _,
+            size: 0,
+            blk_size: 1024,
+            blocks: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            type_: FileType::File,
+            // r--r--r--
+            mode: 0o444,
+            nlinks: 0,
+            uid: 0,
+            gid: 0,
+            rdev: make_rdev(0x5, 0x6),
+        })
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
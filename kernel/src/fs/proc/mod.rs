@@ -16,12 +16,14 @@ use spin::RwLock;
 
 use crate::{function, kdebug};
 
-use self::{maps::Maps, selfdir::SelfDir};
+use self::{kthreads::KernelThreads, maps::Maps, selfdir::SelfDir, slabinfo::SlabInfo};
 
 use super::INODE_COUNT;
 
+pub mod kthreads;
 pub mod maps;
 pub mod selfdir;
+pub mod slabinfo;
 
 lazy_static! {
     pub static ref PROC_FS: Arc<ProcFileSystem> = ProcFileSystem::new();
@@ -42,6 +44,18 @@ impl ProcFileSystem {
             .children
             .write()
             .insert("self".into(), SelfDir::new(Arc::downgrade(&fs.mount_point)));
+        // Add `kthreads`.
+        fs.mount_point.children.write().insert(
+            "kthreads".into(),
+            Arc::new(KernelThreads::new(
+                INODE_COUNT.fetch_add(0x1, Ordering::SeqCst),
+            )),
+        );
+        // Add `slabinfo`.
+        fs.mount_point.children.write().insert(
+            "slabinfo".into(),
+            Arc::new(SlabInfo::new(INODE_COUNT.fetch_add(0x1, Ordering::SeqCst))),
+        );
         *fs.mount_point.fs.write() = Arc::downgrade(&fs);
         fs
     }
@@ -0,0 +1,298 @@
+//! A write-ahead log of the metadata of SFS.
+//!
+//! Updates of the superblock, the freemap, the inodes, the indirect blocks and the directory blocks are staged in
+//! memory and committed as one transaction on `sync`:
+//!
+//! 1. The blocks are copied into the journal after the descriptor, and the descriptor records their home locations.
+//! 2. A commit record carrying the checksum of the transaction is written after the last block.
+//! 3. The blocks are written to their home locations, and the descriptor is cleared.
+//!
+//! Each step is flushed to the device before the next one starts. A crash before the commit record reaches the disk
+//! leaves the home locations untouched; a crash after that is repaired by copying the transaction again when the
+//! volume is opened.
+//!
+//! A transaction must never end in the middle of an operation, so every operation reserves the blocks it may stage
+//! before it starts, and the staged transaction is committed first if it cannot take them. Blocks freed by a
+//! transaction are only handed out again after it commits, because the disk still refers to them until then.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use crc::{Crc, CRC_32_ISCSI};
+use rcore_fs::{dev::Device, vfs};
+
+use super::{
+    AsBuf, BlockId, DeviceExt, JournalCommit, JournalDescriptor, BLKSIZE, COMMIT_MAGIC,
+    JOURNAL_MAGIC, MAX_TRANSACTION_BLOCKS,
+};
+use crate::{function, kerror, kinfo, kwarn};
+
+const JOURNAL_CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+/// Blocks kept free in every transaction for the inodes written back outside any operation, e.g., when they are
+/// dropped.
+const SLACK_BLOCKS: usize = 16;
+
+/// Whether the metadata updates of SFS go through the journal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalMode {
+    /// Metadata is committed through the journal on `sync`.
+    Journaled,
+    /// Metadata is written in place. An existing journal is still replayed on open.
+    Unjournaled,
+}
+
+impl JournalMode {
+    /// Picks the mode from comma-separated mount options: `journal` (the default) or `nojournal`. The last one wins.
+    pub fn from_options(options: &str) -> Self {
+        match options
+            .split(',')
+            .rev()
+            .find(|option| matches!(*option, "journal" | "nojournal"))
+        {
+            Some("nojournal") => Self::Unjournaled,
+            _ => Self::Journaled,
+        }
+    }
+}
+
+/// The journal of an SFS volume together with the blocks staged for the next transaction.
+pub struct Journal {
+    /// 1st block of the journal (the descriptor)
+    start: BlockId,
+    /// number of journal blocks
+    blocks: usize,
+    /// sequence number of the next transaction
+    sequence: u64,
+    /// full contents of the blocks staged for the next transaction
+    pending: BTreeMap<BlockId, Vec<u8>>,
+    /// blocks reserved by the operations in flight
+    reserved: usize,
+    /// blocks freed since the last transaction committed
+    freed: Vec<BlockId>,
+}
+
+impl Journal {
+    /// Writes an empty journal to the reserved region.
+    pub fn format(device: &Arc<dyn Device>, start: BlockId, blocks: usize) -> vfs::Result<Self> {
+        let journal = Self {
+            start,
+            blocks,
+            sequence: 0,
+            pending: BTreeMap::new(),
+            reserved: 0,
+            freed: Vec::new(),
+        };
+        journal.clear(device)?;
+        Ok(journal)
+    }
+
+    /// Opens the journal and replays the last transaction if it was committed but not checkpointed.
+    pub fn open(device: &Arc<dyn Device>, start: BlockId, blocks: usize) -> vfs::Result<Self> {
+        let descriptor = device.load_struct::<JournalDescriptor>(start)?;
+        if descriptor.magic != JOURNAL_MAGIC {
+            kwarn!(
+                "the journal at block {:#x} is corrupted; formatting it",
+                start
+            );
+            return Self::format(device, start, blocks);
+        }
+
+        let mut journal = Self {
+            start,
+            blocks,
+            sequence: descriptor.sequence,
+            pending: BTreeMap::new(),
+            reserved: 0,
+            freed: Vec::new(),
+        };
+        if descriptor.count != 0 {
+            if journal.is_committed(device, &descriptor)? {
+                kinfo!(
+                    "replaying transaction {} of {} blocks",
+                    descriptor.sequence,
+                    descriptor.count
+                );
+                let mut buf = vec![0u8; BLKSIZE];
+                for (i, &home) in descriptor.blocks[..descriptor.count as usize]
+                    .iter()
+                    .enumerate()
+                {
+                    device.read_block(start + 1 + i, 0, &mut buf)?;
+                    device.write_block(home as BlockId, 0, &buf)?;
+                }
+                device.sync()?;
+            } else {
+                kwarn!(
+                    "discarding the incomplete transaction {}",
+                    descriptor.sequence
+                );
+            }
+            journal.sequence += 1;
+            journal.clear(device)?;
+        }
+
+        Ok(journal)
+    }
+
+    /// Maximum number of blocks in one transaction.
+    fn capacity(&self) -> usize {
+        // The descriptor and the commit record take one block each.
+        (self.blocks - 2).min(MAX_TRANSACTION_BLOCKS)
+    }
+
+    /// Checks that the transaction described by `descriptor` has a valid commit record.
+    fn is_committed(
+        &self,
+        device: &Arc<dyn Device>,
+        descriptor: &JournalDescriptor,
+    ) -> vfs::Result<bool> {
+        let count = descriptor.count as usize;
+        if count > self.capacity() {
+            return Ok(false);
+        }
+
+        let commit = device.load_struct::<JournalCommit>(self.start + 1 + count)?;
+        if commit.magic != COMMIT_MAGIC
+            || commit.count != descriptor.count
+            || commit.sequence != descriptor.sequence
+        {
+            return Ok(false);
+        }
+
+        let mut digest = JOURNAL_CRC.digest();
+        let mut buf = vec![0u8; BLKSIZE];
+        for (i, home) in descriptor.blocks[..count].iter().enumerate() {
+            device.read_block(self.start + 1 + i, 0, &mut buf)?;
+            digest.update(home.as_buf());
+            digest.update(&buf);
+        }
+        Ok(digest.finalize() == commit.checksum)
+    }
+
+    /// Writes an empty descriptor carrying the next sequence number.
+    fn clear(&self, device: &Arc<dyn Device>) -> vfs::Result<()> {
+        let descriptor = JournalDescriptor {
+            magic: JOURNAL_MAGIC,
+            count: 0,
+            sequence: self.sequence,
+            blocks: [0; MAX_TRANSACTION_BLOCKS],
+        };
+        device.write_block(self.start, 0, descriptor.as_buf())?;
+        device.sync()?;
+        Ok(())
+    }
+
+    /// Gets the staged contents of block `id`.
+    pub fn get(&self, id: BlockId) -> Option<&[u8]> {
+        self.pending.get(&id).map(|block| block.as_slice())
+    }
+
+    /// Checks if block `id` is staged for the next transaction.
+    pub fn contains(&self, id: BlockId) -> bool {
+        self.pending.contains_key(&id)
+    }
+
+    /// Checks if there is neither a staged block nor an operation in flight.
+    pub fn is_idle(&self) -> bool {
+        self.pending.is_empty() && self.reserved == 0
+    }
+
+    /// Reserves `blocks` for an operation if the transaction can take them on top of the `extra` blocks that `sync`
+    /// stages when it commits.
+    pub fn reserve(&mut self, blocks: usize, extra: usize) -> bool {
+        let used = self.pending.len() + self.reserved + extra + SLACK_BLOCKS;
+        if used + blocks > self.capacity() {
+            return false;
+        }
+        self.reserved += blocks;
+        true
+    }
+
+    /// Releases the blocks reserved by an operation that is done.
+    pub fn release(&mut self, blocks: usize) {
+        self.reserved -= blocks;
+    }
+
+    /// Holds block `id` until the staged transaction commits.
+    pub fn defer_free(&mut self, id: BlockId) {
+        self.freed.push(id);
+    }
+
+    /// Stages a write to block `id`. The block is read from `device` the first time it is staged.
+    pub fn write(
+        &mut self,
+        device: &Arc<dyn Device>,
+        id: BlockId,
+        offset: usize,
+        buf: &[u8],
+    ) -> vfs::Result<()> {
+        debug_assert!(offset + buf.len() <= BLKSIZE);
+        if !self.pending.contains_key(&id) {
+            // Operations reserve their blocks up front, so this only happens if a reservation is too small.
+            if self.pending.len() >= self.capacity() {
+                kerror!("the transaction does not fit in the journal");
+                return Err(vfs::FsError::NoDeviceSpace);
+            }
+            let mut block = vec![0u8; BLKSIZE];
+            if offset != 0 || buf.len() != BLKSIZE {
+                device.read_block(id, 0, &mut block)?;
+            }
+            self.pending.insert(id, block);
+        }
+
+        self.pending.get_mut(&id).unwrap()[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    /// Commits the staged blocks and writes them to their home locations. Returns the blocks freed by the
+    /// transaction, which can be reused from now on.
+    pub fn commit(&mut self, device: &Arc<dyn Device>) -> vfs::Result<Vec<BlockId>> {
+        if !self.pending.is_empty() {
+            debug_assert!(self.pending.len() <= self.capacity());
+            self.commit_transaction(device)?;
+            // The blocks stay staged until they reach their home locations, so that a failed commit can be retried
+            // and reads keep seeing them in the meantime.
+            self.pending.clear();
+        }
+
+        Ok(core::mem::take(&mut self.freed))
+    }
+
+    fn commit_transaction(&mut self, device: &Arc<dyn Device>) -> vfs::Result<()> {
+        let mut descriptor = JournalDescriptor {
+            magic: JOURNAL_MAGIC,
+            count: self.pending.len() as u32,
+            sequence: self.sequence,
+            blocks: [0; MAX_TRANSACTION_BLOCKS],
+        };
+        let mut digest = JOURNAL_CRC.digest();
+        for (i, (home, block)) in self.pending.iter().enumerate() {
+            descriptor.blocks[i] = *home as u32;
+            digest.update(descriptor.blocks[i].as_buf());
+            digest.update(block);
+            device.write_block(self.start + 1 + i, 0, block)?;
+        }
+        device.write_block(self.start, 0, descriptor.as_buf())?;
+        device.sync()?;
+
+        // The transaction is durable once the commit record reaches the disk.
+        let commit = JournalCommit {
+            magic: COMMIT_MAGIC,
+            count: descriptor.count,
+            sequence: self.sequence,
+            checksum: digest.finalize(),
+        };
+        let mut block = vec![0u8; BLKSIZE];
+        block[..core::mem::size_of::<JournalCommit>()].copy_from_slice(commit.as_buf());
+        device.write_block(self.start + 1 + self.pending.len(), 0, &block)?;
+        device.sync()?;
+
+        // Checkpoint.
+        for (home, block) in self.pending.iter() {
+            device.write_block(*home, 0, block)?;
+        }
+        device.sync()?;
+
+        self.sequence += 1;
+        self.clear(device)
+    }
+}
@@ -16,14 +16,18 @@ use rcore_fs::{
     util::{uninit_memory, BlockIter, BlockRange},
     vfs::{self, FsError, INode, MMapArea, Metadata},
 };
-use spin::RwLock;
+use spin::{RwLock, RwLockReadGuard};
 
+pub mod journal;
 pub mod structs;
+pub use journal::JournalMode;
 pub use structs::*;
 
+use journal::Journal;
+
 use bitvec::{order::Lsb0, vec::BitVec};
 
-use crate::{function, ktrace, kwarn};
+use crate::{function, kerror, kinfo, ktrace, kwarn};
 
 trait DeviceExt: Device {
     fn read_block(&self, id: BlockId, offset: usize, buf: &mut [u8]) -> vfs::Result<()> {
@@ -65,33 +69,64 @@ pub struct SimpleFileSystem {
     self_ptr: Weak<SimpleFileSystem>,
     /// device inode
     device_inodes: RwLock<BTreeMap<usize, Arc<DeviceINode>>>,
+    /// metadata journal, `None` if the volume is mounted unjournaled
+    journal: RwLock<Option<Journal>>,
+    /// held shared by the operations that modify the volume and exclusively by `sync`, so that a transaction never
+    /// ends in the middle of an operation
+    ops: RwLock<()>,
+}
+
+/// Journal blocks reserved by an operation on directory entries: two directory blocks, the indirect blocks of a
+/// growing directory and the inodes involved.
+const OP_BLOCKS: usize = 16;
+/// Most data blocks added to a file by one operation; larger files grow in several operations.
+const GROW_STEP_BLOCKS: usize = 256 * BLK_NENTRY;
+
+/// An operation that modifies the volume. Its reservation in the journal is released when it is dropped.
+struct Operation<'a> {
+    fs: &'a SimpleFileSystem,
+    blocks: usize,
+    _guard: RwLockReadGuard<'a, ()>,
+}
+
+impl Drop for Operation<'_> {
+    fn drop(&mut self) {
+        if let Some(journal) = self.fs.journal.write().as_mut() {
+            journal.release(self.blocks);
+        }
+    }
 }
 
 impl vfs::FileSystem for SimpleFileSystem {
     /// Write back super block if dirty
+    /// In journaled mode the metadata is committed as one transaction.
     fn sync(&self) -> vfs::Result<()> {
+        let _ops = self.ops.write();
         // order is important, see issue #18
         let mut free_map = self.free_map.write();
         let mut super_block = self.super_block.write();
         if super_block.dirty() {
-            self.device
-                .write_at(BLKSIZE * BLKN_SUPER, super_block.as_buf())?;
+            self.write_meta_block(BLKN_SUPER, 0, super_block.as_buf())?;
             super_block.sync();
         }
         if free_map.dirty() {
             let data = free_map.as_buf();
             for i in 0..super_block.freemap_blocks as usize {
-                self.device.write_at(
-                    BLKSIZE * (BLKN_FREEMAP + i),
-                    &data[i * BLKSIZE..(i + 1) * BLKSIZE],
-                )?;
+                self.write_meta_block(BLKN_FREEMAP + i, 0, &data[i * BLKSIZE..(i + 1) * BLKSIZE])?;
             }
             free_map.sync();
         }
         self.flush_weak_inodes();
         for inode in self.inodes.read().values() {
             if let Some(inode) = inode.upgrade() {
-                inode.sync_all()?;
+                inode.sync_disk_inode()?;
+            }
+        }
+        if let Some(journal) = self.journal.write().as_mut() {
+            // The freed blocks go into the freemap of the next transaction.
+            for block_id in journal.commit(&self.device)? {
+                free_map.set(block_id, true);
+                super_block.unused_blocks += 1;
             }
         }
         self.device.sync()?;
@@ -122,11 +157,22 @@ impl vfs::FileSystem for SimpleFileSystem {
 
 impl SimpleFileSystem {
     /// Load SFS from device
-    pub fn open(device: Arc<dyn Device>) -> vfs::Result<Arc<Self>> {
-        let super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
+    /// The journal is replayed before the metadata is loaded, whatever the `mode` is.
+    pub fn open(device: Arc<dyn Device>, mode: JournalMode) -> vfs::Result<Arc<Self>> {
+        let mut super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
         if !super_block.check() {
             return Err(FsError::WrongFs);
         }
+        let mut journal = None;
+        if super_block.journal_blocks != 0 {
+            journal = Some(Journal::open(
+                &device,
+                super_block.journal_start as BlockId,
+                super_block.journal_blocks as usize,
+            )?);
+            // The replayed transaction may contain the superblock.
+            super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
+        }
         let mut freemap_disk = vec![0u8; BLKSIZE * super_block.freemap_blocks as usize];
         for i in 0..super_block.freemap_blocks as usize {
             device.read_block(
@@ -135,38 +181,71 @@ impl SimpleFileSystem {
                 &mut freemap_disk[i * BLKSIZE..(i + 1) * BLKSIZE],
             )?;
         }
+        let mut free_map = BitVec::from_vec(freemap_disk);
+
+        match mode {
+            JournalMode::Journaled if journal.is_none() => {
+                journal = Self::reserve_journal(&device, &mut super_block, &mut free_map)?;
+            }
+            JournalMode::Journaled => {}
+            JournalMode::Unjournaled => journal = None,
+        }
 
         Ok(SimpleFileSystem {
             super_block: RwLock::new(Dirty::new(super_block)),
-            free_map: RwLock::new(Dirty::new(BitVec::from_vec(freemap_disk))),
+            free_map: RwLock::new(Dirty::new(free_map)),
             inodes: RwLock::new(BTreeMap::new()),
             device,
             self_ptr: Weak::default(),
             device_inodes: RwLock::new(BTreeMap::new()),
+            journal: RwLock::new(journal),
+            ops: RwLock::new(()),
         }
         .wrap())
     }
     /// Create a new SFS on blank disk
-    pub fn create(device: Arc<dyn Device>, space: usize) -> vfs::Result<Arc<Self>> {
+    /// In journaled mode the journal is placed right after the freemap.
+    pub fn create(
+        device: Arc<dyn Device>,
+        space: usize,
+        mode: JournalMode,
+    ) -> vfs::Result<Arc<Self>> {
         let blocks = (space + BLKSIZE - 1) / BLKSIZE;
         let freemap_blocks = (space + BLKBITS * BLKSIZE - 1) / BLKBITS / BLKSIZE;
         assert!(blocks >= 16, "space too small");
+        let journal_blocks = match mode {
+            JournalMode::Journaled => JOURNAL_BLOCKS,
+            JournalMode::Unjournaled => 0,
+        };
+        let journal_start = BLKN_FREEMAP + freemap_blocks;
+        assert!(
+            blocks >= 16 + journal_blocks,
+            "space too small for the journal"
+        );
 
         let super_block = SuperBlock {
             magic: MAGIC,
             blocks: blocks as u32,
-            unused_blocks: (blocks - BLKN_FREEMAP - freemap_blocks) as u32,
+            unused_blocks: (blocks - journal_start - journal_blocks) as u32,
             info: Str32::from(DEFAULT_INFO),
             freemap_blocks: freemap_blocks as u32,
+            journal_start: journal_start as u32,
+            journal_blocks: journal_blocks as u32,
         };
         let free_map = {
             let mut bitset = BitVec::with_capacity(freemap_blocks * BLKBITS);
             bitset.extend(core::iter::repeat(false).take(freemap_blocks * BLKBITS));
-            for i in (BLKN_FREEMAP + freemap_blocks)..blocks {
+            for i in (journal_start + journal_blocks)..blocks {
                 bitset.set(i, true);
             }
             bitset
         };
+        let journal = match mode {
+            JournalMode::Journaled => {
+                Some(Journal::format(&device, journal_start, journal_blocks)?)
+            }
+            JournalMode::Unjournaled => None,
+        };
 
         let sfs = SimpleFileSystem {
             super_block: RwLock::new(Dirty::new_dirty(super_block)),
@@ -175,6 +254,8 @@ impl SimpleFileSystem {
             device,
             self_ptr: Weak::default(),
             device_inodes: RwLock::new(BTreeMap::new()),
+            journal: RwLock::new(journal),
+            ops: RwLock::new(()),
         }
         .wrap();
 
@@ -183,7 +264,7 @@ impl SimpleFileSystem {
         root.init_direntry(BLKN_ROOT)?;
         root.nlinks_inc(); //for .
         root.nlinks_inc(); //for ..(root's parent is itself)
-        root.sync_all()?;
+        root.sync_disk_inode()?;
 
         Ok(sfs)
     }
@@ -201,6 +282,135 @@ impl SimpleFileSystem {
         unsafe { Arc::from_raw(ptr) }
     }
 
+    /// Reserve a journal in the first free run of `JOURNAL_BLOCKS` blocks of a volume created without one.
+    /// The superblock and the freemap are written in place right away so that the journal is never allocated.
+    fn reserve_journal(
+        device: &Arc<dyn Device>,
+        super_block: &mut SuperBlock,
+        free_map: &mut BitVec<u8, Lsb0>,
+    ) -> vfs::Result<Option<Journal>> {
+        let first = BLKN_FREEMAP + super_block.freemap_blocks as usize;
+        let mut run = 0;
+        let mut start = None;
+        for i in first..super_block.blocks as usize {
+            run = if free_map[i] { run + 1 } else { 0 };
+            if run == JOURNAL_BLOCKS {
+                start = Some(i + 1 - JOURNAL_BLOCKS);
+                break;
+            }
+        }
+        let start = match start {
+            Some(start) => start,
+            None => {
+                kwarn!("no room for the journal; mounting SFS unjournaled");
+                return Ok(None);
+            }
+        };
+
+        let journal = Journal::format(device, start, JOURNAL_BLOCKS)?;
+        for i in start..start + JOURNAL_BLOCKS {
+            free_map.set(i, false);
+        }
+        let data = free_map.as_buf();
+        for i in 0..super_block.freemap_blocks as usize {
+            device.write_block(BLKN_FREEMAP + i, 0, &data[i * BLKSIZE..(i + 1) * BLKSIZE])?;
+        }
+        super_block.unused_blocks -= JOURNAL_BLOCKS as u32;
+        super_block.journal_start = start as u32;
+        super_block.journal_blocks = JOURNAL_BLOCKS as u32;
+        device.write_block(BLKN_SUPER, 0, super_block.as_buf())?;
+        device.sync()?;
+        kinfo!("reserved the journal at block {:#x}", start);
+
+        Ok(Some(journal))
+    }
+
+    /// Start an operation that stages at most `blocks` blocks in the journal
+    /// The staged transaction is committed first if it cannot take them.
+    fn begin_op(&self, blocks: usize) -> vfs::Result<Operation> {
+        let mut synced = false;
+        loop {
+            let guard = self.ops.read();
+            if self.journal.read().is_none() {
+                return Ok(Operation {
+                    fs: self,
+                    blocks: 0,
+                    _guard: guard,
+                });
+            }
+
+            let extra = self.blocks_staged_by_sync();
+            if let Some(journal) = self.journal.write().as_mut() {
+                if journal.reserve(blocks, extra) {
+                    return Ok(Operation {
+                        fs: self,
+                        blocks,
+                        _guard: guard,
+                    });
+                }
+                if synced && journal.is_idle() {
+                    kerror!(
+                        "an operation of {} blocks does not fit in the journal",
+                        blocks
+                    );
+                    return Err(FsError::NoDeviceSpace);
+                }
+            }
+            drop(guard);
+            vfs::FileSystem::sync(self)?;
+            synced = true;
+        }
+    }
+
+    /// Number of blocks that `sync` stages besides those already in the journal
+    fn blocks_staged_by_sync(&self) -> usize {
+        let dirty_inodes = self
+            .inodes
+            .read()
+            .values()
+            .filter_map(|inode| inode.upgrade())
+            .filter(|inode| inode.disk_inode.read().dirty())
+            .count();
+        1 + self.super_block.read().freemap_blocks as usize + dirty_inodes
+    }
+
+    /// Read a block, preferring the contents staged in the journal
+    fn read_block(&self, id: BlockId, offset: usize, buf: &mut [u8]) -> vfs::Result<()> {
+        let journal = self.journal.read();
+        match journal.as_ref().and_then(|journal| journal.get(id)) {
+            Some(block) => {
+                buf.copy_from_slice(&block[offset..offset + buf.len()]);
+                Ok(())
+            }
+            None => self.device.read_block(id, offset, buf),
+        }
+    }
+
+    /// Write a metadata block, staging it in the journal if there is one
+    fn write_meta_block(&self, id: BlockId, offset: usize, buf: &[u8]) -> vfs::Result<()> {
+        match self.journal.write().as_mut() {
+            Some(journal) => journal.write(&self.device, id, offset, buf),
+            None => self.device.write_block(id, offset, buf),
+        }
+    }
+
+    /// Write a data block in place
+    /// A block still staged in the journal (e.g., a freed directory block) is updated there instead, or the
+    /// checkpoint would overwrite the new data.
+    fn write_data_block(&self, id: BlockId, offset: usize, buf: &[u8]) -> vfs::Result<()> {
+        match self.journal.write().as_mut() {
+            Some(journal) if journal.contains(id) => journal.write(&self.device, id, offset, buf),
+            _ => self.device.write_block(id, offset, buf),
+        }
+    }
+
+    /// Load struct `T` from given block, preferring the contents staged in the journal
+    fn load_struct<T: AsBuf>(&self, id: BlockId) -> vfs::Result<T> {
+        let mut s: T = unsafe { uninit_memory() };
+        self.read_block(id, 0, s.as_buf_mut())?;
+        Ok(s)
+    }
+
     /// Allocate a block, return block id
     fn alloc_block(&self) -> Option<usize> {
         let mut free_map = self.free_map.write();
@@ -220,9 +430,15 @@ impl SimpleFileSystem {
         id
     }
     /// Free a block
+    /// In journaled mode the block is only freed once the staged transaction commits.
     fn free_block(&self, block_id: usize) {
         let mut free_map = self.free_map.write();
         assert!(!free_map[block_id]);
+        if let Some(journal) = self.journal.write().as_mut() {
+            journal.defer_free(block_id);
+            ktrace!("defer freeing block {:#x}", block_id);
+            return;
+        }
         free_map.set(block_id, true);
         self.super_block.write().unused_blocks += 1;
         ktrace!("free block {:#x}", block_id);
@@ -260,7 +476,7 @@ impl SimpleFileSystem {
             }
         }
         // Load if not in set, or is weak ref.
-        let disk_inode = Dirty::new(self.device.load_struct::<DiskINode>(id).unwrap());
+        let disk_inode = Dirty::new(self.load_struct::<DiskINode>(id).unwrap());
         self._new_inode(id, disk_inode)
     }
     /// Create a new INode file
@@ -303,6 +519,27 @@ impl SimpleFileSystem {
     }
 }
 
+/// Number of indirect blocks under the double indirect block of a file with `blocks` blocks
+fn db_indirect_count(blocks: usize) -> usize {
+    let blocks =
+        blocks.clamp(MAX_NBLOCK_INDIRECT, MAX_NBLOCK_DOUBLE_INDIRECT) - MAX_NBLOCK_INDIRECT;
+    (blocks + BLK_NENTRY - 1) / BLK_NENTRY
+}
+
+/// Number of double indirect blocks under the triple indirect block of a file with `blocks` blocks
+fn tp_db_indirect_count(blocks: usize) -> usize {
+    let blocks = blocks.clamp(MAX_NBLOCK_DOUBLE_INDIRECT, MAX_NBLOCK_TRIPLE_INDIRECT)
+        - MAX_NBLOCK_DOUBLE_INDIRECT;
+    (blocks + BLK_NENTRY * BLK_NENTRY - 1) / (BLK_NENTRY * BLK_NENTRY)
+}
+
+/// Number of indirect blocks under the triple indirect block of a file with `blocks` blocks
+fn tp_indirect_count(blocks: usize) -> usize {
+    let blocks = blocks.clamp(MAX_NBLOCK_DOUBLE_INDIRECT, MAX_NBLOCK_TRIPLE_INDIRECT)
+        - MAX_NBLOCK_DOUBLE_INDIRECT;
+    (blocks + BLK_NENTRY - 1) / BLK_NENTRY
+}
+
 /// INode for SFS
 pub struct INodeImpl {
     /// INode number
@@ -325,7 +562,7 @@ impl INodeImpl {
             id if id < MAX_NBLOCK_DIRECT => Ok(disk_inode.direct[id] as BlockId),
             id if id < MAX_NBLOCK_INDIRECT => {
                 let mut disk_block_id: u32 = 0;
-                self.fs.device.read_block(
+                self.fs.read_block(
                     disk_inode.indirect as usize,
                     ENTRY_SIZE * (id - NDIRECT),
                     disk_block_id.as_buf_mut(),
@@ -336,14 +573,14 @@ impl INodeImpl {
                 // double indirect
                 let indirect_id = id - MAX_NBLOCK_INDIRECT;
                 let mut indirect_block_id: u32 = 0;
-                self.fs.device.read_block(
+                self.fs.read_block(
                     disk_inode.db_indirect as usize,
                     ENTRY_SIZE * (indirect_id / BLK_NENTRY),
                     indirect_block_id.as_buf_mut(),
                 )?;
                 assert!(indirect_block_id > 0);
                 let mut disk_block_id: u32 = 0;
-                self.fs.device.read_block(
+                self.fs.read_block(
                     indirect_block_id as usize,
                     ENTRY_SIZE * (indirect_id % BLK_NENTRY),
                     disk_block_id.as_buf_mut(),
@@ -351,7 +588,19 @@ impl INodeImpl {
                 assert!(disk_block_id > 0);
                 Ok(disk_block_id as BlockId)
             }
-            _ => unimplemented!("triple indirect blocks is not supported"),
+            id if id < MAX_NBLOCK_TRIPLE_INDIRECT => {
+                // triple indirect
+                let indirect_id = id - MAX_NBLOCK_DOUBLE_INDIRECT;
+                let db_indirect_block_id = self.read_entry(
+                    disk_inode.tp_indirect,
+                    indirect_id / (BLK_NENTRY * BLK_NENTRY),
+                )?;
+                let indirect_block_id =
+                    self.read_entry(db_indirect_block_id, indirect_id / BLK_NENTRY % BLK_NENTRY)?;
+                let disk_block_id = self.read_entry(indirect_block_id, indirect_id % BLK_NENTRY)?;
+                Ok(disk_block_id as BlockId)
+            }
+            _ => Err(FsError::InvalidParam),
         }
     }
     fn set_disk_block_id(&self, file_block_id: BlockId, disk_block_id: BlockId) -> vfs::Result<()> {
@@ -363,7 +612,7 @@ impl INodeImpl {
             }
             id if id < MAX_NBLOCK_INDIRECT => {
                 let disk_block_id = disk_block_id as u32;
-                self.fs.device.write_block(
+                self.fs.write_meta_block(
                     self.disk_inode.read().indirect as usize,
                     ENTRY_SIZE * (id - NDIRECT),
                     disk_block_id.as_buf(),
@@ -374,26 +623,79 @@ impl INodeImpl {
                 // double indirect
                 let indirect_id = id - MAX_NBLOCK_INDIRECT;
                 let mut indirect_block_id: u32 = 0;
-                self.fs.device.read_block(
+                self.fs.read_block(
                     self.disk_inode.read().db_indirect as usize,
                     ENTRY_SIZE * (indirect_id / BLK_NENTRY),
                     indirect_block_id.as_buf_mut(),
                 )?;
                 assert!(indirect_block_id > 0);
                 let disk_block_id = disk_block_id as u32;
-                self.fs.device.write_block(
+                self.fs.write_meta_block(
                     indirect_block_id as usize,
                     ENTRY_SIZE * (indirect_id % BLK_NENTRY),
                     disk_block_id.as_buf(),
                 )?;
                 Ok(())
             }
-            _ => unimplemented!("triple indirect blocks is not supported"),
+            id if id < MAX_NBLOCK_TRIPLE_INDIRECT => {
+                // triple indirect
+                let indirect_id = id - MAX_NBLOCK_DOUBLE_INDIRECT;
+                let db_indirect_block_id = self.read_entry(
+                    self.disk_inode.read().tp_indirect,
+                    indirect_id / (BLK_NENTRY * BLK_NENTRY),
+                )?;
+                let indirect_block_id =
+                    self.read_entry(db_indirect_block_id, indirect_id / BLK_NENTRY % BLK_NENTRY)?;
+                self.write_entry(
+                    indirect_block_id,
+                    indirect_id % BLK_NENTRY,
+                    disk_block_id as u32,
+                )
+            }
+            _ => Err(FsError::InvalidParam),
+        }
+    }
+    /// Read the `index`-th entry of an indirect block
+    fn read_entry(&self, block_id: u32, index: usize) -> vfs::Result<u32> {
+        assert!(block_id > 0);
+        let mut entry: u32 = 0;
+        self.fs
+            .read_block(block_id as usize, ENTRY_SIZE * index, entry.as_buf_mut())?;
+        assert!(entry > 0);
+        Ok(entry)
+    }
+    /// Write the `index`-th entry of an indirect block
+    fn write_entry(&self, block_id: u32, index: usize, entry: u32) -> vfs::Result<()> {
+        assert!(block_id > 0);
+        self.fs
+            .write_meta_block(block_id as usize, ENTRY_SIZE * index, entry.as_buf())
+    }
+    /// Number of blocks used by the indirect blocks of a file with `blocks` blocks
+    fn indirect_blocks(blocks: usize) -> usize {
+        let mut count = 0;
+        if blocks >= MAX_NBLOCK_DIRECT {
+            count += 1;
+        }
+        if blocks >= MAX_NBLOCK_INDIRECT {
+            count += 1 + db_indirect_count(blocks);
+        }
+        if blocks > MAX_NBLOCK_DOUBLE_INDIRECT {
+            count += 1 + tp_db_indirect_count(blocks) + tp_indirect_count(blocks);
+        }
+        count
+    }
+    /// Write back the disk inode if dirty (staged in the journal if there is one)
+    fn sync_disk_inode(&self) -> vfs::Result<()> {
+        let mut disk_inode = self.disk_inode.write();
+        if disk_inode.dirty() {
+            self.fs.write_meta_block(self.id, 0, disk_inode.as_buf())?;
+            disk_inode.sync();
         }
+        Ok(())
     }
     /// Only for Dir
     fn get_file_inode_and_entry_id(&self, name: &str) -> Option<(INodeId, usize)> {
-        (0..self.disk_inode.read().size as usize / DIRENT_SIZE)
+        (0..self.disk_inode.read().size() / DIRENT_SIZE)
             .map(|i| (self.read_direntry(i).unwrap(), i))
             .find(|(entry, _)| entry.name.as_ref() == name)
             .map(|(entry, id)| (entry.id as INodeId, id))
@@ -433,7 +735,7 @@ impl INodeImpl {
         Ok(())
     }
     fn append_direntry(&self, direntry: &DiskEntry) -> vfs::Result<()> {
-        let size = self.disk_inode.read().size as usize;
+        let size = self.disk_inode.read().size();
         let dirent_count = size / DIRENT_SIZE;
         self._resize(size + DIRENT_SIZE)?;
         self.write_direntry(dirent_count, direntry)?;
@@ -442,7 +744,7 @@ impl INodeImpl {
     /// remove a direntry in middle of file and insert the last one here, useful for direntry remove
     /// should be only used in unlink
     fn remove_direntry(&self, id: usize) -> vfs::Result<()> {
-        let size = self.disk_inode.read().size as usize;
+        let size = self.disk_inode.read().size();
         let dirent_count = size / DIRENT_SIZE;
         debug_assert!(id < dirent_count);
         let last_dirent = self.read_direntry(dirent_count - 1)?;
@@ -450,60 +752,135 @@ impl INodeImpl {
         self._resize(size - DIRENT_SIZE)?;
         Ok(())
     }
+    /// Map the blocks `old_blocks..blocks` and the indirect blocks they need to the blocks in `reserved`, which must
+    /// hold enough of them.
+    fn _grow(
+        &self,
+        old_blocks: u32,
+        blocks: u32,
+        reserved: &mut impl Iterator<Item = usize>,
+    ) -> vfs::Result<()> {
+        let mut next = || reserved.next().ok_or(FsError::NoDeviceSpace);
+        let mut disk_inode = self.disk_inode.write();
+        disk_inode.blocks = blocks;
+        // allocate indirect block if needed
+        if old_blocks < MAX_NBLOCK_DIRECT as u32 && blocks >= MAX_NBLOCK_DIRECT as u32 {
+            disk_inode.indirect = next()? as u32;
+        }
+        // allocate double indirect block if needed
+        if blocks >= MAX_NBLOCK_INDIRECT as u32 {
+            if disk_inode.db_indirect == 0 {
+                disk_inode.db_indirect = next()? as u32;
+            }
+            for i in db_indirect_count(old_blocks as usize)..db_indirect_count(blocks as usize) {
+                self.write_entry(disk_inode.db_indirect, i, next()? as u32)?;
+            }
+        }
+        // allocate triple indirect block if needed
+        if blocks > MAX_NBLOCK_DOUBLE_INDIRECT as u32 {
+            if disk_inode.tp_indirect == 0 {
+                disk_inode.tp_indirect = next()? as u32;
+            }
+            for i in
+                tp_db_indirect_count(old_blocks as usize)..tp_db_indirect_count(blocks as usize)
+            {
+                self.write_entry(disk_inode.tp_indirect, i, next()? as u32)?;
+            }
+            for i in tp_indirect_count(old_blocks as usize)..tp_indirect_count(blocks as usize) {
+                let db_indirect = self.read_entry(disk_inode.tp_indirect, i / BLK_NENTRY)?;
+                self.write_entry(db_indirect, i % BLK_NENTRY, next()? as u32)?;
+            }
+        }
+        drop(disk_inode);
+        // allocate extra blocks
+        for i in old_blocks..blocks {
+            self.set_disk_block_id(i as usize, next()?)?;
+        }
+        Ok(())
+    }
+    /// Resize a file as one or more operations, each of which fits in the journal
+    /// A file grows by at most `GROW_STEP_BLOCKS` blocks per operation; every step leaves a consistent file.
+    fn resize_in_steps(&self, len: usize) -> vfs::Result<()> {
+        if len > MAX_FILE_SIZE {
+            return Err(FsError::InvalidParam);
+        }
+        loop {
+            let (size, old_blocks) = {
+                let disk_inode = self.disk_inode.read();
+                (disk_inode.size(), disk_inode.blocks as usize)
+            };
+            let step = len.min(size.saturating_add(GROW_STEP_BLOCKS * BLKSIZE));
+            let blocks = (step + BLKSIZE - 1) / BLKSIZE;
+            let indirect =
+                Self::indirect_blocks(blocks).saturating_sub(Self::indirect_blocks(old_blocks));
+
+            let _op = self.fs.begin_op(indirect + OP_BLOCKS)?;
+            self._resize(step)?;
+            if step == len {
+                return Ok(());
+            }
+        }
+    }
     /// Resize content size, no matter what type it is.
     fn _resize(&self, len: usize) -> vfs::Result<()> {
         if len > MAX_FILE_SIZE {
             return Err(FsError::InvalidParam);
         }
-        let blocks = ((len + BLKSIZE - 1) / BLKSIZE) as u32;
-        if blocks > MAX_NBLOCK_DOUBLE_INDIRECT as u32 {
+        let blocks = (len + BLKSIZE - 1) / BLKSIZE;
+        if blocks > MAX_NBLOCK_TRIPLE_INDIRECT {
             return Err(FsError::InvalidParam);
         }
+        let blocks = blocks as u32;
         use core::cmp::Ordering;
         let old_blocks = self.disk_inode.read().blocks;
         match blocks.cmp(&old_blocks) {
             Ordering::Equal => {
-                self.disk_inode.write().size = len as u32;
+                self.disk_inode.write().set_size(len);
             }
             Ordering::Greater => {
-                let mut disk_inode = self.disk_inode.write();
-                disk_inode.blocks = blocks;
-                // allocate indirect block if needed
-                if old_blocks < MAX_NBLOCK_DIRECT as u32 && blocks >= MAX_NBLOCK_DIRECT as u32 {
-                    disk_inode.indirect = self.fs.alloc_block().expect("no space") as u32;
-                }
-                // allocate double indirect block if needed
-                if blocks >= MAX_NBLOCK_INDIRECT as u32 {
-                    if disk_inode.db_indirect == 0 {
-                        disk_inode.db_indirect = self.fs.alloc_block().expect("no space") as u32;
-                    }
-                    let indirect_begin = {
-                        if (old_blocks as usize) < MAX_NBLOCK_INDIRECT {
-                            0
-                        } else {
-                            (old_blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1
+                // Reserve all the data and indirect blocks up front, so that running out of space leaves the file
+                // as it was instead of half-mapped.
+                let needed = (blocks - old_blocks) as usize
+                    + Self::indirect_blocks(blocks as usize)
+                    - Self::indirect_blocks(old_blocks as usize);
+                let mut reserved = Vec::with_capacity(needed);
+                for _ in 0..needed {
+                    match self.fs.alloc_block() {
+                        Some(block_id) => reserved.push(block_id),
+                        None => {
+                            reserved
+                                .into_iter()
+                                .for_each(|block_id| self.fs.free_block(block_id));
+                            return Err(FsError::NoDeviceSpace);
                         }
-                    };
-                    let indirect_end = (blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1;
-                    for i in indirect_begin..indirect_end {
-                        let indirect = self.fs.alloc_block().expect("no space") as u32;
-                        self.fs.device.write_block(
-                            disk_inode.db_indirect as usize,
-                            ENTRY_SIZE * i,
-                            indirect.as_buf(),
-                        )?;
                     }
                 }
-                drop(disk_inode);
-                // allocate extra blocks
-                for i in old_blocks..blocks {
-                    let disk_block_id = self.fs.alloc_block().expect("no space");
-                    self.set_disk_block_id(i as usize, disk_block_id)?;
+                let (old_indirect, old_db_indirect, old_tp_indirect) = {
+                    let disk_inode = self.disk_inode.read();
+                    (
+                        disk_inode.indirect,
+                        disk_inode.db_indirect,
+                        disk_inode.tp_indirect,
+                    )
+                };
+                if let Err(err) = self._grow(old_blocks, blocks, &mut reserved.iter().copied()) {
+                    // Only the entries past the old blocks were written, so restoring the inode and releasing the
+                    // reservation brings back the old file.
+                    let mut disk_inode = self.disk_inode.write();
+                    disk_inode.blocks = old_blocks;
+                    disk_inode.indirect = old_indirect;
+                    disk_inode.db_indirect = old_db_indirect;
+                    disk_inode.tp_indirect = old_tp_indirect;
+                    drop(disk_inode);
+                    reserved
+                        .into_iter()
+                        .for_each(|block_id| self.fs.free_block(block_id));
+                    return Err(err);
                 }
                 // clean up
                 let mut disk_inode = self.disk_inode.write();
-                let old_size = disk_inode.size as usize;
-                disk_inode.size = len as u32;
+                let old_size = disk_inode.size();
+                disk_inode.set_size(len);
                 drop(disk_inode);
                 self._clean_at(old_size, len)?;
             }
@@ -523,23 +900,10 @@ impl INodeImpl {
                 }
                 // free double indirect block if needed
                 if disk_inode.blocks >= MAX_NBLOCK_INDIRECT as u32 {
-                    let indirect_begin = {
-                        if (blocks as usize) < MAX_NBLOCK_INDIRECT {
-                            0
-                        } else {
-                            (blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1
-                        }
-                    };
-                    let indirect_end =
-                        (disk_inode.blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1;
-                    for i in indirect_begin..indirect_end {
-                        let mut indirect: u32 = 0;
-                        self.fs.device.read_block(
-                            disk_inode.db_indirect as usize,
-                            ENTRY_SIZE * i,
-                            indirect.as_buf_mut(),
-                        )?;
-                        assert!(indirect > 0);
+                    for i in db_indirect_count(blocks as usize)
+                        ..db_indirect_count(disk_inode.blocks as usize)
+                    {
+                        let indirect = self.read_entry(disk_inode.db_indirect, i)?;
                         self.fs.free_block(indirect as usize);
                     }
                     if blocks < MAX_NBLOCK_INDIRECT as u32 {
@@ -548,8 +912,29 @@ impl INodeImpl {
                         disk_inode.db_indirect = 0;
                     }
                 }
+                // free triple indirect block if needed
+                if disk_inode.blocks > MAX_NBLOCK_DOUBLE_INDIRECT as u32 {
+                    for i in tp_indirect_count(blocks as usize)
+                        ..tp_indirect_count(disk_inode.blocks as usize)
+                    {
+                        let db_indirect =
+                            self.read_entry(disk_inode.tp_indirect, i / BLK_NENTRY)?;
+                        let indirect = self.read_entry(db_indirect, i % BLK_NENTRY)?;
+                        self.fs.free_block(indirect as usize);
+                    }
+                    for i in tp_db_indirect_count(blocks as usize)
+                        ..tp_db_indirect_count(disk_inode.blocks as usize)
+                    {
+                        let db_indirect = self.read_entry(disk_inode.tp_indirect, i)?;
+                        self.fs.free_block(db_indirect as usize);
+                    }
+                    if blocks <= MAX_NBLOCK_DOUBLE_INDIRECT as u32 {
+                        self.fs.free_block(disk_inode.tp_indirect as usize);
+                        disk_inode.tp_indirect = 0;
+                    }
+                }
                 disk_inode.blocks = blocks;
-                disk_inode.size = len as u32;
+                disk_inode.set_size(len);
             }
         }
         Ok(())
@@ -558,9 +943,9 @@ impl INodeImpl {
     /// Read/Write content, no matter what type it is
     fn _io_at<F>(&self, begin: usize, end: usize, mut f: F) -> vfs::Result<usize>
     where
-        F: FnMut(&Arc<dyn Device>, &BlockRange, usize) -> vfs::Result<()>,
+        F: FnMut(&BlockRange, usize) -> vfs::Result<()>,
     {
-        let size = self.disk_inode.read().size as usize;
+        let size = self.disk_inode.read().size();
         let iter = BlockIter {
             begin: size.min(begin),
             end: size.min(end),
@@ -571,15 +956,15 @@ impl INodeImpl {
         let mut buf_offset = 0usize;
         for mut range in iter {
             range.block = self.get_disk_block_id(range.block)?;
-            f(&self.fs.device, &range, buf_offset)?;
+            f(&range, buf_offset)?;
             buf_offset += range.len();
         }
         Ok(buf_offset)
     }
     /// Read content, no matter what type it is
     fn _read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
-        self._io_at(offset, offset + buf.len(), |device, range, offset| {
-            device.read_block(
+        self._io_at(offset, offset + buf.len(), |range, offset| {
+            self.fs.read_block(
                 range.block,
                 range.begin,
                 &mut buf[offset..offset + range.len()],
@@ -588,17 +973,27 @@ impl INodeImpl {
     }
     /// Write content, no matter what type it is
     fn _write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
-        self._io_at(offset, offset + buf.len(), |device, range, offset| {
-            device.write_block(range.block, range.begin, &buf[offset..offset + range.len()])
+        let is_dir = self.disk_inode.read().type_ == FileType::Dir;
+        self._io_at(offset, offset + buf.len(), |range, offset| {
+            self.write_content_block(is_dir, range, &buf[offset..offset + range.len()])
         })
     }
     /// Clean content, no matter what type it is
     fn _clean_at(&self, begin: usize, end: usize) -> vfs::Result<usize> {
         static ZEROS: [u8; BLKSIZE] = [0; BLKSIZE];
-        self._io_at(begin, end, |device, range, _| {
-            device.write_block(range.block, range.begin, &ZEROS[..range.len()])
+        let is_dir = self.disk_inode.read().type_ == FileType::Dir;
+        self._io_at(begin, end, |range, _| {
+            self.write_content_block(is_dir, range, &ZEROS[..range.len()])
         })
     }
+    /// Write a content block; the blocks of a directory are metadata
+    fn write_content_block(&self, is_dir: bool, range: &BlockRange, buf: &[u8]) -> vfs::Result<()> {
+        if is_dir {
+            self.fs.write_meta_block(range.block, range.begin, buf)
+        } else {
+            self.fs.write_data_block(range.block, range.begin, buf)
+        }
+    }
     fn nlinks_inc(&self) {
         self.disk_inode.write().nlinks += 1;
     }
@@ -631,7 +1026,7 @@ impl INodeImpl {
             name: Str256::from(name),
         };
         let disk_inode = self.disk_inode.write();
-        let old_size = disk_inode.size as usize;
+        let old_size = disk_inode.size();
         self._resize(old_size + BLKSIZE)?;
         self._write_at(old_size, entry.as_buf()).unwrap();
         child.nlinks_inc();
@@ -656,12 +1051,15 @@ impl vfs::INode for INodeImpl {
         }
     }
     fn write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
-        let DiskINode { type_, size, .. } = **self.disk_inode.read();
+        let (type_, size) = {
+            let disk_inode = self.disk_inode.read();
+            (disk_inode.type_, disk_inode.size())
+        };
         match type_ {
             FileType::File | FileType::SymLink => {
                 let end_offset = offset + buf.len();
-                if (size as usize) < end_offset {
-                    self._resize(end_offset)?;
+                if size < end_offset {
+                    self.resize_in_steps(end_offset)?;
                 }
                 self._write_at(offset, buf)
             }
@@ -690,15 +1088,16 @@ impl vfs::INode for INodeImpl {
             dev: 0,
             inode: self.id,
             size: match disk_inode.type_ {
-                FileType::File | FileType::SymLink => disk_inode.size as usize,
-                FileType::Dir => disk_inode.size as usize,
+                FileType::File | FileType::SymLink => disk_inode.size(),
+                FileType::Dir => disk_inode.size(),
                 FileType::CharDevice => 0,
                 FileType::BlockDevice => 0,
                 _ => panic!("Unknown file type"),
             },
             mode: 0o777,
             type_: vfs::FileType::from(disk_inode.type_),
-            blocks: disk_inode.blocks as usize,
+            // Indirect blocks are counted as well.
+            blocks: disk_inode.blocks as usize + Self::indirect_blocks(disk_inode.blocks as usize),
             atime: disk_inode.atime,
             mtime: disk_inode.mtime,
             ctime: disk_inode.ctime,
@@ -710,6 +1109,7 @@ impl vfs::INode for INodeImpl {
         })
     }
     fn set_metadata(&self, metadata: &vfs::Metadata) -> vfs::Result<()> {
+        let _op = self.fs.begin_op(1)?;
         let mut disk_inode = self.disk_inode.write();
         disk_inode.atime = metadata.atime;
         disk_inode.mtime = metadata.mtime;
@@ -717,14 +1117,11 @@ impl vfs::INode for INodeImpl {
         Ok(())
     }
     fn sync_all(&self) -> vfs::Result<()> {
-        let mut disk_inode = self.disk_inode.write();
-        if disk_inode.dirty() {
-            self.fs
-                .device
-                .write_block(self.id, 0, disk_inode.as_buf())?;
-            disk_inode.sync();
+        // A journaled inode is only durable together with the freemap it refers to.
+        if self.fs.journal.read().is_some() {
+            return self.fs.sync();
         }
-        Ok(())
+        self.sync_disk_inode()
     }
     fn sync_data(&self) -> vfs::Result<()> {
         self.sync_all()
@@ -735,7 +1132,7 @@ impl vfs::INode for INodeImpl {
         {
             return Err(FsError::NotFile);
         }
-        self._resize(len)
+        self.resize_in_steps(len)
     }
     fn create2(
         &self,
@@ -744,6 +1141,7 @@ impl vfs::INode for INodeImpl {
         _mode: u32,
         data: usize,
     ) -> vfs::Result<Arc<dyn vfs::INode>> {
+        let _op = self.fs.begin_op(OP_BLOCKS)?;
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -781,6 +1179,7 @@ impl vfs::INode for INodeImpl {
     }
 
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> vfs::Result<()> {
+        let _op = self.fs.begin_op(OP_BLOCKS)?;
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -808,6 +1207,7 @@ impl vfs::INode for INodeImpl {
         Ok(())
     }
     fn unlink(&self, name: &str) -> vfs::Result<()> {
+        let _op = self.fs.begin_op(OP_BLOCKS)?;
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -830,7 +1230,7 @@ impl vfs::INode for INodeImpl {
         let type_ = inode.disk_inode.read().type_;
         if type_ == FileType::Dir {
             // only . and ..
-            if inode.disk_inode.read().size as usize / DIRENT_SIZE > 2 {
+            if inode.disk_inode.read().size() / DIRENT_SIZE > 2 {
                 return Err(FsError::DirNotEmpty);
             }
         }
@@ -844,6 +1244,7 @@ impl vfs::INode for INodeImpl {
         Ok(())
     }
     fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> vfs::Result<()> {
+        let _op = self.fs.begin_op(OP_BLOCKS)?;
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -915,7 +1316,7 @@ impl vfs::INode for INodeImpl {
         if self.disk_inode.read().type_ != FileType::Dir {
             return Err(FsError::NotDir);
         }
-        if id >= self.disk_inode.read().size as usize / DIRENT_SIZE {
+        if id >= self.disk_inode.read().size() / DIRENT_SIZE {
             return Err(FsError::EntryNotFound);
         };
         let entry = self.read_direntry(id)?;
@@ -926,7 +1327,7 @@ impl vfs::INode for INodeImpl {
         if self.disk_inode.read().type_ != FileType::Dir {
             return Err(FsError::NotDir);
         }
-        if id >= self.disk_inode.read().size as usize / DIRENT_SIZE {
+        if id >= self.disk_inode.read().size() / DIRENT_SIZE {
             return Err(FsError::EntryNotFound);
         };
         let entry = self.read_direntry(id)?;
@@ -989,7 +1390,7 @@ impl Debug for INodeImpl {
 impl Drop for INodeImpl {
     /// Auto sync when drop
     fn drop(&mut self) {
-        self.sync_all()
+        self.sync_disk_inode()
             .expect("Failed to sync when dropping the SimpleFileSystem Inode");
         if self.disk_inode.read().nlinks == 0 {
             self._resize(0).unwrap();
@@ -17,6 +17,11 @@ pub struct SuperBlock {
     pub info: Str32,
     /// number of freemap blocks
     pub freemap_blocks: u32,
+    /// 1st block of the journal
+    /// Appended so that the volumes created before read it as 0, i.e., without a journal.
+    pub journal_start: u32,
+    /// number of journal blocks, 0 if the volume has no journal
+    pub journal_blocks: u32,
 }
 
 /// inode (on disk)
@@ -47,6 +52,11 @@ pub struct DiskINode {
     pub mtime: Timespec,
     /// Time of last change
     pub ctime: Timespec,
+    /// triple indirect blocks
+    /// Appended so that the layout of the inodes created before stays the same.
+    pub tp_indirect: u32,
+    /// the high 32 bits of the size
+    pub size_hi: u32,
 }
 
 /*
@@ -73,6 +83,32 @@ pub struct DiskEntry {
     pub name: Str256,
 }
 
+/// journal descriptor (on disk), the 1st block of the journal
+#[repr(C)]
+pub struct JournalDescriptor {
+    /// magic number, should be JOURNAL_MAGIC
+    pub magic: u32,
+    /// number of blocks in the transaction, 0 if the journal is empty
+    pub count: u32,
+    /// sequence number of the transaction
+    pub sequence: u64,
+    /// home location of each block in the transaction
+    pub blocks: [u32; MAX_TRANSACTION_BLOCKS],
+}
+
+/// journal commit record (on disk), right after the last block of the transaction
+#[repr(C)]
+pub struct JournalCommit {
+    /// magic number, should be COMMIT_MAGIC
+    pub magic: u32,
+    /// number of blocks in the transaction
+    pub count: u32,
+    /// sequence number of the transaction
+    pub sequence: u64,
+    /// crc32 of the home locations and the contents of the blocks
+    pub checksum: u32,
+}
+
 #[repr(C)]
 pub struct Str256(pub [u8; 256]);
 
@@ -128,6 +164,17 @@ impl SuperBlock {
 }
 
 impl DiskINode {
+    /// Gets the size of the file (in bytes).
+    pub fn size(&self) -> usize {
+        self.size as usize | ((self.size_hi as usize) << 32)
+    }
+
+    /// Sets the size of the file (in bytes).
+    pub fn set_size(&mut self, size: usize) {
+        self.size = size as u32;
+        self.size_hi = (size >> 32) as u32;
+    }
+
     pub const fn new_file() -> Self {
         DiskINode {
             size: 0,
@@ -141,6 +188,8 @@ impl DiskINode {
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            tp_indirect: 0,
+            size_hi: 0,
         }
     }
     pub const fn new_symlink() -> Self {
@@ -156,6 +205,8 @@ impl DiskINode {
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            tp_indirect: 0,
+            size_hi: 0,
         }
     }
     pub const fn new_dir() -> Self {
@@ -171,6 +222,8 @@ impl DiskINode {
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            tp_indirect: 0,
+            size_hi: 0,
         }
     }
     pub const fn new_chardevice(device_inode_id: usize) -> Self {
@@ -186,6 +239,8 @@ impl DiskINode {
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            tp_indirect: 0,
+            size_hi: 0,
         }
     }
 }
@@ -206,6 +261,10 @@ impl AsBuf for DiskINode {}
 
 impl AsBuf for DiskEntry {}
 
+impl AsBuf for JournalDescriptor {}
+
+impl AsBuf for JournalCommit {}
+
 impl AsBuf for u32 {}
 
 /*
@@ -231,15 +290,22 @@ pub const DEFAULT_INFO: &str = "simple file system";
 pub const MAX_INFO_LEN: usize = 31;
 /// max length of filename
 pub const MAX_FNAME_LEN: usize = 255;
-/// max file size in theory (48KB + 4MB + 4GB)
-/// however, the file size is stored in u32
-pub const MAX_FILE_SIZE: usize = 0xffffffff;
+/// max file size (48KB + 4MB + 4GB + 4TB)
+pub const MAX_FILE_SIZE: usize = MAX_NBLOCK_TRIPLE_INDIRECT * BLKSIZE;
 /// block the superblock lives in
 pub const BLKN_SUPER: BlockId = 0;
 /// location of the root dir inode
 pub const BLKN_ROOT: BlockId = 1;
 /// 1st block of the freemap
 pub const BLKN_FREEMAP: BlockId = 2;
+/// magic number for the journal descriptor
+pub const JOURNAL_MAGIC: u32 = 0x6a726e6c;
+/// magic number for the journal commit record
+pub const COMMIT_MAGIC: u32 = 0x636d6974;
+/// number of blocks reserved for the journal
+pub const JOURNAL_BLOCKS: usize = 1024;
+/// max number of blocks in a transaction (limited by the descriptor)
+pub const MAX_TRANSACTION_BLOCKS: usize = (BLKSIZE - 16) / ENTRY_SIZE;
 /// number of bits in a block
 pub const BLKBITS: usize = BLKSIZE * 8;
 /// size of one entry
@@ -254,6 +320,9 @@ pub const MAX_NBLOCK_DIRECT: usize = NDIRECT;
 pub const MAX_NBLOCK_INDIRECT: usize = NDIRECT + BLK_NENTRY;
 /// max number of blocks with double indirect blocks
 pub const MAX_NBLOCK_DOUBLE_INDIRECT: usize = NDIRECT + BLK_NENTRY + BLK_NENTRY * BLK_NENTRY;
+/// max number of blocks with triple indirect blocks
+pub const MAX_NBLOCK_TRIPLE_INDIRECT: usize =
+    MAX_NBLOCK_DOUBLE_INDIRECT + BLK_NENTRY * BLK_NENTRY * BLK_NENTRY;
 
 /// file types
 #[repr(u16)]
@@ -0,0 +1,729 @@
+//! Implements tmpfs, a filesystem that keeps all of its files in memory, so that there is writable scratch space at
+//! `/tmp` and `/run` even if the root filesystem is read-only.
+//!
+//! Every inode holds its data in physical frames taken from the frame allocator rather than on the kernel heap, and
+//! every directory holds its children in a `BTreeMap`, so nothing is written back and everything is lost on reboot.
+//! Files are sparse: a frame is only allocated when a page is first written, and the holes read as zeros. Each frame
+//! is charged to the filesystem and the frames cannot exceed the size limit given on creation; they are returned once
+//! the file is truncated, or once the last link and the last reference to the inode are gone.
+
+use core::{
+    any::Any,
+    fmt::{Debug, Error, Formatter},
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use lazy_static::lazy_static;
+use rcore_fs::vfs::{
+    self, FileSystem, FileType, FsError, FsInfo, INode, MMapArea, Metadata, PollStatus, Timespec,
+};
+use spin::RwLock;
+
+use x86_64::PhysAddr;
+
+use crate::{
+    arch::PAGE_SIZE,
+    memory::{phys_to_virt, FrameAlloc, KernelFrameAllocator},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::INODE_COUNT;
+
+/// The size limit of the tmpfs mounted at `/tmp` in pages (64 MiB).
+pub const TMPFS_MAX_PAGES: usize = 0x4000;
+/// The maximum length of a file name.
+pub const MAX_NAME_LEN: usize = 0xff;
+
+lazy_static! {
+    pub static ref TMP_FS: Arc<TmpFileSystem> = TmpFileSystem::new(TMPFS_MAX_PAGES);
+}
+
+/// Gets the current time.
+fn now() -> Timespec {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Timespec {
+        sec: now.as_secs() as _,
+        nsec: now.subsec_nanos() as _,
+    }
+}
+
+/// Gets the number of pages needed to hold `len` bytes.
+fn pages(len: usize) -> usize {
+    (len + PAGE_SIZE - 1) / PAGE_SIZE
+}
+
+fn check_name(name: &str) -> vfs::Result<()> {
+    match name {
+        "" | "." | ".." => Err(FsError::InvalidParam),
+        name if name.len() > MAX_NAME_LEN || name.contains('/') => Err(FsError::InvalidParam),
+        _ => Ok(()),
+    }
+}
+
+/// An in-memory filesystem whose data is limited to `max_pages` pages.
+pub struct TmpFileSystem {
+    root: Arc<TmpINode>,
+    /// The size limit in pages.
+    max_pages: usize,
+    /// The number of pages charged to the files.
+    used_pages: AtomicUsize,
+    /// Serializes the operations that change the directory tree.
+    tree: RwLock<()>,
+}
+
+impl TmpFileSystem {
+    /// Creates an empty filesystem that holds at most `max_pages` pages of data.
+    pub fn new(max_pages: usize) -> Arc<Self> {
+        Arc::new_cyclic(|fs| Self {
+            root: TmpINode::new(fs.clone(), FileType::Dir, 0o1777, Weak::new()),
+            max_pages,
+            used_pages: AtomicUsize::new(0),
+            tree: RwLock::new(()),
+        })
+    }
+
+    /// Charges `count` new pages to the filesystem.
+    fn charge(&self, count: usize) -> vfs::Result<()> {
+        self.used_pages
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(count)
+                    .filter(|&used| used <= self.max_pages)
+            })
+            .map(|_| ())
+            .map_err(|_| FsError::NoDeviceSpace)
+    }
+
+    /// Allocates a zeroed page charged to the filesystem.
+    fn alloc_page(&self) -> vfs::Result<TmpPage> {
+        self.charge(1)?;
+        TmpPage::new().map_err(|err| {
+            self.release(1);
+            err
+        })
+    }
+
+    /// Returns `count` pages to the filesystem. This cannot fail, so it is safe to call while dropping a file.
+    fn release(&self, count: usize) {
+        let _ = self
+            .used_pages
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                Some(used.saturating_sub(count))
+            });
+    }
+
+    /// Gets the size limit in bytes.
+    pub fn max_file_size(&self) -> usize {
+        self.max_pages.saturating_mul(PAGE_SIZE)
+    }
+}
+
+impl FileSystem for TmpFileSystem {
+    fn sync(&self) -> vfs::Result<()> {
+        Ok(())
+    }
+
+    fn root_inode(&self) -> Arc<dyn INode> {
+        self.root.clone()
+    }
+
+    fn info(&self) -> FsInfo {
+        let free = self
+            .max_pages
+            .saturating_sub(self.used_pages.load(Ordering::SeqCst));
+        FsInfo {
+            bsize: PAGE_SIZE,
+            frsize: PAGE_SIZE,
+            blocks: self.max_pages,
+            bfree: free,
+            bavail: free,
+            files: 0,
+            ffree: 0,
+            namemax: MAX_NAME_LEN,
+        }
+    }
+}
+
+/// A page of a file held in a physical frame, which is freed on drop.
+struct TmpPage {
+    frame: PhysAddr,
+}
+
+impl TmpPage {
+    fn new() -> vfs::Result<Self> {
+        let frame = KernelFrameAllocator
+            .alloc()
+            .map_err(|_| FsError::NoDeviceSpace)?;
+        let page = Self { frame };
+        page.data_mut().fill(0);
+        Ok(page)
+    }
+
+    fn data(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(phys_to_virt(self.frame.as_u64()) as *const u8, PAGE_SIZE)
+        }
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn data_mut(&self) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(phys_to_virt(self.frame.as_u64()) as *mut u8, PAGE_SIZE)
+        }
+    }
+}
+
+impl Drop for TmpPage {
+    fn drop(&mut self) {
+        if let Err(errno) = KernelFrameAllocator.dealloc(self.frame.as_u64()) {
+            kerror!(
+                "drop(): failed to free the tmpfs frame {:#x}. Errno: {:?}",
+                self.frame,
+                errno
+            );
+        }
+    }
+}
+
+struct TmpINodeInner {
+    /// The size in the metadata is not used; it is derived from `len` or `children`.
+    metadata: Metadata,
+    /// The length of a regular file or the target of a symbolic link.
+    len: usize,
+    /// The pages of the content that have been written, keyed by their index.
+    pages: BTreeMap<usize, TmpPage>,
+    children: BTreeMap<String, Arc<TmpINode>>,
+    /// The parent directory; only used by the directories.
+    parent: Weak<TmpINode>,
+}
+
+impl TmpINodeInner {
+    fn check_dir(&self) -> vfs::Result<()> {
+        match self.metadata.type_ {
+            FileType::Dir => Ok(()),
+            _ => Err(FsError::NotDir),
+        }
+    }
+
+    /// Updates the modification and change time.
+    fn touch(&mut self) {
+        let now = now();
+        self.metadata.mtime = now;
+        self.metadata.ctime = now;
+    }
+}
+
+pub struct TmpINode {
+    id: usize,
+    fs: Weak<TmpFileSystem>,
+    self_ptr: Weak<TmpINode>,
+    inner: RwLock<TmpINodeInner>,
+}
+
+impl TmpINode {
+    fn new(
+        fs: Weak<TmpFileSystem>,
+        type_: FileType,
+        mode: u32,
+        parent: Weak<TmpINode>,
+    ) -> Arc<Self> {
+        let now = now();
+        let id = INODE_COUNT.fetch_add(1, Ordering::SeqCst) as usize;
+        Arc::new_cyclic(|self_ptr| Self {
+            id,
+            fs,
+            self_ptr: self_ptr.clone(),
+            inner: RwLock::new(TmpINodeInner {
+                metadata: Metadata {
+                    dev: 0,
+                    inode: id,
+                    size: 0,
+                    blk_size: PAGE_SIZE,
+                    blocks: 0,
+                    atime: now,
+                    mtime: now,
+                    ctime: now,
+                    type_,
+                    mode: (mode & 0o7777) as _,
+                    nlinks: match type_ {
+                        FileType::Dir => 2,
+                        _ => 1,
+                    },
+                    uid: 0,
+                    gid: 0,
+                    rdev: 0,
+                },
+                len: 0,
+                pages: BTreeMap::new(),
+                children: BTreeMap::new(),
+                parent,
+            }),
+        })
+    }
+
+    fn arc(&self) -> Arc<TmpINode> {
+        self.self_ptr.upgrade().unwrap()
+    }
+
+    fn tmpfs(&self) -> Arc<TmpFileSystem> {
+        self.fs.upgrade().unwrap()
+    }
+
+    /// Gets the maximum size of the files on the filesystem.
+    pub fn max_file_size(&self) -> usize {
+        self.tmpfs().max_file_size()
+    }
+
+    fn is_dir(&self) -> bool {
+        self.inner.read().metadata.type_ == FileType::Dir
+    }
+
+    fn check_empty(&self) -> vfs::Result<()> {
+        match self.inner.read().children.is_empty() {
+            true => Ok(()),
+            false => Err(FsError::DirNotEmpty),
+        }
+    }
+
+    /// Puts `inode` into the directory as `name` in place of `victim`.
+    fn put_entry(
+        inner: &mut TmpINodeInner,
+        name: &str,
+        inode: &Arc<TmpINode>,
+        victim: Option<Arc<TmpINode>>,
+    ) {
+        if let Some(victim) = victim {
+            let mut victim_inner = victim.inner.write();
+            if victim_inner.metadata.type_ == FileType::Dir {
+                victim_inner.metadata.nlinks = 0;
+                // for ..
+                inner.metadata.nlinks -= 1;
+            } else {
+                victim_inner.metadata.nlinks -= 1;
+            }
+            victim_inner.metadata.ctime = now();
+        }
+        inner.children.insert(name.into(), inode.clone());
+        inner.touch();
+    }
+
+    /// Sets the length of the file. Growing only leaves a hole; shrinking frees the pages past the end and zeroes the
+    /// tail of the last page, so that the hole left by growing again reads as zeros.
+    fn _resize(&self, inner: &mut TmpINodeInner, len: usize) -> vfs::Result<()> {
+        if len < inner.len {
+            let freed = inner.pages.split_off(&pages(len));
+            self.tmpfs().release(freed.len());
+            if let Some(page) = inner.pages.get(&(len / PAGE_SIZE)) {
+                page.data_mut()[len % PAGE_SIZE..].fill(0);
+            }
+        }
+        inner.len = len;
+        Ok(())
+    }
+}
+
+impl INode for TmpINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
+        let inner = self.inner.read();
+        match inner.metadata.type_ {
+            FileType::File | FileType::SymLink => {}
+            FileType::Dir => return Err(FsError::IsDir),
+            _ => return Err(FsError::NotFile),
+        }
+        let start = offset.min(inner.len);
+        let end = offset.saturating_add(buf.len()).min(inner.len);
+        let mut pos = start;
+        while pos < end {
+            let (index, page_offset) = (pos / PAGE_SIZE, pos % PAGE_SIZE);
+            let len = (PAGE_SIZE - page_offset).min(end - pos);
+            let dst = &mut buf[pos - start..pos - start + len];
+            match inner.pages.get(&index) {
+                Some(page) => dst.copy_from_slice(&page.data()[page_offset..page_offset + len]),
+                None => dst.fill(0),
+            }
+            pos += len;
+        }
+        Ok(end - start)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
+        let mut inner = self.inner.write();
+        match inner.metadata.type_ {
+            FileType::File | FileType::SymLink => {}
+            FileType::Dir => return Err(FsError::IsDir),
+            _ => return Err(FsError::NotFile),
+        }
+        let end = offset.checked_add(buf.len()).ok_or(FsError::InvalidParam)?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // Allocate the missing pages first so that a failure leaves the file untouched.
+        let fs = self.tmpfs();
+        let mut new_pages = Vec::new();
+        for index in offset / PAGE_SIZE..pages(end) {
+            if !inner.pages.contains_key(&index) {
+                match fs.alloc_page() {
+                    Ok(page) => new_pages.push((index, page)),
+                    Err(err) => {
+                        fs.release(new_pages.len());
+                        return Err(err);
+                    }
+                }
+            }
+        }
+        inner.pages.extend(new_pages);
+        if end > inner.len {
+            inner.len = end;
+        }
+
+        let mut pos = offset;
+        while pos < end {
+            let (index, page_offset) = (pos / PAGE_SIZE, pos % PAGE_SIZE);
+            let len = (PAGE_SIZE - page_offset).min(end - pos);
+            inner.pages[&index].data_mut()[page_offset..page_offset + len]
+                .copy_from_slice(&buf[pos - offset..pos - offset + len]);
+            pos += len;
+        }
+        inner.touch();
+        Ok(buf.len())
+    }
+
+    fn poll(&self) -> vfs::Result<PollStatus> {
+        Ok(PollStatus {
+            read: true,
+            write: true,
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> vfs::Result<Metadata> {
+        let inner = self.inner.read();
+        let mut metadata = inner.metadata.clone();
+        metadata.size = match metadata.type_ {
+            FileType::Dir => inner.children.len(),
+            _ => inner.len,
+        };
+        metadata.blocks = inner.pages.len() * (PAGE_SIZE / 512);
+        Ok(metadata)
+    }
+
+    fn set_metadata(&self, metadata: &Metadata) -> vfs::Result<()> {
+        let mut inner = self.inner.write();
+        inner.metadata.atime = metadata.atime;
+        inner.metadata.mtime = metadata.mtime;
+        inner.metadata.ctime = metadata.ctime;
+        inner.metadata.mode = metadata.mode & 0o7777;
+        inner.metadata.uid = metadata.uid;
+        inner.metadata.gid = metadata.gid;
+        Ok(())
+    }
+
+    fn sync_all(&self) -> vfs::Result<()> {
+        Ok(())
+    }
+
+    fn sync_data(&self) -> vfs::Result<()> {
+        Ok(())
+    }
+
+    fn resize(&self, len: usize) -> vfs::Result<()> {
+        let mut inner = self.inner.write();
+        match inner.metadata.type_ {
+            FileType::File | FileType::SymLink => {}
+            FileType::Dir => return Err(FsError::IsDir),
+            _ => return Err(FsError::NotFile),
+        }
+        self._resize(&mut inner, len)?;
+        inner.touch();
+        Ok(())
+    }
+
+    fn create2(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+        data: usize,
+    ) -> vfs::Result<Arc<dyn INode>> {
+        check_name(name)?;
+        match type_ {
+            FileType::File | FileType::Dir | FileType::SymLink => {}
+            FileType::CharDevice | FileType::BlockDevice => {}
+            _ => return Err(FsError::NotSupported),
+        }
+
+        let fs = self.tmpfs();
+        let _tree = fs.tree.write();
+        let mut inner = self.inner.write();
+        inner.check_dir()?;
+        // Ensure the name is not exist
+        if inner.children.contains_key(name) {
+            return Err(FsError::EntryExist);
+        }
+
+        let mode = match type_ {
+            FileType::SymLink => 0o777,
+            _ => mode,
+        };
+        let inode = TmpINode::new(self.fs.clone(), type_, mode, self.self_ptr.clone());
+        if let FileType::CharDevice | FileType::BlockDevice = type_ {
+            inode.inner.write().metadata.rdev = data;
+        }
+        inner.children.insert(name.into(), inode.clone());
+        if type_ == FileType::Dir {
+            // for ..
+            inner.metadata.nlinks += 1;
+        }
+        inner.touch();
+
+        Ok(inode)
+    }
+
+    fn link(&self, name: &str, other: &Arc<dyn INode>) -> vfs::Result<()> {
+        check_name(name)?;
+        let child = other.downcast_ref::<TmpINode>().ok_or(FsError::NotSameFs)?;
+        if !Weak::ptr_eq(&self.fs, &child.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        if child.is_dir() {
+            return Err(FsError::IsDir);
+        }
+
+        let fs = self.tmpfs();
+        let _tree = fs.tree.write();
+        let mut inner = self.inner.write();
+        inner.check_dir()?;
+        if inner.children.contains_key(name) {
+            return Err(FsError::EntryExist);
+        }
+        inner.children.insert(name.into(), child.arc());
+        inner.touch();
+
+        let mut child_inner = child.inner.write();
+        child_inner.metadata.nlinks += 1;
+        child_inner.metadata.ctime = now();
+        Ok(())
+    }
+
+    fn unlink(&self, name: &str) -> vfs::Result<()> {
+        if name == "." || name == ".." {
+            return Err(FsError::IsDir);
+        }
+
+        let fs = self.tmpfs();
+        let _tree = fs.tree.write();
+        let mut inner = self.inner.write();
+        inner.check_dir()?;
+        let child = inner
+            .children
+            .get(name)
+            .cloned()
+            .ok_or(FsError::EntryNotFound)?;
+        let is_dir = child.is_dir();
+        if is_dir {
+            child.check_empty()?;
+            // for ..
+            inner.metadata.nlinks -= 1;
+        }
+        inner.children.remove(name);
+        inner.touch();
+
+        let mut child_inner = child.inner.write();
+        child_inner.metadata.nlinks = match is_dir {
+            true => 0,
+            false => child_inner.metadata.nlinks - 1,
+        };
+        child_inner.metadata.ctime = now();
+        Ok(())
+    }
+
+    fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> vfs::Result<()> {
+        if old_name == "." || old_name == ".." {
+            return Err(FsError::IsDir);
+        }
+        check_name(new_name)?;
+        let dest = target
+            .downcast_ref::<TmpINode>()
+            .ok_or(FsError::NotSameFs)?;
+        if !Weak::ptr_eq(&self.fs, &dest.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        let same_dir = ptr::eq(self, dest);
+
+        let fs = self.tmpfs();
+        let _tree = fs.tree.write();
+        let inode = {
+            let inner = self.inner.read();
+            inner.check_dir()?;
+            inner
+                .children
+                .get(old_name)
+                .cloned()
+                .ok_or(FsError::EntryNotFound)?
+        };
+        dest.inner.read().check_dir()?;
+        let is_dir = inode.is_dir();
+
+        // Cannot move a directory into itself or its subdirectories.
+        let mut ancestor = Some(dest.arc());
+        while let Some(dir) = ancestor {
+            if Arc::ptr_eq(&dir, &inode) {
+                return Err(FsError::InvalidParam);
+            }
+            ancestor = dir.inner.read().parent.upgrade();
+        }
+
+        // Replace the existing target.
+        let existing = dest.inner.read().children.get(new_name).cloned();
+        if let Some(victim) = existing.as_ref() {
+            if Arc::ptr_eq(victim, &inode) {
+                // Both names refer to the same inode.
+                return Ok(());
+            }
+            if ptr::eq(victim.as_ref(), self) {
+                // `self` contains `old_name`.
+                return Err(FsError::DirNotEmpty);
+            }
+            match (is_dir, victim.is_dir()) {
+                (true, false) => return Err(FsError::NotDir),
+                (false, true) => return Err(FsError::IsDir),
+                (true, true) => victim.check_empty()?,
+                (false, false) => {}
+            }
+        }
+
+        let mut inner = self.inner.write();
+        inner.children.remove(old_name);
+        inner.touch();
+        if same_dir {
+            Self::put_entry(&mut inner, new_name, &inode, existing);
+        } else {
+            if is_dir {
+                // for ..
+                inner.metadata.nlinks -= 1;
+            }
+            drop(inner);
+            let mut dest_inner = dest.inner.write();
+            Self::put_entry(&mut dest_inner, new_name, &inode, existing);
+            if is_dir {
+                // for ..
+                dest_inner.metadata.nlinks += 1;
+            }
+        }
+
+        let mut node = inode.inner.write();
+        if is_dir {
+            node.parent = dest.self_ptr.clone();
+        }
+        node.metadata.ctime = now();
+        Ok(())
+    }
+
+    fn find(&self, name: &str) -> vfs::Result<Arc<dyn INode>> {
+        let inner = self.inner.read();
+        inner.check_dir()?;
+        match name {
+            "." => Ok(self.arc()),
+            ".." => Ok(inner.parent.upgrade().unwrap_or_else(|| self.arc())),
+            name => inner
+                .children
+                .get(name)
+                .cloned()
+                .map(|inode| inode as Arc<dyn INode>)
+                .ok_or(FsError::EntryNotFound),
+        }
+    }
+
+    fn get_entry(&self, id: usize) -> vfs::Result<String> {
+        self.list()?
+            .into_iter()
+            .nth(id)
+            .map(|(_, name)| name)
+            .ok_or(FsError::EntryNotFound)
+    }
+
+    fn get_entry_with_metadata(&self, id: usize) -> vfs::Result<(Metadata, String)> {
+        let (inode, name): (Arc<dyn INode>, String) = {
+            let inner = self.inner.read();
+            inner.check_dir()?;
+            match id {
+                0 => (self.arc(), String::from(".")),
+                1 => (
+                    inner.parent.upgrade().unwrap_or_else(|| self.arc()),
+                    String::from(".."),
+                ),
+                _ => inner
+                    .children
+                    .iter()
+                    .nth(id - 2)
+                    .map(|(name, inode)| (inode.clone() as Arc<dyn INode>, name.clone()))
+                    .ok_or(FsError::EntryNotFound)?,
+            }
+        };
+        Ok((inode.metadata()?, name))
+    }
+
+    fn list(&self) -> vfs::Result<Vec<(usize, String)>> {
+        let inner = self.inner.read();
+        inner.check_dir()?;
+        let parent_id = inner.parent.upgrade().map_or(self.id, |parent| parent.id);
+
+        let mut list = vec![
+            (self.id, String::from(".")),
+            (parent_id, String::from("..")),
+        ];
+        list.extend(
+            inner
+                .children
+                .iter()
+                .map(|(name, inode)| (inode.id, name.clone())),
+        );
+        Ok(list)
+    }
+
+    fn io_control(&self, _cmd: u32, _data: usize) -> vfs::Result<usize> {
+        Err(FsError::IOCTLError)
+    }
+
+    fn mmap(&self, _area: MMapArea) -> vfs::Result<()> {
+        Err(FsError::NotSupported)
+    }
+
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        self.tmpfs()
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Debug for TmpINode {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        let inner = self.inner.read();
+        write!(
+            f,
+            "INode {{ id: {}, type: {:?}, size: {:#x} }}",
+            self.id, inner.metadata.type_, inner.len
+        )
+    }
+}
+
+impl Drop for TmpINode {
+    /// Returns the pages of the data to the filesystem; the frames are freed along with them.
+    fn drop(&mut self) {
+        if let Some(fs) = self.fs.upgrade() {
+            fs.release(self.inner.read().pages.len());
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Implements the extended attributes of the inodes. Only APFS stores extended attributes; on the other filesystems
+//! all operations fail with `EOPNOTSUPP`.
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use bitflags::bitflags;
+use rcore_fs::vfs::INode;
+
+use crate::error::{Errno, KResult};
+#[cfg(feature = "apfs")]
+use crate::fs::{apfs::AppleFileSystemInode, inner_inode};
+
+/// The maximum length of the name of an extended attribute.
+pub const XATTR_NAME_MAX: usize = 255;
+/// The maximum size of the value of an extended attribute.
+pub const XATTR_SIZE_MAX: usize = 0x10000;
+
+bitflags! {
+    #[derive(Default)]
+    pub struct XattrFlags: u64 {
+        /// Fails if the attribute already exists.
+        const XATTR_CREATE = 0x1;
+        /// Fails if the attribute does not exist.
+        const XATTR_REPLACE = 0x2;
+    }
+}
+
+#[cfg(feature = "apfs")]
+fn as_apfs(inode: &Arc<dyn INode>) -> KResult<&AppleFileSystemInode> {
+    inner_inode(inode)
+        .as_any_ref()
+        .downcast_ref::<AppleFileSystemInode>()
+        .ok_or(Errno::EOPNOTSUPP)
+}
+
+/// Gets the value of the extended attribute `name` of `inode`.
+#[cfg_attr(not(feature = "apfs"), allow(unused_variables))]
+pub fn get_xattr(inode: &Arc<dyn INode>, name: &str) -> KResult<Vec<u8>> {
+    #[cfg(feature = "apfs")]
+    return as_apfs(inode)?.get_xattr(name);
+
+    #[cfg(not(feature = "apfs"))]
+    Err(Errno::EOPNOTSUPP)
+}
+
+/// Lists the names of the extended attributes of `inode`.
+#[cfg_attr(not(feature = "apfs"), allow(unused_variables))]
+pub fn list_xattrs(inode: &Arc<dyn INode>) -> KResult<Vec<String>> {
+    #[cfg(feature = "apfs")]
+    return Ok(as_apfs(inode)?.list_xattrs());
+
+    #[cfg(not(feature = "apfs"))]
+    Err(Errno::EOPNOTSUPP)
+}
+
+/// Sets the extended attribute `name` of `inode` to `value`.
+#[cfg_attr(not(feature = "apfs"), allow(unused_variables))]
+pub fn set_xattr(
+    inode: &Arc<dyn INode>,
+    name: &str,
+    value: &[u8],
+    flags: XattrFlags,
+) -> KResult<()> {
+    #[cfg(feature = "apfs")]
+    return as_apfs(inode)?.set_xattr(name, value, flags);
+
+    #[cfg(not(feature = "apfs"))]
+    Err(Errno::EOPNOTSUPP)
+}
+
+/// Removes the extended attribute `name` of `inode`.
+#[cfg_attr(not(feature = "apfs"), allow(unused_variables))]
+pub fn remove_xattr(inode: &Arc<dyn INode>, name: &str) -> KResult<()> {
+    #[cfg(feature = "apfs")]
+    return as_apfs(inode)?.remove_xattr(name);
+
+    #[cfg(not(feature = "apfs"))]
+    Err(Errno::EOPNOTSUPP)
+}
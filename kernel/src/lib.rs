@@ -46,6 +46,7 @@ pub mod memory;
 pub mod mm;
 pub mod process;
 pub mod signal;
+pub mod slab;
 pub mod sync;
 pub mod syscall;
 pub mod time;
@@ -99,18 +100,40 @@ pub fn kmain() -> ! {
 pub fn cpu_idle() -> ! {
     loop {
         FIFO_SCHEDULER.start_schedule();
+
+        // If there is nothing to run, there is no point in waking up on every tick; sleep until the next deadline.
+        #[cfg(feature = "tickless")]
+        {
+            x86_64::instructions::interrupts::disable();
+            if FIFO_SCHEDULER.is_idle() {
+                arch::interrupt::timer::tickless_enter();
+            }
+
+            // Check again: an interrupt may have woken up some task before we disabled interrupts.
+            if FIFO_SCHEDULER.is_idle() {
+                x86_64::instructions::interrupts::enable_and_hlt();
+            } else {
+                x86_64::instructions::interrupts::enable();
+            }
+            arch::interrupt::timer::tickless_exit();
+        }
+
+        #[cfg(not(feature = "tickless"))]
         x86_64::instructions::interrupts::enable_and_hlt();
     }
 }
 
-/// The global allocator for the heap memory.
+/// The heap memory allocator.
 ///
 /// Note that we use the on-the-shelf implementation for the heap allocator with kernel-level.
 /// spin lock [`mutex::SpinLockNoInterrupt`] and a heap grow utility function to rescue us from OOM.
 /// Before oom, the allocator will try to call rescue function and try for one more time.
-#[global_allocator]
 static ALLOCATOR: LockedHeapWithRescue<32> = LockedHeapWithRescue::new(memory::grow_heap_on_oom);
 
+/// The global allocator. Small objects are served by the slab caches; others fall back to [`ALLOCATOR`].
+#[global_allocator]
+static SLAB_ALLOCATOR: slab::SlabAllocator = slab::SlabAllocator::new(&ALLOCATOR);
+
 /// `#![no_std]` is a crate level attribute that indicates that the crate will link to the core crate instead of the std crate,
 /// but what does this mean for applications?
 ///
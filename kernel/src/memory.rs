@@ -32,25 +32,34 @@
 //! ```
 
 use bit_field::BitField;
-use core::{ffi::c_void, fmt::Debug, ops::Range, sync::atomic::Ordering};
+use core::{
+    ffi::c_void,
+    fmt::Debug,
+    ops::Range,
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
 use num_traits::AsPrimitive;
 
 use crate::{
     arch::{
-        mm::paging::{KernelPageTable, PageTableBehaviors},
-        KERNEL_BASE, KERNEL_HEAP_SIZE, PAGE_MASK, PAGE_SIZE, PHYSICAL_MEMORY_START, USER_MEM_TOP,
+        cpu::{cpu_id, MAX_CPU_NUM},
+        mm::{
+            paging::{KernelPageTable, PageTableBehaviors},
+            tlb::{defer_frame, TlbGather},
+            uaccess::copy_user,
+        },
+        KERNEL_BASE, KERNEL_HEAP_SIZE, KERNEL_STACK_END, KERNEL_STACK_START, PAGE_MASK, PAGE_SIZE,
+        PHYSICAL_MEMORY_START, USER_MEM_TOP, USER_SPACE_END,
     },
     error::{Errno, KResult},
+    mm::page_cache::shrink_page_caches,
     sync::mutex::SpinLockNoInterrupt as Mutex,
 };
-use alloc::{
-    alloc::{alloc, dealloc, Layout},
-    boxed::Box,
-    vec::Vec,
-};
+use alloc::{boxed::Box, vec::Vec};
 
 use buddy_system_allocator::Heap;
-use x86_64::{PhysAddr, VirtAddr};
+use x86_64::{instructions::interrupts::without_interrupts, PhysAddr, VirtAddr};
 
 pub const USER_STACK_SIZE: usize = 0x0040_0000;
 pub const USER_STACK_START: usize = 0x0000_8000_0000_0000 - USER_STACK_SIZE;
@@ -60,22 +69,6 @@ pub const ELF_DEFAULT_ENTRY: u64 = 0x400000;
 /// The locked frame allocator for user-space processes.
 pub static LOCKED_FRAME_ALLOCATOR: Mutex<Chunk256MiB> = Mutex::new(Chunk256MiB::DEFAULT);
 
-/// Expose the interface into user space.
-#[inline(never)]
-#[link_section = ".text.copy_user"]
-unsafe extern "C" fn __copy_from_user<T>(dst: *mut T, src: *const T) -> usize {
-    dst.copy_from_nonoverlapping(src, 1);
-    0
-}
-
-/// Expose the interface into user space.
-#[inline(never)]
-#[link_section = ".text.copy_user"]
-unsafe extern "C" fn __copy_to_user<T>(dst: *mut T, src: *const T) -> usize {
-    dst.copy_from_nonoverlapping(src, 1);
-    0
-}
-
 /// Allows user application to track memory allocation.
 #[linkage = "weak"]
 #[no_mangle]
@@ -149,6 +142,9 @@ pub trait BitMapAlloc: Default {
 
     /// Gets the correpsonding bit.
     fn get_bit(&self, key: usize) -> bool;
+
+    /// Counts the free bits.
+    fn count(&self) -> usize;
 }
 
 /// Implement the bit allocator by segment tree algorithm. This allocator is used to support user-space memory allocations.
@@ -226,6 +222,10 @@ impl BitMapAlloc for BitAlloc16bit {
     fn get_bit(&self, key: usize) -> bool {
         self.0.get_bit(key)
     }
+
+    fn count(&self) -> usize {
+        self.0.count_ones() as usize
+    }
 }
 
 impl<T> BitAllocUnit<T>
@@ -327,6 +327,13 @@ where
     fn get_bit(&self, key: usize) -> bool {
         self.sub[key / T::CAPBILITY].get_bit(key % T::CAPBILITY)
     }
+
+    fn count(&self) -> usize {
+        (0..16)
+            .filter(|&i| self.bitset.get_bit(i))
+            .map(|i| self.sub[i].count())
+            .sum()
+    }
 }
 
 // A sequence of chunks managed by the bitmap. The minimal unit is 16 bits.
@@ -356,25 +363,243 @@ pub trait FrameAlloc: Debug + Clone + Send + Sync + 'static {
     fn dealloc(&self, addr: u64) -> KResult<()>;
 }
 
+/// A per-CPU stack of free frame numbers. Single-frame allocations are served from here so that page-fault-heavy
+/// workloads on different CPUs do not serialize on [`LOCKED_FRAME_ALLOCATOR`].
+struct FrameCache {
+    frames: [usize; FRAME_CACHE_HIGH],
+    len: usize,
+}
+
+/// The maximum number of frames a CPU can cache.
+const FRAME_CACHE_HIGH: usize = 0x40;
+/// How many frames are moved between a CPU cache and the global allocator at once.
+const FRAME_CACHE_BATCH: usize = FRAME_CACHE_HIGH / 2;
+
+/// Only touched by the owning CPU with interrupts disabled.
+static mut FRAME_CACHES: [FrameCache; MAX_CPU_NUM] = {
+    const CACHE: FrameCache = FrameCache {
+        frames: [0; FRAME_CACHE_HIGH],
+        len: 0,
+    };
+    [CACHE; MAX_CPU_NUM]
+};
+
+/// Runs `f` on the frame cache of the current CPU.
+///
+/// # Note
+///
+/// Never lock [`LOCKED_FRAME_ALLOCATOR`] inside `f`: dropping its guard re-enables interrupts.
+fn with_frame_cache<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut FrameCache) -> R,
+{
+    without_interrupts(|| f(unsafe { &mut FRAME_CACHES[cpu_id()] }))
+}
+
+/// One bit per frame that is set while the frame is handed out. Freeing tests and clears the bit atomically, so double
+/// frees are caught without taking the global lock, wherever the first free went (the bitmap, a per-CPU cache, or a
+/// [`TlbGather`]). It lives in frames carved out by [`init_frame_owners`].
+static FRAME_OWNERS: AtomicPtr<AtomicU64> = AtomicPtr::new(null_mut());
+static FRAME_OWNERS_LEN: AtomicUsize = AtomicUsize::new(0);
+
+fn frame_owners() -> &'static [AtomicU64] {
+    match FRAME_OWNERS.load(Ordering::Acquire) {
+        ptr if ptr.is_null() => &[],
+        ptr => unsafe {
+            core::slice::from_raw_parts(ptr, FRAME_OWNERS_LEN.load(Ordering::Relaxed))
+        },
+    }
+}
+
+/// Allocates the owner bitmap for `frames` frames from `allocator`. Must be called once the usable memory is inserted
+/// and before any frame is handed out.
+pub fn init_frame_owners(allocator: &mut Chunk256MiB, frames: usize) -> KResult<()> {
+    let len = (frames + u64::BITS as usize - 1) / u64::BITS as usize;
+    let bytes = len * core::mem::size_of::<AtomicU64>();
+    let base = allocator.alloc_contiguous((bytes + PAGE_SIZE - 1) / PAGE_SIZE, 0)?;
+
+    let ptr = phys_to_virt((base * PAGE_SIZE) as u64) as *mut AtomicU64;
+    unsafe { core::ptr::write_bytes(ptr as *mut u8, 0, bytes) };
+    FRAME_OWNERS_LEN.store(len, Ordering::Relaxed);
+    FRAME_OWNERS.store(ptr, Ordering::Release);
+
+    Ok(())
+}
+
+/// Marks `num` frames starting at `frame` as handed out.
+fn own_frames(frame: usize, num: usize) {
+    let owners = frame_owners();
+    (frame..frame + num)
+        .filter(|&frame| frame / (u64::BITS as usize) < owners.len())
+        .for_each(|frame| {
+            owners[frame / u64::BITS as usize]
+                .fetch_or(1 << (frame % u64::BITS as usize), Ordering::AcqRel);
+        });
+}
+
+/// Takes `frame` back from its owner. Returns false if it was not handed out, i.e., it is being freed twice.
+fn disown_frame(frame: usize) -> bool {
+    match frame_owners().get(frame / u64::BITS as usize) {
+        Some(bits) => {
+            let bit = 1 << (frame % u64::BITS as usize);
+            bits.fetch_and(!bit, Ordering::AcqRel) & bit != 0
+        }
+        // Not tracked.
+        None => true,
+    }
+}
+
+/// Returns frames to the global allocator.
+fn free_frames(frames: &[usize]) {
+    let invalid = {
+        let mut allocator = LOCKED_FRAME_ALLOCATOR.lock();
+        frames
+            .iter()
+            .filter(|&&frame| allocator.dealloc(frame).is_err())
+            .count()
+    };
+
+    // Logging may allocate, so do not hold the lock here.
+    if invalid != 0 {
+        kerror!(
+            "free_frames(): {} frames were freed twice or are invalid",
+            invalid
+        );
+    }
+}
+
+/// Gives all the frames cached by the current CPU back to the global allocator.
+pub fn drain_frame_cache() {
+    let mut frames = [0usize; FRAME_CACHE_HIGH];
+    let len = with_frame_cache(|cache| {
+        let len = cache.len;
+        frames[..len].copy_from_slice(&cache.frames[..len]);
+        cache.len = 0;
+        len
+    });
+
+    free_frames(&frames[..len]);
+}
+
+impl KernelFrameAllocator {
+    /// Takes a frame from the cache of the current CPU, refilling it from the global allocator if it is empty.
+    fn alloc_cached(&self) -> KResult<PhysAddr> {
+        let to_phys = |v: usize| PhysAddr::new(v as u64 * PAGE_SIZE as u64);
+
+        if let Some(frame) = with_frame_cache(|cache| match cache.len {
+            0 => None,
+            _ => {
+                cache.len -= 1;
+                Some(cache.frames[cache.len])
+            }
+        }) {
+            return Ok(to_phys(frame));
+        }
+
+        // Refill a batch from the global allocator; we keep the first one for ourselves.
+        let mut frames = [0usize; FRAME_CACHE_BATCH];
+        let mut len = 0;
+        {
+            let mut allocator = LOCKED_FRAME_ALLOCATOR.lock();
+            while len < FRAME_CACHE_BATCH {
+                match allocator.alloc() {
+                    Ok(frame) => frames[len] = frame,
+                    Err(_) => break,
+                }
+                len += 1;
+            }
+        }
+
+        if len == 0 {
+            return Err(Errno::ENOMEM);
+        }
+
+        // An interrupt handler may have filled the cache in the meantime.
+        let pushed = with_frame_cache(|cache| {
+            let pushed = (len - 1).min(FRAME_CACHE_HIGH - cache.len);
+            cache.frames[cache.len..cache.len + pushed].copy_from_slice(&frames[1..1 + pushed]);
+            cache.len += pushed;
+            pushed
+        });
+        if 1 + pushed < len {
+            free_frames(&frames[1 + pushed..len]);
+        }
+
+        Ok(to_phys(frames[0]))
+    }
+
+    /// Puts a frame that has been disowned into the cache of the current CPU.
+    fn free_cached(&self, frame: usize) {
+        let mut frames = [0usize; FRAME_CACHE_BATCH];
+        let drained = with_frame_cache(|cache| {
+            let mut drained = 0;
+            if cache.len == FRAME_CACHE_HIGH {
+                // Drain the oldest half.
+                frames.copy_from_slice(&cache.frames[..FRAME_CACHE_BATCH]);
+                cache.frames.copy_within(FRAME_CACHE_BATCH.., 0);
+                cache.len -= FRAME_CACHE_BATCH;
+                drained = FRAME_CACHE_BATCH;
+            }
+
+            cache.frames[cache.len] = frame;
+            cache.len += 1;
+            drained
+        });
+
+        if drained != 0 {
+            free_frames(&frames[..drained]);
+        }
+    }
+}
+
 impl FrameAlloc for KernelFrameAllocator {
     fn alloc(&self) -> KResult<PhysAddr> {
-        LOCKED_FRAME_ALLOCATOR
-            .lock()
-            .alloc()
-            .map(|v| PhysAddr::new(v as u64 * PAGE_SIZE as u64))
+        let addr = self.alloc_cached().or_else(|_| {
+            // Out of memory: drop some clean pages from the page caches and try again.
+            match shrink_page_caches(FRAME_CACHE_BATCH) {
+                0 => Err(Errno::ENOMEM),
+                _ => self.alloc_cached(),
+            }
+        })?;
+
+        own_frames(addr.as_u64() as usize / PAGE_SIZE, 1);
+        Ok(addr)
     }
 
     fn alloc_contiguous(&self, size: usize, align_log2: usize) -> KResult<PhysAddr> {
-        LOCKED_FRAME_ALLOCATOR
-            .lock()
-            .alloc_contiguous(size, align_log2)
-            .map(|v| PhysAddr::new(v as u64 * PAGE_SIZE as u64))
+        let alloc = || {
+            LOCKED_FRAME_ALLOCATOR
+                .lock()
+                .alloc_contiguous(size, align_log2)
+                .map(|v| PhysAddr::new(v as u64 * PAGE_SIZE as u64))
+        };
+
+        // Cached frames may fragment the bitmap; give ours back and try again.
+        let addr = alloc().or_else(|_| {
+            drain_frame_cache();
+            alloc()
+        })?;
+
+        own_frames(addr.as_u64() as usize / PAGE_SIZE, size);
+        Ok(addr)
     }
 
     fn dealloc(&self, addr: u64) -> KResult<()> {
-        LOCKED_FRAME_ALLOCATOR
-            .lock()
-            .dealloc((addr / PAGE_SIZE as u64) as usize)
+        let frame = (addr / PAGE_SIZE as u64) as usize;
+        if frame >= Chunk256MiB::CAPBILITY {
+            return Err(Errno::EINVAL);
+        }
+        if !disown_frame(frame) {
+            kerror!("dealloc(): frame {:#x} is not allocated", addr);
+            return Err(Errno::EINVAL);
+        }
+
+        // Other CPUs may still reach the frame through their TLBs.
+        if !defer_frame(addr) {
+            self.free_cached(frame);
+        }
+
+        Ok(())
     }
 }
 
@@ -407,71 +632,173 @@ pub fn init_heap() -> usize {
     }
 }
 
+/// The number of frames the heap grows by at least when it runs out of memory (64 MiB).
+const HEAP_GROW_FRAMES: usize = 0x4000;
+
 /// When OOM occurs, we try to grow the heap to prevent the kernel from panicking.
+///
+/// The heap grows by one contiguous region aligned to its size, so that the buddy allocator can carve the requested
+/// block out of it. If there is no such region of [`HEAP_GROW_FRAMES`] frames, the smallest one that fits the request
+/// is tried before giving up, in which case the allocation fails.
 pub fn grow_heap_on_oom(mem: &mut Heap<32>, layout: &core::alloc::Layout) {
     kinfo!(
         "grow_heap_on_oom(): Heap is OOM at {:?}. Trying to grow the heap.",
         layout
     );
 
-    let mut addrs = [(0, 0); 32];
-    let mut addr_len = 0;
-    for _i in 0..0x4000 {
-        let page = KernelFrameAllocator.alloc().unwrap();
-        let virtual_addr = page + PHYSICAL_MEMORY_START;
-        addrs[addr_len] = (virtual_addr.as_u64(), PAGE_SIZE);
-        addr_len += 1;
-    }
-
+    let needed =
+        ((layout.size().max(layout.align()) + PAGE_SIZE - 1) / PAGE_SIZE).next_power_of_two();
+    let mut frames = needed.max(HEAP_GROW_FRAMES);
     // TODO: Need an OOM killer for this function so that the kernel won't panic.
-
-    for (addr, len) in addrs[..addr_len].iter() {
-        kinfo!(
-            "grow_heap_on_oom(): created {:#x} with length {:#x}",
-            addr,
-            len
-        );
-        unsafe {
-            mem.init(*addr as usize, *len);
+    loop {
+        match KernelFrameAllocator.alloc_contiguous(frames, frames.trailing_zeros() as usize) {
+            Ok(addr) => {
+                let addr = phys_to_virt(addr.as_u64());
+                let len = frames * PAGE_SIZE;
+                kinfo!(
+                    "grow_heap_on_oom(): created {:#x} with length {:#x}",
+                    addr,
+                    len
+                );
+                unsafe {
+                    mem.init(addr as usize, len);
+                }
+                return;
+            }
+            Err(_) if frames > needed => frames /= 2,
+            Err(errno) => {
+                kerror!(
+                    "grow_heap_on_oom(): cannot grow the heap by {:#x} frames. Errno: {:?}",
+                    frames,
+                    errno
+                );
+                return;
+            }
         }
     }
 }
 
-/// Allocate the kernel stack from the heap.
+/// Allocate the kernel stack from the kernel stack region.
 /// The kernel stack is used by the kernel to store a variety of data, including the current
 /// state of the process, the parameters of a function call, and the return address when a
 /// function is called. This allows the kernel to manage the execution of processes and to
 /// switch between them efficiently.
 ///
-/// Note that this kernel stack is allocated for *each process*.
+/// Each stack occupies the top of a [`KERNEL_STACK_SLOT_SIZE`] slot in [`KERNEL_STACK_START`]..[`KERNEL_STACK_END`] and
+/// the rest of the slot is never mapped, so an overflow hits a guard page and faults instead of silently corrupting
+/// whatever lies below.
 ///
 /// There is another `kernel_stack` which is used for the kernel itself and initialized upon
-/// boot. See `boot.cfg` under `esp/efi/boot/boot.cfg`.
-pub struct KernelStack(usize);
+/// boot. See `boot.cfg` under `esp/efi/boot/boot.cfg`. It has no guard page, so the BSP only uses it until it enters
+/// `kmain`.
+pub struct KernelStack {
+    /// The start address of the slot.
+    slot: u64,
+    /// The mapped size.
+    size: usize,
+}
 pub const STACK_SIZE: usize = 0x8000;
+/// The virtual size reserved for each kernel stack including its guard pages.
+pub const KERNEL_STACK_SLOT_SIZE: u64 = 0x10_0000;
+
+/// The next slot that has never been used.
+static KERNEL_STACK_NEXT: AtomicU64 = AtomicU64::new(KERNEL_STACK_START);
+/// Slots that were used by dropped stacks.
+static KERNEL_STACK_FREE: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+/// Serializes the changes to the kernel stack region, whose page tables are shared by all address spaces.
+static KERNEL_STACK_MAPPING: Mutex<()> = Mutex::new(());
+
 impl KernelStack {
     pub fn new() -> Self {
-        let kernel_bottom =
-            unsafe { alloc(Layout::from_size_align(STACK_SIZE, STACK_SIZE).unwrap()) } as usize;
+        Self::with_size(STACK_SIZE).expect("new(): failed to allocate the kernel stack")
+    }
+
+    /// Allocates a kernel stack of `size` bytes (rounded up to pages). At least one page of the slot is kept as the
+    /// guard.
+    pub fn with_size(size: usize) -> KResult<Self> {
+        let size = page_frame_number(size + PAGE_SIZE - 1);
+        if size == 0 || (size + PAGE_SIZE) as u64 > KERNEL_STACK_SLOT_SIZE {
+            return Err(Errno::EINVAL);
+        }
+
+        let slot = KERNEL_STACK_FREE.lock().pop();
+        let slot = match slot {
+            Some(slot) => slot,
+            None => {
+                let slot = KERNEL_STACK_NEXT.fetch_add(KERNEL_STACK_SLOT_SIZE, Ordering::SeqCst);
+                if slot >= KERNEL_STACK_END {
+                    kerror!("with_size(): the kernel stack region is exhausted.");
+                    return Err(Errno::ENOMEM);
+                }
+                slot
+            }
+        };
+
+        // Map from the top; if we run out of frames, dropping the partial stack cleans everything up.
+        let mut stack = Self { slot, size: 0 };
+        // Declared after `stack` so that it is released before the partial stack is dropped.
+        let _guard = KERNEL_STACK_MAPPING.lock();
+        let mut page_table = KernelPageTable::active();
+        while stack.size < size {
+            let addr = stack.bottom() - PAGE_SIZE;
+            let frame = KernelFrameAllocator.alloc()?;
+            let entry = page_table.map(virt!(addr as u64), frame);
+            entry.set_user(false);
+            entry.update();
+            stack.size += PAGE_SIZE;
+        }
 
-        Self(kernel_bottom)
+        Ok(stack)
     }
 
     /// Returns the current stack top.
     pub fn top(&self) -> usize {
-        self.0 + STACK_SIZE
+        (self.slot + KERNEL_STACK_SLOT_SIZE) as usize
+    }
+
+    /// Returns the lowest mapped address. The page right below it is a guard page.
+    pub fn bottom(&self) -> usize {
+        self.top() - self.size
+    }
+
+    /// Leaks the stack, e.g., for stacks that live as long as the CPU does, and returns its top.
+    pub fn leak(self) -> usize {
+        let top = self.top();
+        core::mem::forget(self);
+        top
     }
 }
 
-/// Automatically reclaim the stack into the heap.
+/// Automatically unmap the stack and reclaim the frames.
 impl Drop for KernelStack {
     fn drop(&mut self) {
-        unsafe {
-            dealloc(
-                self.0 as *mut u8,
-                Layout::from_size_align(STACK_SIZE, STACK_SIZE).unwrap(),
-            );
+        // Every CPU may cache the stack, and the frames are held back until all of them have flushed it. The lock must
+        // be released before that, since the other CPUs may be spinning on it with interrupts disabled.
+        let mut gather = TlbGather::kernel();
+        gather.add(self.bottom() as u64..self.top() as u64);
+
+        let guard = KERNEL_STACK_MAPPING.lock();
+        let mut page_table = KernelPageTable::active();
+        for addr in (self.bottom()..self.top()).step_by(PAGE_SIZE) {
+            let target = match page_table.get_entry(virt!(addr as u64)) {
+                Ok(entry) if entry.present() => entry.target(),
+                _ => continue,
+            };
+
+            page_table.unmap(virt!(addr as u64));
+            if let Err(errno) = KernelFrameAllocator.dealloc(target.as_u64()) {
+                kerror!(
+                    "drop(): failed to free the stack frame {:#x}. Errno: {:?}",
+                    target,
+                    errno
+                );
+            }
         }
+        drop(guard);
+        drop(gather);
+
+        // Nobody can reach the slot any longer.
+        KERNEL_STACK_FREE.lock().push(self.slot);
     }
 }
 
@@ -479,6 +806,14 @@ impl Drop for KernelStack {
 #[inline(always)]
 pub fn check_within_stack(bp: u64) -> bool {
     (0xFFFF_FFFF_F000_0000..=0xFFFF_FFFF_F000_0000 + 512 * PAGE_SIZE as u64).contains(&bp)
+        || check_within_kernel_stacks(bp)
+}
+
+/// Checks whether the given address is within the region of [`KernelStack`]s. A fault on such address means that some
+/// kernel stack has overflowed into its guard page.
+#[inline(always)]
+pub fn check_within_kernel_stacks(addr: u64) -> bool {
+    (KERNEL_STACK_START..KERNEL_STACK_END).contains(&addr)
 }
 
 /// Checks whether the given memory region is within the kernel memory space.
@@ -487,10 +822,12 @@ pub fn check_within_kernel(addr: u64, size: usize) -> bool {
     !(addr < USER_MEM_TOP && (addr + size as u64) < USER_MEM_TOP)
 }
 
-/// Checks whether the given memory region is within the user space (0x0 - 0xffff7fffffffffff) below kernel space.
+/// Checks whether the given memory region is within the user space (0x0 - 0x7fffffffffff). Anything above is either
+/// kernel space or non-canonical, and touching a non-canonical address raises #GP instead of a page fault.
 #[inline(always)]
 pub fn check_within_user(addr: u64, size: usize) -> bool {
-    addr < PHYSICAL_MEMORY_START && (addr + size as u64) < PHYSICAL_MEMORY_START
+    addr.checked_add(size as u64)
+        .map_or(false, |end| end <= USER_SPACE_END)
 }
 
 /// Copies a buffer from the user space into kernel space. This is useful for kernel modules / drivers.
@@ -501,19 +838,12 @@ pub fn check_within_user(addr: u64, size: usize) -> bool {
 /// this function checks the pointer address is within the thread's virtual memory, there is no guarantee that the
 /// data read from the user thread is always valid at all.
 pub unsafe fn copy_from_user<T>(src: *const T) -> KResult<T> {
-    // Check the memory address first.
-    let addr = src as u64;
-    if !check_within_user(addr, core::mem::size_of::<T>()) {
-        return Err(Errno::ERANGE);
-    }
-
-    // Copy from user.
     let mut kern_repr = core::mem::MaybeUninit::<T>::zeroed();
-    let ret = __copy_from_user::<T>(kern_repr.as_mut_ptr(), src);
-    match ret {
-        0 => Ok(kern_repr.assume_init()),
-        _ => Err(Errno::EFAULT),
-    }
+    copy_slice_from_user(
+        core::slice::from_raw_parts_mut(kern_repr.as_mut_ptr(), 1),
+        src,
+    )?;
+    Ok(kern_repr.assume_init())
 }
 
 /// Copies a buffer to the user space into kernel space. This is useful for kernel modules / drivers.
@@ -523,20 +853,37 @@ pub unsafe fn copy_from_user<T>(src: *const T) -> KResult<T> {
 /// Similar to [`copy_from_user`], this function is unsafe because we require that the user thread does not always read
 /// valid data from the kernel even though the input pointers are valid.
 pub unsafe fn copy_to_user<T>(src: *const T, dst: *mut T) -> KResult<()> {
-    // Check the memory address first.
-    let kern_addr = src as u64;
-    let user_addr = dst as u64;
-    let size = core::mem::size_of::<T>();
-    if !check_within_kernel(kern_addr, size) || !check_within_user(user_addr, size) {
+    copy_slice_to_user(dst, core::slice::from_raw_parts(src, 1))
+}
+
+/// Copies `dst.len()` elements starting at the user pointer `src` into `dst`. Returns [`Errno::EFAULT`] if some page
+/// of the user buffer is not accessible.
+///
+/// # Safety
+///
+/// See [`copy_from_user`].
+pub unsafe fn copy_slice_from_user<T>(dst: &mut [T], src: *const T) -> KResult<()> {
+    let size = core::mem::size_of_val(dst);
+    if !check_within_user(src as u64, size) {
         return Err(Errno::ERANGE);
     }
 
-    // Copy into user.
-    let ret = __copy_to_user::<T>(dst, src);
-    match ret {
-        0 => Ok(()),
-        _ => Err(Errno::EFAULT),
+    copy_user(dst.as_mut_ptr() as _, src as _, size)
+}
+
+/// Copies `src` into the user buffer starting at `dst`. Returns [`Errno::EFAULT`] if some page of the user buffer is
+/// not accessible.
+///
+/// # Safety
+///
+/// See [`copy_to_user`].
+pub unsafe fn copy_slice_to_user<T>(dst: *mut T, src: &[T]) -> KResult<()> {
+    let size = core::mem::size_of_val(src);
+    if !check_within_kernel(src.as_ptr() as u64, size) || !check_within_user(dst as u64, size) {
+        return Err(Errno::ERANGE);
     }
+
+    copy_user(dst as _, src.as_ptr() as _, size)
 }
 
 /// Checks whether the lower 3 bits are zero.
@@ -583,11 +930,21 @@ pub fn allocate_frame_contiguous(size: usize, align_log2: usize) -> KResult<Phys
     KernelFrameAllocator.alloc_contiguous(size, align_log2)
 }
 
+/// Gets the number of free physical frames, not counting those cached by the CPUs.
+pub fn free_frame_count() -> usize {
+    LOCKED_FRAME_ALLOCATOR.lock().count()
+}
+
 /// Drops a physical frame and adds it to the bitmap. An invalid input is allowed.
 pub fn deallocate_frame(addr: u64) -> KResult<()> {
     KernelFrameAllocator.dealloc(addr)
 }
 
+/// Frees a frame that [`KernelFrameAllocator::dealloc`] has handed to [`defer_frame`].
+pub fn free_deferred_frame(addr: u64) {
+    KernelFrameAllocator.free_cached((addr / PAGE_SIZE as u64) as usize)
+}
+
 /// kmalloc: Allocate heap from kernel memory. This function ensures that we always return
 /// a contiguous *physical* memory region.
 ///
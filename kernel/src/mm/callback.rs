@@ -1,16 +1,23 @@
 //! Implements the underlying operations by `Arena`.
 
-use core::{fmt::Debug, marker::PhantomData};
+use core::{fmt::Debug, marker::PhantomData, ops::Range};
 
 use alloc::{boxed::Box, format, sync::Arc};
 use rcore_fs::vfs::INode;
-use x86_64::VirtAddr;
+use x86_64::{
+    structures::paging::{Page, Size4KiB},
+    PhysAddr, VirtAddr,
+};
 
 use crate::{
-    arch::{mm::paging::PageTableBehaviors, PAGE_SIZE},
+    arch::{
+        mm::paging::{EntryBehaviors, HugePageSize, PageTableBehaviors},
+        PAGE_SIZE,
+    },
     error::{fserror_to_kerror, KResult},
     fs::file::ReadAsFile,
-    memory::{page_frame_number, FrameAlloc},
+    memory::{page_frame_number, phys_to_virt, FrameAlloc},
+    mm::page_cache::{PageCache, PageCacheHandle},
 };
 
 use super::{check_permission, AccessType, ArenaFlags};
@@ -30,6 +37,42 @@ pub trait ArenaCallback: Debug + Send + Sync + 'static {
 
     fn unmap(&self, page_table: &mut dyn PageTableBehaviors, addr: VirtAddr);
 
+    /// Maps the whole `range` into `page_table`. Callbacks that map more than a page at once override this.
+    fn map_range(
+        &self,
+        page_table: &mut dyn PageTableBehaviors,
+        range: &Range<u64>,
+        flags: &ArenaFlags,
+    ) {
+        for page in page_range(range) {
+            self.map(page_table, page.start_address(), flags);
+        }
+    }
+
+    /// Unmaps the whole `range` from `page_table`.
+    fn unmap_range(&self, page_table: &mut dyn PageTableBehaviors, range: &Range<u64>) {
+        for page in page_range(range) {
+            self.unmap(page_table, page.start_address());
+        }
+    }
+
+    /// Applies `flags` to the pages in `range` that have an entry in `page_table`.
+    fn protect(
+        &self,
+        page_table: &mut dyn PageTableBehaviors,
+        range: &Range<u64>,
+        flags: &ArenaFlags,
+    ) {
+        for page in page_range(range) {
+            if let Ok(entry) = page_table.get_entry(page.start_address()) {
+                entry.set_writable(flags.writable);
+                entry.set_execute(!flags.non_executable);
+                entry.set_user(flags.user_accessible);
+                entry.update();
+            }
+        }
+    }
+
     /// Kernel interrupt handler -> kernel::handle_page_fault -> thread.vm::handle_page_fault ->
     /// arena::callback::handle_page_fault (this interface).
     fn handle_page_fault(&self, page_table: &mut dyn PageTableBehaviors, addr: u64) -> bool {
@@ -52,6 +95,11 @@ pub trait ArenaCallback: Debug + Send + Sync + 'static {
     }
 }
 
+/// Returns the 4 KiB pages covering `range`.
+fn page_range(range: &Range<u64>) -> impl Iterator<Item = Page<Size4KiB>> {
+    Page::<Size4KiB>::range_inclusive(page!(range.start), page!(range.end.saturating_sub(1)))
+}
+
 impl Clone for Box<dyn ArenaCallback> {
     fn clone(&self) -> Self {
         self.clone_as_box()
@@ -66,6 +114,8 @@ pub struct FileArenaCallback<F, A> {
     pub file_start: u64,
     pub file_end: u64,
     pub frame_allocator: A,
+    /// Whether this is a `MAP_SHARED` mapping whose stores go to the file.
+    pub shared: bool,
 }
 
 /// The callback for virtual memory mappings like `mmap` would do.
@@ -74,6 +124,19 @@ pub struct UserArenaCallback<A> {
     frame_allocator: A,
 }
 
+/// The callback for large anonymous mappings that opted in to transparent huge pages via `MAP_HUGETLB`.
+///
+/// Every 2 MiB aligned chunk that lies entirely in the arena is left without page table entries at first, and the first
+/// fault in it maps a whole 2 MiB frame with a single entry. Everything else (the unaligned head and tail, or chunks for
+/// which no contiguous memory is available) falls back to 4 KiB pages handled exactly like [`UserArenaCallback`].
+#[derive(Clone, Debug)]
+pub struct HugeArenaCallback<A> {
+    inner: UserArenaCallback<A>,
+    /// The flags of the arena. Chunks that have not been touched have no entries to keep them, so they are mapped with
+    /// these; `protect` splits such chunks first.
+    flags: ArenaFlags,
+}
+
 /// The callback for normal memory allocators.
 #[derive(Clone, Debug)]
 pub struct SystemArenaCallback<A>
@@ -121,11 +184,118 @@ where
     }
 }
 
+impl<A> HugeArenaCallback<A>
+where
+    A: FrameAlloc,
+{
+    const HUGE_PAGE_SIZE: u64 = HugePageSize::Size2MiB.size();
+
+    pub fn new(frame_allocator: A, flags: ArenaFlags) -> Self {
+        Self {
+            inner: UserArenaCallback::new(frame_allocator),
+            flags,
+        }
+    }
+
+    /// Turns the chunk containing `addr` into 4 KiB pages: a huge page is split and a chunk that has not been touched
+    /// yet gets the same lazy entries as [`UserArenaCallback::map`]. Must be done before a part of the chunk is unmapped
+    /// or protected.
+    fn split_chunk(&self, page_table: &mut dyn PageTableBehaviors, addr: u64) {
+        if page_table.huge_page_size(virt!(addr)).is_some() {
+            if let Err(errno) = page_table.split_huge(virt!(addr)) {
+                kerror!(
+                    "split_chunk(): failed to split the huge page @ {:#x}. Errno: {:?}",
+                    addr,
+                    errno
+                );
+            }
+        } else if page_table.get_entry(virt!(addr)).is_err() {
+            let chunk = addr & !(Self::HUGE_PAGE_SIZE - 1);
+            self.inner.map_range(
+                page_table,
+                &(chunk..chunk + Self::HUGE_PAGE_SIZE),
+                &self.flags,
+            );
+        }
+    }
+
+    /// Returns true if the 2 MiB chunk starting at `addr` is within `[start, end)`.
+    fn chunk_covered(addr: u64, start: u64, end: u64) -> bool {
+        addr % Self::HUGE_PAGE_SIZE == 0 && addr >= start && addr + Self::HUGE_PAGE_SIZE <= end
+    }
+}
+
 impl<F, A> FileArenaCallback<F, A>
 where
     F: ReadAsFile,
     A: FrameAlloc,
 {
+    /// Returns the file offset of the page containing `addr`.
+    fn file_offset(&self, addr: VirtAddr) -> u64 {
+        page_frame_number(addr.as_u64()) + self.file_start - self.mem_start
+    }
+
+    /// Returns true if the page at `addr` can share the frame of the page cache. Pages of a shared mapping always do,
+    /// while a page of a private mapping must come entirely from the file and nobody can write to it.
+    fn can_share(&self, addr: VirtAddr, writable: bool) -> bool {
+        let file_offset = self.file_offset(addr);
+        file_offset % PAGE_SIZE as u64 == 0
+            && match self.shared {
+                true => file_offset < self.file_end,
+                false => !writable && file_offset + PAGE_SIZE as u64 <= self.file_end,
+            }
+    }
+
+    /// Points the not yet present `entry` of `addr` at the frame of the page cache if the page can share it. Returns
+    /// false if the page needs a frame of its own, e.g., because the file is not cached.
+    fn map_shared(&self, entry: &mut dyn EntryBehaviors, addr: VirtAddr) -> KResult<bool> {
+        let writable = entry.writable();
+        if !self.can_share(addr, writable) {
+            return Ok(false);
+        }
+
+        match self
+            .file
+            .map_page(self.file_offset(addr) as usize, writable)?
+        {
+            Some(frame) => {
+                entry.set_target(frame);
+                entry.set_present(true);
+                entry.update();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Replaces the page cache frame mapped at `addr` by a private copy so that it can be written. Does nothing if the
+    /// frame is already private.
+    fn make_private(&self, page_table: &mut dyn PageTableBehaviors, addr: VirtAddr) -> KResult<()> {
+        let frame = self.frame_allocator.alloc()?;
+        let entry = page_table.get_entry(addr)?;
+        let shared = entry.target();
+        // Copy before dropping our reference to the cached page, after which it may be evicted.
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                phys_to_virt(shared.as_u64()) as *const u8,
+                phys_to_virt(frame.as_u64()) as *mut u8,
+                PAGE_SIZE,
+            );
+        }
+
+        if self
+            .file
+            .unmap_page(self.file_offset(addr) as usize, shared, false)
+        {
+            entry.set_target(frame);
+            entry.update();
+        } else {
+            self.frame_allocator.dealloc(frame.as_u64())?;
+        }
+
+        Ok(())
+    }
+
     /// After a new entry is created, we need to copy the file to that entry.
     pub fn fill_data(
         &self,
@@ -136,7 +306,7 @@ where
         let dst = page_table.get_page_slice_mut(addr)?;
         // Prepare source buffer. Memory offset + file base.
         // We always copy a page, so there is a need to round up the address.
-        let file_offset = page_frame_number(addr.as_u64()) + self.file_start - self.mem_start;
+        let file_offset = self.file_offset(addr);
 
         // This function still contain some minor bug. Fix it.
         let read_size = (self.file_end as isize - file_offset as isize)
@@ -157,16 +327,51 @@ where
     }
 }
 
+/// An inode that backs a file mapping. It keeps the page cache of the inode alive while the mapping exists.
 #[derive(Clone)]
-pub struct INodeWrapper(pub Arc<dyn INode>);
+pub struct INodeWrapper {
+    inode: Arc<dyn INode>,
+    page_cache: Option<PageCacheHandle>,
+}
+
+impl INodeWrapper {
+    pub fn new(inode: Arc<dyn INode>) -> Self {
+        Self {
+            page_cache: PageCache::of(&inode),
+            inode,
+        }
+    }
+}
 
 impl ReadAsFile for INodeWrapper {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> KResult<usize> {
-        self.0.read_at(offset, buf).map_err(fserror_to_kerror)
+        match &self.page_cache {
+            Some(cache) => cache.read_at(offset, buf),
+            None => self.inode.read_at(offset, buf).map_err(fserror_to_kerror),
+        }
     }
 
     fn inode(&self) -> u64 {
-        self.0.metadata().unwrap().inode as _
+        self.inode.metadata().unwrap().inode as _
+    }
+
+    fn map_page(&self, offset: usize, writable: bool) -> KResult<Option<PhysAddr>> {
+        self.page_cache
+            .as_ref()
+            .map(|cache| cache.map_page(offset, writable))
+            .transpose()
+    }
+
+    fn unmap_page(&self, offset: usize, frame: PhysAddr, writable: bool) -> bool {
+        self.page_cache
+            .as_ref()
+            .map_or(false, |cache| cache.unmap_page(offset, frame, writable))
+    }
+
+    fn protect_page(&self, offset: usize, frame: PhysAddr, writable: bool) -> bool {
+        self.page_cache
+            .as_ref()
+            .map_or(false, |cache| cache.protect_page(offset, frame, writable))
     }
 }
 
@@ -179,6 +384,10 @@ where
         self.file.inode()
     }
 
+    fn shared(&self) -> bool {
+        self.shared
+    }
+
     fn clone_as_box(&self) -> Box<dyn ArenaCallback> {
         Box::new(self.clone())
     }
@@ -190,6 +399,12 @@ where
         addr: VirtAddr,
         flags: &ArenaFlags,
     ) {
+        // Both sides keep using the page cache; the child faults the page in again.
+        if self.shared {
+            self.map(dst, addr, flags);
+            return;
+        }
+
         let src_entry = src.get_entry(addr).expect(&format!(
             "failed to get entry from the source page table @ {addr:#x}"
         ));
@@ -217,8 +432,6 @@ where
                     "failed to get entry from the source page table @ {addr:#x}",
                 ))
                 .copy_from_slice(src_buf);
-            // FIXME: Notify other cores (?): slow and some bug
-            // tlb_broadcast(None, Some(addr));
         } else {
             // Map to 0x0 and copy from the file instead because the source page table entry is now allowed
             // for us to touch. This is delayed mapping.
@@ -240,7 +453,14 @@ where
     fn unmap(&self, page_table: &mut dyn PageTableBehaviors, addr: VirtAddr) {
         match page_table.get_entry(addr) {
             Ok(entry) => {
-                if entry.present() {
+                // Frames shared with the page cache are not ours to free.
+                if entry.present()
+                    && !self.file.unmap_page(
+                        self.file_offset(addr) as usize,
+                        entry.target(),
+                        entry.writable(),
+                    )
+                {
                     self.frame_allocator
                         .dealloc(entry.target().as_u64())
                         .unwrap();
@@ -258,6 +478,41 @@ where
         };
     }
 
+    fn protect(
+        &self,
+        page_table: &mut dyn PageTableBehaviors,
+        range: &Range<u64>,
+        flags: &ArenaFlags,
+    ) {
+        for page in page_range(range) {
+            let addr = page.start_address();
+            if let Ok(entry) = page_table.get_entry(addr) {
+                if self.shared && entry.present() && flags.writable != entry.writable() {
+                    // The page cache keeps the pages that may be written through the mapping dirty.
+                    let offset = self.file_offset(addr) as usize;
+                    self.file
+                        .protect_page(offset, entry.target(), flags.writable);
+                } else if !self.shared && flags.writable && entry.present() && !entry.writable() {
+                    // A page that becomes writable must stop sharing the page cache.
+                    if let Err(errno) = self.make_private(page_table, addr) {
+                        kerror!(
+                            "FileArenaCallback::protect(): failed to copy the page @ {:#x}. Errno: {:?}",
+                            addr.as_u64(),
+                            errno
+                        );
+                        continue;
+                    }
+                }
+
+                let entry = page_table.get_entry(addr).unwrap();
+                entry.set_writable(flags.writable);
+                entry.set_execute(!flags.non_executable);
+                entry.set_user(flags.user_accessible);
+                entry.update();
+            }
+        }
+    }
+
     fn do_handle_page_fault(
         &self,
         page_table: &mut dyn PageTableBehaviors,
@@ -284,6 +539,16 @@ where
             }
         }
 
+        // Pages that can use the frame of the page cache are mapped straight to it.
+        match self.map_shared(entry, VirtAddr::new(addr)) {
+            Ok(true) => return true,
+            Ok(false) => (),
+            Err(errno) => {
+                kerror!("failed to read the page cache. Error: {:?}", errno);
+                return false;
+            }
+        }
+
         // Allocate a new physical frame for this page table entry.
         let frame = match self.frame_allocator.alloc() {
             Ok(f) => f,
@@ -436,6 +701,189 @@ where
     }
 }
 
+impl<A> ArenaCallback for HugeArenaCallback<A>
+where
+    A: FrameAlloc,
+{
+    fn clone_as_box(&self) -> Box<dyn ArenaCallback> {
+        Box::new(self.clone())
+    }
+
+    fn clone_and_map(
+        &self,
+        dst: &mut dyn PageTableBehaviors,
+        src: &mut dyn PageTableBehaviors,
+        addr: VirtAddr,
+        flags: &ArenaFlags,
+    ) {
+        // Untouched chunks stay untouched in the child; huge pages are copied page by page.
+        if src.get_entry(addr).is_ok() {
+            self.inner.clone_and_map(dst, src, addr, flags);
+        }
+    }
+
+    fn map(&self, page_table: &mut dyn PageTableBehaviors, addr: VirtAddr, flags: &ArenaFlags) {
+        self.inner.map(page_table, addr, flags);
+    }
+
+    fn unmap(&self, page_table: &mut dyn PageTableBehaviors, addr: VirtAddr) {
+        self.unmap_range(
+            page_table,
+            &(addr.as_u64()..addr.as_u64() + PAGE_SIZE as u64),
+        );
+    }
+
+    fn map_range(
+        &self,
+        page_table: &mut dyn PageTableBehaviors,
+        range: &Range<u64>,
+        flags: &ArenaFlags,
+    ) {
+        let start = page_frame_number(range.start);
+        let end = page_frame_number(range.end + PAGE_SIZE as u64 - 1);
+
+        let mut addr = start;
+        while addr < end {
+            // A chunk that already has a page table (e.g., left by a previous mapping) is not a candidate.
+            if Self::chunk_covered(addr, start, end) && page_table.get_entry(virt!(addr)).is_err() {
+                addr += Self::HUGE_PAGE_SIZE;
+                continue;
+            }
+
+            self.inner.map(page_table, virt!(addr), flags);
+            addr += PAGE_SIZE as u64;
+        }
+    }
+
+    fn unmap_range(&self, page_table: &mut dyn PageTableBehaviors, range: &Range<u64>) {
+        let start = page_frame_number(range.start);
+        let end = page_frame_number(range.end + PAGE_SIZE as u64 - 1);
+
+        let mut addr = start;
+        while addr < end {
+            if Self::chunk_covered(addr, start, end) {
+                if let Some(size) = page_table.huge_page_size(virt!(addr)) {
+                    match page_table.unmap_huge(virt!(addr), size) {
+                        Ok(target) => (0..size.frames()).for_each(|i| {
+                            let _ = self
+                                .inner
+                                .frame_allocator
+                                .dealloc(target.as_u64() + (i * PAGE_SIZE) as u64);
+                        }),
+                        Err(errno) => {
+                            kwarn!("failed to unmap the huge page @ {addr:#x}: {errno:?}")
+                        }
+                    }
+
+                    addr += Self::HUGE_PAGE_SIZE;
+                    continue;
+                } else if page_table.get_entry(virt!(addr)).is_err() {
+                    // Never touched.
+                    addr += Self::HUGE_PAGE_SIZE;
+                    continue;
+                }
+            }
+
+            self.split_chunk(page_table, addr);
+            self.inner.unmap(page_table, virt!(addr));
+            addr += PAGE_SIZE as u64;
+        }
+    }
+
+    fn protect(
+        &self,
+        page_table: &mut dyn PageTableBehaviors,
+        range: &Range<u64>,
+        flags: &ArenaFlags,
+    ) {
+        let start = page_frame_number(range.start);
+        let end = page_frame_number(range.end + PAGE_SIZE as u64 - 1);
+
+        let mut addr = start;
+        while addr < end {
+            if Self::chunk_covered(addr, start, end)
+                && page_table.huge_page_size(virt!(addr)).is_some()
+            {
+                if let Ok(entry) = page_table.get_entry(virt!(addr)) {
+                    entry.set_writable(flags.writable);
+                    entry.set_execute(!flags.non_executable);
+                    entry.set_user(flags.user_accessible);
+                    entry.update();
+                }
+
+                addr += Self::HUGE_PAGE_SIZE;
+                continue;
+            }
+
+            // Untouched chunks are split as well because the flags must be kept somewhere.
+            self.split_chunk(page_table, addr);
+            self.inner
+                .protect(page_table, &(addr..addr + PAGE_SIZE as u64), flags);
+            addr += PAGE_SIZE as u64;
+        }
+    }
+
+    fn do_handle_page_fault(
+        &self,
+        page_table: &mut dyn PageTableBehaviors,
+        addr: u64,
+        access_type: AccessType,
+    ) -> bool {
+        if page_table.get_entry(virt!(addr)).is_err() {
+            // The first touch of a huge page candidate.
+            let chunk = addr & !(Self::HUGE_PAGE_SIZE - 1);
+            let frames = HugePageSize::Size2MiB.frames();
+
+            match self
+                .inner
+                .frame_allocator
+                .alloc_contiguous(frames, frames.trailing_zeros() as usize)
+            {
+                Ok(target) => {
+                    unsafe {
+                        core::ptr::write_bytes(
+                            phys_to_virt(target.as_u64()) as *mut u8,
+                            0,
+                            Self::HUGE_PAGE_SIZE as usize,
+                        );
+                    }
+
+                    match page_table.map_huge(
+                        virt!(chunk),
+                        target,
+                        HugePageSize::Size2MiB,
+                        &self.flags,
+                    ) {
+                        Ok(_) => return true,
+                        Err(errno) => {
+                            kerror!(
+                                "failed to map the huge page @ {:#x}. Errno: {:?}",
+                                chunk,
+                                errno
+                            );
+                            (0..frames).for_each(|i| {
+                                let _ = self
+                                    .inner
+                                    .frame_allocator
+                                    .dealloc(target.as_u64() + (i * PAGE_SIZE) as u64);
+                            });
+                        }
+                    }
+                }
+                Err(_) => kdebug!(
+                    "no contiguous memory for the huge page @ {:#x}; falling back to 4 KiB pages.",
+                    chunk
+                ),
+            }
+
+            self.split_chunk(page_table, addr);
+        }
+
+        self.inner
+            .do_handle_page_fault(page_table, addr, access_type)
+    }
+}
+
 impl<A> ArenaCallback for DummyArenaCallback<A>
 where
     A: FrameAlloc,
@@ -6,6 +6,7 @@
 //! to split larger memory regions across non-continuous physical frames.
 
 pub mod callback;
+pub mod page_cache;
 
 use alloc::{
     boxed::Box,
@@ -16,18 +17,22 @@ use alloc::{
 };
 use bitflags::bitflags;
 use core::{future::Future, ops::Range, pin::Pin};
-use x86_64::structures::paging::{Page, Size4KiB};
+use x86_64::structures::paging::Page;
 
 use crate::{
     arch::{
         cpu::cpu_id,
-        mm::paging::{set_page_table, EntryBehaviors, PageTableBehaviors, PageTableMoreBehaviors},
+        mm::{
+            paging::{set_page_table, EntryBehaviors, PageTableBehaviors, PageTableMoreBehaviors},
+            tlb::TlbGather,
+        },
         PAGE_SIZE,
     },
     error::{Errno, KResult},
     memory::{page_frame_number, KernelFrameAllocator},
     process::thread::{Thread, CURRENT_THREAD_PER_CPU},
-    sync::mutex::SpinLockNoInterrupt as Mutex,
+    slab::{typed_box, ARENA_CACHE},
+    sync::mutex::FlagsGuard,
     sys::Prot,
     utils::ptr::Ptr,
 };
@@ -85,7 +90,9 @@ impl From<Prot> for ArenaFlags {
 /// This struct contains the future itself, a `Mutex` to allow for synchronization, the CR3 value of
 /// the required page table, and an `Arc` reference to the thread that will run the future.
 pub struct FutureWithPageTable {
-    future: Mutex<Pin<Box<dyn Future<Output = ()> + Send + 'static>>>,
+    /// Only keeps two CPUs from polling the same future, so it does not count towards the preempt count; see
+    /// `Task::future`. Interrupts are still masked while the future is polled.
+    future: spin::Mutex<Pin<Box<dyn Future<Output = ()> + Send + 'static>>>,
     cr3: u64,
     thread: Arc<Thread>,
 }
@@ -97,7 +104,7 @@ impl FutureWithPageTable {
         thread: Arc<Thread>,
     ) -> Self {
         Self {
-            future: Mutex::new(future),
+            future: spin::Mutex::new(future),
             cr3,
             thread,
         }
@@ -114,6 +121,7 @@ impl Future for FutureWithPageTable {
         let old = unsafe { CURRENT_THREAD_PER_CPU[cpu_id()].replace(self.thread.clone()) };
 
         set_page_table(self.cr3);
+        let _irq = FlagsGuard::no_irq_region();
         let poll_res = self.future.lock().as_mut().poll(cx);
 
         if let Some(old) = old {
@@ -226,14 +234,9 @@ impl Arena {
         //     kwarn!("arena size is not 4KB aligned.");
         // }
 
-        for page in Page::<Size4KiB>::range_inclusive(
-            page!(self.range.start),
-            page!(self.range.end.saturating_sub(1)),
-        ) {
-            // Invoke callback and let it do something for us.
-            self.callback
-                .map(page_table, page.start_address(), &self.flags);
-        }
+        // Invoke callback and let it do something for us.
+        self.callback
+            .map_range(page_table, &self.range, &self.flags);
 
         Ok(())
     }
@@ -249,12 +252,7 @@ impl Arena {
         //     return Err(Errno::EINVAL);
         // }
 
-        for page in Page::<Size4KiB>::range_inclusive(
-            page!(self.range.start),
-            page!(self.range.end.saturating_sub(1)),
-        ) {
-            self.callback.unmap(page_table, page.start_address());
-        }
+        self.callback.unmap_range(page_table, &self.range);
 
         Ok(())
     }
@@ -326,7 +324,7 @@ where
     P: PageTableBehaviors + PageTableMoreBehaviors,
 {
     /// The free chunk list in Linux.
-    arena: Vec<Arena>,
+    arena: Vec<Box<Arena>>,
     page_table: P,
     /// The heap ending point.
     heap_end: Option<u64>,
@@ -391,7 +389,7 @@ where
             name: "".into(),
         };
 
-        self.arena.insert(0, reserved);
+        self.arena.insert(0, typed_box(&ARENA_CACHE, reserved));
     }
 
     #[inline]
@@ -447,6 +445,7 @@ where
     /// otherwise, we return [`Errno::EINVAL`].
     pub fn remove_addr(&mut self, addr: u64, len: usize) -> KResult<()> {
         let range = addr..addr + len as u64;
+        let mut gather = TlbGather::new(self.page_table.cr3());
 
         let mut i = 0usize;
         while i < self.arena.len() {
@@ -456,6 +455,7 @@ where
                 {
                     let cur_arena = self.arena.remove(i);
                     cur_arena.unmap(&mut self.page_table)?;
+                    gather.add(cur_arena.range.clone());
 
                     // Adjust the iterator.
                     i = i.wrapping_sub(1);
@@ -479,6 +479,7 @@ where
                         name: cur_arena.name.clone(),
                     };
                     should_remove.unmap(&mut self.page_table)?;
+                    gather.add(should_remove.range.clone());
                     let remaining = Arena {
                         range: range.end..cur_arena.range.end,
                         flags: cur_arena.flags.clone(),
@@ -486,7 +487,7 @@ where
                         ty: cur_arena.ty,
                         name: cur_arena.name.clone(),
                     };
-                    self.arena.insert(i, remaining);
+                    self.arena.insert(i, typed_box(&ARENA_CACHE, remaining));
                 } else if self.arena[i].range.end <= range.end
                     && self.arena[i].range.end > range.start
                 {
@@ -507,6 +508,7 @@ where
                         name: cur_arena.name.clone(),
                     };
                     should_remove.unmap(&mut self.page_table)?;
+                    gather.add(should_remove.range.clone());
                     let remaining = Arena {
                         range: cur_arena.range.start..range.start,
                         flags: cur_arena.flags.clone(),
@@ -514,7 +516,7 @@ where
                         ty: cur_arena.ty,
                         name: cur_arena.name.clone(),
                     };
-                    self.arena.insert(i, remaining);
+                    self.arena.insert(i, typed_box(&ARENA_CACHE, remaining));
                 } else {
                     // Superset.
                     // [               ]          <- cur_arena
@@ -532,6 +534,7 @@ where
                         name: cur_arena.name.clone(),
                     };
                     should_remove.unmap(&mut self.page_table)?;
+                    gather.add(should_remove.range.clone());
                     let remaining_lhs = Arena {
                         range: cur_arena.range.start..range.start,
                         flags: cur_arena.flags.clone(),
@@ -546,8 +549,9 @@ where
                         ty: cur_arena.ty,
                         name: cur_arena.name.clone(),
                     };
-                    self.arena.insert(i, remaining_lhs);
-                    self.arena.insert(i + 1, remaining_rhs);
+                    self.arena.insert(i, typed_box(&ARENA_CACHE, remaining_lhs));
+                    self.arena
+                        .insert(i + 1, typed_box(&ARENA_CACHE, remaining_rhs));
                     i += 1;
                 }
             }
@@ -558,19 +562,84 @@ where
         Ok(())
     }
 
+    /// Changes the flags of the memory region [addr, addr + len) to `flags`. Arenas that are only partially covered are
+    /// split. Returns [`Errno::ENOMEM`] if some part of the region is not mapped.
+    pub fn protect(&mut self, addr: u64, len: usize, flags: ArenaFlags) -> KResult<()> {
+        let range = addr..addr + len as u64;
+
+        // The arenas are sorted, so we can check if the region has any hole in one pass.
+        let mapped_end = self
+            .arena
+            .iter()
+            .skip_while(|arena| arena.range.end <= range.start)
+            .try_fold(range.start, |cur, arena| {
+                if cur >= range.end || arena.range.start > cur {
+                    Err(cur)
+                } else {
+                    Ok(arena.range.end)
+                }
+            })
+            .unwrap_or_else(|cur| cur);
+        if mapped_end < range.end {
+            return Err(Errno::ENOMEM);
+        }
+
+        self.split_at(range.start);
+        self.split_at(range.end);
+
+        let mut gather = TlbGather::new(self.page_table.cr3());
+        let MemoryManager {
+            ref mut arena,
+            ref mut page_table,
+            ..
+        } = self;
+        for item in arena
+            .iter_mut()
+            .filter(|item| item.range.start >= range.start && item.range.end <= range.end)
+        {
+            // Other CPUs may keep using the old permissions until they are told otherwise. Upgrades need this as well
+            // because a file mapping that becomes writable gets a private copy of its shared pages.
+            if item.flags != flags {
+                gather.add(item.range.clone());
+            }
+            item.flags = flags.clone();
+            item.callback.protect(page_table, &item.range, &flags);
+        }
+
+        Ok(())
+    }
+
+    /// Splits the arena containing `addr` into two at `addr`. The page table is left untouched.
+    fn split_at(&mut self, addr: u64) {
+        if let Some(i) = self
+            .arena
+            .iter()
+            .position(|arena| arena.range.start < addr && arena.range.end > addr)
+        {
+            let mut rhs = typed_box(&ARENA_CACHE, self.arena[i].as_ref().clone());
+            rhs.range.start = addr;
+            self.arena[i].range.end = addr;
+            self.arena.insert(i + 1, rhs);
+        }
+    }
+
     /// Checks whether a read request is valid, i.e., the given address + size should
-    /// not exceed `self.range`. If the pointer is valid, we convert it into a slice.
-    pub fn get_slice<T>(&self, mut addr: u64, size: usize) -> KResult<&'static [T]>
+    /// not exceed `self.range`. If the pointer is valid, we return a pointer to the start of the area.
+    ///
+    /// The area is still user memory: copy it with [`Ptr::read_slice`] *after* this memory manager is unlocked, since
+    /// touching a page that is not yet present needs the lock in the page fault handler.
+    pub fn get_slice<T>(&self, mut addr: u64, size: usize) -> KResult<Ptr<T>>
     where
         T: Sized + 'static,
     {
+        let start = addr;
         let mut valid_size = 0;
 
         for item in self.arena.iter() {
             valid_size += item.check_read(addr as *const T, size).unwrap_or(0);
 
             if valid_size >= core::mem::size_of::<T>() * size {
-                return unsafe { Ok(core::slice::from_raw_parts(addr as _, size)) };
+                return Ok(Ptr::new(start));
             }
 
             addr += valid_size as u64;
@@ -579,9 +648,11 @@ where
         Err(Errno::EINVAL)
     }
 
-    /// Checks whether a read request is valid, i.e., the given address + size should
-    /// not exceed `self.range`. If the pointer is valid, we convert it into a slice.
-    pub fn get_mut_slice<T>(&self, addr: u64, size: usize) -> KResult<&'static mut [T]>
+    /// Checks whether a write request is valid, i.e., the given address + size should
+    /// not exceed `self.range`. If the pointer is valid, we return a pointer to the start of the area.
+    ///
+    /// See [`MemoryManager::get_slice`] for how to access the area.
+    pub fn get_mut_slice<T>(&self, addr: u64, size: usize) -> KResult<Ptr<T>>
     where
         T: Sized + 'static,
     {
@@ -591,7 +662,7 @@ where
             valid_size += item.check_write(addr as *mut T, size).unwrap_or(0);
 
             if valid_size >= core::mem::size_of::<T>() * size {
-                return Ok(unsafe { core::slice::from_raw_parts_mut(addr as _, size) });
+                return Ok(Ptr::new(addr));
             }
         }
 
@@ -604,7 +675,6 @@ where
         T: Sized + 'static,
     {
         self.get_slice::<T>(addr as _, 1)
-            .map(|arr| Ptr::new(arr.as_ptr() as u64))
     }
 
     /// Checks whether a write request is valid for a given pointer.
@@ -613,7 +683,6 @@ where
         T: Sized + 'static,
     {
         self.get_mut_slice::<T>(addr as _, 1)
-            .map(|arr| Ptr::new(arr.as_ptr() as u64))
     }
 
     /// Executes function `f` with `page_table`.
@@ -652,9 +721,11 @@ where
     }
 
     pub fn clear(&mut self) {
+        let mut gather = TlbGather::new(self.page_table.cr3());
         for arena in self.arena.iter_mut() {
             kdebug!("clear(): dropping arena {:#x?}", arena.range);
             arena.unmap(&mut self.page_table).unwrap();
+            gather.add(arena.range.clone());
         }
 
         self.arena.clear();
@@ -685,7 +756,11 @@ where
         }
 
         Self {
-            arena: self.arena.clone(),
+            arena: self
+                .arena
+                .iter()
+                .map(|arena| typed_box(&ARENA_CACHE, arena.as_ref().clone()))
+                .collect(),
             page_table: new_page_table,
             heap_end: self.heap_end.clone(),
         }
@@ -742,7 +817,7 @@ where
         };
 
         // Insert into the arena.
-        self.arena.insert(index, other);
+        self.arena.insert(index, typed_box(&ARENA_CACHE, other));
     }
 
     /// Returns the page table.
@@ -752,11 +827,11 @@ where
 
     /// Returns the iterator.
     pub fn iter(&self) -> impl Iterator<Item = &Arena> {
-        self.arena.iter()
+        self.arena.iter().map(|arena| arena.as_ref())
     }
 
     pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Arena> {
-        self.arena.iter_mut()
+        self.arena.iter_mut().map(|arena| arena.as_mut())
     }
 
     /// Validates the page table.
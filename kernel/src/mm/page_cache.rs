@@ -0,0 +1,617 @@
+//! A per-inode page cache.
+//!
+//! Every regular file on a filesystem registered with [`enable_page_cache`] gets a [`PageCache`] which keeps the pages
+//! of the file that have been read or written in physical frames. `read`, `write` and file-backed `mmap` all go through
+//! the same frames, so everyone who opens or maps the file shares them.
+//!
+//! * A cache is active as long as someone holds a [`PageCacheHandle`] to it, i.e., while the file is open or mapped.
+//!   When the last user goes away, the dirty pages are written back (or dropped if the file has been unlinked
+//!   meanwhile) and the clean pages are moved to an LRU list of retained pages that only remembers the inode by its
+//!   key, so that it does not pin the inode. Opening the file again picks them up unless the size or the modification
+//!   time of the inode has changed meanwhile, e.g., because the inode number has been reused.
+//! * Writes only dirty the cached page. Dirty pages are written back by `fsync` / `sync`, or as soon as there are more
+//!   than [`DIRTY_LIMIT`] dirty pages in the system. Pages mapped by writable `MAP_SHARED` mappings are written back
+//!   on every sync, since stores through the mapping cannot be seen, and once more when they are unmapped.
+//! * When the frame allocator runs out of memory, the retained pages are dropped first, least recently released
+//!   first, followed by the clean pages of active caches that are not mapped by anyone.
+//! * Sequential reads grow a read-ahead window up to [`READ_AHEAD_MAX`] pages.
+//!
+//! Pseudo filesystems like procfs and devfs must never be cached since their content is generated on each read.
+
+use core::{
+    ops::Deref,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+use lazy_static::lazy_static;
+use rcore_fs::vfs::{FileSystem, FileType, INode, Metadata};
+use spin::RwLock;
+use x86_64::PhysAddr;
+
+use crate::{
+    arch::PAGE_SIZE,
+    error::{fserror_to_kerror, KResult},
+    memory::{phys_to_virt, FrameAlloc, KernelFrameAllocator},
+    process::scheduler::cond_resched,
+    sync::mutex::SpinLockNoInterrupt as Mutex,
+};
+
+/// Dirty pages are written back once the number of dirty pages in the system exceeds this limit.
+pub const DIRTY_LIMIT: usize = 0x400;
+/// The initial read-ahead window in pages.
+pub const READ_AHEAD_MIN: usize = 0x4;
+/// The maximum read-ahead window in pages.
+pub const READ_AHEAD_MAX: usize = 0x20;
+
+/// The number of dirty pages in all page caches.
+static DIRTY_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    /// Filesystems whose regular files are cached.
+    static ref CACHED_FILESYSTEMS: RwLock<Vec<Arc<dyn FileSystem>>> = RwLock::new(Vec::new());
+    /// All page caches keyed by (filesystem, inode number).
+    static ref PAGE_CACHES: RwLock<BTreeMap<(usize, usize), Arc<PageCache>>> =
+        RwLock::new(BTreeMap::new());
+    /// The pages of caches that nobody uses any longer, the least recently released first. Locked after
+    /// [`PAGE_CACHES`].
+    static ref RETAINED_PAGES: Mutex<VecDeque<RetainedPages>> = Mutex::new(VecDeque::new());
+}
+
+/// The size and the modification time of an inode, used to tell whether retained pages are still valid.
+type Stamp = (usize, i64, i64);
+
+/// The clean pages of a released cache.
+struct RetainedPages {
+    key: (usize, usize),
+    stamp: Stamp,
+    pages: BTreeMap<usize, Arc<CachedPage>>,
+}
+
+/// A page of a file held in a physical frame.
+pub struct CachedPage {
+    frame: PhysAddr,
+    /// The page differs from the disk.
+    dirty: AtomicBool,
+    /// The number of page table entries that point to `frame`. Mapped pages are never evicted.
+    mapped: AtomicUsize,
+    /// How many of them are writable.
+    writers: AtomicUsize,
+}
+
+/// The page cache of a single inode.
+pub struct PageCache {
+    inode: Arc<dyn INode>,
+    /// The key in [`PAGE_CACHES`].
+    key: (usize, usize),
+    /// The number of [`PageCacheHandle`]s.
+    users: AtomicUsize,
+    pages: Mutex<BTreeMap<usize, Arc<CachedPage>>>,
+    /// The page index at which the next sequential read is expected to miss.
+    read_ahead_next: AtomicUsize,
+    /// The current read-ahead window in pages.
+    read_ahead_window: AtomicUsize,
+}
+
+impl CachedPage {
+    fn new() -> KResult<Self> {
+        let frame = KernelFrameAllocator.alloc()?;
+
+        Ok(Self {
+            frame,
+            dirty: AtomicBool::new(false),
+            mapped: AtomicUsize::new(0),
+            writers: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn frame(&self) -> PhysAddr {
+        self.frame
+    }
+
+    /// The content of the page. Like the frames of a page table, it may be accessed by several parties at once.
+    #[allow(clippy::mut_from_ref)]
+    fn data(&self) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(phys_to_virt(self.frame.as_u64()) as *mut u8, PAGE_SIZE)
+        }
+    }
+
+    fn set_dirty(&self) {
+        if !self.dirty.swap(true, Ordering::AcqRel) {
+            DIRTY_PAGES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Clears the dirty bit and returns if it was set.
+    fn clear_dirty(&self) -> bool {
+        let dirty = self.dirty.swap(false, Ordering::AcqRel);
+        if dirty {
+            DIRTY_PAGES.fetch_sub(1, Ordering::Relaxed);
+        }
+        dirty
+    }
+
+    /// Returns true if the page can be dropped without losing anything.
+    fn evictable(self: &Arc<Self>) -> bool {
+        Arc::strong_count(self) == 1
+            && self.mapped.load(Ordering::Acquire) == 0
+            && !self.dirty.load(Ordering::Acquire)
+    }
+}
+
+/// A reference to a [`PageCache`] held by a user of the file, e.g., an open file or a file mapping.
+pub struct PageCacheHandle(Arc<PageCache>);
+
+impl Drop for CachedPage {
+    fn drop(&mut self) {
+        self.clear_dirty();
+        if let Err(errno) = KernelFrameAllocator.dealloc(self.frame.as_u64()) {
+            kerror!(
+                "drop(): failed to free the cached frame {:#x}. Errno: {:?}",
+                self.frame,
+                errno
+            );
+        }
+    }
+}
+
+impl PageCacheHandle {
+    /// Must be called with [`PAGE_CACHES`] locked so that [`PageCache::release`] cannot forget the cache meanwhile.
+    fn new(cache: Arc<PageCache>) -> Self {
+        cache.users.fetch_add(1, Ordering::AcqRel);
+        Self(cache)
+    }
+}
+
+impl Clone for PageCacheHandle {
+    fn clone(&self) -> Self {
+        self.0.users.fetch_add(1, Ordering::AcqRel);
+        Self(self.0.clone())
+    }
+}
+
+impl Drop for PageCacheHandle {
+    fn drop(&mut self) {
+        if self.0.users.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.release();
+        }
+    }
+}
+
+impl Deref for PageCacheHandle {
+    type Target = PageCache;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl PageCache {
+    fn new(inode: Arc<dyn INode>, key: (usize, usize)) -> Self {
+        Self {
+            inode,
+            key,
+            users: AtomicUsize::new(0),
+            pages: Mutex::new(BTreeMap::new()),
+            read_ahead_next: AtomicUsize::new(0),
+            read_ahead_window: AtomicUsize::new(READ_AHEAD_MIN),
+        }
+    }
+
+    /// Returns the page cache of `inode`, or `None` if `inode` is not a regular file on a cached filesystem. The cache
+    /// is kept until the returned handle and all its clones are dropped.
+    pub fn of(inode: &Arc<dyn INode>) -> Option<PageCacheHandle> {
+        let metadata = inode.metadata().ok()?;
+        if metadata.type_ != FileType::File {
+            return None;
+        }
+
+        let fs = inode.fs();
+        let fs = Arc::as_ptr(&fs) as *const u8 as usize;
+        if !CACHED_FILESYSTEMS
+            .read()
+            .iter()
+            .any(|cached| Arc::as_ptr(cached) as *const u8 as usize == fs)
+        {
+            return None;
+        }
+
+        let key = (fs, metadata.inode);
+        if let Some(cache) = PAGE_CACHES.read().get(&key) {
+            return Some(PageCacheHandle::new(cache.clone()));
+        }
+
+        let mut caches = PAGE_CACHES.write();
+        if let Some(cache) = caches.get(&key) {
+            return Some(PageCacheHandle::new(cache.clone()));
+        }
+
+        let cache = Arc::new(Self::new(inode.clone(), key));
+        let retained = take_retained_pages(key);
+        let stale = match retained {
+            Some(retained) if retained.stamp == stamp(&metadata) => {
+                *cache.pages.lock() = retained.pages;
+                None
+            }
+            retained => retained,
+        };
+        caches.insert(key, cache.clone());
+        let handle = PageCacheHandle::new(cache);
+        drop(caches);
+
+        // Frames are freed outside the lock.
+        drop(stale);
+        Some(handle)
+    }
+
+    /// Called when the last [`PageCacheHandle`] is dropped. Writes the dirty pages back, or drops them if the file has
+    /// no links left since the inode is about to be freed, and forgets the cache unless someone has opened the file
+    /// again meanwhile. The clean pages of a file that still exists are retained for the next user.
+    fn release(self: &Arc<Self>) {
+        let metadata = self
+            .inode
+            .metadata()
+            .ok()
+            .filter(|metadata| metadata.nlinks != 0);
+        let retain = match metadata {
+            None => {
+                self.pages.lock().values().for_each(|page| {
+                    page.clear_dirty();
+                });
+                false
+            }
+            Some(_) => match self.sync() {
+                Ok(()) => true,
+                Err(errno) => {
+                    kerror!(
+                        "release(): failed to write back the page cache of inode {}. Errno: {:?}",
+                        self.key.1,
+                        errno
+                    );
+                    false
+                }
+            },
+        };
+
+        let (forgotten, stale) = {
+            let mut caches = PAGE_CACHES.write();
+            let forgotten = match caches.get(&self.key) {
+                Some(cache)
+                    if Arc::ptr_eq(cache, self) && self.users.load(Ordering::Acquire) == 0 =>
+                {
+                    caches.remove(&self.key)
+                }
+                _ => None,
+            };
+
+            // Still under the lock so that nobody can open the file and miss the pages meanwhile.
+            let stale = take_retained_pages(self.key);
+            if let (Some(_), Some(metadata), true) = (&forgotten, &metadata, retain) {
+                RETAINED_PAGES.lock().push_back(RetainedPages {
+                    key: self.key,
+                    stamp: stamp(metadata),
+                    pages: core::mem::take(&mut *self.pages.lock()),
+                });
+            }
+
+            (forgotten, stale)
+        };
+
+        // Frames are freed outside the lock.
+        drop(forgotten);
+        drop(stale);
+    }
+
+    fn size(&self) -> KResult<usize> {
+        self.inode
+            .metadata()
+            .map(|metadata| metadata.size)
+            .map_err(fserror_to_kerror)
+    }
+
+    /// Reads page `index` from the disk into a new frame. The part beyond the end of file is zeroed.
+    fn load_page(&self, index: usize) -> KResult<Arc<CachedPage>> {
+        let page = CachedPage::new()?;
+        let data = page.data();
+        let mut len = 0;
+        while len < PAGE_SIZE {
+            match self
+                .inode
+                .read_at(index * PAGE_SIZE + len, &mut data[len..])
+                .map_err(fserror_to_kerror)?
+            {
+                0 => break,
+                read => len += read,
+            }
+        }
+        data[len..].fill(0);
+
+        // Someone may have loaded the same page while we were reading; theirs wins.
+        Ok(self
+            .pages
+            .lock()
+            .entry(index)
+            .or_insert_with(|| Arc::new(page))
+            .clone())
+    }
+
+    /// Returns page `index`, reading it (and the pages following it if the file is read sequentially) if it is not
+    /// cached yet.
+    pub fn get_page(&self, index: usize) -> KResult<Arc<CachedPage>> {
+        if let Some(page) = self.pages.lock().get(&index) {
+            return Ok(page.clone());
+        }
+
+        // Grow the window on sequential misses and start over on random ones.
+        let window = match self.read_ahead_next.load(Ordering::Relaxed) == index {
+            true => (self.read_ahead_window.load(Ordering::Relaxed) * 2).min(READ_AHEAD_MAX),
+            false => READ_AHEAD_MIN,
+        };
+        let last = (self.size()? + PAGE_SIZE - 1) / PAGE_SIZE;
+        let end = (index + window).min(last);
+        self.read_ahead_window.store(window, Ordering::Relaxed);
+        self.read_ahead_next.store(end, Ordering::Relaxed);
+
+        let page = self.load_page(index)?;
+        for ahead in index + 1..end {
+            if self.pages.lock().contains_key(&ahead) {
+                continue;
+            }
+            // Read-ahead is only a hint.
+            if self.load_page(ahead).is_err() {
+                break;
+            }
+            // No page is locked here. Page faults come in with the vm locked, which keeps this a no-op for them.
+            cond_resched();
+        }
+
+        Ok(page)
+    }
+
+    /// Reads from the cache at `offset`.
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> KResult<usize> {
+        let size = self.size()?;
+        if offset >= size {
+            return Ok(0);
+        }
+
+        let end = (offset + buf.len()).min(size);
+        let mut pos = offset;
+        while pos < end {
+            let page = self.get_page(pos / PAGE_SIZE)?;
+            let start = pos % PAGE_SIZE;
+            let len = (PAGE_SIZE - start).min(end - pos);
+            buf[pos - offset..pos - offset + len].copy_from_slice(&page.data()[start..start + len]);
+            pos += len;
+        }
+
+        Ok(end - offset)
+    }
+
+    /// Writes into the cache at `offset`. The file is extended on the disk right away, but the data is only written
+    /// back later.
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> KResult<usize> {
+        let end = offset + buf.len();
+        if end > self.size()? {
+            self.inode.resize(end).map_err(fserror_to_kerror)?;
+        }
+
+        let mut pos = offset;
+        while pos < end {
+            let page = self.get_page(pos / PAGE_SIZE)?;
+            let start = pos % PAGE_SIZE;
+            let len = (PAGE_SIZE - start).min(end - pos);
+            page.data()[start..start + len].copy_from_slice(&buf[pos - offset..pos - offset + len]);
+            page.set_dirty();
+            pos += len;
+        }
+
+        if DIRTY_PAGES.load(Ordering::Relaxed) > DIRTY_LIMIT {
+            self.sync()?;
+        }
+
+        Ok(buf.len())
+    }
+
+    /// Writes all dirty pages back to the inode.
+    pub fn sync(&self) -> KResult<()> {
+        let size = self.size()?;
+        let dirty = self
+            .pages
+            .lock()
+            .iter()
+            .filter(|(_, page)| {
+                page.dirty.load(Ordering::Acquire) || page.writers.load(Ordering::Acquire) != 0
+            })
+            .map(|(&index, page)| (index, page.clone()))
+            .collect::<Vec<_>>();
+
+        for (index, page) in dirty {
+            let offset = index * PAGE_SIZE;
+            // Stores through a writable shared mapping do not set the dirty bit.
+            let mapped_writable = page.writers.load(Ordering::Acquire) != 0;
+            if offset >= size || !(page.clear_dirty() | mapped_writable) {
+                continue;
+            }
+
+            let len = (size - offset).min(PAGE_SIZE);
+            if let Err(errno) = self.inode.write_at(offset, &page.data()[..len]) {
+                page.set_dirty();
+                return Err(fserror_to_kerror(errno));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drops the pages beyond `size` after the file has been truncated. Pages that are still mapped are zeroed instead.
+    pub fn truncate(&self, size: usize) {
+        let mut dropped = Vec::new();
+        {
+            let mut pages = self.pages.lock();
+            let first = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+            for (index, page) in pages.split_off(&first) {
+                if page.mapped.load(Ordering::Acquire) != 0 {
+                    page.data().fill(0);
+                    page.clear_dirty();
+                    pages.insert(index, page);
+                } else {
+                    dropped.push(page);
+                }
+            }
+
+            // The last page is partial.
+            if size % PAGE_SIZE != 0 {
+                if let Some(page) = pages.get(&(size / PAGE_SIZE)) {
+                    page.data()[size % PAGE_SIZE..].fill(0);
+                }
+            }
+        }
+
+        // Frames are freed outside the lock.
+        drop(dropped);
+    }
+
+    /// Returns the frame of the page at `offset` and records that it has been mapped into a page table. A `writable`
+    /// mapping keeps the page dirty until it is unmapped or made read-only.
+    pub fn map_page(&self, offset: usize, writable: bool) -> KResult<PhysAddr> {
+        let page = self.get_page(offset / PAGE_SIZE)?;
+        page.mapped.fetch_add(1, Ordering::AcqRel);
+        if writable {
+            page.writers.fetch_add(1, Ordering::AcqRel);
+            page.set_dirty();
+        }
+        Ok(page.frame())
+    }
+
+    /// Undoes [`Self::map_page`] if `frame` is the cached frame of the page at `offset`. Returns false if the frame
+    /// does not belong to the cache.
+    pub fn unmap_page(&self, offset: usize, frame: PhysAddr, writable: bool) -> bool {
+        match self.pages.lock().get(&(offset / PAGE_SIZE)) {
+            Some(page) if page.frame == frame => {
+                if writable {
+                    // Whatever was stored through the mapping has to be written back.
+                    page.set_dirty();
+                    page.writers.fetch_sub(1, Ordering::AcqRel);
+                }
+                page.mapped.fetch_sub(1, Ordering::AcqRel);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Records that a mapping of `frame` at `offset` has become `writable` or read-only. Returns false if the frame does
+    /// not belong to the cache.
+    pub fn protect_page(&self, offset: usize, frame: PhysAddr, writable: bool) -> bool {
+        match self.pages.lock().get(&(offset / PAGE_SIZE)) {
+            Some(page) if page.frame == frame => {
+                page.set_dirty();
+                match writable {
+                    true => page.writers.fetch_add(1, Ordering::AcqRel),
+                    false => page.writers.fetch_sub(1, Ordering::AcqRel),
+                };
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Drops up to `count` clean pages that are not in use. Returns the number of pages dropped.
+    fn shrink(&self, count: usize) -> usize {
+        // We may be called by the frame allocator while this cache is locked.
+        let mut pages = match self.pages.try_lock() {
+            Some(pages) => pages,
+            None => return 0,
+        };
+
+        let victims = pages
+            .iter()
+            .filter(|(_, page)| page.evictable())
+            .map(|(&index, _)| index)
+            .take(count)
+            .collect::<Vec<_>>();
+        let dropped = victims
+            .iter()
+            .filter_map(|index| pages.remove(index))
+            .collect::<Vec<_>>();
+        drop(pages);
+
+        // Frames are freed outside the lock.
+        dropped.len()
+    }
+}
+
+/// Enables the page cache for the regular files on `fs`.
+pub fn enable_page_cache(fs: Arc<dyn FileSystem>) {
+    CACHED_FILESYSTEMS.write().push(fs);
+}
+
+/// Writes back all dirty pages and syncs the cached filesystems.
+pub fn sync_page_caches() -> KResult<()> {
+    let caches = PAGE_CACHES.read().values().cloned().collect::<Vec<_>>();
+    for cache in caches {
+        cache.sync()?;
+    }
+
+    let filesystems = CACHED_FILESYSTEMS.read().clone();
+    for fs in filesystems {
+        fs.sync().map_err(fserror_to_kerror)?;
+    }
+
+    Ok(())
+}
+
+fn stamp(metadata: &Metadata) -> Stamp {
+    (
+        metadata.size,
+        metadata.mtime.sec,
+        metadata.mtime.nsec as i64,
+    )
+}
+
+/// Takes the retained pages of the inode at `key` out of the LRU list.
+fn take_retained_pages(key: (usize, usize)) -> Option<RetainedPages> {
+    let mut retained = RETAINED_PAGES.lock();
+    let pos = retained.iter().position(|pages| pages.key == key)?;
+    retained.remove(pos)
+}
+
+/// Drops up to `count` clean pages from the page caches to relieve memory pressure. Returns the number of pages dropped.
+pub fn shrink_page_caches(count: usize) -> usize {
+    // The retained pages go first, the least recently released first.
+    let mut dropped = 0;
+    let mut victims = Vec::new();
+    if let Some(mut retained) = RETAINED_PAGES.try_lock() {
+        while dropped < count {
+            match retained.pop_front() {
+                Some(pages) => {
+                    dropped += pages.pages.len();
+                    victims.push(pages);
+                }
+                None => break,
+            }
+        }
+    }
+    // Frames are freed outside the lock.
+    drop(victims);
+
+    let caches = match PAGE_CACHES.try_read() {
+        Some(caches) => caches.values().cloned().collect::<Vec<_>>(),
+        None => return dropped,
+    };
+
+    for cache in caches {
+        if dropped >= count {
+            break;
+        }
+        dropped += cache.shrink(count - dropped);
+    }
+
+    dropped
+}
@@ -3,7 +3,6 @@
 use alloc::{
     boxed::Box,
     collections::{BTreeMap, BTreeSet, VecDeque},
-    vec,
     vec::Vec,
 };
 use lazy_static::lazy_static;
@@ -24,7 +23,10 @@ use core::{
 
 use crate::{
     arch::cpu::rdrand,
+    drivers::NETWORK_DRIVERS,
     error::{Errno, KResult},
+    process::{scheduler::yield_now, workqueue::run_pending_work},
+    slab::{typed_buffer, SOCKET_BUFFER_CACHE},
     sync::mutex::SpinLock as Mutex,
     sys::SocketOptions,
 };
@@ -53,6 +55,12 @@ lazy_static! {
 pub const RECVBUF_LEN: usize = 4096;
 pub const SENDBUF_LEN: usize = 4096;
 pub const IPV4_HDR_LEN: usize = 20;
+
+/// Allocates a zeroed smoltcp socket buffer from the `socket_buffer` slab cache.
+pub fn socket_buffer(len: usize) -> Vec<u8> {
+    typed_buffer(&SOCKET_BUFFER_CACHE, len)
+}
+
 /// Allocates a port between 49152 and 65535 as requied by smoltcp.
 pub fn get_free_port() -> u16 {
     loop {
@@ -67,6 +75,21 @@ pub fn get_free_port() -> u16 {
     }
 }
 
+/// Kicks the bottom halves of all network drivers and gives them a chance to run. The NIC is only polled in the
+/// deferred context, so socket syscalls call this while they wait for the network instead of polling it themselves.
+///
+/// No lock may be held by the caller since we may switch to another task. If we cannot (e.g., we are the task that
+/// another one switched to), the work pending on this CPU is run here instead so that the wait can still end.
+pub fn wait_for_network() {
+    NETWORK_DRIVERS
+        .read()
+        .iter()
+        .for_each(|driver| driver.schedule_poll());
+    if !yield_now() {
+        run_pending_work();
+    }
+}
+
 /// Converts [`SocketAddrV4`] into [`IpAddress`].
 #[inline]
 pub fn convert_addr(src: &SocketAddrV4) -> IpAddress {
@@ -127,8 +150,8 @@ impl ListenTable {
             }
 
             let mut socket = smoltcp::socket::tcp::Socket::new(
-                SocketBuffer::new(vec![0u8; RECVBUF_LEN]),
-                SocketBuffer::new(vec![0u8; SENDBUF_LEN]),
+                SocketBuffer::new(socket_buffer(RECVBUF_LEN)),
+                SocketBuffer::new(socket_buffer(SENDBUF_LEN)),
             );
             if socket.listen(dst.port()).is_ok() {
                 let socket_handle = SOCKET_SET.lock().add(socket);
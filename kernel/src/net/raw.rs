@@ -21,8 +21,8 @@ use crate::{
 };
 
 use super::{
-    Shutdown, Socket as SocketTrait, SocketType, SocketWrapper, IPV4_HDR_LEN, RECVBUF_LEN,
-    SENDBUF_LEN, SOCKET_SET,
+    socket_buffer, Shutdown, Socket as SocketTrait, SocketType, SocketWrapper, IPV4_HDR_LEN,
+    RECVBUF_LEN, SENDBUF_LEN, SOCKET_SET,
 };
 
 /// Represents a L3-layer raw socket that can be used to examine the IP header and construct the corresponding network
@@ -46,8 +46,14 @@ impl RawSocket {
         let socket = Socket::new(
             IpVersion::Ipv4,
             protocol_type,
-            PacketBuffer::new(vec![PacketMetadata::EMPTY; 1024], vec![0u8; RECVBUF_LEN]),
-            PacketBuffer::new(vec![PacketMetadata::EMPTY; 1024], vec![0u8; SENDBUF_LEN]),
+            PacketBuffer::new(
+                vec![PacketMetadata::EMPTY; 1024],
+                socket_buffer(RECVBUF_LEN),
+            ),
+            PacketBuffer::new(
+                vec![PacketMetadata::EMPTY; 1024],
+                socket_buffer(SENDBUF_LEN),
+            ),
         );
 
         Self {
@@ -80,13 +86,14 @@ impl RawSocket {
 
 impl SocketTrait for RawSocket {
     fn read(&self, buf: &mut [u8]) -> KResult<(usize, Option<SocketAddr>)> {
-        let mut socket_set = SOCKET_SET.lock();
-        let socket = socket_set.get_mut::<Socket>(self.socket.0);
-
+        // Packets are received by the bottom half of the drivers.
         NETWORK_DRIVERS.read().iter().for_each(|driver| {
-            driver.poll();
+            driver.schedule_poll();
         });
 
+        let mut socket_set = SOCKET_SET.lock();
+        let socket = socket_set.get_mut::<Socket>(self.socket.0);
+
         if let Ok(read_len) = socket.recv_slice(buf) {
             // Construct the remote socket address.
             let packet = Ipv4Packet::new_checked(buf.to_vec()).map_err(|_| Errno::EINVAL)?;
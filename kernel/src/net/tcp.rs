@@ -1,4 +1,4 @@
-use alloc::{boxed::Box, collections::BTreeMap, vec, vec::Vec};
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
 use rcore_fs::vfs::PollStatus;
 use smoltcp::{
     socket::tcp::{RecvError, Socket, SocketBuffer},
@@ -11,7 +11,7 @@ use crate::{
     drivers::NETWORK_DRIVERS,
     error::{Errno, KResult},
     function, kdebug, kerror, kinfo,
-    net::{LISTEN_TABLE, RECVBUF_LEN, SENDBUF_LEN, SOCKET_SET},
+    net::{socket_buffer, wait_for_network, LISTEN_TABLE, RECVBUF_LEN, SENDBUF_LEN, SOCKET_SET},
     sys::SocketOptions,
 };
 
@@ -74,8 +74,8 @@ pub struct TcpStream {
 impl TcpStream {
     pub fn new() -> Self {
         let tcp_socket_inner = Socket::new(
-            SocketBuffer::new(vec![0u8; RECVBUF_LEN]),
-            SocketBuffer::new(vec![0u8; SENDBUF_LEN]),
+            SocketBuffer::new(socket_buffer(RECVBUF_LEN)),
+            SocketBuffer::new(socket_buffer(SENDBUF_LEN)),
         );
         let socket = SocketWrapper(SOCKET_SET.lock().add(tcp_socket_inner));
         Self {
@@ -107,12 +107,9 @@ impl TcpStream {
             };
 
             loop {
-                // Polls each driver.
-                NETWORK_DRIVERS.read().iter().for_each(|driver| {
-                    driver.poll();
-                });
-
-                match LISTEN_TABLE.write().accept(local_endpoint.port, false) {
+                // The table must not be locked while we wait.
+                let res = LISTEN_TABLE.write().accept(local_endpoint.port, false);
+                match res {
                     Ok((socket_handle, remove_address)) => {
                         return Ok(Box::new(Self {
                             socket: Some(SocketWrapper(socket_handle)),
@@ -125,7 +122,7 @@ impl TcpStream {
                         }));
                     }
 
-                    Err(Errno::EAGAIN) => continue,
+                    Err(Errno::EAGAIN) => wait_for_network(),
                     Err(errno) => {
                         kerror!("cannot accept incoming connection.");
                         return Err(errno);
@@ -153,14 +150,13 @@ impl SocketTrait for TcpStream {
             }),
 
             _ => {
-                // Do something.
+                // The status is refreshed by the bottom half of the drivers.
+                NETWORK_DRIVERS.read().iter().for_each(|driver| {
+                    driver.schedule_poll();
+                });
                 let mut socket_set = SOCKET_SET.lock();
 
                 if let Some(ref socket_handle) = self.socket {
-                    NETWORK_DRIVERS.read().iter().for_each(|driver| {
-                        driver.poll();
-                    });
-
                     let socket = socket_set.get_mut::<Socket>(socket_handle.0);
                     if !socket.is_open() || !socket.is_active() {
                         return Ok(PollStatus {
@@ -207,9 +203,6 @@ impl SocketTrait for TcpStream {
             return Err(Errno::ECONNREFUSED);
         }
         loop {
-            NETWORK_DRIVERS.read().iter().for_each(|driver| {
-                driver.poll();
-            });
             // Receive from the socket handle.
             let mut socket_set = SOCKET_SET.lock();
             let socket = socket_set.get_mut::<Socket>(self.socket.as_ref().unwrap().0);
@@ -232,6 +225,7 @@ impl SocketTrait for TcpStream {
                         kdebug!("{:x?}", &buf[..len]);
                         return Ok((len, None));
                     }
+                    wait_for_network();
                 }
                 Err(RecvError::Finished) => return Ok((0, None)),
                 Err(err) => {
@@ -258,8 +252,10 @@ impl SocketTrait for TcpStream {
         kdebug!("sending {:x?}", buf);
 
         let res = socket.send_slice(buf).map_err(|_| Errno::ECONNREFUSED)?;
+        drop(socket_set);
+        // Transmitted by the bottom half.
         NETWORK_DRIVERS.read().iter().for_each(|driver| {
-            driver.poll();
+            driver.schedule_poll();
         });
         Ok(res)
     }
@@ -313,8 +309,12 @@ impl SocketTrait for TcpStream {
     }
 
     fn connect(&mut self, addr: SocketAddr) -> KResult<()> {
-        let lock = NETWORK_DRIVERS.read();
-        let driver = lock.first().ok_or(Errno::ENODEV)?;
+        // Connecting waits for the network, so the driver list must not stay locked.
+        let driver = NETWORK_DRIVERS
+            .read()
+            .first()
+            .cloned()
+            .ok_or(Errno::ENODEV)?;
 
         driver.connect(addr, self.socket.as_ref().unwrap().0)?;
         self.state = TcpState::Alive;
@@ -16,8 +16,8 @@ use crate::{
 };
 
 use super::{
-    convert_addr, Shutdown, Socket as SocketTrait, SocketType, SocketWrapper, RECVBUF_LEN,
-    SENDBUF_LEN, SOCKET_SET,
+    convert_addr, socket_buffer, Shutdown, Socket as SocketTrait, SocketType, SocketWrapper,
+    RECVBUF_LEN, SENDBUF_LEN, SOCKET_SET,
 };
 
 pub const UDP_META_LEN: usize = 1024;
@@ -34,11 +34,11 @@ impl UdpStream {
         let udp_socket_inner = Socket::new(
             PacketBuffer::new(
                 vec![PacketMetadata::EMPTY; UDP_META_LEN],
-                vec![0u8; RECVBUF_LEN],
+                socket_buffer(RECVBUF_LEN),
             ),
             PacketBuffer::new(
                 vec![PacketMetadata::EMPTY; UDP_META_LEN],
-                vec![0u8; SENDBUF_LEN],
+                socket_buffer(SENDBUF_LEN),
             ),
         );
 
@@ -0,0 +1,140 @@
+//! Kernel threads. A kernel thread is a task that lives entirely in the kernel address space: it has no user context,
+//! no page table of its own and never returns to ring 3. It is nothing more than a named future that is bound to a
+//! CPU, so it can be scheduled the same way as user threads while being visible to the user via `/proc/kthreads`.
+
+use core::{
+    fmt::Write,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+};
+use lazy_static::lazy_static;
+use spin::RwLock;
+
+use crate::arch::cpu::cpu_id;
+
+use super::scheduler::FIFO_SCHEDULER;
+
+/// Kernel threads occupy a separate id space from user threads.
+static KTHREAD_ID: AtomicU64 = AtomicU64::new(0x1);
+
+lazy_static! {
+    /// All the kernel threads that are alive.
+    pub static ref KTHREAD_TABLE: RwLock<BTreeMap<u64, Arc<KernelThread>>> = RwLock::new(BTreeMap::new());
+}
+
+/// The control block of a kernel thread.
+#[derive(Debug)]
+pub struct KernelThread {
+    /// The kernel thread id.
+    pub id: u64,
+    /// A human readable name like `kworker/0`.
+    pub name: String,
+    /// The CPU this thread is bound to.
+    pub cpu: usize,
+    /// Whether the thread is currently being polled.
+    running: AtomicBool,
+    /// How many times the thread has been polled.
+    polled: AtomicU64,
+}
+
+impl KernelThread {
+    pub fn running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    pub fn polled(&self) -> u64 {
+        self.polled.load(Ordering::Relaxed)
+    }
+}
+
+/// Wraps the body of the kernel thread so that we can do some bookkeeping on each poll and remove the thread from the
+/// table once it finishes.
+struct KernelThreadFuture {
+    thread: Arc<KernelThread>,
+    inner: Pin<Box<dyn Future<Output = ()> + Send + 'static>>,
+}
+
+impl Future for KernelThreadFuture {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.thread.running.store(true, Ordering::Relaxed);
+        self.thread.polled.fetch_add(0x1, Ordering::Relaxed);
+        let res = self.inner.as_mut().poll(cx);
+        self.thread.running.store(false, Ordering::Relaxed);
+
+        if res.is_ready() {
+            ktrace!(
+                "kthread {} ({:#x}) exited.",
+                self.thread.name,
+                self.thread.id
+            );
+            KTHREAD_TABLE.write().remove(&self.thread.id);
+        }
+
+        res
+    }
+}
+
+/// Creates a kernel thread named `name` that runs `future` on the given `cpu` (or the current one if [`None`]).
+pub fn kthread_run<F>(name: &str, cpu: Option<usize>, future: F) -> Arc<KernelThread>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let cpu = cpu.unwrap_or_else(cpu_id);
+    let thread = Arc::new(KernelThread {
+        id: KTHREAD_ID.fetch_add(0x1, Ordering::SeqCst),
+        name: name.to_string(),
+        cpu,
+        running: AtomicBool::new(false),
+        polled: AtomicU64::new(0),
+    });
+
+    KTHREAD_TABLE.write().insert(thread.id, thread.clone());
+    kinfo!(
+        "kthread_run(): created kernel thread {} ({:#x}) on CPU #{:#x}",
+        name,
+        thread.id,
+        cpu
+    );
+
+    FIFO_SCHEDULER.spawn_on(
+        cpu,
+        KernelThreadFuture {
+            thread: thread.clone(),
+            inner: Box::pin(future),
+        },
+        None,
+    );
+
+    thread
+}
+
+/// Formats the kernel thread table for procfs.
+pub fn kthread_info() -> String {
+    let mut content = String::new();
+    writeln!(content, "{:>6} {:>4} {:>5} {:>10} NAME", "KTID", "CPU", "STATE", "POLLED").unwrap();
+    KTHREAD_TABLE.read().values().for_each(|thread| {
+        writeln!(
+            content,
+            "{:>6} {:>4} {:>5} {:>10} [{}]",
+            thread.id,
+            thread.cpu,
+            if thread.running() { "R" } else { "S" },
+            thread.polled(),
+            thread.name
+        )
+        .unwrap();
+    });
+
+    content
+}
@@ -4,6 +4,8 @@
 
 use alloc::{collections::BTreeMap, string::String, vec::Vec};
 
+use crate::{error::KResult, memory::copy_slice_to_user};
+
 pub const AT_PHDR: u8 = 3;
 pub const AT_PHENT: u8 = 4;
 pub const AT_PHNUM: u8 = 5;
@@ -24,68 +26,66 @@ pub struct InitInfo {
 }
 
 impl StackWriter {
-    pub fn write<T>(&mut self, val: &[T])
+    pub fn write<T>(&mut self, val: &[T]) -> KResult<()>
     where
         T: Copy,
     {
         self.stack -= (val.len() * core::mem::size_of::<T>()) as u64;
         self.stack -= self.stack % core::mem::align_of::<T>() as u64;
 
-        unsafe {
-            core::ptr::copy(val.as_ptr(), self.stack as *mut T, val.len());
-        }
+        unsafe { copy_slice_to_user(self.stack as *mut T, val) }
     }
 
-    pub fn write_str(&mut self, s: &str) {
+    pub fn write_str(&mut self, s: &str) -> KResult<()> {
         // Must be zero-terminated or the kernel does not know the length.
-        self.write(b"\0");
-        self.write(s.as_bytes());
+        self.write(b"\0")?;
+        self.write(s.as_bytes())
     }
 }
 
 impl InitInfo {
     /// Pushes itself onto the given stack address.
     /// Returns the stack top.
-    pub unsafe fn push_at(&self, stack: u64) -> u64 {
+    pub unsafe fn push_at(&self, stack: u64) -> KResult<u64> {
         let mut writer = StackWriter { stack };
         // Push the program name.
-        writer.write_str(&self.args[0]);
+        writer.write_str(&self.args[0])?;
 
         // Push environment strings and get their addresses.
         let auxv = self
             .envs
             .iter()
             .map(|env| {
-                writer.write_str(env);
-                writer.stack
+                writer.write_str(env)?;
+                Ok(writer.stack)
             })
-            .collect::<Vec<_>>();
+            .collect::<KResult<Vec<_>>>()?;
         // Push arguments.
         let argv = self
             .args
             .iter()
             .map(|arg| {
-                writer.write_str(arg);
-                writer.stack
+                writer.write_str(arg)?;
+                Ok(writer.stack)
             })
-            .collect::<Vec<_>>();
+            .collect::<KResult<Vec<_>>>()?;
 
         let auxv_terminator = [core::ptr::null::<u8>(), core::ptr::null::<u8>()];
         let str_terminator = [core::ptr::null::<u8>()];
 
         // Auxiliary vectors in ELF loader.
-        writer.write(&auxv_terminator);
-        self.auxv.iter().for_each(|(key, value)| {
-            writer.write(&[*key as usize, *value]);
-        });
+        writer.write(&auxv_terminator)?;
+        for (key, value) in self.auxv.iter() {
+            writer.write(&[*key as usize, *value])?;
+        }
 
         // Other pointers.
-        writer.write(&str_terminator);
-        writer.write(auxv.as_slice());
-        writer.write(&str_terminator);
-        writer.write(argv.as_slice());
-        writer.write(&[argv.len()]);
+        writer.write(&str_terminator)?;
+        writer.write(auxv.as_slice())?;
+        writer.write(&str_terminator)?;
+        writer.write(argv.as_slice())?;
+        writer.write(&[argv.len()])?;
 
-        writer.stack
+        Ok(writer.stack)
     }
 }
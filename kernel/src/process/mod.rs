@@ -8,10 +8,12 @@ use crate::{
     net::Shutdown,
     process::event::Event,
     signal::{SigAction, SigInfo, SigSet},
+    slab::{typed_box, FILE_OBJECT_CACHE},
     sync::{futex::SimpleFutex, mutex::SpinLockNoInterrupt as Mutex},
     utils::split_path,
 };
 use alloc::{
+    boxed::Box,
     collections::{BTreeMap, VecDeque},
     string::String,
     sync::{Arc, Weak},
@@ -26,9 +28,11 @@ use event::EventBus;
 use self::thread::THREAD_TABLE;
 
 pub mod event;
+pub mod kthread;
 pub mod ld;
 pub mod scheduler;
 pub mod thread;
+pub mod workqueue;
 
 lazy_static! {
     pub static ref KERNEL_PROCESS_LIST: RwLock<BTreeMap<u64, Arc<Mutex<Process>>>> =
@@ -82,7 +86,7 @@ pub struct Process {
     /// The name.
     pub name: String,
     /// Opened files.
-    pub opened_files: BTreeMap<u64, FileObject>,
+    pub opened_files: BTreeMap<u64, Box<FileObject>>,
     /// Exit code.
     pub exit_code: u8,
     /// Events like exiting
@@ -113,12 +117,18 @@ impl Process {
     }
 
     pub fn get_fd(&mut self, fd: u64) -> KResult<&mut FileObject> {
-        self.opened_files.get_mut(&fd).ok_or(Errno::EBADF)
+        self.opened_files
+            .get_mut(&fd)
+            .map(|file| file.as_mut())
+            .ok_or(Errno::EBADF)
     }
 
     pub fn get_fd_ref(&self, fd: u64) -> KResult<&FileObject> {
         // Prevent multiple mutable borrows.
-        self.opened_files.get(&fd).ok_or(Errno::EBADF)
+        self.opened_files
+            .get(&fd)
+            .map(|file| file.as_ref())
+            .ok_or(Errno::EBADF)
     }
 
     pub fn fd_exists(&self, fd: u64) -> bool {
@@ -127,10 +137,16 @@ impl Process {
 
     pub fn add_file(&mut self, file: FileObject) -> KResult<u64> {
         let fd = self.get_free_fd()?;
-        self.opened_files.insert(fd, file);
+        self.set_file(fd, file);
         Ok(fd)
     }
 
+    /// Installs `file` at `fd`, replacing the file that was there.
+    pub fn set_file(&mut self, fd: u64, file: FileObject) {
+        self.opened_files
+            .insert(fd, typed_box(&FILE_OBJECT_CACHE, file));
+    }
+
     pub fn exit(&mut self, exit_code: u8) {
         let all_fd = self.opened_files.keys().copied().collect::<Vec<u64>>();
         for fd in all_fd.iter() {
@@ -190,7 +206,7 @@ impl Process {
     #[inline]
     pub fn remove_file(&mut self, fd: u64) -> KResult<()> {
         let file = self.opened_files.remove(&fd).ok_or(Errno::EBADF)?;
-        if let FileObject::Socket(mut socket) = file {
+        if let FileObject::Socket(mut socket) = *file {
             socket.shutdown(Shutdown::Both)?;
         }
 
@@ -4,7 +4,12 @@
 //!
 //! For our simple kernel, we choose to design the most popular scheduling algorithm (Round-Robin) and Priority-based one.
 
-use core::{future::Future, pin::Pin, task::Context};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::Ordering,
+    task::{Context, Poll},
+};
 
 use alloc::{
     boxed::Box,
@@ -20,11 +25,15 @@ use crate::{
     arch::{
         cpu::{cpu_id, CPU_NUM},
         interrupt::ipi::{send_ipi, IpiType},
+        mm::paging::{get_page_table_addr, set_page_table},
+    },
+    sync::{
+        mutex::SpinLock as Mutex,
+        preempt::{preempt_disable, preempt_enable, preemptible},
     },
-    sync::mutex::SpinLock as Mutex,
 };
 
-use super::thread::ThreadState;
+use super::thread::{current, ThreadState, CURRENT_THREAD_PER_CPU};
 
 type RRTask = (Task, TaskInfo);
 
@@ -57,8 +66,10 @@ pub enum ScheduleType {
 /// An abstract task type; must be asynchronous. A task is simply a top most level future. Executors will poll on
 /// a list of task futures that will poll their child executors.
 pub struct Task {
-    /// A task can be executed by different threads, so we need to protect the future by a mutual exclusive lock.
-    future: Mutex<Pin<Box<dyn Future<Output = ()> + 'static + Send>>>,
+    /// A task can be executed by different threads, so we need to protect the future by a mutual exclusive lock. The
+    /// lock only keeps two CPUs from polling the same task and is not a critical section, so it comes from the `spin`
+    /// crate and does not count towards the preempt count; otherwise the task could never be preempted.
+    future: spin::Mutex<Pin<Box<dyn Future<Output = ()> + 'static + Send>>>,
     /// The state of the current task.
     state: Mutex<ThreadState>,
     /// The priority of this task.
@@ -92,6 +103,10 @@ impl Task {
     pub fn set_waiting(&self) {
         *self.state.lock() = ThreadState::WAITING;
     }
+
+    fn poll(&self, cx: &mut Context<'_>) -> Poll<()> {
+        self.future.lock().as_mut().poll(cx)
+    }
 }
 
 impl Woke for Task {
@@ -118,6 +133,11 @@ pub trait SchedAlgorithm: Send + Sync {
     fn first_ready(&self) -> Option<(Arc<Task>, Option<TaskInfo>)>;
     /// Push a task into the algorithm.
     fn add_task(&self, task: Arc<Task>, task_info: Option<TaskInfo>);
+    /// Push a task into the run queue of a given CPU. Algorithms with a single run queue shared by all CPUs need not
+    /// override this.
+    fn add_task_to(&self, cpu: u64, task: Arc<Task>, task_info: Option<TaskInfo>) {
+        self.add_task(task, task_info)
+    }
     /// Get the type.
     fn ty(&self) -> ScheduleType;
     /// Load balance function. This function is invoked when a CPU finishes all its jobs.
@@ -156,11 +176,14 @@ impl Fifo {
 
 impl SchedAlgorithm for Fifo {
     fn add_task(&self, task: Arc<Task>, task_info: Option<TaskInfo>) {
+        self.add_task_to(cpu_id() as u64, task, task_info);
+    }
+
+    fn add_task_to(&self, cpu: u64, task: Arc<Task>, task_info: Option<TaskInfo>) {
         if task_info.is_some() {
             kwarn!("add_task(): FIFO ignores the `task_info` struct. You are feeding the algorithm the wrong input.");
         }
 
-        let cpu = cpu_id() as u64;
         // Need to check whether this task has been already put into the queue.
         let task_list = self.task_list.read();
         let mut task_list = task_list.get(&cpu).unwrap().lock();
@@ -314,7 +337,7 @@ impl Scheduler {
 
         self.add_task(
             Arc::new(Task {
-                future: Mutex::new(Box::pin(future)),
+                future: spin::Mutex::new(Box::pin(future)),
                 state: Mutex::new(ThreadState::WAITING),
                 priority: 1, // todo.
             }),
@@ -322,6 +345,33 @@ impl Scheduler {
         );
     }
 
+    /// Spawns a task on the run queue of `cpu` rather than the current one and kicks that CPU so that an idle core
+    /// notices the new task.
+    pub fn spawn_on<F>(&self, cpu: usize, future: F, task_info: Option<TaskInfo>)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        kdebug!(
+            "spawn_on(): the scheduler {:?} is spawning new thread on CPU #{:#x}!",
+            self.algorithm.ty(),
+            cpu
+        );
+
+        self.algorithm.add_task_to(
+            cpu as u64,
+            Arc::new(Task {
+                future: spin::Mutex::new(Box::pin(future)),
+                state: Mutex::new(ThreadState::WAITING),
+                priority: 1,
+            }),
+            task_info,
+        );
+
+        if cpu != cpu_id() {
+            send_ipi(|| {}, Some(cpu as _), false, IpiType::WakeUp);
+        }
+    }
+
     fn add_task(&self, task: Arc<Task>, task_info: Option<TaskInfo>) {
         self.algorithm.add_task(task, task_info);
     }
@@ -337,6 +387,11 @@ impl Scheduler {
         }
     }
 
+    /// Returns true if the current CPU has no runnable task.
+    pub fn is_idle(&self) -> bool {
+        self.algorithm.is_empty()
+    }
+
     pub fn migrate_task(&self) {
         let task = TASK_MIGRATION.read();
         let (src, dst) = task.unwrap();
@@ -354,7 +409,7 @@ impl Scheduler {
             let mut ctx = Context::from_waker(&waker);
 
             // Still not ok. Add to the task list again.
-            if task.future.lock().as_mut().poll(&mut ctx).is_pending() {
+            if task.poll(&mut ctx).is_pending() {
                 self.add_task(task.clone(), task_info);
             }
         }
@@ -369,3 +424,66 @@ impl Scheduler {
         // TODO.
     }
 }
+
+/// Marks the thread running on this CPU as having used up its time slice. Called from the timer interrupt.
+pub fn set_need_resched() {
+    if let Ok(thread) = current() {
+        thread.need_schedule.store(true, Ordering::Release);
+    }
+}
+
+/// A preemption point for long-running synchronous kernel paths (e.g., large block I/O).
+///
+/// Since kernel tasks are stackless futures, we cannot switch away in the middle of a synchronous call. Instead, if
+/// the timer has asked the current thread to reschedule and the CPU is preemptible (no spin lock held and not in the
+/// interrupt context), we run one round of the scheduler right here and then resume. Nested rounds are not allowed,
+/// which bounds the extra stack usage to a single task.
+///
+/// # Note
+///
+/// Locks from the `spin` crate (e.g., the `RwLock`s in fs and mm) are not tracked by the preempt count, and the task we
+/// switch to may spin on them forever. This must therefore only be called where no such lock is held, e.g., between two
+/// calls into the VFS, and never below the VFS such as in the block drivers, which are entered with the filesystem
+/// locks held.
+pub fn cond_resched() {
+    if !preemptible() {
+        return;
+    }
+
+    if let Ok(thread) = current() {
+        if thread.need_schedule.swap(false, Ordering::AcqRel) {
+            yield_now();
+        }
+    }
+}
+
+/// Gives the CPU to another task for one round of the scheduler at a safe point, no matter whether the time slice of
+/// the current thread is used up. Used by synchronous paths that wait for deferred work, which may need this CPU to
+/// make progress. Returns false if the CPU is not preemptible here.
+///
+/// The same rules as for [`cond_resched`] apply.
+pub fn yield_now() -> bool {
+    if !preemptible() {
+        return false;
+    }
+
+    let thread = match current() {
+        Ok(thread) => thread,
+        Err(_) => return false,
+    };
+
+    let cr3 = get_page_table_addr();
+    preempt_disable();
+    FIFO_SCHEDULER.start_schedule();
+    preempt_enable();
+
+    // The other task has switched the page table and the current thread; switch them back.
+    if get_page_table_addr() != cr3 {
+        set_page_table(cr3);
+    }
+    unsafe {
+        CURRENT_THREAD_PER_CPU[cpu_id()].replace(thread);
+    }
+
+    true
+}
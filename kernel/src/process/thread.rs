@@ -7,7 +7,11 @@
 //! types that are guaranteed to be threadsafe are easily shared between threads using the
 //! atomically-reference-counted container, Arc.
 
-use core::{fmt::Debug, time::Duration};
+use core::{
+    fmt::Debug,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
 use alloc::{
     boxed::Box,
@@ -45,6 +49,7 @@ use crate::{
     },
     process::ld::{AT_BASE, AT_ENTRY},
     signal::{handle_signal, SigAction, SigSet, SigStack},
+    slab::{typed_arc, typed_box, FILE_OBJECT_CACHE, THREAD_CACHE},
     sync::mutex::SpinLockNoInterrupt as Mutex,
 };
 
@@ -106,8 +111,8 @@ pub struct Thread {
     pub inner: Arc<Mutex<ThreadInner>>,
     /// Proc.vm
     pub vm: Arc<Mutex<MemoryManager<KernelPageTable>>>,
-    /// Need schedule?
-    pub need_schedule: bool,
+    /// Set by the timer when the time slice is used up; the thread gives up the CPU at its next safe point.
+    pub need_schedule: AtomicBool,
 }
 
 /// Finds a free tid and assigns it to the current thread by `register`.
@@ -154,7 +159,7 @@ impl Thread {
         auxv: BTreeMap<u8, usize>,
     ) -> KResult<usize> {
         let user_stack_bottom = USER_STACK_START;
-        let mut user_stack_top = USER_STACK_START + USER_STACK_SIZE;
+        let user_stack_top = USER_STACK_START + USER_STACK_SIZE;
 
         // Reserve 4 pages for init info.
         // This is because the execution of the ELF file must requrie argc, argc, envp things.
@@ -182,12 +187,13 @@ impl Thread {
             name: "[stack]".into(),
         });
 
+        let mut stack_top = Err(Errno::EFAULT);
         unsafe {
             vm.with(|| {
-                user_stack_top = InitInfo { args, envs, auxv }.push_at(user_stack_top as _) as _;
+                stack_top = InitInfo { args, envs, auxv }.push_at(user_stack_top as _);
             });
         }
-        Ok(user_stack_top)
+        stack_top.map(|stack_top| stack_top as _)
     }
 
     /// Activates this thread and registers it to the global thread table `THREAD_TABLE`.
@@ -197,7 +203,7 @@ impl Thread {
             id => id,
         };
         self.id = id;
-        let arced_self = Arc::new(self);
+        let arced_self = typed_arc(&THREAD_CACHE, self);
         THREAD_TABLE.write().insert(id, arced_self.clone());
 
         Ok(arced_self)
@@ -220,7 +226,11 @@ impl Thread {
             vm: vm.clone(),
             exec_path: lock.exec_path.clone(),
             cwd: lock.cwd.clone(),
-            opened_files: lock.opened_files.clone(),
+            opened_files: lock
+                .opened_files
+                .iter()
+                .map(|(&fd, file)| (fd, typed_box(&FILE_OBJECT_CACHE, (**file).clone())))
+                .collect(),
             exit_code: 0,
             name: lock.name.clone(),
             event_bus: EventBus::new(),
@@ -241,13 +251,13 @@ impl Thread {
                 sigmask: self.inner.lock().sigmask,
                 thread_context: Some(ThreadContext {
                     user_context: Box::new(ctx),
-                    fp_state: Box::new(FpState::new()),
+                    fp_state: FpState::new(),
                 }),
                 sigaltstack: self.inner.lock().sigaltstack.clone(),
                 clear_child_tid: self.inner.lock().clear_child_tid,
             })),
             vm,
-            need_schedule: false,
+            need_schedule: AtomicBool::new(false),
         }
         .register()
         .unwrap();
@@ -259,6 +269,34 @@ impl Thread {
         thread
     }
 
+    /// Creates a thread that shares the process and the address space with this thread. The new thread starts with
+    /// `context` and returns 0 from the system call.
+    pub fn new_thread(&self, context: &Context, clear_child_tid: u64) -> KResult<Arc<Self>> {
+        let mut ctx = context.clone();
+        ctx.regs.rax = 0;
+
+        let thread = Thread {
+            id: 0,
+            parent: self.parent.clone(),
+            inner: Arc::new(Mutex::new(ThreadInner {
+                sigmask: self.inner.lock().sigmask,
+                thread_context: Some(ThreadContext {
+                    user_context: Box::new(ctx),
+                    fp_state: FpState::new(),
+                }),
+                sigaltstack: SigStack::default(),
+                clear_child_tid,
+            })),
+            vm: self.vm.clone(),
+            need_schedule: AtomicBool::new(false),
+        }
+        .register()?;
+
+        self.parent.lock().threads.push(thread.id);
+
+        Ok(thread)
+    }
+
     pub fn take(&self) -> ThreadContext {
         self.inner.lock().thread_context.take().unwrap()
     }
@@ -418,13 +456,13 @@ impl Thread {
                 sigmask: SigSet::new(),
                 thread_context: Some(ThreadContext {
                     user_context: Box::new(context),
-                    fp_state: Box::new(FpState::new()),
+                    fp_state: FpState::new(),
                 }),
                 sigaltstack: SigStack::default(),
                 clear_child_tid: 0, // NULL by default.
             })),
             vm,
-            need_schedule: false,
+            need_schedule: AtomicBool::new(false),
         };
 
         // Add itself into the global thread table.
@@ -451,8 +489,8 @@ pub struct ThreadInner {
 ///
 /// This struct contains two fields: `user_context` and `fp_state`.
 /// `user_context` is a boxed [`Context`] struct, which represents the user-mode
-/// state of the thread. `fp_state` is a boxed [`FpState`] struct, which represents
-/// the floating-point state of the thread.
+/// state of the thread. `fp_state` is a [`FpState`] struct, which represents
+/// the floating-point and vector register state of the thread.
 ///
 /// # Examples
 ///
@@ -462,14 +500,14 @@ pub struct ThreadInner {
 /// ```
 pub struct ThreadContext {
     user_context: Box<Context>,
-    fp_state: Box<FpState>,
+    fp_state: FpState,
 }
 
 impl ThreadContext {
     pub fn switch(&mut self) {
-        self.fp_state.fxrstor();
+        self.fp_state.restore();
         self.user_context.start();
-        self.fp_state.fxsave();
+        self.fp_state.save();
     }
 
     pub fn get_trapno(&self) -> usize {
@@ -479,13 +517,17 @@ impl ThreadContext {
     pub fn get_user_context(&mut self) -> &mut Box<Context> {
         &mut self.user_context
     }
+
+    pub fn get_fp_state(&mut self) -> &mut FpState {
+        &mut self.fp_state
+    }
 }
 
 impl Debug for ThreadContext {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("ThreadContext")
             .field("User Context", self.user_context.as_ref())
-            .field("Float Point State", self.fp_state.as_ref())
+            .field("Float Point State", &self.fp_state)
             .finish()
     }
 }
@@ -510,41 +552,50 @@ pub fn current() -> KResult<Arc<Thread>> {
 }
 
 /// A helper function that initializes the default stdio for the thread.
-fn init_stdio() -> BTreeMap<u64, FileObject> {
+fn init_stdio() -> BTreeMap<u64, Box<FileObject>> {
     let mut files = BTreeMap::new();
 
     // IO. stdin, stdout, stderr.
     files.insert(
         0,
-        FileObject::File(File::new(
-            TTY.clone(),
-            "/dev/tty",
-            false,
-            FileOpenOption::READ,
-            FileType::CONVENTIONAL,
-        )),
+        typed_box(
+            &FILE_OBJECT_CACHE,
+            FileObject::File(File::new(
+                TTY.clone(),
+                "/dev/tty",
+                false,
+                FileOpenOption::READ,
+                FileType::CONVENTIONAL,
+            )),
+        ),
     );
 
     files.insert(
         1,
-        FileObject::File(File::new(
-            TTY.clone(),
-            "/dev/tty",
-            false,
-            FileOpenOption::WRITE,
-            FileType::CONVENTIONAL,
-        )),
+        typed_box(
+            &FILE_OBJECT_CACHE,
+            FileObject::File(File::new(
+                TTY.clone(),
+                "/dev/tty",
+                false,
+                FileOpenOption::WRITE,
+                FileType::CONVENTIONAL,
+            )),
+        ),
     );
 
     files.insert(
         2,
-        FileObject::File(File::new(
-            TTY.clone(),
-            "/dev/tty",
-            false,
-            FileOpenOption::WRITE,
-            FileType::CONVENTIONAL,
-        )),
+        typed_box(
+            &FILE_OBJECT_CACHE,
+            FileObject::File(File::new(
+                TTY.clone(),
+                "/dev/tty",
+                false,
+                FileOpenOption::WRITE,
+                FileType::CONVENTIONAL,
+            )),
+        ),
     );
 
     files
@@ -578,14 +629,15 @@ pub fn spawn(thread: Arc<Thread>) -> KResult<()> {
 
             // Handle signal or other errors.
             if !exited {
-                exited = handle_signal(&thread, &mut ctx.user_context);
+                exited = handle_signal(&thread, &mut ctx.user_context, &ctx.fp_state);
             }
 
             thread.restore(ctx);
             if exited {
                 break;
             }
-            if should_yield {
+            if should_yield || thread.need_schedule.swap(false, Ordering::AcqRel) {
+                should_yield = false;
                 // Suspend execution until is ready.
                 ktrace!("spawn(): thread {:#x} yields the CPU.", thread.id);
                 Yield::default().await
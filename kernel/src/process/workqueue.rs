@@ -0,0 +1,219 @@
+//! Deferred work. Interrupt handlers should be as short as possible, so anything that may take long (e.g., feeding
+//! the network stack) is split into a "bottom half" that is queued here and later executed by a per-CPU kernel thread
+//! (`kworker/<cpu>`) outside of interrupt context.
+//!
+//! Two flavors are provided:
+//! * [`queue_work`]: runs a one-shot closure on the given CPU.
+//! * [`Tasklet`]: a reusable bottom half that is queued at most once no matter how many times it is scheduled before it
+//!   runs. This is what drivers usually want in [`crate::drivers::Driver::dispatch`].
+
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    format,
+    string::{String, ToString},
+    sync::Arc,
+};
+use lazy_static::lazy_static;
+use spin::RwLock;
+
+use crate::{
+    arch::{
+        cpu::{cpu_id, CPU_NUM},
+        interrupt::ipi::{send_ipi, IpiType},
+    },
+    sync::mutex::SpinLockNoInterrupt as Mutex,
+};
+
+use super::kthread::kthread_run;
+
+/// A worker runs at most this many items before yielding the CPU to other tasks.
+const WORKER_BUDGET: usize = 0x10;
+
+pub type Work = Box<dyn FnOnce() + Send + 'static>;
+
+lazy_static! {
+    /// The system workqueues indexed by CPU id.
+    pub static ref WORKQUEUES: RwLock<BTreeMap<usize, Arc<WorkQueue>>> = RwLock::new(BTreeMap::new());
+}
+
+/// A queue of pending work items that is drained by a dedicated kernel thread bound to `cpu`.
+pub struct WorkQueue {
+    /// The name of the worker thread.
+    name: String,
+    /// The CPU on which the work is executed.
+    cpu: usize,
+    /// Pending work items. Can be touched in the interrupt context, so interrupts must be disabled.
+    works: Mutex<VecDeque<Work>>,
+    /// The waker of the worker thread if it is sleeping.
+    waker: Mutex<Option<Waker>>,
+}
+
+impl WorkQueue {
+    /// Creates a new workqueue and its worker thread on `cpu`.
+    pub fn create(name: &str, cpu: usize) -> Arc<Self> {
+        let wq = Arc::new(Self {
+            name: name.to_string(),
+            cpu,
+            works: Mutex::new(VecDeque::new()),
+            waker: Mutex::new(None),
+        });
+
+        kthread_run(name, Some(cpu), Worker { wq: wq.clone() });
+        wq
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+
+    /// Pushes `work` into the queue and wakes up the worker. Safe to be called in the interrupt context.
+    pub fn queue(&self, work: Work) {
+        self.works.lock().push_back(work);
+
+        if let Some(waker) = self.waker.lock().take() {
+            waker.wake();
+        }
+
+        // The target CPU may be halted; kick it so that it notices the worker.
+        if self.cpu != cpu_id() {
+            send_ipi(|| {}, Some(self.cpu as _), false, IpiType::WakeUp);
+        }
+    }
+
+    fn pop(&self) -> Option<Work> {
+        self.works.lock().pop_front()
+    }
+}
+
+/// The body of a `kworker` thread.
+struct Worker {
+    wq: Arc<WorkQueue>,
+}
+
+impl Future for Worker {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        for _ in 0..WORKER_BUDGET {
+            match self.wq.pop() {
+                Some(work) => work(),
+                None => {
+                    // Register the waker before checking again so that we never miss a wakeup.
+                    self.wq.waker.lock().replace(cx.waker().clone());
+                    if self.wq.works.lock().is_empty() {
+                        return Poll::Pending;
+                    }
+                }
+            }
+        }
+
+        // Budget exhausted: give others a chance and come back later.
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Creates the system workqueues, one for each CPU. Must be called after the scheduler is initialized.
+pub fn init_workqueues() {
+    let cpu_num = *CPU_NUM.get().unwrap();
+    let mut workqueues = WORKQUEUES.write();
+
+    (0..cpu_num).for_each(|cpu| {
+        workqueues.insert(cpu, WorkQueue::create(&format!("kworker/{cpu}"), cpu));
+    });
+
+    kinfo!("init_workqueues(): created {cpu_num} workqueues");
+}
+
+/// Queues `work` on the system workqueue of `cpu` (or the current CPU if [`None`]).
+///
+/// If the workqueues are not yet available (i.e., during early boot), the work is executed immediately.
+pub fn queue_work<F>(cpu: Option<usize>, work: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    let cpu = cpu.unwrap_or_else(cpu_id);
+    let wq = WORKQUEUES.read().get(&cpu).cloned();
+
+    match wq {
+        Some(wq) => wq.queue(Box::new(work)),
+        None => {
+            ktrace!("queue_work(): no workqueue on CPU #{:#x}; running inline.", cpu);
+            work()
+        }
+    }
+}
+
+/// Queues `work` on the workqueue of the current CPU.
+pub fn schedule_work<F>(work: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    queue_work(None, work)
+}
+
+/// Runs the work pending on the workqueue of the current CPU right here rather than in its worker. For callers that
+/// wait for deferred work but cannot give the CPU to the worker.
+pub fn run_pending_work() {
+    let wq = WORKQUEUES.read().get(&cpu_id()).cloned();
+    if let Some(wq) = wq {
+        while let Some(work) = wq.pop() {
+            work();
+        }
+    }
+}
+
+/// A bottom half that is scheduled from an interrupt handler. Scheduling an already pending tasklet is a no-op, so a
+/// burst of interrupts only results in a single run.
+pub struct Tasklet {
+    /// The handler.
+    func: Arc<dyn Fn() + Send + Sync + 'static>,
+    /// The CPU on which the tasklet should run; [`None`] means the CPU that schedules it.
+    cpu: Option<usize>,
+    /// Whether the tasklet is queued but not yet executed.
+    pending: Arc<AtomicBool>,
+}
+
+impl Tasklet {
+    pub fn new<F>(cpu: Option<usize>, func: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        Self {
+            func: Arc::new(func),
+            cpu,
+            pending: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Schedules the tasklet if it is not pending.
+    pub fn schedule(&self) {
+        if self.pending.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let func = self.func.clone();
+        let pending = self.pending.clone();
+        queue_work(self.cpu, move || {
+            // Clear the flag first so that interrupts arriving while we run will schedule us again.
+            pending.store(false, Ordering::Release);
+            func();
+        });
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::Acquire)
+    }
+}
@@ -14,7 +14,8 @@ use bitflags::bitflags;
 use num_enum::FromPrimitive;
 
 use crate::{
-    arch::{interrupt::Context, signal::SigContext},
+    arch::{cpu::FpState, interrupt::Context, signal::SigContext},
+    memory::{copy_slice_to_user, copy_to_user},
     process::{event::Event, thread::Thread, Process},
     sync::mutex::SpinLockNoInterrupt as Mutex,
 };
@@ -38,6 +39,10 @@ pub const SIG_ERR: isize = -1;
 /// A constant for the prevention of breaking the alignment of `SiFields`.
 const X64_PAD: usize = 0x100 - 2 * core::mem::size_of::<i32>() - core::mem::size_of::<usize>();
 const SIGFRAME_SIZE: usize = core::mem::size_of::<SigFrame>();
+/// The area below the stack pointer that leaf functions may use without moving it.
+const REDZONE_SIZE: u64 = 0x80;
+/// `xrstor` needs the saved floating-point state to be aligned on a 64-byte boundary.
+const FPSTATE_ALIGN: u64 = 0x40;
 /// Equivalent to
 /// ```asm
 /// mov eax, 0xf  ; b8 0x 00 00 00
@@ -227,7 +232,8 @@ pub struct SigUcontext {
 ///
 /// ```text
 /// --------------+
-/// FPSTATE       |
+/// FPSTATE       | <- SigContext::fpstate
+/// --------------+
 /// MASK          |
 /// --------------+
 /// SigContext    |
@@ -341,7 +347,7 @@ pub fn send_signal(current_process: Arc<Mutex<Process>>, dest: i64, info: SigInf
 /// A process may also specify that a default action is to be taken by the system when a signal occurs.  A signal may also
 /// be blocked, in which case its delivery is postponed until it is unblocked.  The action to be taken on delivery is
 /// determined at the time of delivery.  Normally, signal handlers execute on the current stack of the process.
-pub fn handle_signal(thread: &Arc<Thread>, ctx: &mut Context) -> bool {
+pub fn handle_signal(thread: &Arc<Thread>, ctx: &mut Context, fp_state: &FpState) -> bool {
     let mut process = thread.parent.lock();
 
     // Iterate over the signal queue. Linux 2.6 do_signal() uses a loop.
@@ -450,26 +456,37 @@ pub fn handle_signal(thread: &Arc<Thread>, ctx: &mut Context) -> bool {
                 drop(inner);
 
                 // Get the signal stack's stack pointer.
-                let sp = match get_sigstack_sp(&sigstack, thread, sa_flags) {
+                let stack_top = match get_sigstack_sp(&sigstack, thread, sa_flags) {
                     Some(sp) => sp,
-                    None => ctx.get_rsp(),
-                } - SIGFRAME_SIZE as u64;
-
-                // Write the sigframe to the memory.
-                let sig_frame = unsafe { &mut *(sp as *mut SigFrame) };
-                sig_frame.info = info.clone();
-                sig_frame.ucontext = SigUcontext {
-                    uc_flags: 0,
-                    uc_link: 0,
-                    uc_stack: sigstack,
-                    uc_context: SigContext::from_uctx(ctx),
-                    uc_mask: sig_mask,
+                    None => ctx.get_rsp() - REDZONE_SIZE,
+                };
+                // The floating-point state goes above the frame.
+                let fpstate = (stack_top - fp_state.size() as u64) & !(FPSTATE_ALIGN - 1);
+                // The handler is entered as if it were called: `rsp + 8` is 16-byte aligned.
+                let sp = ((fpstate - SIGFRAME_SIZE as u64) & !0xf) - 8;
+
+                // Build the sigframe in the kernel and then copy it to the user stack.
+                let mut sig_frame = SigFrame {
+                    pretcode: 0,
+                    info: info.clone(),
+                    ucontext: SigUcontext {
+                        uc_flags: 0,
+                        uc_link: 0,
+                        uc_stack: sigstack,
+                        uc_context: SigContext::from_uctx(ctx),
+                        uc_mask: sig_mask,
+                    },
+                    retcode: [0u8; 7],
                 };
+                sig_frame.ucontext.uc_context.fpstate = fpstate as _;
+                // Translates the address of a field of the kernel copy into that of the user copy.
+                let base = &sig_frame as *const SigFrame as u64;
+                let user_addr = |field: *const u8| sp + (field as u64 - base);
 
                 if sa_flags.contains(SignalActionFlags::RESTORER) {
                     sig_frame.pretcode = sa.sa_restorer as _;
                 } else {
-                    sig_frame.pretcode = sig_frame.retcode.as_ptr() as _;
+                    sig_frame.pretcode = user_addr(sig_frame.retcode.as_ptr());
 
                     if sig_frame.retcode.len() <= SYSRETURN.len() {
                         kwarn!("handle_signal(): the return code length is insufficient. This should be reported.");
@@ -477,11 +494,25 @@ pub fn handle_signal(thread: &Arc<Thread>, ctx: &mut Context) -> bool {
                     sig_frame.retcode.copy_from_slice(SYSRETURN);
                 }
 
+                if let Err(errno) = unsafe {
+                    copy_slice_to_user(fpstate as *mut u8, fp_state.as_bytes())
+                        .and_then(|_| copy_to_user(&sig_frame, sp as *mut SigFrame))
+                } {
+                    // The user stack is unusable; there is no way to run the handler.
+                    kerror!(
+                        "handle_signal(): cannot write the signal frame @ {:#x}. Errno: {:?}",
+                        sp,
+                        errno
+                    );
+                    process.exit(make_unix_error_code!(Signal::SIGSEGV));
+                    return true;
+                }
+
                 ctx.set_rsp(sp);
                 ctx.set_rip(sa.sa_handler as _);
                 ctx.regs.rdi = info.signo as _;
-                ctx.regs.rsi = &sig_frame.info as *const _ as _;
-                ctx.regs.rdx = &sig_frame.ucontext as *const _ as _;
+                ctx.regs.rsi = user_addr(&sig_frame.info as *const _ as _);
+                ctx.regs.rdx = user_addr(&sig_frame.ucontext as *const _ as _);
             }
         }
     }
@@ -0,0 +1,615 @@
+//! The slab allocator for small and hot kernel objects.
+//!
+//! The buddy heap behind [`buddy_system_allocator::LockedHeapWithRescue`] is protected by a single lock, so every
+//! `Box::new` or `Arc::new` on every core contends on it. The slab allocator sits in front of the heap and serves small
+//! objects from caches of equally sized objects that are carved out of physical frames obtained by
+//! [`crate::memory::allocate_frame_contiguous`]. There are two kinds of caches:
+//!
+//! * Per-size caches (`kmalloc-16` ... `kmalloc-2048`) for general small allocations.
+//! * Per-type caches for hot kernel objects (threads, arenas, socket buffers and file objects). Only objects created
+//!   by [`typed_box`], [`typed_arc`] or [`typed_buffer`] go there, so unrelated allocations of the same size are not
+//!   counted as, say, threads.
+//!
+//! Each cache has a per-CPU magazine, a small stack of free objects that can be handed out without touching any lock.
+//! Magazines are refilled from and drained to the slab lists in batches. Statistics are exported via `/proc/slabinfo`.
+//!
+//! Each slab records its cache in its header, so a pointer is freed to the cache it came from whatever its layout.
+//! Allocations that are too large, or that happen before the frame allocator is ready, fall back to the buddy heap.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::UnsafeCell,
+    fmt::Write,
+    mem::{align_of, size_of},
+    ptr::null_mut,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+
+use alloc::{boxed::Box, string::String, sync::Arc, vec, vec::Vec};
+use buddy_system_allocator::LockedHeapWithRescue;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    arch::{
+        cpu::{cpu_id, MAX_CPU_NUM},
+        PAGE_SIZE,
+    },
+    fs::file::FileObject,
+    memory::{allocate_frame_contiguous, deallocate_frame, phys_to_virt, virt_to_phys},
+    mm::Arena,
+    net::RECVBUF_LEN,
+    process::thread::Thread,
+    sync::mutex::SpinLock as Mutex,
+};
+
+/// How many objects a per-CPU magazine can hold.
+const MAGAZINE_SIZE: usize = 0x10;
+/// How many objects are moved between a magazine and the slab lists at once.
+const MAGAZINE_BATCH: usize = MAGAZINE_SIZE / 2;
+/// The largest slab is 2^4 = 16 pages.
+const SLAB_MAX_ORDER: usize = 0x4;
+/// A slab should at least hold this many objects unless it reaches [`SLAB_MAX_ORDER`].
+const SLAB_MIN_OBJECTS: usize = 0x8;
+/// How many completely free slabs a cache keeps before returning them to the frame allocator.
+const SLAB_MAX_FREE: usize = 0x2;
+/// Slabs are only carved from frames below this address so that the ownership bitmap stays small.
+const SLAB_PHYS_LIMIT: u64 = 0x4_0000_0000;
+const SLAB_BITMAP_LEN: usize = (SLAB_PHYS_LIMIT as usize / PAGE_SIZE) / u64::BITS as usize;
+
+/// Whether the frame allocator is ready so that slabs can be created.
+static SLAB_ENABLED: AtomicBool = AtomicBool::new(false);
+/// Records which physical frames belong to slabs. This tells us whether a pointer being freed should go back to a slab
+/// cache or to the buddy heap.
+static SLAB_FRAMES: [AtomicU64; SLAB_BITMAP_LEN] = {
+    const BITS: AtomicU64 = AtomicU64::new(0);
+    [BITS; SLAB_BITMAP_LEN]
+};
+/// Records the first frame of each slab, where its [`SlabHeader`] lives.
+static SLAB_HEADS: [AtomicU64; SLAB_BITMAP_LEN] = {
+    const BITS: AtomicU64 = AtomicU64::new(0);
+    [BITS; SLAB_BITMAP_LEN]
+};
+/// The cache that the next allocation on each CPU should come from; set by [`alloc_from`].
+static REQUESTED_CACHE: [AtomicPtr<SlabCache>; MAX_CPU_NUM] = {
+    const NONE: AtomicPtr<SlabCache> = AtomicPtr::new(null_mut());
+    [NONE; MAX_CPU_NUM]
+};
+
+/// The general purpose caches.
+pub static KMALLOC_CACHES: [SlabCache; 8] = [
+    SlabCache::new("kmalloc-16", 0x10, 0x10),
+    SlabCache::new("kmalloc-32", 0x20, 0x20),
+    SlabCache::new("kmalloc-64", 0x40, 0x40),
+    SlabCache::new("kmalloc-128", 0x80, 0x80),
+    SlabCache::new("kmalloc-256", 0x100, 0x100),
+    SlabCache::new("kmalloc-512", 0x200, 0x200),
+    SlabCache::new("kmalloc-1024", 0x400, 0x400),
+    SlabCache::new("kmalloc-2048", 0x800, 0x800),
+];
+
+/// `Arc<Thread>` created by [`crate::process::thread::Thread::register`].
+pub static THREAD_CACHE: SlabCache = SlabCache::new(
+    "thread",
+    arc_inner_size(size_of::<Thread>(), align_of::<Thread>()),
+    arc_inner_align(align_of::<Thread>()),
+);
+/// `Box<Arena>` held by [`crate::mm::MemoryManager`].
+pub static ARENA_CACHE: SlabCache =
+    SlabCache::new("arena", size_of::<Arena>(), align_of::<Arena>());
+/// The smoltcp socket buffers.
+pub static SOCKET_BUFFER_CACHE: SlabCache =
+    SlabCache::new("socket_buffer", RECVBUF_LEN, align_of::<u8>());
+/// `Box<FileObject>` in the file descriptor table of a process.
+pub static FILE_OBJECT_CACHE: SlabCache = SlabCache::new(
+    "file_object",
+    size_of::<FileObject>(),
+    align_of::<FileObject>(),
+);
+
+/// The caches dedicated to hot kernel objects.
+pub static OBJECT_CACHES: [&SlabCache; 4] = [
+    &THREAD_CACHE,
+    &ARENA_CACHE,
+    &SOCKET_BUFFER_CACHE,
+    &FILE_OBJECT_CACHE,
+];
+
+const fn round_up(num: usize, align: usize) -> usize {
+    (num + align - 1) & !(align - 1)
+}
+
+const fn max(lhs: usize, rhs: usize) -> usize {
+    if lhs > rhs {
+        lhs
+    } else {
+        rhs
+    }
+}
+
+/// `Arc<T>` allocates `ArcInner<T>` which prepends the strong and weak counters to `T`.
+const fn arc_inner_align(align: usize) -> usize {
+    max(align_of::<usize>(), align)
+}
+
+const fn arc_inner_size(size: usize, align: usize) -> usize {
+    let offset = round_up(2 * size_of::<usize>(), align);
+    round_up(offset + size, arc_inner_align(align))
+}
+
+/// A free object is a node in the intrusive free list of a slab.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// The header is placed at the beginning of each slab. A slab is aligned to its own size, so that the header of any
+/// object can be found by masking the object address.
+struct SlabHeader {
+    /// The cache owning this slab.
+    cache: *const SlabCache,
+    /// The free list.
+    free: *mut FreeObject,
+    /// How many objects are not on the free list.
+    inuse: usize,
+    /// Links of the partial list.
+    prev: *mut SlabHeader,
+    next: *mut SlabHeader,
+}
+
+/// The slab lists of a cache. Slabs that are full are not linked anywhere; they are found again by address when one of
+/// their objects is freed.
+struct SlabList {
+    /// Slabs with at least one free object.
+    partial: *mut SlabHeader,
+    /// How many slabs on the partial list are completely free.
+    free_slabs: usize,
+}
+
+unsafe impl Send for SlabList {}
+
+impl SlabList {
+    unsafe fn push(&mut self, slab: *mut SlabHeader) {
+        (*slab).prev = null_mut();
+        (*slab).next = self.partial;
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut SlabHeader) {
+        if (*slab).prev.is_null() {
+            self.partial = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+        (*slab).prev = null_mut();
+        (*slab).next = null_mut();
+    }
+}
+
+/// A per-CPU stack of free objects.
+struct Magazine {
+    objs: [*mut u8; MAGAZINE_SIZE],
+    len: usize,
+}
+
+/// Statistics of a cache.
+struct SlabStats {
+    /// Objects handed out to users.
+    active: AtomicUsize,
+    /// Slabs owned by the cache.
+    slabs: AtomicUsize,
+    /// Total allocations and frees.
+    allocs: AtomicU64,
+    frees: AtomicU64,
+    /// Allocations served by the magazine without taking the lock.
+    hits: AtomicU64,
+}
+
+/// A cache of equally sized objects.
+pub struct SlabCache {
+    /// The name shown in `/proc/slabinfo`.
+    name: &'static str,
+    /// The actual size of each object slot.
+    size: usize,
+    align: usize,
+    /// Each slab is `PAGE_SIZE << order` bytes.
+    order: usize,
+    /// The offset of the first object in a slab.
+    offset: usize,
+    /// Objects per slab.
+    capacity: usize,
+    list: Mutex<SlabList>,
+    magazines: [UnsafeCell<Magazine>; MAX_CPU_NUM],
+    stats: SlabStats,
+}
+
+// Magazines are only touched by their own CPU with interrupts disabled.
+unsafe impl Sync for SlabCache {}
+
+impl SlabCache {
+    pub const fn new(name: &'static str, obj_size: usize, align: usize) -> Self {
+        const MAGAZINE: UnsafeCell<Magazine> = UnsafeCell::new(Magazine {
+            objs: [null_mut(); MAGAZINE_SIZE],
+            len: 0,
+        });
+
+        // A free slot must be able to hold the free list pointer.
+        let align = max(align, align_of::<FreeObject>());
+        let size = round_up(max(obj_size, size_of::<FreeObject>()), align);
+        let offset = round_up(size_of::<SlabHeader>(), align);
+
+        let mut order = 0;
+        while order < SLAB_MAX_ORDER && ((PAGE_SIZE << order) - offset) / size < SLAB_MIN_OBJECTS {
+            order += 1;
+        }
+
+        Self {
+            name,
+            size,
+            align,
+            order,
+            offset,
+            capacity: ((PAGE_SIZE << order) - offset) / size,
+            list: Mutex::new(SlabList {
+                partial: null_mut(),
+                free_slabs: 0,
+            }),
+            magazines: [MAGAZINE; MAX_CPU_NUM],
+            stats: SlabStats {
+                active: AtomicUsize::new(0),
+                slabs: AtomicUsize::new(0),
+                allocs: AtomicU64::new(0),
+                frees: AtomicU64::new(0),
+                hits: AtomicU64::new(0),
+            },
+        }
+    }
+
+    #[inline(always)]
+    fn slab_bytes(&self) -> usize {
+        PAGE_SIZE << self.order
+    }
+
+    #[inline(always)]
+    fn slab_of(&self, ptr: *mut u8) -> *mut SlabHeader {
+        (ptr as usize & !(self.slab_bytes() - 1)) as *mut SlabHeader
+    }
+
+    /// Allocates an object; returns [`None`] if there is no memory.
+    pub fn alloc(&self) -> Option<*mut u8> {
+        loop {
+            let obj = without_interrupts(|| {
+                let magazine = unsafe { &mut *self.magazines[cpu_id()].get() };
+                if magazine.len != 0 {
+                    self.stats.hits.fetch_add(0x1, Ordering::Relaxed);
+                } else {
+                    self.refill(magazine);
+                }
+
+                match magazine.len {
+                    0 => None,
+                    _ => {
+                        magazine.len -= 1;
+                        Some(magazine.objs[magazine.len])
+                    }
+                }
+            });
+
+            if let Some(obj) = obj {
+                self.stats.active.fetch_add(0x1, Ordering::Relaxed);
+                self.stats.allocs.fetch_add(0x1, Ordering::Relaxed);
+                return Some(obj);
+            }
+
+            // No free objects at all. The frame allocator may enable interrupts, so grow the cache outside of the
+            // critical section and retry.
+            self.grow()?;
+        }
+    }
+
+    /// Frees an object that was allocated from this cache.
+    pub fn free(&self, ptr: *mut u8) {
+        let mut released = [null_mut(); MAGAZINE_BATCH];
+        let released_num = without_interrupts(|| {
+            let magazine = unsafe { &mut *self.magazines[cpu_id()].get() };
+            let released_num = match magazine.len {
+                MAGAZINE_SIZE => self.drain(magazine, &mut released),
+                _ => 0,
+            };
+
+            magazine.objs[magazine.len] = ptr;
+            magazine.len += 1;
+            released_num
+        });
+
+        self.stats.active.fetch_sub(0x1, Ordering::Relaxed);
+        self.stats.frees.fetch_add(0x1, Ordering::Relaxed);
+
+        released[..released_num]
+            .iter()
+            .for_each(|&slab| self.release(slab));
+    }
+
+    /// Moves a batch of free objects from the slabs into the magazine. Interrupts must be disabled.
+    fn refill(&self, magazine: &mut Magazine) {
+        let mut list = self.list.lock();
+
+        while magazine.len < MAGAZINE_BATCH && !list.partial.is_null() {
+            let slab = list.partial;
+            unsafe {
+                if (*slab).inuse == 0 {
+                    list.free_slabs -= 1;
+                }
+
+                while magazine.len < MAGAZINE_BATCH && !(*slab).free.is_null() {
+                    let obj = (*slab).free;
+                    (*slab).free = (*obj).next;
+                    (*slab).inuse += 1;
+                    magazine.objs[magazine.len] = obj as *mut u8;
+                    magazine.len += 1;
+                }
+
+                // Full.
+                if (*slab).free.is_null() {
+                    list.remove(slab);
+                }
+            }
+        }
+    }
+
+    /// Returns a batch of objects from the magazine to their slabs. Interrupts must be disabled. Slabs that become
+    /// free and exceed the limit are unlinked and stored in `released`; the caller gives them back to the frame
+    /// allocator after the critical section.
+    fn drain(&self, magazine: &mut Magazine, released: &mut [*mut SlabHeader]) -> usize {
+        let mut list = self.list.lock();
+        let mut released_num = 0;
+
+        for _ in 0..MAGAZINE_BATCH {
+            magazine.len -= 1;
+            let obj = magazine.objs[magazine.len] as *mut FreeObject;
+            let slab = self.slab_of(obj as *mut u8);
+
+            unsafe {
+                // The slab was full and is not on the partial list.
+                if (*slab).free.is_null() {
+                    list.push(slab);
+                }
+
+                (*obj).next = (*slab).free;
+                (*slab).free = obj;
+                (*slab).inuse -= 1;
+
+                if (*slab).inuse == 0 {
+                    if list.free_slabs >= SLAB_MAX_FREE {
+                        list.remove(slab);
+                        released[released_num] = slab;
+                        released_num += 1;
+                    } else {
+                        list.free_slabs += 1;
+                    }
+                }
+            }
+        }
+
+        released_num
+    }
+
+    /// Allocates a new slab from the frame allocator.
+    fn grow(&self) -> Option<()> {
+        let phys = allocate_frame_contiguous(0x1 << self.order, self.order).ok()?;
+        let frames = phys.as_u64() as usize / PAGE_SIZE;
+
+        if phys.as_u64() + self.slab_bytes() as u64 > SLAB_PHYS_LIMIT {
+            (0..0x1 << self.order).for_each(|i| {
+                deallocate_frame(phys.as_u64() + (i * PAGE_SIZE) as u64).unwrap();
+            });
+            return None;
+        }
+
+        let slab = phys_to_virt(phys.as_u64()) as *mut SlabHeader;
+        unsafe {
+            // Thread all objects into the free list.
+            let base = slab as usize + self.offset;
+            let mut free = null_mut::<FreeObject>();
+            (0..self.capacity).rev().for_each(|idx| {
+                let obj = (base + idx * self.size) as *mut FreeObject;
+                (*obj).next = free;
+                free = obj;
+            });
+
+            slab.write(SlabHeader {
+                cache: self,
+                free,
+                inuse: 0,
+                prev: null_mut(),
+                next: null_mut(),
+            });
+        }
+
+        (frames..frames + (0x1 << self.order)).for_each(|frame| set_bit(&SLAB_FRAMES, frame));
+        set_bit(&SLAB_HEADS, frames);
+
+        without_interrupts(|| {
+            let mut list = self.list.lock();
+            unsafe { list.push(slab) };
+            list.free_slabs += 1;
+        });
+        self.stats.slabs.fetch_add(0x1, Ordering::Relaxed);
+
+        Some(())
+    }
+
+    /// Gives a free slab back to the frame allocator.
+    fn release(&self, slab: *mut SlabHeader) {
+        let phys = virt_to_phys(slab as u64);
+        let frames = phys as usize / PAGE_SIZE;
+
+        clear_bit(&SLAB_HEADS, frames);
+        (frames..frames + (0x1 << self.order)).for_each(|frame| {
+            clear_bit(&SLAB_FRAMES, frame);
+            deallocate_frame((frame * PAGE_SIZE) as u64).unwrap();
+        });
+
+        self.stats.slabs.fetch_sub(0x1, Ordering::Relaxed);
+    }
+}
+
+fn set_bit(bitmap: &[AtomicU64; SLAB_BITMAP_LEN], frame: usize) {
+    bitmap[frame / u64::BITS as usize]
+        .fetch_or(1 << (frame % u64::BITS as usize), Ordering::Release);
+}
+
+fn clear_bit(bitmap: &[AtomicU64; SLAB_BITMAP_LEN], frame: usize) {
+    bitmap[frame / u64::BITS as usize]
+        .fetch_and(!(1 << (frame % u64::BITS as usize)), Ordering::Release);
+}
+
+fn test_bit(bitmap: &[AtomicU64; SLAB_BITMAP_LEN], frame: usize) -> bool {
+    bitmap[frame / u64::BITS as usize].load(Ordering::Acquire) & (1 << (frame % u64::BITS as usize))
+        != 0
+}
+
+/// Finds the general purpose cache that serves `layout`.
+fn find_cache(layout: &Layout) -> Option<&'static SlabCache> {
+    KMALLOC_CACHES
+        .iter()
+        .find(|cache| cache.size >= layout.size() && cache.align >= layout.align())
+}
+
+/// Checks whether `ptr` points into a slab.
+fn is_slab_object(ptr: *mut u8) -> bool {
+    let addr = ptr as u64;
+    if addr < phys_to_virt(0) || addr >= phys_to_virt(SLAB_PHYS_LIMIT) {
+        return false;
+    }
+
+    test_bit(&SLAB_FRAMES, virt_to_phys(addr) as usize / PAGE_SIZE)
+}
+
+/// Finds the cache that owns a slab object. Slabs are aligned to their size, so the head frame of the slab is the
+/// first one found by rounding the address down to growing slab sizes.
+fn cache_of(ptr: *mut u8) -> &'static SlabCache {
+    let phys = virt_to_phys(ptr as u64) as usize;
+    let head = (0..=SLAB_MAX_ORDER)
+        .map(|order| phys & !((PAGE_SIZE << order) - 1))
+        .find(|&head| test_bit(&SLAB_HEADS, head / PAGE_SIZE))
+        .expect("cache_of(): slab object without a slab header");
+
+    unsafe { &*(*(phys_to_virt(head as u64) as *const SlabHeader)).cache }
+}
+
+/// Runs `f` with the next allocation on this CPU served by `cache`. `f` must allocate exactly once; if the layout does
+/// not fit in `cache`, the allocation falls back to the general purpose caches.
+fn alloc_from<R>(cache: &'static SlabCache, f: impl FnOnce() -> R) -> R {
+    // An interrupt handler on this CPU must not take the request, so keep interrupts off until it is consumed.
+    without_interrupts(|| {
+        let requested = &REQUESTED_CACHE[cpu_id()];
+        requested.store(cache as *const _ as *mut _, Ordering::Relaxed);
+        let res = f();
+        requested.store(null_mut(), Ordering::Relaxed);
+        res
+    })
+}
+
+/// Boxes `value` in the per-type `cache`.
+pub fn typed_box<T>(cache: &'static SlabCache, value: T) -> Box<T> {
+    alloc_from(cache, || Box::new(value))
+}
+
+/// Creates an [`Arc`] of `value` in the per-type `cache`.
+pub fn typed_arc<T>(cache: &'static SlabCache, value: T) -> Arc<T> {
+    alloc_from(cache, || Arc::new(value))
+}
+
+/// Allocates a zeroed buffer of `len` bytes in the per-type `cache`.
+pub fn typed_buffer(cache: &'static SlabCache, len: usize) -> Vec<u8> {
+    alloc_from(cache, || vec![0u8; len])
+}
+
+/// The global allocator: small objects go to the slab caches and everything else goes to the buddy heap.
+pub struct SlabAllocator {
+    heap: &'static LockedHeapWithRescue<32>,
+}
+
+impl SlabAllocator {
+    pub const fn new(heap: &'static LockedHeapWithRescue<32>) -> Self {
+        Self { heap }
+    }
+}
+
+unsafe impl GlobalAlloc for SlabAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if SLAB_ENABLED.load(Ordering::Relaxed) {
+            let requested = REQUESTED_CACHE[cpu_id()].swap(null_mut(), Ordering::Relaxed);
+            let cache = match requested.as_ref() {
+                Some(cache) if cache.size >= layout.size() && cache.align >= layout.align() => {
+                    Some(cache)
+                }
+                _ => find_cache(&layout),
+            };
+
+            if let Some(obj) = cache.and_then(|cache| cache.alloc()) {
+                return obj;
+            }
+        }
+
+        self.heap.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if is_slab_object(ptr) {
+            cache_of(ptr).free(ptr)
+        } else {
+            self.heap.dealloc(ptr, layout)
+        }
+    }
+}
+
+/// Enables the slab caches. Must be called after the frame allocator is initialized.
+pub fn init_slab() {
+    SLAB_ENABLED.store(true, Ordering::Release);
+    kinfo!(
+        "init_slab(): {} caches are ready",
+        KMALLOC_CACHES.len() + OBJECT_CACHES.len()
+    );
+}
+
+/// Formats the statistics of all caches like Linux's `/proc/slabinfo`.
+pub fn slab_info() -> String {
+    let mut content = String::new();
+    writeln!(content, "slabinfo - version: 2.1").unwrap();
+    writeln!(
+        content,
+        "# name            <active_objs> <num_objs> <objsize> <objperslab> <pagesperslab> : slabdata <num_slabs> : stats <allocs> <frees> <cpuhits>"
+    )
+    .unwrap();
+
+    OBJECT_CACHES
+        .iter()
+        .copied()
+        .chain(KMALLOC_CACHES.iter())
+        .for_each(|cache| {
+            let slabs = cache.stats.slabs.load(Ordering::Relaxed);
+            writeln!(
+                content,
+                "{:<17} {:>13} {:>10} {:>9} {:>12} {:>14} : slabdata {:>11} : stats {:>8} {:>7} {:>9}",
+                cache.name,
+                cache.stats.active.load(Ordering::Relaxed),
+                slabs * cache.capacity,
+                cache.size,
+                cache.capacity,
+                0x1 << cache.order,
+                slabs,
+                cache.stats.allocs.load(Ordering::Relaxed),
+                cache.stats.frees.load(Ordering::Relaxed),
+                cache.stats.hits.load(Ordering::Relaxed),
+            )
+            .unwrap();
+        });
+
+    content
+}
@@ -1,5 +1,6 @@
 pub mod futex;
 pub mod mutex;
+pub mod preempt;
 pub mod raw_mutex;
 
 pub use raw_mutex::*;
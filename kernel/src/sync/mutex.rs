@@ -4,15 +4,19 @@ use core::cell::UnsafeCell;
 use core::marker::PhantomData;
 use core::mem::MaybeUninit;
 use core::ops::{Deref, DerefMut};
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use crate::arch::cpu::cpu_id;
 use crate::arch::interrupt;
+use crate::arch::mm::tlb::handle_tlb_shootdown;
+use crate::sync::preempt::{preempt_disable, preempt_enable};
 
 use atomic_enum::atomic_enum;
 use log::error;
 
 pub const MAX_LOCK_ATTEMPT: usize = 0x100000;
+/// The owner of a [`Mutex`] that is not held.
+const NO_OWNER: usize = usize::MAX;
 
 #[atomic_enum]
 enum MutexStatus {
@@ -60,10 +64,13 @@ impl MutexSupport for Spin {
     }
 
     fn lock_prologue() -> Self::GuardData {
+        preempt_disable();
         PhantomData
     }
 
-    fn lock_epilogue(&self) {}
+    fn lock_epilogue(&self) {
+        preempt_enable();
+    }
 }
 
 impl MutexSupport for SpinNoInterrupt {
@@ -72,14 +79,20 @@ impl MutexSupport for SpinNoInterrupt {
         Self
     }
 
+    fn cpu_relax(&self) {
+        core::hint::spin_loop();
+        // We spin with interrupts masked, and the owner of the lock may be waiting for us to answer a TLB shootdown.
+        handle_tlb_shootdown();
+    }
+
     fn lock_prologue() -> Self::GuardData {
-        FlagsGuard {
-            flags: unsafe { interrupt::disable_and_store() },
-        }
+        let flags = unsafe { interrupt::disable_and_store() };
+        preempt_disable();
+        FlagsGuard { flags }
     }
 
     fn lock_epilogue(&self) {
-        // Notify.
+        preempt_enable();
     }
 }
 
@@ -103,6 +116,8 @@ pub struct Mutex<T: ?Sized, S: MutexSupport> {
     // 0 = uninitialized, 1 = initializing, 2 = initialized
     support_initialization: MutexStatus,
     user: UnsafeCell<(usize, usize)>, // (cid, tid)
+    /// The CPU that holds the lock, or [`NO_OWNER`].
+    owner: AtomicUsize,
     data: UnsafeCell<T>,
 }
 
@@ -138,6 +153,7 @@ impl<T, S: MutexSupport> Mutex<T, S> {
             support: MaybeUninit::uninit(),
             support_initialization: MutexStatus::Uninitialized,
             user: UnsafeCell::new((0, 0)),
+            owner: AtomicUsize::new(NO_OWNER),
         }
     }
 
@@ -151,6 +167,7 @@ impl<T, S: MutexSupport> Mutex<T, S> {
             let _support = self.support;
             let _support_initialization_ = self.support_initialization;
             let _user = self.user;
+            let _owner = self.owner;
         }
 
         data.into_inner()
@@ -208,9 +225,39 @@ impl<T: ?Sized, S: MutexSupport> Mutex<T, S> {
     pub fn lock(&self) -> MutexGuard<T, S> {
         let guard = S::lock_prologue();
         self.get_lock();
+        self.owner.store(cpu_id(), Ordering::Relaxed);
 
         MutexGuard { mutex: self, guard }
     }
+
+    /// Tries to lock the [`Mutex`] without spinning. Returns `None` if the lock is held by someone else, which may be
+    /// the caller itself (e.g., a page fault taken while the faulting code holds the lock).
+    #[inline(always)]
+    pub fn try_lock(&self) -> Option<MutexGuard<T, S>> {
+        let guard = S::lock_prologue();
+        match self
+            .lock
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        {
+            Ok(_) => {
+                self.owner.store(cpu_id(), Ordering::Relaxed);
+                Some(MutexGuard { mutex: self, guard })
+            }
+            Err(_) => {
+                // Undo the prologue as the guard would; dropping `guard` restores the interrupts.
+                unsafe {
+                    (*self.support.as_ptr()).lock_epilogue();
+                }
+                None
+            }
+        }
+    }
+
+    /// Returns true if the lock is held on the current CPU. Since holding a [`Mutex`] disables preemption, the holder
+    /// is the code that the caller (e.g., an exception handler) has interrupted.
+    pub fn held_by_current_cpu(&self) -> bool {
+        self.lock.load(Ordering::Relaxed) && self.owner.load(Ordering::Relaxed) == cpu_id()
+    }
 }
 
 /// Allows direct operation on `MutexGuard`.
@@ -231,6 +278,7 @@ impl<'a, T: ?Sized, S: MutexSupport> DerefMut for MutexGuard<'a, T, S> {
 impl<'a, T: ?Sized, S: MutexSupport> Drop for MutexGuard<'a, T, S> {
     fn drop(&mut self) {
         // Release the lock and die.
+        self.mutex.owner.store(NO_OWNER, Ordering::Relaxed);
         self.mutex.lock.store(false, Ordering::Release);
         unsafe {
             (*self.mutex.support.as_ptr()).lock_epilogue();
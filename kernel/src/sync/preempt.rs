@@ -0,0 +1,66 @@
+//! Preemption control. Our kernel is built on cooperative futures, so a task can only be switched out where it
+//! `await`s. Long synchronous paths call [`crate::process::scheduler::cond_resched`] at safe points instead, which is
+//! only allowed when the per-CPU preempt count is zero and no lock from the `spin` crate is held.
+//!
+//! The preempt count is raised whenever the CPU holds one of our spin locks or is handling an interrupt, so that we
+//! never switch away while another task may spin on the same lock or while we are on the interrupt path.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::arch::cpu::{cpu_id, MAX_CPU_NUM};
+
+/// The bits below this offset count the nesting level of preemption-disabled regions.
+pub const PREEMPT_OFFSET: usize = 0x1;
+/// The bits starting from this offset count the nesting level of hardware interrupts.
+pub const HARDIRQ_OFFSET: usize = 0x1 << 16;
+
+static PREEMPT_COUNT: [AtomicUsize; MAX_CPU_NUM] = {
+    const COUNT: AtomicUsize = AtomicUsize::new(0);
+    [COUNT; MAX_CPU_NUM]
+};
+
+/// Returns the preempt count of the current CPU.
+#[inline(always)]
+pub fn preempt_count() -> usize {
+    PREEMPT_COUNT[cpu_id()].load(Ordering::Relaxed)
+}
+
+/// Disables preemption on the current CPU. Calls can be nested.
+#[inline(always)]
+pub fn preempt_disable() {
+    PREEMPT_COUNT[cpu_id()].fetch_add(PREEMPT_OFFSET, Ordering::Relaxed);
+}
+
+/// Re-enables preemption on the current CPU.
+#[inline(always)]
+pub fn preempt_enable() {
+    PREEMPT_COUNT[cpu_id()].fetch_sub(PREEMPT_OFFSET, Ordering::Relaxed);
+}
+
+/// Returns true if we are in the interrupt context.
+#[inline(always)]
+pub fn in_interrupt() -> bool {
+    preempt_count() >= HARDIRQ_OFFSET
+}
+
+/// Returns true if the current CPU can be preempted at a safe point.
+#[inline(always)]
+pub fn preemptible() -> bool {
+    preempt_count() == 0
+}
+
+/// Marks the interrupt context; the preempt count is raised until the guard is dropped.
+pub struct IrqContext;
+
+impl IrqContext {
+    pub fn enter() -> Self {
+        PREEMPT_COUNT[cpu_id()].fetch_add(HARDIRQ_OFFSET, Ordering::Relaxed);
+        Self
+    }
+}
+
+impl Drop for IrqContext {
+    fn drop(&mut self) {
+        PREEMPT_COUNT[cpu_id()].fetch_sub(HARDIRQ_OFFSET, Ordering::Relaxed);
+    }
+}
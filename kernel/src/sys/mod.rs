@@ -295,6 +295,30 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// Flags of `clone`. See <https://man7.org/linux/man-pages/man2/clone.2.html>.
+    #[derive(Default)]
+    pub struct CloneFlags: u64 {
+        /// The calling process and the child process run in the same memory space.
+        const CLONE_VM = 0x100;
+        const CLONE_FS = 0x200;
+        const CLONE_FILES = 0x400;
+        const CLONE_SIGHAND = 0x800;
+        const CLONE_VFORK = 0x4000;
+        /// The child is placed in the same thread group as the calling process.
+        const CLONE_THREAD = 0x10000;
+        const CLONE_SYSVSEM = 0x40000;
+        /// The TLS (Thread Local Storage) descriptor is set to `tls`.
+        const CLONE_SETTLS = 0x80000;
+        /// Store the child thread ID at `parent_tid` in the parent's memory.
+        const CLONE_PARENT_SETTID = 0x100000;
+        /// Clear the child thread ID at `child_tid` in the child's memory when the child exits.
+        const CLONE_CHILD_CLEARTID = 0x200000;
+        /// Store the child thread ID at `child_tid` in the child's memory.
+        const CLONE_CHILD_SETTID = 0x1000000;
+    }
+}
+
 bitflags! {
     #[derive(Default)]
     pub struct StatMode: u32 {
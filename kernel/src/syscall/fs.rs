@@ -17,18 +17,23 @@ use alloc::{
     vec::Vec,
 };
 use bitflags::bitflags;
-use rcore_fs::vfs::FsError;
+use rcore_fs::vfs::{FsError, INode};
 
 use crate::{
-    arch::{interrupt::SYSCALL_REGS_NUM, io::IoVec, QWORD_LEN},
+    arch::{interrupt::SYSCALL_REGS_NUM, io::IoVec, PAGE_SIZE, QWORD_LEN},
     dummy_impl,
     error::{fserror_to_kerror, Errno, KResult},
     fs::{
         epoll::{EpollInstance, EPOLL_QUEUE},
         file::{do_dup, File, FileObject, FileOpenOption, FileType, Seek},
+        xattr::{self, XattrFlags, XATTR_NAME_MAX, XATTR_SIZE_MAX},
         InodeOpType, AT_FDCWD,
     },
-    process::thread::{Thread, ThreadContext},
+    mm::page_cache::sync_page_caches,
+    process::{
+        scheduler::cond_resched,
+        thread::{Thread, ThreadContext},
+    },
     sys::{
         Dirent, DirentType, EpollEvent, EpollFlags, EpollOp, PollEvents, Pollfd, Stat,
         AT_SYMLINK_NOFOLLOW, SEEK_CUR, SEEK_END, SEEK_SET,
@@ -213,8 +218,7 @@ pub fn sys_openat(
 
     // Open the directory.
     let mut proc = thread.parent.lock();
-    let vm = thread.vm.lock();
-    let p_path = vm.get_ptr(path)?;
+    let p_path = thread.vm.lock().get_ptr(path)?;
     let path = p_path.read_c_string()?;
     let oflags = Oflags::from_bits_truncate(flags);
 
@@ -298,9 +302,56 @@ pub async fn sys_poll(
     // milliseconds
     let timeout = syscall_registers[2];
 
-    let fds = unsafe { core::slice::from_raw_parts_mut(fds as *mut Pollfd, nfds as usize) };
+    let fds_ptr = Ptr::<Pollfd>::new(fds);
+    let mut fds = (0..nfds as usize)
+        .map(|i| unsafe { fds_ptr.add(i).read() })
+        .collect::<KResult<Vec<_>>>()?;
     let timeout = Duration::from_millis(timeout as _);
-    SysPoll { fds, thread }.await
+    let ready = SysPoll {
+        fds: &mut fds,
+        thread,
+    }
+    .await?;
+    // Report `revents` back to the user.
+    unsafe { fds_ptr.write_slice(&fds) }?;
+
+    Ok(ready)
+}
+
+/// The largest kernel buffer through which `read` and `write` move the user data, so that a huge user length cannot
+/// exhaust the kernel heap.
+const IO_CHUNK_SIZE: usize = PAGE_SIZE;
+
+/// Copies `len` bytes at the user buffer `buf` to `write` one chunk at a time. `write` is given the number of bytes
+/// written so far and the chunk. Stops at the first short write and returns the number of bytes written; an error is
+/// only reported if nothing has been written.
+///
+/// The CPU may be given away between two chunks, so the caller must not hold any lock.
+fn write_from_user(
+    buf: &Ptr<u8>,
+    len: usize,
+    mut write: impl FnMut(usize, &[u8]) -> KResult<usize>,
+) -> KResult<usize> {
+    let mut chunk = vec![0u8; len.min(IO_CHUNK_SIZE)];
+    let mut written = 0;
+    while written < len {
+        let size = chunk.len().min(len - written);
+        let res = unsafe { buf.add(written).read_slice(&mut chunk[..size]) }
+            .and_then(|_| write(written, &chunk[..size]));
+        let n = match res {
+            Ok(n) => n,
+            Err(errno) if written == 0 => return Err(errno),
+            Err(_) => break,
+        };
+
+        written += n;
+        if n < size {
+            break;
+        }
+        cond_resched();
+    }
+
+    Ok(written)
 }
 
 pub async fn sys_read(
@@ -313,13 +364,39 @@ pub async fn sys_read(
     let len = syscall_registers[2] as usize;
 
     let mut proc = thread.parent.lock();
-    // Currently assume this is valid.
-    // let slice = proc.vm.lock().check_write_array(&buf, len)?;
-    let slice = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, len) };
-    let file = proc.get_fd(file_fd)?;
-    let len = file.read(slice).await?;
+    let buf_ptr = thread.vm.lock().get_mut_slice::<u8>(buf, len)?;
+    let file = proc.get_fd(file_fd)?.clone();
+    // Do not hold the process lock during I/O so that the read can be preempted.
+    drop(proc);
+    // Pipes, terminals and sockets return what they have; reading on would block for data that may never come.
+    let regular = match &file {
+        FileObject::File(file) => file.metadata().map_or(false, |metadata| {
+            metadata.type_ == rcore_fs::vfs::FileType::File
+        }),
+        _ => false,
+    };
 
-    Ok(len)
+    let mut chunk = vec![0u8; len.min(IO_CHUNK_SIZE)];
+    let mut read = 0;
+    while read < len {
+        let size = chunk.len().min(len - read);
+        let res = file.read(&mut chunk[..size]).await;
+        let res =
+            res.and_then(|n| unsafe { buf_ptr.add(read).write_slice(&chunk[..n]) }.map(|_| n));
+        let n = match res {
+            Ok(n) => n,
+            Err(errno) if read == 0 => return Err(errno),
+            Err(_) => break,
+        };
+
+        read += n;
+        if n < size || !regular {
+            break;
+        }
+        cond_resched();
+    }
+
+    Ok(read)
 }
 
 pub fn sys_write(
@@ -332,11 +409,12 @@ pub fn sys_write(
     let len = syscall_registers[2] as usize;
 
     let mut proc = thread.parent.lock();
-    let slice = proc.vm.lock().get_slice::<u8>(buf, len)?;
-    let file = proc.get_fd(file_fd)?;
-    let len = file.write(slice)?;
+    let buf_ptr = proc.vm.lock().get_slice::<u8>(buf, len)?;
+    let file = proc.get_fd(file_fd)?.clone();
+    // Do not hold the process lock during I/O so that the write can be preempted.
+    drop(proc);
 
-    Ok(len)
+    write_from_user(&buf_ptr, len, |_, chunk| file.write(chunk))
 }
 
 /// The readv() system call reads iovcnt buffers from the file associated with the file descriptor fd into the buffers
@@ -411,10 +489,10 @@ pub fn sys_chdir(
     syscall_registers: [u64; SYSCALL_REGS_NUM],
 ) -> KResult<usize> {
     let mut proc = thread.parent.lock();
-    let vm = thread.vm.lock();
 
     let pathname = syscall_registers[0];
-    let pathname = vm.get_ptr(pathname)?.read_c_string()?;
+    let p_pathname = thread.vm.lock().get_ptr(pathname)?;
+    let pathname = p_pathname.read_c_string()?;
     // busybox shell will do this for us, but w.l.o.g. we should do this.
     let realpath = realpath(&pathname);
 
@@ -443,7 +521,6 @@ pub async fn sys_sendfile(
     let count = syscall_registers[3];
 
     let mut proc = thread.parent.lock();
-    let vm = thread.vm.lock();
     // Since MIRI detects multiple mutable borrows from proc's opened files, we need to first copy
     // the content from the source file using a scope and then copy to the destination using another
     // scope to avoid race condition.
@@ -451,7 +528,7 @@ pub async fn sys_sendfile(
         let src = proc.get_fd(in_fd)?;
         // If `offset` is not NULL, then we read from `offset`; otherwise, we read from the offset
         // stored in the source file object, and if it is non-NULL, we need to update this value.
-        let offset = vm.get_mut_ptr::<u64>(offset)?;
+        let offset = thread.vm.lock().get_mut_ptr::<u64>(offset)?;
         // Prepare a buffer.
         let mut buf = vec![0u8; count as usize];
 
@@ -494,16 +571,10 @@ pub fn sys_getcwd(
     }
 
     // Check the pointer before use.
-    let buf_ptr = Ptr::<u8>::new(
-        thread
-            .vm
-            .lock()
-            .get_mut_slice::<u8>(buf, cwd.len() + 1)?
-            .as_ptr() as u64,
-    );
+    let buf_ptr = thread.vm.lock().get_mut_slice::<u8>(buf, cwd.len() + 1)?;
 
     unsafe {
-        buf_ptr.write_c_string(cwd);
+        buf_ptr.write_c_string(cwd)?;
     }
 
     Ok(buf_ptr.as_ptr() as usize)
@@ -517,7 +588,8 @@ pub fn sys_lstat(
     let pathname = syscall_registers[0];
     let statbuf = syscall_registers[1];
 
-    let filename = thread.vm.lock().get_ptr(pathname)?.read_c_string()?;
+    let filename_ptr = thread.vm.lock().get_ptr(pathname)?;
+    let filename = filename_ptr.read_c_string()?;
 
     do_stat(
         thread,
@@ -598,20 +670,35 @@ pub async fn sys_pread(
     let offset = syscall_registers[3];
 
     let mut proc = thread.parent.lock();
-    let file = proc.get_fd(fd)?;
+    let file = proc.get_fd(fd)?.clone();
+    drop(proc);
 
     if let FileObject::File(file) = file {
         let p_buf = thread.vm.lock().get_ptr(buf)?;
         let filesz = file.metadata().unwrap().size;
         let offset = filesz.min(offset as usize);
-        let mut buf = vec![0u8; count as usize];
-        let len = file.read_at(offset, &mut buf).await?;
+        let count = count as usize;
+        let mut chunk = vec![0u8; count.min(IO_CHUNK_SIZE)];
+        let mut read = 0;
+        while read < count {
+            let size = chunk.len().min(count - read);
+            let res = file.read_at(offset + read, &mut chunk[..size]).await;
+            let res =
+                res.and_then(|n| unsafe { p_buf.add(read).write_slice(&chunk[..n]) }.map(|_| n));
+            let n = match res {
+                Ok(n) => n,
+                Err(errno) if read == 0 => return Err(errno),
+                Err(_) => break,
+            };
 
-        unsafe {
-            p_buf.write_slice(&buf);
+            read += n;
+            if n < size {
+                break;
+            }
+            cond_resched();
         }
 
-        Ok(len)
+        Ok(read)
     } else {
         Err(Errno::EBADF)
     }
@@ -629,14 +716,15 @@ pub fn sys_pwrite(
     let count = syscall_registers[2];
     let offset = syscall_registers[3];
 
-    let buf = unsafe { core::slice::from_raw_parts(buf as *const u8, count as _) };
+    let p_buf = thread.vm.lock().get_slice::<u8>(buf, count as _)?;
     let mut proc = thread.parent.lock();
-    let file = proc.get_fd(fd)?;
+    let file = proc.get_fd(fd)?.clone();
+    drop(proc);
 
     if let FileObject::File(file) = file {
-        let len = file.write_at(offset as _, buf).unwrap();
-
-        Ok(len)
+        write_from_user(&p_buf, count as _, |written, chunk| {
+            file.write_at(offset as usize + written, chunk)
+        })
     } else {
         Err(Errno::EBADF)
     }
@@ -664,6 +752,191 @@ pub fn sys_fstat(
     }
 }
 
+/// getxattr() retrieves the value of the extended attribute identified by name and associated with the given path in
+/// the filesystem. The attribute value is placed in the buffer pointed to by value; size specifies the size of that
+/// buffer. If size is zero, the current size of the named extended attribute is returned.
+pub fn sys_getxattr(
+    thread: &Arc<Thread>,
+    ctx: &mut ThreadContext,
+    syscall_registers: [u64; SYSCALL_REGS_NUM],
+) -> KResult<usize> {
+    let pathname = syscall_registers[0];
+    let name = syscall_registers[1];
+    let value = syscall_registers[2];
+    let size = syscall_registers[3];
+
+    let inode = xattr_path_inode(thread, pathname, true)?;
+    do_getxattr(thread, &inode, name, value, size)
+}
+
+/// lgetxattr() is identical to getxattr(), except in the case of a symbolic link, where the link itself is
+/// interrogated, not the file that it refers to.
+pub fn sys_lgetxattr(
+    thread: &Arc<Thread>,
+    ctx: &mut ThreadContext,
+    syscall_registers: [u64; SYSCALL_REGS_NUM],
+) -> KResult<usize> {
+    let pathname = syscall_registers[0];
+    let name = syscall_registers[1];
+    let value = syscall_registers[2];
+    let size = syscall_registers[3];
+
+    let inode = xattr_path_inode(thread, pathname, false)?;
+    do_getxattr(thread, &inode, name, value, size)
+}
+
+/// fgetxattr() is identical to getxattr(), only the open file referred to by fd is interrogated in place of path.
+pub fn sys_fgetxattr(
+    thread: &Arc<Thread>,
+    ctx: &mut ThreadContext,
+    syscall_registers: [u64; SYSCALL_REGS_NUM],
+) -> KResult<usize> {
+    let fd = syscall_registers[0];
+    let name = syscall_registers[1];
+    let value = syscall_registers[2];
+    let size = syscall_registers[3];
+
+    let inode = xattr_fd_inode(thread, fd)?;
+    do_getxattr(thread, &inode, name, value, size)
+}
+
+/// listxattr() retrieves the list of extended attribute names associated with the given path in the filesystem. The
+/// list is the set of (null-terminated) names, one after the other.
+pub fn sys_listxattr(
+    thread: &Arc<Thread>,
+    ctx: &mut ThreadContext,
+    syscall_registers: [u64; SYSCALL_REGS_NUM],
+) -> KResult<usize> {
+    let pathname = syscall_registers[0];
+    let list = syscall_registers[1];
+    let size = syscall_registers[2];
+
+    let inode = xattr_path_inode(thread, pathname, true)?;
+    do_listxattr(thread, &inode, list, size)
+}
+
+/// llistxattr() is identical to listxattr(), except in the case of a symbolic link, where the list of names of
+/// extended attributes associated with the link itself is retrieved.
+pub fn sys_llistxattr(
+    thread: &Arc<Thread>,
+    ctx: &mut ThreadContext,
+    syscall_registers: [u64; SYSCALL_REGS_NUM],
+) -> KResult<usize> {
+    let pathname = syscall_registers[0];
+    let list = syscall_registers[1];
+    let size = syscall_registers[2];
+
+    let inode = xattr_path_inode(thread, pathname, false)?;
+    do_listxattr(thread, &inode, list, size)
+}
+
+/// flistxattr() is identical to listxattr(), only the open file referred to by fd is interrogated in place of path.
+pub fn sys_flistxattr(
+    thread: &Arc<Thread>,
+    ctx: &mut ThreadContext,
+    syscall_registers: [u64; SYSCALL_REGS_NUM],
+) -> KResult<usize> {
+    let fd = syscall_registers[0];
+    let list = syscall_registers[1];
+    let size = syscall_registers[2];
+
+    let inode = xattr_fd_inode(thread, fd)?;
+    do_listxattr(thread, &inode, list, size)
+}
+
+/// setxattr() sets the value of the extended attribute identified by name and associated with the given path in the
+/// filesystem.
+pub fn sys_setxattr(
+    thread: &Arc<Thread>,
+    ctx: &mut ThreadContext,
+    syscall_registers: [u64; SYSCALL_REGS_NUM],
+) -> KResult<usize> {
+    let pathname = syscall_registers[0];
+    let name = syscall_registers[1];
+    let value = syscall_registers[2];
+    let size = syscall_registers[3];
+    let flags = syscall_registers[4];
+
+    let inode = xattr_path_inode(thread, pathname, true)?;
+    do_setxattr(thread, &inode, name, value, size, flags)
+}
+
+/// lsetxattr() is identical to setxattr(), except in the case of a symbolic link, where the extended attribute is set
+/// on the link itself, not the file that it refers to.
+pub fn sys_lsetxattr(
+    thread: &Arc<Thread>,
+    ctx: &mut ThreadContext,
+    syscall_registers: [u64; SYSCALL_REGS_NUM],
+) -> KResult<usize> {
+    let pathname = syscall_registers[0];
+    let name = syscall_registers[1];
+    let value = syscall_registers[2];
+    let size = syscall_registers[3];
+    let flags = syscall_registers[4];
+
+    let inode = xattr_path_inode(thread, pathname, false)?;
+    do_setxattr(thread, &inode, name, value, size, flags)
+}
+
+/// fsetxattr() is identical to setxattr(), only the extended attribute is set on the open file referred to by fd in
+/// place of path.
+pub fn sys_fsetxattr(
+    thread: &Arc<Thread>,
+    ctx: &mut ThreadContext,
+    syscall_registers: [u64; SYSCALL_REGS_NUM],
+) -> KResult<usize> {
+    let fd = syscall_registers[0];
+    let name = syscall_registers[1];
+    let value = syscall_registers[2];
+    let size = syscall_registers[3];
+    let flags = syscall_registers[4];
+
+    let inode = xattr_fd_inode(thread, fd)?;
+    do_setxattr(thread, &inode, name, value, size, flags)
+}
+
+/// removexattr() removes the extended attribute identified by name and associated with the given path in the
+/// filesystem.
+pub fn sys_removexattr(
+    thread: &Arc<Thread>,
+    ctx: &mut ThreadContext,
+    syscall_registers: [u64; SYSCALL_REGS_NUM],
+) -> KResult<usize> {
+    let pathname = syscall_registers[0];
+    let name = syscall_registers[1];
+
+    let inode = xattr_path_inode(thread, pathname, true)?;
+    do_removexattr(thread, &inode, name)
+}
+
+/// lremovexattr() is identical to removexattr(), except in the case of a symbolic link, where the extended attribute
+/// is removed from the link itself, not the file that it refers to.
+pub fn sys_lremovexattr(
+    thread: &Arc<Thread>,
+    ctx: &mut ThreadContext,
+    syscall_registers: [u64; SYSCALL_REGS_NUM],
+) -> KResult<usize> {
+    let pathname = syscall_registers[0];
+    let name = syscall_registers[1];
+
+    let inode = xattr_path_inode(thread, pathname, false)?;
+    do_removexattr(thread, &inode, name)
+}
+
+/// fremovexattr() is identical to removexattr(), only the extended attribute is removed from the open file referred to
+/// by fd in place of path.
+pub fn sys_fremovexattr(
+    thread: &Arc<Thread>,
+    ctx: &mut ThreadContext,
+    syscall_registers: [u64; SYSCALL_REGS_NUM],
+) -> KResult<usize> {
+    let fd = syscall_registers[0];
+    let name = syscall_registers[1];
+
+    let inode = xattr_fd_inode(thread, fd)?;
+    do_removexattr(thread, &inode, name)
+}
+
 /// This system call is used to add, modify, or remove entries in the interest list of the epoll(7) instance referred to by the file descriptor epfd. It requests that the operation op be performed for the target file descriptor, fd.
 pub fn sys_epoll_ctl(
     thread: &Arc<Thread>,
@@ -684,7 +957,8 @@ pub fn sys_epoll_ctl(
     let epoll = proc.get_fd(epfd)?;
 
     if let FileObject::Epoll(epoll) = epoll {
-        let event = unsafe { thread.vm.lock().get_ptr(event)?.read() }?;
+        let event = thread.vm.lock().get_ptr(event)?;
+        let event = unsafe { event.read() }?;
         let op = EpollOp::try_from(op).map_err(|_| Errno::EINVAL)?;
 
         epoll.epoll_ctl(fd, op, event)
@@ -782,28 +1056,40 @@ pub fn sys_epoll_pwait(
 
                     if status.read && epoll_event_flags.contains(EpollFlags::EPOLLIN) {
                         // Copy the data to the user space.
-                        let event_ptr =
-                            unsafe { &mut *((events as *mut EpollEvent).add(ready_num)) };
-                        event_ptr.events = EpollFlags::EPOLLIN;
-                        event_ptr.data = epoll_event_from_instance.data;
+                        unsafe {
+                            Ptr::<EpollEvent>::new(events)
+                                .add(ready_num)
+                                .write(EpollEvent {
+                                    events: EpollFlags::EPOLLIN,
+                                    data: epoll_event_from_instance.data,
+                                })
+                        }?;
                         ready_num += 1;
                     }
 
                     if status.write && epoll_event_flags.contains(EpollFlags::EPOLLOUT) {
                         // Copy the data to the user space.
-                        let event_ptr =
-                            unsafe { &mut *((events as *mut EpollEvent).add(ready_num)) };
-                        event_ptr.events = EpollFlags::EPOLLOUT;
-                        event_ptr.data = epoll_event_from_instance.data;
+                        unsafe {
+                            Ptr::<EpollEvent>::new(events)
+                                .add(ready_num)
+                                .write(EpollEvent {
+                                    events: EpollFlags::EPOLLOUT,
+                                    data: epoll_event_from_instance.data,
+                                })
+                        }?;
                         ready_num += 1;
                     }
 
                     if status.error && epoll_event_flags.contains(EpollFlags::EPOLLERR) {
                         // Copy the data to the user space.
-                        let event_ptr =
-                            unsafe { &mut *((events as *mut EpollEvent).add(ready_num)) };
-                        event_ptr.events = EpollFlags::EPOLLERR;
-                        event_ptr.data = epoll_event_from_instance.data;
+                        unsafe {
+                            Ptr::<EpollEvent>::new(events)
+                                .add(ready_num)
+                                .write(EpollEvent {
+                                    events: EpollFlags::EPOLLERR,
+                                    data: epoll_event_from_instance.data,
+                                })
+                        }?;
                         ready_num += 1;
                     }
                 }
@@ -874,18 +1160,15 @@ pub fn sys_getdents64(
             }
 
             unsafe {
-                (dirp as *mut Dirent).write(Dirent {
+                Ptr::<Dirent>::new(dirp).write(Dirent {
                     d_ino: inode as _,
                     d_off: 0,
                     d_reclen: size as _,
                     d_type: DirentType::from_type(&metadata.type_).bits(),
-                });
+                })?;
 
                 // Copy directory name.
-                (dirp as *mut u8)
-                    .add(hdr_len)
-                    .copy_from(dirent.as_ptr(), dirent.len());
-                (dirp as *mut u8).add(hdr_len + dirent.len()).write(0);
+                Ptr::<u8>::new(dirp + hdr_len as u64).write_c_string(&dirent)?;
             }
 
             dirp += size as u64;
@@ -994,16 +1277,68 @@ pub fn sys_fnctl(
     file.fcntl(thread, fd, cmd, arg)
 }
 
+/// fsync() transfers ("flushes") all modified in-core data of (i.e., modified buffer cache pages for) the file referred
+/// to by the file descriptor fd to the disk device so that all changed information can be retrieved even if the system
+/// crashes or is rebooted.
+pub fn sys_fsync(
+    thread: &Arc<Thread>,
+    ctx: &mut ThreadContext,
+    syscall_registers: [u64; SYSCALL_REGS_NUM],
+) -> KResult<usize> {
+    let fd = syscall_registers[0];
+
+    let mut proc = thread.parent.lock();
+    if let FileObject::File(file) = proc.get_fd(fd)? {
+        file.sync_all()?;
+        Ok(0)
+    } else {
+        Err(Errno::EINVAL)
+    }
+}
+
+/// fdatasync() is similar to fsync(), but does not flush modified metadata unless that metadata is needed in order to
+/// allow a subsequent data retrieval to be correctly handled.
+pub fn sys_fdatasync(
+    thread: &Arc<Thread>,
+    ctx: &mut ThreadContext,
+    syscall_registers: [u64; SYSCALL_REGS_NUM],
+) -> KResult<usize> {
+    let fd = syscall_registers[0];
+
+    let mut proc = thread.parent.lock();
+    if let FileObject::File(file) = proc.get_fd(fd)? {
+        file.sync_data()?;
+        Ok(0)
+    } else {
+        Err(Errno::EINVAL)
+    }
+}
+
+/// sync() causes all pending modifications to filesystem metadata and cached file data to be written to the underlying
+/// filesystems.
+pub fn sys_sync(
+    thread: &Arc<Thread>,
+    ctx: &mut ThreadContext,
+    syscall_registers: [u64; SYSCALL_REGS_NUM],
+) -> KResult<usize> {
+    sync_page_caches()?;
+
+    Ok(0)
+}
+
 fn do_symlink(
     thread: &Arc<Thread>,
     target: *const u8,
     newdirfd: u64,
     linkpath: *const u8,
 ) -> KResult<usize> {
-    let vm = thread.vm.lock();
+    let (target, linkpath) = {
+        let vm = thread.vm.lock();
+        (vm.get_ptr(target as _)?, vm.get_ptr(linkpath as _)?)
+    };
 
-    let target = vm.get_ptr(target as _)?.read_c_string()?;
-    let linkpath = vm.get_ptr(linkpath as _)?.read_c_string()?;
+    let target = target.read_c_string()?;
+    let linkpath = linkpath.read_c_string()?;
 
     let proc = thread.parent.lock();
     let (dirpath, filename) = split_path(&linkpath)?;
@@ -1040,10 +1375,7 @@ fn do_stat(
     let inode = proc.read_inode_at(dfd, &filename, follow_symlink)?;
     let metadata = inode.metadata().map_err(fserror_to_kerror)?;
 
-    unsafe {
-        let stat = Stat::from(metadata.clone());
-        statbuf.write(Stat::from(metadata));
-    }
+    unsafe { Ptr::new(statbuf as _).write(Stat::from(metadata)) }?;
 
     Ok(0)
 }
@@ -1086,15 +1418,132 @@ fn do_readlink(
     let pathname = Ptr::new(pathname as _).read_c_string()?;
     let proc = thread.parent.lock();
     let inode = proc.read_inode_at(dirfd, pathname.as_str(), false)?;
-    if inode.metadata().map_err(|_| Errno::EINVAL)?.type_ == rcore_fs::vfs::FileType::SymLink {
-        let buf = unsafe { core::slice::from_raw_parts_mut(buf, bufsiz as _) };
-        let len = inode.read_at(0, buf).map_err(fserror_to_kerror)?;
+    let metadata = inode.metadata().map_err(|_| Errno::EINVAL)?;
+    if metadata.type_ == rcore_fs::vfs::FileType::SymLink {
+        let mut kernel_buf = vec![0u8; metadata.size.min(bufsiz as usize)];
+        let len = inode
+            .read_at(0, &mut kernel_buf)
+            .map_err(fserror_to_kerror)?;
+        unsafe { Ptr::new(buf as _).write_slice(&kernel_buf[..len]) }?;
         Ok(len)
     } else {
         Err(Errno::EINVAL)
     }
 }
 
+fn xattr_path_inode(
+    thread: &Arc<Thread>,
+    pathname: u64,
+    follow_symlink: bool,
+) -> KResult<Arc<dyn INode>> {
+    let pathname = thread.vm.lock().get_ptr::<u8>(pathname)?.read_c_string()?;
+    let proc = thread.parent.lock();
+    proc.read_inode_at(AT_FDCWD as _, &pathname, follow_symlink)
+}
+
+fn xattr_fd_inode(thread: &Arc<Thread>, fd: u64) -> KResult<Arc<dyn INode>> {
+    let mut proc = thread.parent.lock();
+    match proc.get_fd(fd)? {
+        FileObject::File(file) => Ok(file.inode()),
+        _ => Err(Errno::EBADF),
+    }
+}
+
+fn read_xattr_name(thread: &Arc<Thread>, name: u64) -> KResult<String> {
+    let name = thread.vm.lock().get_ptr::<u8>(name)?.read_c_string()?;
+    if name.is_empty() || name.len() > XATTR_NAME_MAX {
+        return Err(Errno::ERANGE);
+    }
+
+    Ok(name)
+}
+
+fn do_getxattr(
+    thread: &Arc<Thread>,
+    inode: &Arc<dyn INode>,
+    name: u64,
+    value: u64,
+    size: u64,
+) -> KResult<usize> {
+    let name = read_xattr_name(thread, name)?;
+    let data = xattr::get_xattr(inode, &name)?;
+    // A zero size queries the length of the value.
+    if size == 0 {
+        return Ok(data.len());
+    }
+    if data.len() > size as usize {
+        return Err(Errno::ERANGE);
+    }
+
+    if !data.is_empty() {
+        let p_value = thread.vm.lock().get_mut_slice::<u8>(value, data.len())?;
+        unsafe { p_value.write_slice(&data) }?;
+    }
+    Ok(data.len())
+}
+
+fn do_listxattr(
+    thread: &Arc<Thread>,
+    inode: &Arc<dyn INode>,
+    list: u64,
+    size: u64,
+) -> KResult<usize> {
+    let mut names = Vec::new();
+    for name in xattr::list_xattrs(inode)? {
+        names.extend_from_slice(name.as_bytes());
+        names.push(0);
+    }
+
+    if size == 0 {
+        return Ok(names.len());
+    }
+    if names.len() > size as usize {
+        return Err(Errno::ERANGE);
+    }
+
+    if !names.is_empty() {
+        let p_list = thread.vm.lock().get_mut_slice::<u8>(list, names.len())?;
+        unsafe { p_list.write_slice(&names) }?;
+    }
+    Ok(names.len())
+}
+
+fn do_setxattr(
+    thread: &Arc<Thread>,
+    inode: &Arc<dyn INode>,
+    name: u64,
+    value: u64,
+    size: u64,
+    flags: u64,
+) -> KResult<usize> {
+    let flags = XattrFlags::from_bits(flags).ok_or(Errno::EINVAL)?;
+    if flags.contains(XattrFlags::all()) {
+        return Err(Errno::EINVAL);
+    }
+    let size = size as usize;
+    if size > XATTR_SIZE_MAX {
+        return Err(Errno::E2BIG);
+    }
+
+    let name = read_xattr_name(thread, name)?;
+    let mut data = vec![0u8; size];
+    if size != 0 {
+        let p_value = thread.vm.lock().get_slice::<u8>(value, size)?;
+        unsafe { p_value.read_slice(&mut data) }?;
+    }
+
+    xattr::set_xattr(inode, &name, &data, flags)?;
+    update_inode_time(inode, InodeOpType::MODIFY);
+    Ok(0)
+}
+
+fn do_removexattr(thread: &Arc<Thread>, inode: &Arc<dyn INode>, name: u64) -> KResult<usize> {
+    let name = read_xattr_name(thread, name)?;
+    xattr::remove_xattr(inode, &name)?;
+    update_inode_time(inode, InodeOpType::MODIFY);
+    Ok(0)
+}
+
 // Ignored. Permission check will be added in the future.
 dummy_impl!(sys_chown, Ok(0));
 dummy_impl!(sys_fchown, Ok(0));
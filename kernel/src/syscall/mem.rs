@@ -4,16 +4,18 @@ use alloc::{boxed::Box, sync::Arc};
 use rcore_fs::vfs::MMapArea;
 
 use crate::{
-    arch::{interrupt::SYSCALL_REGS_NUM, PAGE_SIZE},
+    arch::{interrupt::SYSCALL_REGS_NUM, mm::paging::HugePageSize, PAGE_SIZE},
     error::{Errno, KResult},
     fs::file::FileObject,
     memory::{brk_hook, is_page_aligned, mmap_hook, page_frame_number, KernelFrameAllocator},
     mm::{
-        callback::{ArenaCallback, UserArenaCallback},
+        callback::{ArenaCallback, HugeArenaCallback, UserArenaCallback},
         Arena, ArenaFlags, ArenaType,
     },
     process::thread::{Thread, ThreadContext},
-    sys::{Prot, MAP_ANONYMOUS, MAP_FIXED, MAP_PRIVATE, MAP_SHARED, MAP_SHARED_VALIDATE},
+    sys::{
+        Prot, MAP_ANONYMOUS, MAP_FIXED, MAP_HUGETLB, MAP_PRIVATE, MAP_SHARED, MAP_SHARED_VALIDATE,
+    },
 };
 
 /// mmap() creates a new mapping in the virtual address space of the calling process. The starting address for the new
@@ -52,6 +54,10 @@ pub fn sys_mmap(
 
     let prot = Prot::from_bits_truncate(prot);
     let mut proc = thread.parent.lock();
+    // Transparent huge pages are opt-in and only make sense if the mapping can hold at least one of them.
+    let huge_page_size = HugePageSize::Size2MiB.size();
+    let use_huge_page =
+        flags & MAP_HUGETLB != 0 && flags & MAP_ANONYMOUS != 0 && length >= huge_page_size;
 
     if flags & MAP_FIXED != 0 {
         // Don't interpret addr as a hint: place the mapping at
@@ -69,12 +75,20 @@ pub fn sys_mmap(
         // We follow the hint, but if there is no free memory, we force
         // use another address to map.
         addr = page_frame_number(thread.vm.lock().cur_heap_end() + PAGE_SIZE as u64 - 1);
+
+        if use_huge_page {
+            // Align the start so that as many chunks as possible can be mapped by huge pages.
+            addr = (addr + huge_page_size - 1) & !(huge_page_size - 1);
+        }
     }
 
     // Then, we push the region back again into the process memory area.
     if flags & MAP_ANONYMOUS != 0 {
         let callback: Box<dyn ArenaCallback> = match flags & MAP_SHARED != 0 {
             true => todo!(),
+            false if use_huge_page => {
+                Box::new(HugeArenaCallback::new(KernelFrameAllocator, prot.into()))
+            }
             false => Box::new(UserArenaCallback::new(KernelFrameAllocator)),
         };
 
@@ -111,12 +125,23 @@ pub fn sys_mmap(
     }
 }
 
-/// The reverse of `mmap` syscall.
+/// The reverse of `mmap` syscall. The munmap() system call deletes the mappings for the specified address range. If only
+/// a part of a mapping is covered, the mapping is split and the rest of it stays valid.
 pub fn sys_munmap(
     thread: &Arc<Thread>,
     ctx: &mut ThreadContext,
     syscall_registers: [u64; SYSCALL_REGS_NUM],
 ) -> KResult<usize> {
+    let addr = syscall_registers[0];
+    let len = syscall_registers[1];
+
+    if !is_page_aligned(addr) || len == 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let len = page_frame_number(len + PAGE_SIZE as u64 - 1);
+    thread.vm.lock().remove_addr(addr, len as _)?;
+
     Ok(0)
 }
 
@@ -141,14 +166,11 @@ pub fn sys_mprotect(
         return Err(Errno::EINVAL);
     }
 
-    let mut vm = thread.vm.lock();
-    let arena = vm
-        .iter_mut()
-        // Now we assume that some arena must "contain" the mprotected area.
-        .find(|arena| arena.subset_of(addr..addr + len))
-        .ok_or(Errno::ENOMEM)?;
-
-    arena.flags = ArenaFlags::from(prot);
+    let len = page_frame_number(len + PAGE_SIZE as u64 - 1);
+    thread
+        .vm
+        .lock()
+        .protect(addr, len as _, ArenaFlags::from(prot))?;
 
     Ok(0)
 }
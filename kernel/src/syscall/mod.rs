@@ -439,6 +439,18 @@ async fn do_handle_syscall(
         SYS_CHDIR => sys_chdir(thread, ctx, syscall_registers),
         SYS_READLINK => sys_readlink(thread, ctx, syscall_registers),
         SYS_READLINKAT => sys_readlinkat(thread, ctx, syscall_registers),
+        SYS_SETXATTR => sys_setxattr(thread, ctx, syscall_registers),
+        SYS_LSETXATTR => sys_lsetxattr(thread, ctx, syscall_registers),
+        SYS_FSETXATTR => sys_fsetxattr(thread, ctx, syscall_registers),
+        SYS_GETXATTR => sys_getxattr(thread, ctx, syscall_registers),
+        SYS_LGETXATTR => sys_lgetxattr(thread, ctx, syscall_registers),
+        SYS_FGETXATTR => sys_fgetxattr(thread, ctx, syscall_registers),
+        SYS_LISTXATTR => sys_listxattr(thread, ctx, syscall_registers),
+        SYS_LLISTXATTR => sys_llistxattr(thread, ctx, syscall_registers),
+        SYS_FLISTXATTR => sys_flistxattr(thread, ctx, syscall_registers),
+        SYS_REMOVEXATTR => sys_removexattr(thread, ctx, syscall_registers),
+        SYS_LREMOVEXATTR => sys_lremovexattr(thread, ctx, syscall_registers),
+        SYS_FREMOVEXATTR => sys_fremovexattr(thread, ctx, syscall_registers),
         SYS_SENDFILE => sys_sendfile(thread, ctx, syscall_registers).await,
         SYS_DUP => sys_dup(thread, ctx, syscall_registers),
         SYS_DUP2 => sys_dup2(thread, ctx, syscall_registers),
@@ -452,6 +464,9 @@ async fn do_handle_syscall(
         SYS_MKDIR => sys_mkdir(thread, ctx, syscall_registers),
         SYS_MKDIRAT => sys_mkdirat(thread, ctx, syscall_registers),
         SYS_FCNTL => sys_fnctl(thread, ctx, syscall_registers),
+        SYS_FSYNC => sys_fsync(thread, ctx, syscall_registers),
+        SYS_FDATASYNC => sys_fdatasync(thread, ctx, syscall_registers),
+        SYS_SYNC => sys_sync(thread, ctx, syscall_registers),
         SYS_FSTAT => sys_fstat(thread, ctx, syscall_registers),
         SYS_LSTAT => sys_lstat(thread, ctx, syscall_registers),
         SYS_STAT => sys_stat(thread, ctx, syscall_registers),
@@ -493,6 +508,7 @@ async fn do_handle_syscall(
         SYS_SET_TID_ADDRESS => sys_set_tid_address(thread, ctx, syscall_registers),
         SYS_EXIT => sys_exit(thread, ctx, syscall_registers),
         SYS_FORK | SYS_VFORK => sys_fork(thread, ctx, syscall_registers),
+        SYS_CLONE => sys_clone(thread, ctx, syscall_registers),
         SYS_WAIT4 => sys_wait4(thread, ctx, syscall_registers).await,
         SYS_EXIT_GROUP => sys_exit_group(thread, ctx, syscall_registers),
         SYS_SCHED_GETAFFINITY => sys_sched_getaffinity(thread, ctx, syscall_registers),
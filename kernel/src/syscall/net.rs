@@ -2,7 +2,7 @@
 
 use core::net::{IpAddr, SocketAddr};
 
-use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
 use smoltcp::wire::IpProtocol;
 
 use crate::{
@@ -240,10 +240,12 @@ pub fn sys_setsockopt(
     let socket = proc.get_fd(sockfd)?;
 
     if let FileObject::Socket(socket) = socket {
-        // TODO: Check pointer.
-        let value =
-            unsafe { core::slice::from_raw_parts(option_value as *const u8, option_len as _) }
-                .to_vec();
+        let value_ptr = thread
+            .vm
+            .lock()
+            .get_slice::<u8>(option_value, option_len as _)?;
+        let mut value = vec![0u8; option_len as usize];
+        unsafe { value_ptr.read_slice(&mut value) }?;
         socket.setsockopt(option_name, value).map(|_| 0)
     } else {
         Err(Errno::EBADF)
@@ -262,9 +264,10 @@ pub fn sys_getsockopt(
     let optval = syscall_registers[3];
     let optlen = syscall_registers[4];
 
-    let vm = thread.vm.lock();
-    let opt_ptr = vm.get_ptr(optname)?;
-    let len_ptr = vm.get_ptr::<u64>(optlen)?;
+    let (opt_ptr, len_ptr) = {
+        let vm = thread.vm.lock();
+        (vm.get_ptr(optval)?, vm.get_ptr::<u64>(optlen)?)
+    };
     if opt_ptr.is_null() || len_ptr.is_null() {
         return Err(Errno::EFAULT);
     }
@@ -276,7 +279,7 @@ pub fn sys_getsockopt(
         let optname = SocketOptions::from(optname);
         match socket.getsockopt(optname) {
             Ok(val) => unsafe {
-                opt_ptr.write_slice(&val);
+                opt_ptr.write_slice(&val)?;
                 len_ptr.write(val.len() as _).map(|_| val.len())
             },
             Err(_) => Ok(0),
@@ -325,11 +328,10 @@ pub fn sys_sendto(
     let addr_len = syscall_registers[5];
 
     let mut proc = thread.parent.lock();
-    let vm = thread.vm.lock();
     let socket = proc.get_fd(sockfd)?;
     if let FileObject::Socket(socket) = socket {
         // Check if there is dst_addr.
-        let dst_addr = vm.get_ptr::<SockAddr>(dst_addr)?;
+        let dst_addr = thread.vm.lock().get_ptr::<SockAddr>(dst_addr)?;
         let dst_addr = match dst_addr.is_null() {
             true => None,
             false => {
@@ -338,8 +340,10 @@ pub fn sys_sendto(
             }
         };
 
-        let buf = vm.get_slice(buf, len as _)?;
-        let len = socket.write(buf, dst_addr)?;
+        let buf_ptr = thread.vm.lock().get_slice::<u8>(buf, len as _)?;
+        let mut buf = vec![0u8; len as usize];
+        unsafe { buf_ptr.read_slice(&mut buf) }?;
+        let len = socket.write(&buf, dst_addr)?;
         Ok(len)
     } else {
         Err(Errno::ENOTSOCK)
@@ -359,9 +363,7 @@ pub fn sys_sendmsg(
     let socket = proc.get_fd(sockfd)?;
 
     if let FileObject::Socket(socket) = socket {
-        let vm = thread.vm.lock();
-
-        let msg_ptr = vm.get_ptr::<MsgHdr>(msghdr)?;
+        let msg_ptr = thread.vm.lock().get_ptr::<MsgHdr>(msghdr)?;
         let msg = unsafe { msg_ptr.read() }?;
         // Read messages from iovec.
         let iovec = msg.msg_iov;
@@ -372,7 +374,8 @@ pub fn sys_sendmsg(
             .collect::<Vec<_>>();
 
         // Get destination address.
-        let dst = match unsafe { vm.get_ptr::<SockAddr>(msg.msg_name as _)?.read() } {
+        let dst_ptr = thread.vm.lock().get_ptr::<SockAddr>(msg.msg_name as _)?;
+        let dst = match unsafe { dst_ptr.read() } {
             Ok(addr) => Some(addr.to_core_sockaddr()),
             Err(_) => None,
         };
@@ -400,12 +403,13 @@ pub fn sys_recvfrom(
     let mut proc = thread.parent.lock();
     let socket = proc.get_fd(sockfd)?;
     if let FileObject::Socket(socket) = socket {
-        let vm = thread.vm.lock();
-        let buf = vm.get_mut_slice(buf, len as _)?;
-        let (len, addr) = socket.read(buf)?;
+        let buf_ptr = thread.vm.lock().get_mut_slice::<u8>(buf, len as _)?;
+        let mut buf = vec![0u8; len as usize];
+        let (len, addr) = socket.read(&mut buf)?;
+        unsafe { buf_ptr.write_slice(&buf[..len]) }?;
 
         kinfo!("trying to get mutable pointer @ {src_addr:#x}");
-        let ptr = vm.get_mut_ptr(src_addr).unwrap_or_default();
+        let ptr = thread.vm.lock().get_mut_ptr(src_addr).unwrap_or_default();
         if let (false, Some(SocketAddr::V4(addr))) = (ptr.is_null(), addr) {
             // We need to write to the `src_addr` (e.g., for UDP connections).
             unsafe {
@@ -441,15 +445,13 @@ pub fn sys_recvmsg(
     let socket = proc.get_fd(sockfd)?;
 
     if let FileObject::Socket(socket) = socket {
-        let vm = thread.vm.lock();
-        let mgs_ptr = vm.get_mut_ptr::<MsgHdr>(msghdr)?;
+        let mgs_ptr = thread.vm.lock().get_mut_ptr::<MsgHdr>(msghdr)?;
         let msg = unsafe { mgs_ptr.read() }?;
         let iovec = msg.msg_iov;
         let iovec_len = msg.msg_iovlen;
 
         let mut buf = [0u8; 4096];
         let (len, addr) = socket.read(&mut buf)?;
-        drop(vm);
         let len = IoVec::write_all_iovecs(thread, iovec, iovec_len as _, &buf[..len])?;
 
         Ok(len)
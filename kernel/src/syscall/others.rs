@@ -9,6 +9,7 @@ use crate::{
     process::thread::{Thread, ThreadContext},
     sys::{Time, Timespec, Timeval, Timezone, Utsname},
     time::{SystemTime, UNIX_EPOCH},
+    utils::ptr::Ptr,
 };
 
 const ARCH_SET_GS: u64 = 0x1001;
@@ -32,7 +33,6 @@ pub fn sys_arch_prctl(
 
     let code = syscall_registers[0];
     let addr = syscall_registers[1];
-    let vm = thread.vm.lock();
 
     match code {
         ARCH_SET_FS => {
@@ -44,14 +44,14 @@ pub fn sys_arch_prctl(
             Ok(0)
         }
         ARCH_GET_FS => {
-            let ptr = vm.get_mut_ptr(addr)?;
+            let ptr = thread.vm.lock().get_mut_ptr(addr)?;
             unsafe {
                 ptr.write(ctx.get_user_context().regs.fs)?;
             }
             Ok(0)
         }
         ARCH_GET_GS => {
-            let ptr = vm.get_mut_ptr(addr)?;
+            let ptr = thread.vm.lock().get_mut_ptr(addr)?;
             unsafe {
                 ptr.write(ctx.get_user_context().regs.gs)?;
             }
@@ -92,10 +92,11 @@ pub fn sys_gettimeofday(
         .duration_since(UNIX_EPOCH)
         .map_err(|_| Errno::EINVAL)?;
 
-    let vm = thread.vm.lock();
-    let p_tv = vm.get_mut_ptr(tv)?;
-    // The use of the timezone structure is obsolete; the tz argument should normally be specified as NULL.
-    let p_tz = vm.get_mut_ptr::<Timezone>(tz)?;
+    let (p_tv, p_tz) = {
+        let vm = thread.vm.lock();
+        // The use of the timezone structure is obsolete; the tz argument should normally be specified as NULL.
+        (vm.get_mut_ptr(tv)?, vm.get_mut_ptr::<Timezone>(tz)?)
+    };
     if !p_tz.is_null() {
         return Err(Errno::EINVAL);
     }
@@ -142,10 +143,12 @@ pub fn sys_getgroups(
     let size = syscall_registers[0];
     let list = syscall_registers[1];
 
-    let list = unsafe { core::slice::from_raw_parts_mut(list as *mut u64, size as _) };
     let gid = thread.parent.lock().process_group_id;
 
-    list[0] = gid;
+    // If size is zero, list is not modified, but the total number of supplementary group IDs is returned.
+    if size != 0 {
+        unsafe { Ptr::<u64>::new(list).write(gid) }?;
+    }
     Ok(1)
 }
 
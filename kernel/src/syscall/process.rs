@@ -14,6 +14,7 @@ use crate::{
     },
     signal::SigAction,
     sync::futex::Futex,
+    sys::CloneFlags,
     utils::{ptr::Ptr, split_path},
 };
 
@@ -32,6 +33,58 @@ pub fn sys_fork(
     Ok(new_pid as _)
 }
 
+/// clone() creates a new thread if `CLONE_THREAD` is given. Any other kind of clone is treated as a fork whose child
+/// starts on `stack`.
+pub fn sys_clone(
+    thread: &Arc<Thread>,
+    ctx: &mut ThreadContext,
+    syscall_registers: [u64; SYSCALL_REGS_NUM],
+) -> KResult<usize> {
+    let flags = CloneFlags::from_bits_truncate(syscall_registers[0]);
+    let stack = syscall_registers[1];
+    let parent_tid = syscall_registers[2];
+    let child_tid = syscall_registers[3];
+    let tls = syscall_registers[4];
+
+    let mut context = ctx.get_user_context().as_ref().clone();
+    if stack != 0 {
+        context.regs.rsp = stack;
+    }
+
+    if !flags.contains(CloneFlags::CLONE_THREAD) {
+        let new_thread = thread.fork(&context);
+        let new_pid = new_thread.parent.lock().process_id;
+        spawn(new_thread)?;
+        return Ok(new_pid as _);
+    }
+
+    if flags.contains(CloneFlags::CLONE_SETTLS) {
+        context.regs.fs = tls;
+    }
+    let clear_child_tid = match flags.contains(CloneFlags::CLONE_CHILD_CLEARTID) {
+        true => child_tid,
+        false => 0,
+    };
+    let new_thread = thread.new_thread(&context, clear_child_tid)?;
+    let tid = new_thread.id;
+
+    // Both live in the same address space.
+    for (flag, addr) in [
+        (CloneFlags::CLONE_PARENT_SETTID, parent_tid),
+        (CloneFlags::CLONE_CHILD_SETTID, child_tid),
+    ] {
+        if flags.contains(flag) {
+            let ptr = thread.vm.lock().get_mut_ptr::<i32>(addr)?;
+            unsafe {
+                ptr.write(tid as _)?;
+            }
+        }
+    }
+
+    spawn(new_thread)?;
+    Ok(tid as _)
+}
+
 /// Waits for process to change state. On success, returns the process ID of the child whose state has changed.
 pub async fn sys_wait4(
     thread: &Arc<Thread>,
@@ -179,8 +232,10 @@ pub fn sys_exit(
     // futex wake operation are ignored.
     let clear_child_tid = thread.inner.lock().clear_child_tid;
     if clear_child_tid != 0 {
-        if let Ok(tid) = thread.vm.lock().get_mut_slice(clear_child_tid, 1) {
-            tid[0] = 0;
+        let tid = thread.vm.lock().get_mut_ptr::<i32>(clear_child_tid);
+        if let Ok(tid) = tid {
+            // Like the futex wake below, a failed write is ignored.
+            let _ = unsafe { tid.write(0) };
 
             // Get the fast userspace mutex lock.
             let futex = proc
@@ -341,7 +396,7 @@ pub fn sys_execve(
         .opened_files
         .iter()
         .filter(|&(fd, file)| {
-            if let FileObject::File(file) = file {
+            if let FileObject::File(file) = file.as_ref() {
                 file.fd_cloexec
             } else {
                 false
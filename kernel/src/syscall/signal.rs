@@ -1,9 +1,9 @@
 use alloc::sync::Arc;
 
 use crate::{
-    arch::{interrupt::SYSCALL_REGS_NUM, signal::SigContext, QWORD_LEN},
+    arch::{interrupt::SYSCALL_REGS_NUM, QWORD_LEN},
     error::{Errno, KResult},
-    memory::{copy_from_user, copy_to_user},
+    memory::{copy_from_user, copy_slice_from_user, copy_to_user},
     process::{
         search_by_id, search_by_thread,
         thread::{Thread, ThreadContext},
@@ -106,23 +106,42 @@ pub fn sys_rt_sigreturn(
     // Copy the pointer from the user space into the kernel.
     let sig_frame_ptr =
         (ctx.get_user_context().get_rsp() - core::mem::size_of::<u64>() as u64) as *const SigFrame;
-    let mut sig_frame = match unsafe { copy_from_user(sig_frame_ptr) } {
+    let sig_frame = match unsafe { copy_from_user(sig_frame_ptr) } {
         Ok(sig_frame) => sig_frame,
         Err(errno) => {
             kerror!("cannot copy from the user space! Errno: {:?}", errno);
             return Err(errno);
         }
     };
+    let uc_context = &sig_frame.ucontext.uc_context;
+
+    // Restore the floating-point state saved by `handle_signal`. It comes from the user, so it must be sanitized
+    // before `xrstor` sees it, even if it was only partially copied.
+    let fp_state = ctx.get_fp_state();
+    match uc_context.fpstate {
+        0 => fp_state.reset(),
+        fpstate => {
+            let res =
+                unsafe { copy_slice_from_user(fp_state.as_bytes_mut(), fpstate as *const u8) };
+            fp_state.sanitize();
+            res?;
+        }
+    }
 
     // Process the signal on the alternative stack.
     kdebug!("handling `sys_ret_sigreturn`");
     {
-        thread.inner.lock().sigaltstack = sig_frame.ucontext.uc_stack;
+        let mut inner = thread.inner.lock();
+        inner.sigaltstack = sig_frame.ucontext.uc_stack;
+        inner.sigmask = sig_frame.ucontext.uc_mask;
     }
 
-    // Restore the context and resume it. ThreadContext -> User Context -> Signal Frame
-    sig_frame.ucontext.uc_context = SigContext::from_uctx(ctx.get_user_context());
-    Ok(ctx.get_user_context().regs.rax as _)
+    // Restore the context and resume it. Signal Frame -> User Context -> ThreadContext
+    let user_context = ctx.get_user_context();
+    uc_context.restore_uctx(user_context)?;
+    // `sysret` clobbers rcx and r11, so go back through `iretq` which restores every register.
+    user_context.trapno = 0;
+    Ok(user_context.regs.rax as _)
 }
 
 /// The kill() system call can be used to send any signal to any process group or process.
@@ -172,9 +191,10 @@ pub fn sys_rt_sigprocmask(
         return Err(Errno::EINVAL);
     }
 
-    let vm = thread.vm.lock();
-    let set = vm.get_ptr(set)?;
-    let oldset = vm.get_mut_ptr(oldset)?;
+    let (set, oldset) = {
+        let vm = thread.vm.lock();
+        (vm.get_ptr(set)?, vm.get_mut_ptr(oldset)?)
+    };
 
     if !oldset.is_null() {
         unsafe {
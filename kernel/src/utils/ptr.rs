@@ -1,6 +1,6 @@
 //! A wrapper for raw pointer.
 
-use core::{ffi::CStr, marker::PhantomData};
+use core::marker::PhantomData;
 
 use alloc::{
     string::{String, ToString},
@@ -9,7 +9,7 @@ use alloc::{
 
 use crate::{
     error::{Errno, KResult},
-    memory::{copy_from_user, copy_to_user},
+    memory::{copy_from_user, copy_slice_from_user, copy_slice_to_user, copy_to_user},
 };
 
 /// A workaround for sending and syncing raw pointers between different threads because compile rejects sending something
@@ -111,15 +111,20 @@ where
     /// Similar to other pointer-manipulating functions, this function is unsafe because there is no guarantee that
     /// the buffer pointed by this pointer is valid. Any incautios use of the function can cause undefined behavior.
     /// It is recommended that the caller always check the pointer before use.
-    pub unsafe fn write_c_string(&self, src: &str) {
-        (self.ptr as *mut u8).copy_from(src.as_ptr(), src.len());
+    pub unsafe fn write_c_string(&self, src: &str) -> KResult<()> {
+        copy_slice_to_user(self.ptr as *mut u8, src.as_bytes())?;
         // Write a null byte to the end of the buffer.
-        (self.ptr as *mut u8).add(src.len()).write(0u8);
+        copy_to_user(&0u8, (self.ptr as *mut u8).add(src.len()))
     }
 
     /// Writes a byte array to the area pointed by this pointer.
-    pub unsafe fn write_slice(&self, src: &[T]) {
-        core::ptr::copy(src.as_ptr(), self.ptr as _, src.len());
+    pub unsafe fn write_slice(&self, src: &[T]) -> KResult<()> {
+        copy_slice_to_user(self.ptr as _, src)
+    }
+
+    /// Reads `dst.len()` elements from the area pointed by this pointer.
+    pub unsafe fn read_slice(&self, dst: &mut [T]) -> KResult<()> {
+        copy_slice_from_user(dst, self.ptr as _)
     }
 }
 
@@ -202,11 +207,6 @@ impl ToString for Ptr<u8> {
     /// println!("{}", ptr.to_string());
     ///```
     fn to_string(&self) -> String {
-        unsafe {
-            CStr::from_ptr(self.ptr as _)
-                .to_str()
-                .unwrap_or_default()
-                .to_string()
-        }
+        self.read_c_string().unwrap_or_default()
     }
 }
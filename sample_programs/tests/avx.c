@@ -0,0 +1,124 @@
+/* Checks that the kernel preserves the AVX registers.
+ *
+ * Two processes load distinct patterns into the upper YMM registers, then
+ * yield the CPU and deliver themselves a signal whose handler clobbers every
+ * YMM register. Both context switches and signal returns must give the
+ * registers back untouched. */
+
+#include <signal.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/syscall.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+#define ROUNDS 1000
+#define LANES 8
+#define REGS 8
+
+static volatile int handled;
+
+__attribute__((target("avx"))) static void clobber(int sig) {
+  __asm__ volatile("vpcmpeqd %%ymm0, %%ymm0, %%ymm0\n\t"
+                   "vmovdqa %%ymm0, %%ymm8\n\t"
+                   "vmovdqa %%ymm0, %%ymm9\n\t"
+                   "vmovdqa %%ymm0, %%ymm10\n\t"
+                   "vmovdqa %%ymm0, %%ymm11\n\t"
+                   "vmovdqa %%ymm0, %%ymm12\n\t"
+                   "vmovdqa %%ymm0, %%ymm13\n\t"
+                   "vmovdqa %%ymm0, %%ymm14\n\t"
+                   "vmovdqa %%ymm0, %%ymm15\n\t" ::
+                       : "xmm0", "xmm8", "xmm9", "xmm10", "xmm11", "xmm12",
+                         "xmm13", "xmm14", "xmm15");
+  handled++;
+}
+
+// Loads `in` into ymm8-ymm15, yields, raises SIGUSR1 and stores the registers
+// into `out`. Everything happens in one asm block so that the compiler cannot
+// touch the registers in between.
+__attribute__((target("avx"))) static void round_trip(const uint32_t *in,
+                                                      uint32_t *out,
+                                                      long pid) {
+  __asm__ volatile("vmovdqu 0x00(%[in]), %%ymm8\n\t"
+                   "vmovdqu 0x20(%[in]), %%ymm9\n\t"
+                   "vmovdqu 0x40(%[in]), %%ymm10\n\t"
+                   "vmovdqu 0x60(%[in]), %%ymm11\n\t"
+                   "vmovdqu 0x80(%[in]), %%ymm12\n\t"
+                   "vmovdqu 0xa0(%[in]), %%ymm13\n\t"
+                   "vmovdqu 0xc0(%[in]), %%ymm14\n\t"
+                   "vmovdqu 0xe0(%[in]), %%ymm15\n\t"
+                   "mov %[yield], %%eax\n\t"
+                   "syscall\n\t"
+                   "mov %[kill], %%eax\n\t"
+                   "mov %[pid], %%rdi\n\t"
+                   "mov %[sig], %%esi\n\t"
+                   "syscall\n\t"
+                   "vmovdqu %%ymm8, 0x00(%[out])\n\t"
+                   "vmovdqu %%ymm9, 0x20(%[out])\n\t"
+                   "vmovdqu %%ymm10, 0x40(%[out])\n\t"
+                   "vmovdqu %%ymm11, 0x60(%[out])\n\t"
+                   "vmovdqu %%ymm12, 0x80(%[out])\n\t"
+                   "vmovdqu %%ymm13, 0xa0(%[out])\n\t"
+                   "vmovdqu %%ymm14, 0xc0(%[out])\n\t"
+                   "vmovdqu %%ymm15, 0xe0(%[out])\n\t"
+                   :
+                   : [in] "r"(in), [out] "r"(out), [pid] "r"(pid),
+                     [yield] "i"(SYS_sched_yield), [kill] "i"(SYS_kill),
+                     [sig] "i"(SIGUSR1)
+                   : "rax", "rcx", "rdx", "rsi", "rdi", "r11", "xmm8", "xmm9",
+                     "xmm10", "xmm11", "xmm12", "xmm13", "xmm14", "xmm15",
+                     "memory");
+}
+
+static int run(uint32_t seed) {
+  uint32_t in[REGS * LANES], out[REGS * LANES];
+  int failed = 0;
+
+  for (int round = 0; round < ROUNDS; round++) {
+    for (int i = 0; i < REGS * LANES; i++) {
+      in[i] = seed ^ (round * 0x9e3779b9u) ^ i;
+    }
+
+    round_trip(in, out, getpid());
+    if (memcmp(in, out, sizeof(in)) != 0) {
+      failed++;
+    }
+  }
+
+  printf("avx: seed %#x: %d rounds, %d signals, %d corrupted\n", seed, ROUNDS,
+         handled, failed);
+  return failed != 0;
+}
+
+int main(void) {
+  __builtin_cpu_init();
+  if (!__builtin_cpu_supports("avx")) {
+    printf("avx: SKIPPED (no AVX support)\n");
+    return 0;
+  }
+
+  struct sigaction action = {0};
+  action.sa_handler = clobber;
+  sigaction(SIGUSR1, &action, NULL);
+
+  pid_t child = fork();
+  if (child == 0) {
+    return run(0x5a5a5a5a);
+  }
+
+  int failed = run(0xa5a5a5a5);
+  int status = 0;
+  if (child == -1 || waitpid(child, &status, 0) == -1 ||
+      !WIFEXITED(status) || WEXITSTATUS(status) != 0) {
+    failed = 1;
+  }
+
+  if (failed) {
+    printf("avx: FAILED\n");
+    return 1;
+  }
+
+  printf("avx: PASSED\n");
+  return 0;
+}
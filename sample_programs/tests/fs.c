@@ -1,12 +1,14 @@
 /* Tests whether the syscall interfaces for filesystem manipulation are correct
  * and sane. */
 
+#include <errno.h>
 #include <fcntl.h>
 #include <stdio.h>
 #include <dirent.h>
 #include <unistd.h>
 #include <string.h>
 #include <sys/stat.h>
+#include <sys/xattr.h>
 #include <time.h>
 
 int test_getdents64(const char *name) {
@@ -210,6 +212,66 @@ int main() {
     passed_suite += 1;
   }
 
+  printf("[-] testing `setxattr` a file `/bin/fs`...");
+  fflush(stdout);
+  const char *xattr_value = "neoos";
+  if (setxattr("/bin/fs", "user.neoos.test", xattr_value, strlen(xattr_value),
+               XATTR_CREATE) < 0) {
+    printf("\tfailed.\n");
+    perror("\t[+] setxattr");
+    failed_suite += 1;
+  } else {
+    printf("\tpassed.\n");
+    passed_suite += 1;
+  }
+
+  printf("[-] testing `getxattr` the attribute of `/bin/fs`...");
+  fflush(stdout);
+  char value[32] = {0};
+  ssize_t value_len = getxattr("/bin/fs", "user.neoos.test", value, sizeof(value));
+  if (value_len != (ssize_t)strlen(xattr_value) ||
+      memcmp(value, xattr_value, value_len) != 0) {
+    printf("\tfailed.\n");
+    perror("\t[+] getxattr");
+    failed_suite += 1;
+  } else {
+    printf("\tpassed.\n");
+    passed_suite += 1;
+  }
+
+  printf("[-] testing `listxattr` a file `/bin/fs`...");
+  fflush(stdout);
+  char names[256] = {0};
+  ssize_t names_len = listxattr("/bin/fs", names, sizeof(names));
+  int listed = 0;
+  // The names are separated by null bytes.
+  for (ssize_t i = 0; i < names_len; i += strlen(names + i) + 1) {
+    if (strcmp(names + i, "user.neoos.test") == 0) {
+      listed = 1;
+    }
+  }
+  if (!listed) {
+    printf("\tfailed.\n");
+    perror("\t[+] listxattr");
+    failed_suite += 1;
+  } else {
+    printf("\tpassed.\n");
+    passed_suite += 1;
+  }
+
+  printf("[-] testing `removexattr` the attribute of `/bin/fs`...");
+  fflush(stdout);
+  if (removexattr("/bin/fs", "user.neoos.test") < 0 ||
+      getxattr("/bin/fs", "user.neoos.test", value, sizeof(value)) >= 0 ||
+      errno != ENODATA) {
+    printf("\tfailed.\n");
+    perror("\t[+] removexattr");
+    failed_suite += 1;
+  } else {
+    printf("\tpassed.\n");
+    passed_suite += 1;
+  }
+
   printf("[-] Test summary:\n");
   printf("\t[+] Passed: %d\n\t[+] Failed: %d\n", passed_suite, failed_suite);
 
@@ -0,0 +1,129 @@
+/* Stress test for TLB shootdowns.
+ *
+ * A sibling thread keeps reading a mapping while the main thread unmaps it and
+ * immediately maps and fills another region, which is likely to reuse the
+ * frames that were just freed. Once munmap() returns, the reader must fault on
+ * the old address; if it sees the new data there instead, its CPU used a stale
+ * translation. */
+
+#define _GNU_SOURCE
+
+#include <sched.h>
+#include <setjmp.h>
+#include <signal.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <sys/mman.h>
+#include <unistd.h>
+
+#define ROUNDS 1000
+#define PAGES 16
+#define PAGE_SIZE 4096
+#define REGION_SIZE (PAGES * PAGE_SIZE)
+#define STACK_SIZE (64 * 1024)
+
+#define OLD_MAGIC 0x1111111111111111UL
+#define NEW_MAGIC 0x2222222222222222UL
+
+static volatile unsigned long *volatile shared;
+static volatile unsigned long reads;
+static volatile int done, reader_exited;
+static volatile int stale, faults;
+static sigjmp_buf reader_env;
+
+static void on_segv(int sig) { siglongjmp(reader_env, 1); }
+
+static int reader(void *arg) {
+  while (!done) {
+    if (sigsetjmp(reader_env, 1)) {
+      // Expected: the mapping went away under us.
+      faults++;
+      continue;
+    }
+
+    volatile unsigned long *p = shared;
+    if (p == NULL) {
+      continue;
+    }
+
+    for (int i = 0; i < PAGES; i++) {
+      if (p[i * PAGE_SIZE / sizeof(long)] == NEW_MAGIC) {
+        stale++;
+      }
+    }
+    reads++;
+  }
+
+  reader_exited = 1;
+  return 0;
+}
+
+static void fill(unsigned long *region, unsigned long magic) {
+  for (int i = 0; i < PAGES; i++) {
+    region[i * PAGE_SIZE / sizeof(long)] = magic;
+  }
+}
+
+int main(void) {
+  struct sigaction action = {0};
+  action.sa_handler = on_segv;
+  action.sa_flags = SA_NODEFER;
+  sigaction(SIGSEGV, &action, NULL);
+
+  // Reserve two regions at fixed addresses so that the new mapping never
+  // takes the virtual address of the old one.
+  char *base = mmap(NULL, 2 * REGION_SIZE, PROT_READ | PROT_WRITE,
+                    MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+  if (base == MAP_FAILED) {
+    printf("tlb: mmap failed\n");
+    return 1;
+  }
+  munmap(base, 2 * REGION_SIZE);
+  char *old_addr = base;
+  char *new_addr = base + REGION_SIZE;
+
+  char *stack = malloc(STACK_SIZE);
+  if (clone(reader, stack + STACK_SIZE,
+            CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD |
+                CLONE_SYSVSEM,
+            NULL) == -1) {
+    printf("tlb: clone failed\n");
+    return 1;
+  }
+
+  for (int round = 0; round < ROUNDS; round++) {
+    unsigned long *old = mmap(old_addr, REGION_SIZE, PROT_READ | PROT_WRITE,
+                              MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED, -1, 0);
+    fill(old, OLD_MAGIC);
+    shared = old;
+
+    // Let the reader pull the translations into its TLB.
+    unsigned long seen = reads;
+    while (reads < seen + 2) {
+    }
+
+    munmap(old, REGION_SIZE);
+    unsigned long *new = mmap(new_addr, REGION_SIZE, PROT_READ | PROT_WRITE,
+                              MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED, -1, 0);
+    fill(new, NEW_MAGIC);
+
+    // Give the reader a chance to trip over stale translations.
+    for (volatile int i = 0; i < 10000; i++) {
+    }
+    shared = NULL;
+    munmap(new, REGION_SIZE);
+  }
+
+  done = 1;
+  while (!reader_exited) {
+  }
+
+  printf("tlb: %d rounds, %d faults, %d stale reads\n", ROUNDS, faults, stale);
+  if (stale != 0) {
+    printf("tlb: FAILED\n");
+    return 1;
+  }
+
+  printf("tlb: PASSED\n");
+  return 0;
+}